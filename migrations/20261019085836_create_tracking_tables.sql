@@ -0,0 +1,24 @@
+-- Add migration script here
+CREATE TABLE tracking_tokens(
+   tracking_token TEXT NOT NULL,
+   newsletter_issue_id uuid NOT NULL
+      REFERENCES newsletter_issues (newsletter_issue_id),
+   subscriber_id uuid NOT NULL
+      REFERENCES subscriptions (id),
+   -- NULL for the open pixel, the original link target for clicks
+   url TEXT NULL,
+   PRIMARY KEY (tracking_token)
+);
+
+CREATE TABLE tracking_events(
+   id uuid NOT NULL,
+   newsletter_issue_id uuid NOT NULL
+      REFERENCES newsletter_issues (newsletter_issue_id),
+   subscriber_id uuid NOT NULL
+      REFERENCES subscriptions (id),
+   kind TEXT NOT NULL,
+   url TEXT NULL,
+   occurred_at timestamptz NOT NULL,
+   PRIMARY KEY (id)
+);
+CREATE INDEX tracking_events_issue_idx ON tracking_events (newsletter_issue_id, kind);
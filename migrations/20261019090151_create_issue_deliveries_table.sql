@@ -0,0 +1,12 @@
+-- Add migration script here
+CREATE TABLE issue_deliveries(
+   newsletter_issue_id uuid NOT NULL
+      REFERENCES newsletter_issues (newsletter_issue_id),
+   subscriber_id uuid NOT NULL
+      REFERENCES subscriptions (id),
+   -- 'sent' once accepted by the email provider, 'failed' if it refused the
+   -- request, 'bounced' when the provider reports a bounce afterwards
+   status TEXT NOT NULL,
+   sent_at timestamptz NOT NULL,
+   PRIMARY KEY (newsletter_issue_id, subscriber_id)
+);
@@ -0,0 +1,31 @@
+-- Add migration script here
+CREATE TABLE lists(
+   list_id uuid NOT NULL,
+   slug TEXT NOT NULL UNIQUE,
+   name TEXT NOT NULL,
+   created_at timestamptz NOT NULL,
+   PRIMARY KEY (list_id)
+);
+
+CREATE TABLE list_memberships(
+   list_id uuid NOT NULL
+      REFERENCES lists (list_id),
+   subscriber_id uuid NOT NULL
+      REFERENCES subscriptions (id),
+   status TEXT NOT NULL,
+   subscribed_at timestamptz NOT NULL,
+   PRIMARY KEY (list_id, subscriber_id)
+);
+
+-- Everybody subscribed so far belongs to the original, single list
+INSERT INTO lists (list_id, slug, name, created_at)
+    VALUES ('00000000-0000-0000-0000-000000000001', 'default', 'Newsletter', now());
+INSERT INTO list_memberships (list_id, subscriber_id, status, subscribed_at)
+    SELECT '00000000-0000-0000-0000-000000000001', id, status, subscribed_at
+    FROM subscriptions;
+
+ALTER TABLE newsletter_issues ADD COLUMN list_id uuid NULL
+    REFERENCES lists (list_id);
+UPDATE newsletter_issues
+    SET list_id = '00000000-0000-0000-0000-000000000001';
+ALTER TABLE newsletter_issues ALTER COLUMN list_id SET NOT NULL;
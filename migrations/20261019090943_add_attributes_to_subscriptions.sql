@@ -0,0 +1,4 @@
+-- Add migration script here
+ALTER TABLE subscriptions ADD COLUMN attributes JSONB NOT NULL DEFAULT '{}';
+-- Declares which extra signup fields a list accepts, see AttributeSchema
+ALTER TABLE lists ADD COLUMN attribute_schema JSONB NOT NULL DEFAULT '{}';
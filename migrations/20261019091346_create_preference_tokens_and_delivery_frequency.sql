@@ -0,0 +1,9 @@
+-- Add migration script here
+CREATE TABLE preference_tokens(
+   preference_token TEXT NOT NULL,
+   subscriber_id uuid NOT NULL UNIQUE
+      REFERENCES subscriptions (id),
+   PRIMARY KEY (preference_token)
+);
+
+ALTER TABLE subscriptions ADD COLUMN delivery_frequency TEXT NOT NULL DEFAULT 'immediate';
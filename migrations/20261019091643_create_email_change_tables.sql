@@ -0,0 +1,20 @@
+-- Add migration script here
+CREATE TABLE email_change_requests(
+   email_change_token TEXT NOT NULL,
+   subscriber_id uuid NOT NULL
+      REFERENCES subscriptions (id),
+   new_email TEXT NOT NULL,
+   requested_at timestamptz NOT NULL,
+   PRIMARY KEY (email_change_token)
+);
+
+-- Audit trail of every confirmed address change
+CREATE TABLE subscriber_email_history(
+   id uuid NOT NULL,
+   subscriber_id uuid NOT NULL
+      REFERENCES subscriptions (id),
+   previous_email TEXT NOT NULL,
+   new_email TEXT NOT NULL,
+   changed_at timestamptz NOT NULL,
+   PRIMARY KEY (id)
+);
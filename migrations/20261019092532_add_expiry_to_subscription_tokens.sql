@@ -0,0 +1,8 @@
+-- Add migration script here
+-- Tokens issued so far get the default three day window from now on
+ALTER TABLE subscription_tokens
+    ADD COLUMN created_at timestamptz NOT NULL DEFAULT now(),
+    ADD COLUMN expires_at timestamptz NOT NULL DEFAULT now() + interval '3 days';
+ALTER TABLE subscription_tokens
+    ALTER COLUMN created_at DROP DEFAULT,
+    ALTER COLUMN expires_at DROP DEFAULT;
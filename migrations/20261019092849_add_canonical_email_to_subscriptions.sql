@@ -0,0 +1,17 @@
+-- Add migration script here
+ALTER TABLE subscriptions ADD COLUMN canonical_email TEXT NULL;
+-- Existing addresses that only differ by case are left distinct: the
+-- oldest subscriber gets the lowercased form, later ones a form no signup
+-- can produce. Aliases are not folded here, the application refreshes the
+-- canonical forms at startup when `fold_email_aliases` is on.
+UPDATE subscriptions s
+    SET canonical_email = CASE
+        WHEN EXISTS (
+            SELECT 1 FROM subscriptions o
+            WHERE lower(o.email) = lower(s.email)
+                AND (o.subscribed_at, o.id) < (s.subscribed_at, s.id)
+        ) THEN lower(s.email) || '#dup-' || s.id
+        ELSE lower(s.email)
+    END;
+ALTER TABLE subscriptions ALTER COLUMN canonical_email SET NOT NULL;
+ALTER TABLE subscriptions ADD CONSTRAINT subscriptions_canonical_email_key UNIQUE (canonical_email);
@@ -0,0 +1,2 @@
+-- Why a signup was accepted but flagged for review, e.g. 'role_account'
+ALTER TABLE subscriptions ADD COLUMN flagged_as TEXT NULL;
@@ -0,0 +1,13 @@
+-- Keys for machine clients, only a SHA-256 hash of the key is stored
+CREATE TABLE api_keys(
+   api_key_id uuid NOT NULL,
+   name TEXT NOT NULL,
+   -- The first characters of the key, to tell keys apart in listings
+   key_prefix TEXT NOT NULL,
+   key_hash TEXT NOT NULL UNIQUE,
+   scopes TEXT[] NOT NULL,
+   created_at timestamptz NOT NULL,
+   last_used_at timestamptz NULL,
+   revoked_at timestamptz NULL,
+   PRIMARY KEY (api_key_id)
+);
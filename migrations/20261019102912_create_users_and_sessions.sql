@@ -0,0 +1,19 @@
+-- Admin users, roles are 'owner', 'editor' or 'viewer'
+CREATE TABLE users(
+   user_id uuid NOT NULL,
+   username TEXT NOT NULL UNIQUE,
+   password_hash TEXT NOT NULL,
+   role TEXT NOT NULL,
+   created_at timestamptz NOT NULL,
+   PRIMARY KEY (user_id)
+);
+
+-- Only a SHA-256 hash of the session token is stored
+CREATE TABLE user_sessions(
+   session_token_hash TEXT NOT NULL,
+   user_id uuid NOT NULL
+      REFERENCES users (user_id),
+   created_at timestamptz NOT NULL,
+   expires_at timestamptz NOT NULL,
+   PRIMARY KEY (session_token_hash)
+);
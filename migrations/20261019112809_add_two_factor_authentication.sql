@@ -0,0 +1,17 @@
+-- The TOTP secret is stored once enrollment starts, it only guards logins
+-- once totp_enabled_at is set. totp_last_step keeps a code from being used twice.
+ALTER TABLE users ADD COLUMN totp_secret TEXT NULL;
+ALTER TABLE users ADD COLUMN totp_enabled_at timestamptz NULL;
+ALTER TABLE users ADD COLUMN totp_last_step BIGINT NULL;
+
+-- Only a SHA-256 hash of each recovery code is stored
+CREATE TABLE recovery_codes(
+   user_id uuid NOT NULL
+      REFERENCES users (user_id),
+   code_hash TEXT NOT NULL,
+   used_at timestamptz NULL,
+   PRIMARY KEY (user_id, code_hash)
+);
+
+-- Sessions of enrolled users start out waiting for the second factor
+ALTER TABLE user_sessions ADD COLUMN second_factor_pending BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,13 @@
+-- Where password reset links go, users without one cannot reset
+ALTER TABLE users ADD COLUMN email TEXT NULL;
+
+-- Only a SHA-256 hash of the reset token is stored
+CREATE TABLE password_reset_tokens(
+   token_hash TEXT NOT NULL,
+   user_id uuid NOT NULL
+      REFERENCES users (user_id),
+   created_at timestamptz NOT NULL,
+   expires_at timestamptz NOT NULL,
+   used_at timestamptz NULL,
+   PRIMARY KEY (token_hash)
+);
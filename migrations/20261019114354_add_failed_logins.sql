@@ -0,0 +1,8 @@
+-- Recent failed logins, for slowing down and locking out password guessing
+CREATE TABLE failed_logins(
+   username TEXT NOT NULL,
+   ip_address TEXT NOT NULL,
+   attempted_at timestamptz NOT NULL
+);
+CREATE INDEX failed_logins_username_idx ON failed_logins (username, attempted_at);
+CREATE INDEX failed_logins_ip_address_idx ON failed_logins (ip_address, attempted_at);
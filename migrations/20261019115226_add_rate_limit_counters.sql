@@ -0,0 +1,8 @@
+-- Request counts of the current window per key, shared by all instances
+CREATE TABLE rate_limit_counters(
+   key TEXT NOT NULL,
+   hits INT NOT NULL,
+   expires_at timestamptz NOT NULL,
+   PRIMARY KEY (key)
+);
+CREATE INDEX rate_limit_counters_expires_at_idx ON rate_limit_counters (expires_at);
@@ -0,0 +1,4 @@
+-- Pending requests so far get a three day window from now on
+ALTER TABLE email_change_requests
+    ADD COLUMN expires_at timestamptz NOT NULL DEFAULT now() + interval '3 days';
+ALTER TABLE email_change_requests ALTER COLUMN expires_at DROP DEFAULT;
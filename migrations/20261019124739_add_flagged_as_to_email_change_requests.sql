@@ -0,0 +1,3 @@
+-- What the new address is flagged as, copied onto the subscriber once the
+-- change is confirmed
+ALTER TABLE email_change_requests ADD COLUMN flagged_as TEXT NULL;
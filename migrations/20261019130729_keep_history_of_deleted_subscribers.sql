@@ -0,0 +1,4 @@
+-- Delivery and tracking history outlives deleted subscribers, their rows
+-- are moved to a random id instead
+ALTER TABLE issue_deliveries DROP CONSTRAINT issue_deliveries_subscriber_id_fkey;
+ALTER TABLE tracking_events DROP CONSTRAINT tracking_events_subscriber_id_fkey;
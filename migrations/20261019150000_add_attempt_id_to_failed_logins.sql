@@ -0,0 +1,3 @@
+-- Attempts are recorded before the password is checked, the id lets a
+-- correct one be taken back
+ALTER TABLE failed_logins ADD COLUMN attempt_id BIGSERIAL PRIMARY KEY;
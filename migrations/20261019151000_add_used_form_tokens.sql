@@ -0,0 +1,6 @@
+-- Nonces of spent signup form tokens, each token is good for one signup
+CREATE TABLE used_form_tokens(
+   nonce TEXT PRIMARY KEY,
+   expires_at timestamptz NOT NULL
+);
+CREATE INDEX used_form_tokens_expires_at_idx ON used_form_tokens (expires_at);
@@ -0,0 +1,203 @@
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::StatusCode;
+use actix_web::HttpMessage;
+
+use crate::authentication::AdminUser;
+use crate::domain::Permission;
+use crate::routes::Problem;
+
+/// What each `/admin` route requires, `None` meaning any signed-in user.
+/// Routes missing here are closed to every role.
+const ADMIN_ROUTES: &[(&str, &str, Option<Permission>)] = &[
+    ("PUT", "/admin/account/password", None),
+    ("POST", "/admin/account/two_factor", None),
+    ("DELETE", "/admin/account/two_factor", None),
+    ("POST", "/admin/account/two_factor/confirm", None),
+    ("GET", "/admin/users", Some(Permission::ManageAccess)),
+    ("POST", "/admin/users", Some(Permission::ManageAccess)),
+    (
+        "PUT",
+        "/admin/users/{user_id}/role",
+        Some(Permission::ManageAccess),
+    ),
+    // Sending right away also needs `Publish`, which depends on the body and
+    // is checked by the handler.
+    ("POST", "/admin/issues", Some(Permission::Edit)),
+    (
+        "POST",
+        "/admin/issues/{issue_id}/publish",
+        Some(Permission::Publish),
+    ),
+    (
+        "GET",
+        "/admin/issues/{issue_id}/stats",
+        Some(Permission::View),
+    ),
+    ("GET", "/admin/api_keys", Some(Permission::ManageAccess)),
+    ("POST", "/admin/api_keys", Some(Permission::ManageAccess)),
+    (
+        "DELETE",
+        "/admin/api_keys/{api_key_id}",
+        Some(Permission::ManageAccess),
+    ),
+    ("GET", "/admin/lists", Some(Permission::View)),
+    ("POST", "/admin/lists", Some(Permission::Edit)),
+    ("GET", "/admin/segments", Some(Permission::View)),
+    ("POST", "/admin/segments", Some(Permission::Edit)),
+    (
+        "DELETE",
+        "/admin/subscribers/{subscriber_id}",
+        Some(Permission::DeleteSubscribers),
+    ),
+    (
+        "POST",
+        "/admin/subscribers/{subscriber_id}/tags",
+        Some(Permission::Edit),
+    ),
+    (
+        "DELETE",
+        "/admin/subscribers/{subscriber_id}/tags/{tag}",
+        Some(Permission::Edit),
+    ),
+];
+
+/// The entry of `ADMIN_ROUTES` matching the request, if any.
+pub fn admin_route_access(method: &str, path: &str) -> Option<Option<Permission>> {
+    ADMIN_ROUTES
+        .iter()
+        .find(|(route_method, pattern, _)| *route_method == method && path_matches(pattern, path))
+        .map(|(_, _, permission)| *permission)
+}
+
+/// `{name}` segments of the pattern match any single non-empty segment.
+fn path_matches(pattern: &str, path: &str) -> bool {
+    let mut pattern = pattern.split('/');
+    let mut path = path.split('/');
+    loop {
+        match (pattern.next(), path.next()) {
+            (None, None) => return true,
+            (Some(expected), Some(actual)) => {
+                let matches = if expected.starts_with('{') {
+                    !actual.is_empty()
+                } else {
+                    expected == actual
+                };
+                if !matches {
+                    return false;
+                }
+            }
+            _ => return false,
+        }
+    }
+}
+
+/// Authenticates every request to the `/admin` scope and checks the role
+/// against `ADMIN_ROUTES` before any handler runs. Handlers still extract
+/// the `AdminUser`, which this stores in the request extensions.
+pub struct AdminAccess;
+
+impl<S, B> Transform<S, ServiceRequest> for AdminAccess
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = AdminAccessMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AdminAccessMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct AdminAccessMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for AdminAccessMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        Box::pin(async move {
+            let user = match req.extract::<AdminUser>().await {
+                Ok(user) => user,
+                Err(e) => return Ok(req.error_response(e).map_into_right_body()),
+            };
+            let allowed = match admin_route_access(req.method().as_str(), req.path()) {
+                Some(Some(permission)) => user.require(permission),
+                Some(None) => Ok(()),
+                None => Err(
+                    Problem::new(StatusCode::FORBIDDEN, "No role is allowed to do this.")
+                        .error_response(),
+                ),
+            };
+            if let Err(response) = allowed {
+                return Ok(req.into_response(response).map_into_right_body());
+            }
+            req.extensions_mut().insert(user);
+            service
+                .call(req)
+                .await
+                .map(ServiceResponse::map_into_left_body)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{admin_route_access, path_matches};
+    use crate::domain::Permission;
+
+    #[test]
+    fn placeholders_match_a_single_segment() {
+        assert!(path_matches(
+            "/admin/users/{user_id}/role",
+            "/admin/users/42/role"
+        ));
+        assert!(!path_matches(
+            "/admin/users/{user_id}/role",
+            "/admin/users//role"
+        ));
+        assert!(!path_matches(
+            "/admin/users/{user_id}/role",
+            "/admin/users/42"
+        ));
+        assert!(!path_matches("/admin/users", "/admin/users/42"));
+    }
+
+    #[test]
+    fn routes_are_looked_up_by_method_and_path() {
+        assert_eq!(
+            admin_route_access("DELETE", "/admin/subscribers/42"),
+            Some(Some(Permission::DeleteSubscribers))
+        );
+        assert_eq!(
+            admin_route_access("POST", "/admin/subscribers/42/tags"),
+            Some(Some(Permission::Edit))
+        );
+        assert_eq!(
+            admin_route_access("PUT", "/admin/account/password"),
+            Some(None)
+        );
+        assert_eq!(admin_route_access("PATCH", "/admin/lists"), None);
+    }
+}
@@ -0,0 +1,116 @@
+use std::future::Future;
+use std::pin::Pin;
+
+use actix_web::http::StatusCode;
+use actix_web::{dev::Payload, web, FromRequest, HttpRequest, HttpResponse};
+use chrono::Utc;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::authentication::{bearer_token, hash_token, internal_error, unauthorized};
+use crate::domain::ApiScope;
+use crate::routes::Problem;
+
+const KEY_PREFIX: &str = "nl_";
+/// Characters of a key shown in listings, including `KEY_PREFIX`.
+const DISPLAYED_PREFIX_LENGTH: usize = 10;
+
+/// A freshly generated key, the plain text is only ever shown once.
+pub struct GeneratedApiKey {
+    pub key: String,
+    pub key_prefix: String,
+    pub key_hash: String,
+}
+
+pub fn generate_api_key() -> GeneratedApiKey {
+    let mut rng = thread_rng();
+    let secret: String = std::iter::repeat_with(|| rng.sample(Alphanumeric))
+        .map(char::from)
+        .take(40)
+        .collect();
+    let key = format!("{}{}", KEY_PREFIX, secret);
+    GeneratedApiKey {
+        key_prefix: key[..DISPLAYED_PREFIX_LENGTH].to_owned(),
+        key_hash: hash_token(&key),
+        key,
+    }
+}
+
+/// The API key a request was authenticated with, extracted from an
+/// `Authorization: Bearer <key>` header.
+#[derive(Debug)]
+pub struct ApiKey {
+    pub api_key_id: Uuid,
+    scopes: Vec<String>,
+}
+
+impl ApiKey {
+    /// Returns the 403 response to send if the key lacks `scope`.
+    pub fn require(&self, scope: ApiScope) -> Result<(), HttpResponse> {
+        if self.scopes.iter().any(|s| s == scope.as_str()) {
+            return Ok(());
+        }
+        Err(Problem::new(
+            StatusCode::FORBIDDEN,
+            format!("This API key lacks the {} scope.", scope.as_str()),
+        )
+        .error_response())
+    }
+}
+
+impl FromRequest for ApiKey {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let pool = req.app_data::<web::Data<PgPool>>().cloned();
+        let key = bearer_token(req);
+        Box::pin(async move {
+            let pool = pool.expect("The database pool is registered as app data");
+            let key = key.ok_or_else(|| unauthorized("Send an API key as a bearer token."))?;
+            match authenticate(&pool, &key).await {
+                Ok(Some(api_key)) => Ok(api_key),
+                Ok(None) => Err(unauthorized("Unknown or revoked API key.")),
+                Err(e) => Err(internal_error(e)),
+            }
+        })
+    }
+}
+
+/// Looks up an active key and records that it was used.
+#[tracing::instrument(name = "Authenticating an API key", skip(pool, key))]
+async fn authenticate(pool: &PgPool, key: &str) -> Result<Option<ApiKey>, sqlx::Error> {
+    sqlx::query_as!(
+        ApiKey,
+        r#"
+        UPDATE api_keys SET last_used_at = $2
+        WHERE key_hash = $1 AND revoked_at IS NULL
+        RETURNING api_key_id, scopes
+        "#,
+        hash_token(key),
+        Utc::now()
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::generate_api_key;
+    use crate::authentication::hash_token;
+
+    #[test]
+    fn generated_keys_are_prefixed_and_hashed() {
+        let generated = generate_api_key();
+        assert!(generated.key.starts_with("nl_"));
+        assert!(generated.key.starts_with(&generated.key_prefix));
+        assert_eq!(generated.key_hash, hash_token(&generated.key));
+        assert_ne!(generated.key_hash, generate_api_key().key_hash);
+    }
+}
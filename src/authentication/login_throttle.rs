@@ -0,0 +1,295 @@
+use chrono::{DateTime, Duration, Utc};
+use sqlx::{PgPool, Postgres, Transaction};
+
+use crate::configuration::LoginThrottleSettings;
+
+/// Failed logins within the window, for a username, for the address the
+/// attempt comes from and for the two together.
+#[derive(Debug, Default)]
+pub struct RecentFailures {
+    pub username_failures: i64,
+    pub username_last_failure: Option<DateTime<Utc>>,
+    pub username_ip_failures: i64,
+    pub username_ip_last_failure: Option<DateTime<Utc>>,
+    pub ip_failures: i64,
+    pub ip_last_failure: Option<DateTime<Utc>>,
+}
+
+/// How long until the next attempt may be made, `None` if it may go ahead
+/// now. Lockouts win over the delay. The username is only locked for the
+/// address the failures came from, the delay applies from everywhere.
+pub fn login_wait(
+    settings: &LoginThrottleSettings,
+    failures: &RecentFailures,
+    now: DateTime<Utc>,
+) -> Option<Duration> {
+    let allowed_at = if failures.ip_failures >= settings.ip_lockout_failures {
+        failures.ip_last_failure? + settings.window()
+    } else if failures.username_ip_failures >= settings.username_lockout_failures {
+        failures.username_ip_last_failure? + settings.window()
+    } else if failures.username_failures >= settings.free_failures {
+        let doublings = (failures.username_failures - settings.free_failures).min(30) as u32;
+        let delay = std::cmp::min(Duration::seconds(1 << doublings), settings.max_delay());
+        failures.username_last_failure? + delay
+    } else {
+        return None;
+    };
+    Some(allowed_at - now).filter(|wait| *wait > Duration::zero())
+}
+
+/// Whether a login may check the password, see `begin_login_attempt`.
+#[derive(Debug)]
+pub enum LoginAttempt {
+    /// Recorded as a failure under this id until the password turns out
+    /// to be right.
+    Started(i64),
+    Throttled {
+        wait: Duration,
+        failures: RecentFailures,
+    },
+}
+
+/// Counts the recent failures and, unless the attempt has to wait, records
+/// it as failed before the password is even checked. Both happen under a
+/// lock on the username and the address, so guesses sent in parallel are
+/// counted as if they came one after the other.
+#[tracing::instrument(name = "Starting a login attempt", skip(pool, settings))]
+pub async fn begin_login_attempt(
+    pool: &PgPool,
+    settings: &LoginThrottleSettings,
+    username: &str,
+    ip_address: &str,
+) -> Result<LoginAttempt, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    // Always the username first, so two attempts never wait on each other.
+    for key in [
+        format!("login:username:{}", username),
+        format!("login:ip:{}", ip_address),
+    ] {
+        sqlx::query("SELECT pg_advisory_xact_lock(hashtext($1))")
+            .bind(key)
+            .execute(&mut transaction)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to execute query: {:?}", e);
+                e
+            })?;
+    }
+    let now = Utc::now();
+    let failures = recent_failures(&mut transaction, settings, username, ip_address, now).await?;
+    if let Some(wait) = login_wait(settings, &failures, now) {
+        return Ok(LoginAttempt::Throttled { wait, failures });
+    }
+    let attempt_id =
+        insert_failed_login(&mut transaction, settings, username, ip_address, now).await?;
+    transaction.commit().await?;
+    Ok(LoginAttempt::Started(attempt_id))
+}
+
+async fn recent_failures(
+    transaction: &mut Transaction<'_, Postgres>,
+    settings: &LoginThrottleSettings,
+    username: &str,
+    ip_address: &str,
+    now: DateTime<Utc>,
+) -> Result<RecentFailures, sqlx::Error> {
+    sqlx::query_as!(
+        RecentFailures,
+        r#"
+        SELECT
+            count(*) FILTER (WHERE username = $1) AS "username_failures!",
+            max(attempted_at) FILTER (WHERE username = $1) AS username_last_failure,
+            count(*) FILTER (WHERE username = $1 AND ip_address = $2)
+                AS "username_ip_failures!",
+            max(attempted_at) FILTER (WHERE username = $1 AND ip_address = $2)
+                AS username_ip_last_failure,
+            count(*) FILTER (WHERE ip_address = $2) AS "ip_failures!",
+            max(attempted_at) FILTER (WHERE ip_address = $2) AS ip_last_failure
+        FROM failed_logins
+        WHERE (username = $1 OR ip_address = $2) AND attempted_at > $3
+        "#,
+        username,
+        ip_address,
+        now - settings.window()
+    )
+    .fetch_one(transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })
+}
+
+/// Records a failed attempt and drops the ones that no longer count.
+#[tracing::instrument(name = "Recording a failed login", skip(pool, settings))]
+pub async fn record_failed_login(
+    pool: &PgPool,
+    settings: &LoginThrottleSettings,
+    username: &str,
+    ip_address: &str,
+) -> Result<(), sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    insert_failed_login(&mut transaction, settings, username, ip_address, Utc::now()).await?;
+    transaction.commit().await
+}
+
+async fn insert_failed_login(
+    transaction: &mut Transaction<'_, Postgres>,
+    settings: &LoginThrottleSettings,
+    username: &str,
+    ip_address: &str,
+    now: DateTime<Utc>,
+) -> Result<i64, sqlx::Error> {
+    let attempt_id = sqlx::query!(
+        r#"
+        INSERT INTO failed_logins (username, ip_address, attempted_at)
+        VALUES ($1, $2, $3)
+        RETURNING attempt_id
+        "#,
+        username,
+        ip_address,
+        now
+    )
+    .fetch_one(&mut *transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?
+    .attempt_id;
+    sqlx::query!(
+        "DELETE FROM failed_logins WHERE attempted_at <= $1",
+        now - settings.window()
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(attempt_id)
+}
+
+/// Takes back an attempt recorded by `begin_login_attempt` whose password
+/// was right, without forgetting the other failures of the username.
+pub async fn forget_login_attempt(pool: &PgPool, attempt_id: i64) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "DELETE FROM failed_logins WHERE attempt_id = $1",
+        attempt_id
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+/// A successful login forgets the failures of the username from its own
+/// address. Those from elsewhere keep counting, logging in must not wipe
+/// the record of someone guessing the password.
+pub async fn clear_failed_logins(
+    pool: &PgPool,
+    username: &str,
+    ip_address: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "DELETE FROM failed_logins WHERE username = $1 AND ip_address = $2",
+        username,
+        ip_address
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{login_wait, RecentFailures};
+    use crate::configuration::LoginThrottleSettings;
+    use chrono::{Duration, Utc};
+
+    fn settings() -> LoginThrottleSettings {
+        LoginThrottleSettings {
+            window_minutes: 15,
+            free_failures: 3,
+            max_delay_seconds: 60,
+            username_lockout_failures: 10,
+            ip_lockout_failures: 50,
+        }
+    }
+
+    fn username_failures(count: i64) -> RecentFailures {
+        RecentFailures {
+            username_failures: count,
+            username_last_failure: Some(Utc::now()),
+            username_ip_failures: count,
+            username_ip_last_failure: Some(Utc::now()),
+            ip_failures: count,
+            ip_last_failure: Some(Utc::now()),
+        }
+    }
+
+    fn wait_seconds(failures: &RecentFailures) -> Option<i64> {
+        // Rounded up, a few milliseconds pass between the failure and now.
+        login_wait(&settings(), failures, Utc::now())
+            .map(|wait| (wait + Duration::milliseconds(999)).num_seconds())
+    }
+
+    #[test]
+    fn the_first_failures_are_free() {
+        assert_eq!(None, wait_seconds(&RecentFailures::default()));
+        assert_eq!(None, wait_seconds(&username_failures(2)));
+    }
+
+    #[test]
+    fn the_delay_doubles_up_to_the_maximum() {
+        let waits: Vec<_> = (3..10)
+            .map(|count| wait_seconds(&username_failures(count)))
+            .collect();
+        assert_eq!(
+            vec![1, 2, 4, 8, 16, 32, 60],
+            waits.into_iter().flatten().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn too_many_failures_lock_the_username_for_a_window() {
+        assert_eq!(Some(15 * 60), wait_seconds(&username_failures(10)));
+    }
+
+    #[test]
+    fn failures_from_other_addresses_only_delay_the_username() {
+        let failures = RecentFailures {
+            username_failures: 10,
+            username_last_failure: Some(Utc::now()),
+            ..Default::default()
+        };
+        assert_eq!(Some(60), wait_seconds(&failures));
+    }
+
+    #[test]
+    fn too_many_failures_from_one_address_lock_it_for_a_window() {
+        let failures = RecentFailures {
+            ip_failures: 50,
+            ip_last_failure: Some(Utc::now()),
+            ..Default::default()
+        };
+        assert_eq!(Some(15 * 60), wait_seconds(&failures));
+    }
+
+    #[test]
+    fn waits_end_after_the_delay() {
+        let failures = RecentFailures {
+            username_failures: 4,
+            username_last_failure: Some(Utc::now() - Duration::seconds(3)),
+            ..Default::default()
+        };
+        assert_eq!(None, wait_seconds(&failures));
+    }
+}
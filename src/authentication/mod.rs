@@ -0,0 +1,50 @@
+mod admin_access;
+mod api_key;
+mod login_throttle;
+mod password;
+mod session;
+mod two_factor;
+mod users;
+
+pub use admin_access::*;
+pub use api_key::*;
+pub use login_throttle::*;
+pub use password::*;
+pub use session::*;
+pub use two_factor::*;
+pub use users::*;
+
+use actix_web::error::InternalError;
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::HttpRequest;
+use sha2::{Digest, Sha256};
+
+use crate::routes::Problem;
+
+/// API keys and session tokens are long random strings, a fast unsalted
+/// hash is enough to keep a database leak from exposing them.
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+fn bearer_token(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(|token| token.trim().to_owned())
+}
+
+fn unauthorized(detail: &'static str) -> actix_web::Error {
+    let mut response = Problem::new(StatusCode::UNAUTHORIZED, detail).error_response();
+    response
+        .headers_mut()
+        .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+    InternalError::from_response(detail, response).into()
+}
+
+fn internal_error(e: sqlx::Error) -> actix_web::Error {
+    InternalError::from_response(e, Problem::internal_error().error_response()).into()
+}
@@ -0,0 +1,140 @@
+use argon2::password_hash::SaltString;
+use argon2::{Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version};
+use once_cell::sync::Lazy;
+use secrecy::{ExposeSecret, Secret};
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::authentication::{end_other_sessions, AdminUser};
+
+/// Verified against when the username is unknown, so response times do not
+/// tell which usernames exist.
+static DUMMY_HASH: Lazy<String> =
+    Lazy::new(|| compute_password_hash(&Secret::new("not a real password".into())));
+
+pub fn compute_password_hash(password: &Secret<String>) -> String {
+    let salt = SaltString::generate(&mut rand::thread_rng());
+    Argon2::new(
+        Algorithm::Argon2id,
+        Version::V0x13,
+        Params::new(15000, 2, 1, None).expect("The Argon2 parameters are valid"),
+    )
+    .hash_password(password.expose_secret().as_bytes(), &salt)
+    .expect("Hashing with valid parameters cannot fail")
+    .to_string()
+}
+
+/// The parameters are read from the stored hash, so older hashes keep
+/// verifying after a change to `compute_password_hash`.
+pub fn verify_password_hash(expected_hash: &str, candidate: &Secret<String>) -> bool {
+    let expected_hash = match PasswordHash::new(expected_hash) {
+        Ok(hash) => hash,
+        Err(e) => {
+            tracing::error!("Stored password hash is invalid: {}", e);
+            return false;
+        }
+    };
+    Argon2::default()
+        .verify_password(candidate.expose_secret().as_bytes(), &expected_hash)
+        .is_ok()
+}
+
+/// Hashing is CPU bound, run it off the async executor.
+pub async fn spawn_blocking_with_tracing<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let current_span = tracing::Span::current();
+    tokio::task::spawn_blocking(move || current_span.in_scope(f))
+        .await
+        .expect("Password hashing panicked")
+}
+
+/// Returns the id of the user if the password matches.
+#[tracing::instrument(name = "Validating credentials", skip(pool, password))]
+pub async fn validate_credentials(
+    pool: &PgPool,
+    username: &str,
+    password: Secret<String>,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let stored = sqlx::query!(
+        "SELECT user_id, password_hash FROM users WHERE username = $1",
+        username
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    let (user_id, expected_hash) = match stored {
+        Some(stored) => (Some(stored.user_id), Some(stored.password_hash)),
+        None => (None, None),
+    };
+    let verified = spawn_blocking_with_tracing(move || {
+        let expected_hash = expected_hash.as_deref().unwrap_or(&DUMMY_HASH);
+        verify_password_hash(expected_hash, &password)
+    })
+    .await;
+    Ok(user_id.filter(|_| verified))
+}
+
+/// Stores a new password and logs the user out everywhere else, a session
+/// an attacker may hold stops working.
+#[tracing::instrument(name = "Changing a password", skip(pool, password, keep))]
+pub async fn change_password(
+    pool: &PgPool,
+    user_id: Uuid,
+    password: Secret<String>,
+    keep: Option<&AdminUser>,
+) -> Result<(), sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    set_password(&mut transaction, user_id, password, keep).await?;
+    transaction.commit().await.map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })
+}
+
+/// `change_password` within a transaction the caller commits.
+pub async fn set_password(
+    transaction: &mut Transaction<'_, Postgres>,
+    user_id: Uuid,
+    password: Secret<String>,
+    keep: Option<&AdminUser>,
+) -> Result<(), sqlx::Error> {
+    let password_hash = spawn_blocking_with_tracing(move || compute_password_hash(&password)).await;
+    sqlx::query!(
+        "UPDATE users SET password_hash = $2 WHERE user_id = $1",
+        user_id,
+        password_hash
+    )
+    .execute(&mut *transaction)
+    .await?;
+    end_other_sessions(transaction, user_id, keep).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compute_password_hash, verify_password_hash};
+    use secrecy::Secret;
+
+    #[test]
+    fn only_the_original_password_verifies() {
+        let hash = compute_password_hash(&Secret::new("correct horse battery staple".into()));
+        assert!(verify_password_hash(
+            &hash,
+            &Secret::new("correct horse battery staple".into())
+        ));
+        assert!(!verify_password_hash(
+            &hash,
+            &Secret::new("Tr0ub4dor&3".into())
+        ));
+    }
+
+    #[test]
+    fn malformed_hashes_never_verify() {
+        assert!(!verify_password_hash("", &Secret::new("".into())));
+    }
+}
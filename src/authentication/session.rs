@@ -0,0 +1,204 @@
+use std::future::Future;
+use std::pin::Pin;
+
+use actix_web::http::StatusCode;
+use actix_web::{dev::Payload, web, FromRequest, HttpMessage, HttpRequest, HttpResponse};
+use chrono::{DateTime, Duration, Utc};
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::authentication::{bearer_token, hash_token, internal_error, unauthorized};
+use crate::domain::{Permission, UserRole};
+use crate::routes::Problem;
+use crate::tokens::generate_token;
+
+/// The admin user a request was authenticated as, extracted from an
+/// `Authorization: Bearer <session token>` header.
+#[derive(Debug, Clone)]
+pub struct AdminUser {
+    pub user_id: Uuid,
+    pub username: String,
+    pub role: UserRole,
+    session_token_hash: String,
+}
+
+impl AdminUser {
+    /// Returns the 403 response to send if the role lacks `permission`.
+    pub fn require(&self, permission: Permission) -> Result<(), HttpResponse> {
+        if self.role.can(permission) {
+            return Ok(());
+        }
+        Err(Problem::new(
+            StatusCode::FORBIDDEN,
+            format!("The {} role is not allowed to do this.", self.role.as_str()),
+        )
+        .error_response())
+    }
+}
+
+impl FromRequest for AdminUser {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        // `AdminAccess` already authenticated requests to the `/admin` scope.
+        if let Some(user) = req.extensions().get::<AdminUser>() {
+            let user = user.clone();
+            return Box::pin(async move { Ok(user) });
+        }
+        let session = authenticate(req);
+        Box::pin(async move {
+            match session.await? {
+                (user, false) => Ok(user),
+                (_, true) => Err(unauthorized(
+                    "Finish logging in with a code from your authenticator app.",
+                )),
+            }
+        })
+    }
+}
+
+/// A session that still waits for the second factor, only good for
+/// `POST /login/two_factor`.
+#[derive(Debug)]
+pub struct PendingLogin(pub AdminUser);
+
+impl FromRequest for PendingLogin {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let session = authenticate(req);
+        Box::pin(async move {
+            match session.await? {
+                (user, true) => Ok(PendingLogin(user)),
+                (_, false) => Err(unauthorized("This session needs no second factor.")),
+            }
+        })
+    }
+}
+
+/// Looks up the session of the bearer token, along with whether it still
+/// waits for the second factor.
+fn authenticate(
+    req: &HttpRequest,
+) -> impl Future<Output = Result<(AdminUser, bool), actix_web::Error>> {
+    let pool = req.app_data::<web::Data<PgPool>>().cloned();
+    let token = bearer_token(req);
+    async move {
+        let pool = pool.expect("The database pool is registered as app data");
+        let token = token.ok_or_else(|| unauthorized("Log in and send the session token."))?;
+        match get_session_user(&pool, &hash_token(&token)).await {
+            Ok(Some(session)) => Ok(session),
+            Ok(None) => Err(unauthorized("Unknown or expired session.")),
+            Err(e) => Err(internal_error(e)),
+        }
+    }
+}
+
+pub struct Session {
+    pub token: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// A session with `second_factor_pending` set only authenticates the second
+/// login step.
+#[tracing::instrument(name = "Starting a session", skip(pool))]
+pub async fn create_session(
+    pool: &PgPool,
+    user_id: Uuid,
+    ttl: Duration,
+    second_factor_pending: bool,
+) -> Result<Session, sqlx::Error> {
+    let token = generate_token();
+    let now = Utc::now();
+    let expires_at = now + ttl;
+    sqlx::query!(
+        r#"
+        INSERT INTO user_sessions
+            (session_token_hash, user_id, created_at, expires_at, second_factor_pending)
+        VALUES ($1, $2, $3, $4, $5)
+        "#,
+        hash_token(&token),
+        user_id,
+        now,
+        expires_at,
+        second_factor_pending
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(Session { token, expires_at })
+}
+
+#[tracing::instrument(name = "Ending a session", skip(pool, user), fields(user_id = %user.user_id))]
+pub async fn end_session(pool: &PgPool, user: &AdminUser) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "DELETE FROM user_sessions WHERE session_token_hash = $1",
+        user.session_token_hash
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+/// Logs the user out everywhere, except for the session of `keep` if given.
+pub async fn end_other_sessions(
+    transaction: &mut Transaction<'_, Postgres>,
+    user_id: Uuid,
+    keep: Option<&AdminUser>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        DELETE FROM user_sessions
+        WHERE user_id = $1 AND session_token_hash IS DISTINCT FROM $2
+        "#,
+        user_id,
+        keep.map(|user| user.session_token_hash.as_str())
+    )
+    .execute(&mut *transaction)
+    .await?;
+    Ok(())
+}
+
+async fn get_session_user(
+    pool: &PgPool,
+    session_token_hash: &str,
+) -> Result<Option<(AdminUser, bool)>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"
+        SELECT u.user_id, u.username, u.role, s.second_factor_pending
+        FROM user_sessions s
+        JOIN users u ON u.user_id = s.user_id
+        WHERE s.session_token_hash = $1 AND s.expires_at > $2
+        "#,
+        session_token_hash,
+        Utc::now()
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    row.map(|r| {
+        UserRole::parse(&r.role).map(|role| {
+            let user = AdminUser {
+                user_id: r.user_id,
+                username: r.username,
+                role,
+                session_token_hash: session_token_hash.to_owned(),
+            };
+            (user, r.second_factor_pending)
+        })
+    })
+    .transpose()
+    .map_err(|e| sqlx::Error::Decode(e.into()))
+}
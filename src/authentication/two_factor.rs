@@ -0,0 +1,456 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use chrono::Utc;
+use qrcode::render::svg;
+use qrcode::QrCode;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use secrecy::ExposeSecret;
+use sha2::{Digest, Sha256};
+use sqlx::{PgPool, Postgres, Transaction};
+use totp_rs::{Algorithm, Secret, TOTP};
+use uuid::Uuid;
+
+use crate::authentication::{end_other_sessions, hash_token, AdminUser};
+
+/// Shown next to the username in authenticator apps.
+const TOTP_ISSUER: &str = "newsletter";
+const TOTP_STEP_SECONDS: u64 = 30;
+const RECOVERY_CODE_COUNT: usize = 10;
+
+const NONCE_LENGTH: usize = 12;
+
+/// What an admin needs to add the account to an authenticator app.
+pub struct TotpEnrollment {
+    /// For typing into the app by hand.
+    pub secret: String,
+    /// The `otpauth://` URI the QR code encodes.
+    pub provisioning_uri: String,
+    /// `None` if the URI is too long for a QR code, which takes a very long
+    /// username.
+    pub qr_code_svg: Option<String>,
+}
+
+/// Encrypts TOTP secrets before they are stored, a database leak alone
+/// does not reveal them. Stored secrets are the hex encoded nonce followed
+/// by the ciphertext.
+pub struct TotpCipher(ChaCha20Poly1305);
+
+impl TotpCipher {
+    /// Any string works as the key, it is hashed down to 256 bits.
+    pub fn new(key: &secrecy::Secret<String>) -> Self {
+        let key = Sha256::digest(key.expose_secret().as_bytes());
+        Self(ChaCha20Poly1305::new(&key))
+    }
+
+    fn encrypt(&self, secret: &str) -> String {
+        let nonce: [u8; NONCE_LENGTH] = thread_rng().gen();
+        let ciphertext = self
+            .0
+            .encrypt(Nonce::from_slice(&nonce), secret.as_bytes())
+            .expect("Only inputs of many gigabytes fail to encrypt");
+        hex::encode([&nonce[..], &ciphertext].concat())
+    }
+
+    fn decrypt(&self, stored: &str) -> Result<String, sqlx::Error> {
+        let undecryptable = || {
+            tracing::error!("Failed to decrypt a stored TOTP secret");
+            sqlx::Error::Decode("The TOTP secret cannot be decrypted with this key.".into())
+        };
+        let bytes = hex::decode(stored).map_err(|_| undecryptable())?;
+        if bytes.len() < NONCE_LENGTH {
+            return Err(undecryptable());
+        }
+        let (nonce, ciphertext) = bytes.split_at(NONCE_LENGTH);
+        let secret = self
+            .0
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| undecryptable())?;
+        String::from_utf8(secret).map_err(|_| undecryptable())
+    }
+}
+
+/// A one-time code from the authenticator app, or one of the recovery codes
+/// handed out at enrollment.
+pub enum SecondFactor {
+    Totp(String),
+    RecoveryCode(String),
+}
+
+fn totp(secret_base32: &str, username: &str) -> TOTP {
+    let secret = Secret::Encoded(secret_base32.to_owned())
+        .to_bytes()
+        .expect("Stored TOTP secrets are valid base32");
+    // The checked constructor rejects a ':' in usernames, the URI encodes it.
+    TOTP::new_unchecked(
+        Algorithm::SHA1,
+        6,
+        1,
+        TOTP_STEP_SECONDS,
+        secret,
+        Some(TOTP_ISSUER.to_owned()),
+        username.to_owned(),
+    )
+}
+
+fn now_in_seconds() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("The clock is past the Unix epoch")
+        .as_secs()
+}
+
+/// Returns the time step `code` belongs to, one step of clock drift either
+/// way is tolerated.
+fn matching_step(totp: &TOTP, code: &str, time: u64) -> Option<i64> {
+    let current = time / TOTP_STEP_SECONDS;
+    (current.saturating_sub(1)..=current + 1)
+        .find(|step| totp.generate(step * TOTP_STEP_SECONDS) == code.trim())
+        .map(|step| step as i64)
+}
+
+/// Ten codes like `k3h8f-q2m9x`, each good for one login.
+pub fn generate_recovery_codes() -> Vec<String> {
+    let mut rng = thread_rng();
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let code: String = std::iter::repeat_with(|| rng.sample(Alphanumeric))
+                .map(|c| char::from(c).to_ascii_lowercase())
+                .take(10)
+                .collect();
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect()
+}
+
+/// Dashes, spaces and case do not matter when typing a recovery code.
+fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    hash_token(&normalized)
+}
+
+pub async fn totp_enabled(pool: &PgPool, user_id: Uuid) -> Result<bool, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT totp_enabled_at IS NOT NULL AS "enabled!" FROM users WHERE user_id = $1"#,
+        user_id
+    )
+    .fetch_one(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(row.enabled)
+}
+
+/// Stores a fresh secret, replacing one from an unfinished enrollment.
+/// Returns `None` if two-factor authentication is already on.
+#[tracing::instrument(name = "Starting TOTP enrollment", skip(pool, cipher))]
+pub async fn start_totp_enrollment(
+    pool: &PgPool,
+    cipher: &TotpCipher,
+    user_id: Uuid,
+    username: &str,
+) -> Result<Option<TotpEnrollment>, sqlx::Error> {
+    let secret = Secret::generate_secret().to_encoded().to_string();
+    let totp = totp(&secret, username);
+    let updated = sqlx::query!(
+        r#"
+        UPDATE users SET totp_secret = $2, totp_last_step = NULL
+        WHERE user_id = $1 AND totp_enabled_at IS NULL
+        "#,
+        user_id,
+        cipher.encrypt(&secret)
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    if updated.rows_affected() == 0 {
+        return Ok(None);
+    }
+    let provisioning_uri = totp.get_url();
+    let qr_code_svg = match QrCode::new(provisioning_uri.as_bytes()) {
+        Ok(code) => Some(code.render::<svg::Color>().min_dimensions(200, 200).build()),
+        Err(e) => {
+            tracing::warn!(error = %e, "The provisioning URI does not fit in a QR code");
+            None
+        }
+    };
+    Ok(Some(TotpEnrollment {
+        secret,
+        provisioning_uri,
+        qr_code_svg,
+    }))
+}
+
+/// Turns two-factor authentication on once the app produced a valid code,
+/// returning the recovery codes. Every other session of the user ends, they
+/// were started without a second factor. `None` if the code is wrong or no
+/// enrollment was started.
+#[tracing::instrument(name = "Confirming TOTP enrollment", skip(pool, cipher, user, code), fields(user_id = %user.user_id))]
+pub async fn confirm_totp_enrollment(
+    pool: &PgPool,
+    cipher: &TotpCipher,
+    user: &AdminUser,
+    code: &str,
+) -> Result<Option<Vec<String>>, sqlx::Error> {
+    let user_id = user.user_id;
+    let mut transaction = pool.begin().await?;
+    let row = sqlx::query!(
+        r#"
+        SELECT username, totp_secret AS "totp_secret!"
+        FROM users
+        WHERE user_id = $1 AND totp_secret IS NOT NULL AND totp_enabled_at IS NULL
+        FOR UPDATE
+        "#,
+        user_id
+    )
+    .fetch_optional(&mut transaction)
+    .await?;
+    let row = match row {
+        Some(row) => row,
+        None => return Ok(None),
+    };
+    let secret = cipher.decrypt(&row.totp_secret)?;
+    let step = match matching_step(&totp(&secret, &row.username), code, now_in_seconds()) {
+        Some(step) => step,
+        None => return Ok(None),
+    };
+    sqlx::query!(
+        "UPDATE users SET totp_enabled_at = $2, totp_last_step = $3 WHERE user_id = $1",
+        user_id,
+        Utc::now(),
+        step
+    )
+    .execute(&mut transaction)
+    .await?;
+    let codes = replace_recovery_codes(&mut transaction, user_id).await?;
+    end_other_sessions(&mut transaction, user_id, Some(user)).await?;
+    transaction.commit().await?;
+    Ok(Some(codes))
+}
+
+async fn replace_recovery_codes(
+    transaction: &mut Transaction<'_, Postgres>,
+    user_id: Uuid,
+) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query!("DELETE FROM recovery_codes WHERE user_id = $1", user_id)
+        .execute(&mut *transaction)
+        .await?;
+    let codes = generate_recovery_codes();
+    let hashes: Vec<String> = codes.iter().map(|c| hash_recovery_code(c)).collect();
+    sqlx::query!(
+        r#"
+        INSERT INTO recovery_codes (user_id, code_hash)
+        SELECT $1, code_hash FROM UNNEST($2::text[]) AS code_hash
+        "#,
+        user_id,
+        &hashes[..]
+    )
+    .execute(&mut *transaction)
+    .await?;
+    Ok(codes)
+}
+
+/// Checks the second factor of an enrolled user. Every code works once:
+/// a TOTP code is rejected if it is not newer than the last one accepted, a
+/// recovery code is marked as used.
+#[tracing::instrument(name = "Verifying a second factor", skip(pool, cipher, factor))]
+pub async fn verify_second_factor(
+    pool: &PgPool,
+    cipher: &TotpCipher,
+    user_id: Uuid,
+    factor: &SecondFactor,
+) -> Result<bool, sqlx::Error> {
+    let verified = match factor {
+        SecondFactor::Totp(code) => {
+            let user = sqlx::query!(
+                r#"
+                SELECT username, totp_secret AS "totp_secret!"
+                FROM users
+                WHERE user_id = $1 AND totp_enabled_at IS NOT NULL
+                "#,
+                user_id
+            )
+            .fetch_optional(pool)
+            .await?;
+            let step = match user {
+                Some(user) => {
+                    let secret = cipher.decrypt(&user.totp_secret)?;
+                    matching_step(&totp(&secret, &user.username), code, now_in_seconds())
+                }
+                None => None,
+            };
+            match step {
+                Some(step) => sqlx::query!(
+                    r#"
+                    UPDATE users SET totp_last_step = $2
+                    WHERE user_id = $1 AND (totp_last_step IS NULL OR totp_last_step < $2)
+                    "#,
+                    user_id,
+                    step
+                )
+                .execute(pool)
+                .await
+                .map(|done| done.rows_affected() == 1),
+                None => Ok(false),
+            }
+        }
+        SecondFactor::RecoveryCode(code) => sqlx::query!(
+            r#"
+            UPDATE recovery_codes SET used_at = $3
+            WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL
+            "#,
+            user_id,
+            hash_recovery_code(code),
+            Utc::now()
+        )
+        .execute(pool)
+        .await
+        .map(|done| done.rows_affected() == 1),
+    };
+    verified.map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })
+}
+
+/// Encrypts secrets stored before they were encrypted, which are base32 and
+/// thus never lowercase hex. Returns how many there were.
+#[tracing::instrument(name = "Encrypting plaintext TOTP secrets", skip(pool, cipher))]
+pub async fn encrypt_plaintext_totp_secrets(
+    pool: &PgPool,
+    cipher: &TotpCipher,
+) -> Result<u64, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT user_id, totp_secret AS "totp_secret!"
+        FROM users
+        WHERE totp_secret IS NOT NULL AND totp_secret !~ '^[0-9a-f]*$'
+        "#
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    for row in &rows {
+        sqlx::query!(
+            "UPDATE users SET totp_secret = $2 WHERE user_id = $1 AND totp_secret = $3",
+            row.user_id,
+            cipher.encrypt(&row.totp_secret),
+            row.totp_secret
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+    }
+    Ok(rows.len() as u64)
+}
+
+/// Turns two-factor authentication off and forgets the secret and the
+/// recovery codes.
+#[tracing::instrument(name = "Disabling TOTP", skip(pool))]
+pub async fn disable_totp(pool: &PgPool, user_id: Uuid) -> Result<(), sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    sqlx::query!(
+        r#"
+        UPDATE users SET totp_secret = NULL, totp_enabled_at = NULL, totp_last_step = NULL
+        WHERE user_id = $1
+        "#,
+        user_id
+    )
+    .execute(&mut transaction)
+    .await?;
+    sqlx::query!("DELETE FROM recovery_codes WHERE user_id = $1", user_id)
+        .execute(&mut transaction)
+        .await?;
+    transaction.commit().await.map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{generate_recovery_codes, hash_recovery_code, matching_step, totp, TotpCipher};
+    use claim::assert_err;
+    use secrecy::Secret;
+
+    // The RFC 6238 test secret, "12345678901234567890" in base32.
+    const SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    #[test]
+    fn codes_of_the_current_and_neighbouring_steps_match() {
+        let totp = totp(SECRET, "ursula");
+        let time = 59;
+        for (offset, expected_step) in [(0, 1), (30, 2)] {
+            let code = totp.generate(time - 29 + offset);
+            assert_eq!(Some(expected_step), matching_step(&totp, &code, time));
+        }
+    }
+
+    #[test]
+    fn codes_from_long_ago_do_not_match() {
+        let totp = totp(SECRET, "ursula");
+        let code = totp.generate(59);
+        assert_eq!(None, matching_step(&totp, &code, 59 + 10 * 30));
+    }
+
+    #[test]
+    fn the_provisioning_uri_names_the_issuer_and_user() {
+        let uri = totp(SECRET, "ursula").get_url();
+        assert_eq!(
+            format!(
+                "otpauth://totp/newsletter:ursula?secret={}&issuer=newsletter",
+                SECRET
+            ),
+            uri
+        );
+    }
+
+    #[test]
+    fn secrets_are_encrypted_with_a_fresh_nonce() {
+        let cipher = TotpCipher::new(&Secret::new("key".to_owned()));
+        let first = cipher.encrypt(SECRET);
+        let second = cipher.encrypt(SECRET);
+        assert_ne!(first, second);
+        assert!(!first.contains(SECRET));
+        assert_eq!(SECRET, cipher.decrypt(&first).unwrap());
+    }
+
+    #[test]
+    fn secrets_do_not_decrypt_with_another_key() {
+        let stored = TotpCipher::new(&Secret::new("key".to_owned())).encrypt(SECRET);
+        let other = TotpCipher::new(&Secret::new("other key".to_owned()));
+        assert_err!(other.decrypt(&stored));
+        assert_err!(other.decrypt(SECRET));
+    }
+
+    #[test]
+    fn recovery_codes_are_distinct_and_forgiving_to_type() {
+        let codes = generate_recovery_codes();
+        let mut unique = codes.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(10, unique.len());
+        let code = &codes[0];
+        assert_eq!(
+            hash_recovery_code(code),
+            hash_recovery_code(&format!(" {} ", code.replace('-', "").to_uppercase()))
+        );
+    }
+}
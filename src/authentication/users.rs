@@ -0,0 +1,71 @@
+use chrono::Utc;
+use secrecy::{ExposeSecret, Secret};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::authentication::{compute_password_hash, spawn_blocking_with_tracing};
+use crate::configuration::AdminSettings;
+use crate::domain::{SubscriberEmail, UserRole};
+
+/// Returns `None` if the username is taken.
+#[tracing::instrument(name = "Creating an admin user", skip(pool, password))]
+pub async fn create_user(
+    pool: &PgPool,
+    username: &str,
+    password: Secret<String>,
+    role: UserRole,
+    email: Option<&SubscriberEmail>,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let password_hash = spawn_blocking_with_tracing(move || compute_password_hash(&password)).await;
+    let user_id = Uuid::new_v4();
+    let inserted = sqlx::query!(
+        r#"
+        INSERT INTO users (user_id, username, password_hash, role, created_at, email)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        ON CONFLICT (username) DO NOTHING
+        "#,
+        user_id,
+        username,
+        password_hash,
+        role.as_str(),
+        Utc::now(),
+        email.map(|e| e.as_ref())
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok((inserted.rows_affected() == 1).then_some(user_id))
+}
+
+/// Creates the configured owner on first start, there is no other way to
+/// get the first admin user in.
+pub async fn ensure_initial_owner(
+    pool: &PgPool,
+    settings: &AdminSettings,
+) -> Result<(), sqlx::Error> {
+    if settings.initial_owner_password.expose_secret().is_empty() {
+        return Ok(());
+    }
+    let email = settings
+        .initial_owner_email()
+        .map_err(|e| sqlx::Error::Configuration(e.into()))?;
+    let has_users = sqlx::query!(r#"SELECT EXISTS (SELECT 1 FROM users) AS "exists!""#)
+        .fetch_one(pool)
+        .await?
+        .exists;
+    if !has_users {
+        create_user(
+            pool,
+            &settings.initial_owner_username,
+            settings.initial_owner_password.clone(),
+            UserRole::Owner,
+            email.as_ref(),
+        )
+        .await?;
+        tracing::info!(username = %settings.initial_owner_username, "Created the initial owner");
+    }
+    Ok(())
+}
@@ -1,4 +1,6 @@
-use crate::domain::SubscriberEmail;
+use crate::authentication::TotpCipher;
+use crate::domain::{RiskyEmail, RiskyEmailLists, SubscriberEmail, SubscriberEmailError};
+use crate::rate_limiting::RateLimit;
 use secrecy::{ExposeSecret, Secret};
 use serde_aux::field_attributes::deserialize_number_from_string;
 use sqlx::postgres::PgConnectOptions;
@@ -14,7 +16,7 @@ pub struct EmailClientSettings {
 }
 
 impl EmailClientSettings {
-    pub fn sender(&self) -> Result<SubscriberEmail, String> {
+    pub fn sender(&self) -> Result<SubscriberEmail, SubscriberEmailError> {
         SubscriberEmail::parse(self.sender_email.clone())
     }
     pub fn timeout(&self) ->std::time::Duration{
@@ -27,6 +29,8 @@ pub struct Settings {
     pub database: DatabaseSettings,
     pub application: ApplicationSettings,
     pub email_client: EmailClientSettings,
+    pub subscriptions: SubscriptionSettings,
+    pub admin: AdminSettings,
 }
 
 #[derive(serde::Deserialize)]
@@ -45,6 +49,226 @@ pub struct ApplicationSettings {
     #[serde(deserialize_with = "deserialize_number_from_string")]
     pub port: u16,
     pub host: String,
+    pub base_url: String,
+    pub api_docs: bool,
+    /// Reverse proxies whose `Forwarded` and `X-Forwarded-For` headers name
+    /// the client, see `client_ip`.
+    #[serde(default)]
+    pub trusted_proxies: Vec<std::net::IpAddr>,
+}
+
+/// Lifetime of confirmation tokens, how long unconfirmed signups are kept
+/// around before the cleanup worker purges them and how duplicates are found.
+#[derive(serde::Deserialize, Clone)]
+pub struct SubscriptionSettings {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub confirmation_token_ttl_hours: i64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub unconfirmed_retention_days: i64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub cleanup_interval_seconds: u64,
+    /// Treat provider aliases (Gmail dots, `+tags`) as the same subscriber.
+    pub fold_email_aliases: bool,
+    pub disposable_email_policy: RiskyEmailPolicy,
+    pub role_email_policy: RiskyEmailPolicy,
+    /// Disposable email domains and role account names, one per line.
+    pub disposable_domains_file: String,
+    pub role_accounts_file: String,
+    /// Reject domains without MX or A records.
+    pub verify_email_domains: bool,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub domain_verification_cache_seconds: u64,
+    pub rate_limiting: RateLimitSettings,
+    pub spam_protection: SpamProtectionSettings,
+}
+
+/// Captcha-free defences against signup bots, see `signup_protection`.
+#[derive(serde::Deserialize, Clone)]
+pub struct SpamProtectionSettings {
+    /// Hidden from people, a bot filling it in gives itself away.
+    pub honeypot_field: String,
+    /// Signs form tokens, all instances need the same one.
+    pub form_secret: Secret<String>,
+    /// 0 stops checking form tokens unless a proof of work is asked for.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub min_fill_seconds: i64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub form_token_ttl_hours: i64,
+    /// Leading zero bits, 0 turns the proof of work off.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub proof_of_work_difficulty: u32,
+}
+
+/// Signups allowed per client address and per email domain.
+#[derive(serde::Deserialize, Clone)]
+pub struct RateLimitSettings {
+    pub store: RateLimitStore,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub per_ip_requests: u32,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub per_ip_window_seconds: u64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub per_domain_requests: u32,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub per_domain_window_seconds: u64,
+    /// Lowercase webmail domains too many people share to limit, only the
+    /// per-address limit applies to them.
+    pub shared_email_domains: Vec<String>,
+}
+
+/// Where request counts live. Counts in memory are per instance, Postgres
+/// shares them between instances.
+#[derive(serde::Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitStore {
+    Memory,
+    Postgres,
+}
+
+/// What to do with signups from addresses `SubscriberEmail::check_risk`
+/// reports.
+#[derive(serde::Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RiskyEmailPolicy {
+    Reject,
+    Flag,
+    Allow,
+}
+
+/// Admin sessions, and the owner account created on first start when there
+/// are no users yet.
+#[derive(serde::Deserialize, Clone)]
+pub struct AdminSettings {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub session_ttl_hours: i64,
+    /// No owner is created while the password is empty.
+    pub initial_owner_username: String,
+    pub initial_owner_password: Secret<String>,
+    /// Where password reset links for the initial owner go.
+    #[serde(default)]
+    pub initial_owner_email: Option<String>,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub password_reset_ttl_minutes: i64,
+    pub login_throttling: LoginThrottleSettings,
+    pub password_reset_rate_limiting: PasswordResetRateLimitSettings,
+    /// Encrypts TOTP secrets in the database, see `TotpCipher`.
+    pub totp_encryption_key: Secret<String>,
+}
+
+/// How failed logins slow down further attempts. Only failures within the
+/// window count, a lockout lasts a window past the last failure.
+#[derive(serde::Deserialize, Clone)]
+pub struct LoginThrottleSettings {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub window_minutes: i64,
+    /// Failures of a username before each further attempt has to wait,
+    /// twice as long after every failure.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub free_failures: i64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_delay_seconds: i64,
+    /// Counted per username and address, failures from elsewhere must not
+    /// lock the owner of the username out.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub username_lockout_failures: i64,
+    /// Higher than the username limit, one address may serve many admins.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub ip_lockout_failures: i64,
+}
+
+/// Reset emails allowed per client address and per username.
+#[derive(serde::Deserialize, Clone)]
+pub struct PasswordResetRateLimitSettings {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub per_ip_requests: u32,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub per_username_requests: u32,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub window_minutes: u64,
+}
+
+impl PasswordResetRateLimitSettings {
+    pub fn per_ip(&self) -> RateLimit {
+        RateLimit {
+            requests: self.per_ip_requests,
+            window: std::time::Duration::from_secs(self.window_minutes * 60),
+        }
+    }
+    pub fn per_username(&self) -> RateLimit {
+        RateLimit {
+            requests: self.per_username_requests,
+            window: std::time::Duration::from_secs(self.window_minutes * 60),
+        }
+    }
+}
+
+impl AdminSettings {
+    pub fn session_ttl(&self) -> chrono::Duration {
+        chrono::Duration::hours(self.session_ttl_hours)
+    }
+    pub fn password_reset_ttl(&self) -> chrono::Duration {
+        chrono::Duration::minutes(self.password_reset_ttl_minutes)
+    }
+    pub fn totp_cipher(&self) -> TotpCipher {
+        TotpCipher::new(&self.totp_encryption_key)
+    }
+    pub fn initial_owner_email(&self) -> Result<Option<SubscriberEmail>, SubscriberEmailError> {
+        self.initial_owner_email
+            .clone()
+            .map(SubscriberEmail::parse)
+            .transpose()
+    }
+}
+
+impl RateLimitSettings {
+    pub fn per_ip(&self) -> RateLimit {
+        RateLimit {
+            requests: self.per_ip_requests,
+            window: std::time::Duration::from_secs(self.per_ip_window_seconds),
+        }
+    }
+    pub fn per_domain(&self) -> RateLimit {
+        RateLimit {
+            requests: self.per_domain_requests,
+            window: std::time::Duration::from_secs(self.per_domain_window_seconds),
+        }
+    }
+}
+
+impl LoginThrottleSettings {
+    pub fn window(&self) -> chrono::Duration {
+        chrono::Duration::minutes(self.window_minutes)
+    }
+    pub fn max_delay(&self) -> chrono::Duration {
+        chrono::Duration::seconds(self.max_delay_seconds)
+    }
+}
+
+impl SubscriptionSettings {
+    pub fn confirmation_token_ttl(&self) -> chrono::Duration {
+        chrono::Duration::hours(self.confirmation_token_ttl_hours)
+    }
+    pub fn unconfirmed_retention(&self) -> chrono::Duration {
+        chrono::Duration::days(self.unconfirmed_retention_days)
+    }
+    pub fn cleanup_interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.cleanup_interval_seconds)
+    }
+    pub fn domain_verification_cache(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.domain_verification_cache_seconds)
+    }
+    pub fn risky_email_lists(&self) -> std::io::Result<RiskyEmailLists> {
+        RiskyEmailLists::load(
+            self.disposable_domains_file.as_ref(),
+            self.role_accounts_file.as_ref(),
+        )
+    }
+    pub fn risky_email_policy(&self, risk: &RiskyEmail) -> RiskyEmailPolicy {
+        match risk {
+            RiskyEmail::DisposableDomain(_) => self.disposable_email_policy,
+            RiskyEmail::RoleAccount(_) => self.role_email_policy,
+        }
+    }
 }
 
 impl DatabaseSettings {
@@ -67,7 +291,7 @@ impl DatabaseSettings {
         PgConnectOptions::new()
             .host(&self.host)
             .username(&self.username)
-            .password(&self.password.expose_secret())
+            .password(self.password.expose_secret())
             .port(self.port)
             .ssl_mode(ssl_mode)
     }
@@ -97,7 +321,46 @@ pub fn get_configuration() -> Result<Settings, config::ConfigError> {
                 .separator("__"),
         )
         .build()?;
-    settings.try_deserialize::<Settings>()
+    let settings = settings.try_deserialize::<Settings>()?;
+    if let Environment::Production = environment {
+        settings.check_production_secrets()?;
+    }
+    Ok(settings)
+}
+
+/// What local.yaml sets for development. Anyone can read them, production
+/// refuses to start with them.
+const DEVELOPMENT_SECRETS: [(&str, &str); 2] = [
+    ("subscriptions.spam_protection.form_secret", "development secret"),
+    ("admin.totp_encryption_key", "development key"),
+];
+
+impl Settings {
+    fn check_production_secrets(&self) -> Result<(), config::ConfigError> {
+        let secrets = [
+            &self.subscriptions.spam_protection.form_secret,
+            &self.admin.totp_encryption_key,
+        ];
+        for (secret, (name, development_value)) in secrets.into_iter().zip(DEVELOPMENT_SECRETS) {
+            check_production_secret(name, secret, development_value)?;
+        }
+        Ok(())
+    }
+}
+
+fn check_production_secret(
+    name: &str,
+    secret: &Secret<String>,
+    development_value: &str,
+) -> Result<(), config::ConfigError> {
+    let secret = secret.expose_secret().trim();
+    if secret.is_empty() || secret == development_value {
+        return Err(config::ConfigError::Message(format!(
+            "{} needs a secret of its own in production.",
+            name
+        )));
+    }
+    Ok(())
 }
 
 pub enum Environment {
@@ -124,3 +387,25 @@ impl TryFrom<String> for Environment {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::check_production_secret;
+    use secrecy::Secret;
+
+    #[test]
+    fn production_secrets_must_be_set_and_not_the_development_ones() {
+        let check = |secret: &str| {
+            check_production_secret(
+                "admin.totp_encryption_key",
+                &Secret::new(secret.into()),
+                "development key",
+            )
+        };
+
+        assert!(check("").is_err());
+        assert!(check("  ").is_err());
+        assert!(check("development key").is_err());
+        assert!(check("a key of its own").is_ok());
+    }
+}
@@ -0,0 +1,51 @@
+/// What an API key may do, serialized as `resource:action`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ApiScope {
+    SubscribersRead,
+    SubscribersWrite,
+    IssuesPublish,
+}
+
+impl ApiScope {
+    pub const ALL: [ApiScope; 3] = [
+        ApiScope::SubscribersRead,
+        ApiScope::SubscribersWrite,
+        ApiScope::IssuesPublish,
+    ];
+
+    pub fn parse(s: &str) -> Result<ApiScope, String> {
+        match s {
+            "subscribers:read" => Ok(Self::SubscribersRead),
+            "subscribers:write" => Ok(Self::SubscribersWrite),
+            "issues:publish" => Ok(Self::IssuesPublish),
+            other => Err(format!("{} is not a valid API scope.", other)),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ApiScope::SubscribersRead => "subscribers:read",
+            ApiScope::SubscribersWrite => "subscribers:write",
+            ApiScope::IssuesPublish => "issues:publish",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ApiScope;
+    use claim::assert_err;
+
+    #[test]
+    fn every_scope_round_trips() {
+        for scope in ApiScope::ALL {
+            assert_eq!(ApiScope::parse(scope.as_str()), Ok(scope));
+        }
+    }
+
+    #[test]
+    fn unknown_scopes_are_rejected() {
+        assert_err!(ApiScope::parse("subscribers"));
+        assert_err!(ApiScope::parse("issues:delete"));
+    }
+}
@@ -0,0 +1,53 @@
+/// How often a reader wants issues. Weekly and monthly readers are skipped
+/// while their last issue is more recent than that.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum DeliveryFrequency {
+    #[default]
+    Immediate,
+    Weekly,
+    Monthly,
+}
+
+impl DeliveryFrequency {
+    pub const ALL: [DeliveryFrequency; 3] = [
+        DeliveryFrequency::Immediate,
+        DeliveryFrequency::Weekly,
+        DeliveryFrequency::Monthly,
+    ];
+
+    pub fn parse(s: &str) -> Result<DeliveryFrequency, String> {
+        match s {
+            "immediate" => Ok(Self::Immediate),
+            "weekly" => Ok(Self::Weekly),
+            "monthly" => Ok(Self::Monthly),
+            other => Err(format!("{} is not a valid delivery frequency.", other)),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryFrequency::Immediate => "immediate",
+            DeliveryFrequency::Weekly => "weekly",
+            DeliveryFrequency::Monthly => "monthly",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DeliveryFrequency;
+    use claim::assert_err;
+
+    #[test]
+    fn every_frequency_round_trips() {
+        for frequency in DeliveryFrequency::ALL {
+            assert_eq!(DeliveryFrequency::parse(frequency.as_str()), Ok(frequency));
+        }
+    }
+
+    #[test]
+    fn unknown_frequencies_are_rejected() {
+        assert_err!(DeliveryFrequency::parse("daily"));
+        assert_err!(DeliveryFrequency::parse("Weekly"));
+    }
+}
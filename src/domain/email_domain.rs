@@ -0,0 +1,124 @@
+/// Mailbox providers popular enough that a near miss is most likely a typo.
+const POPULAR_DOMAINS: &[&str] = &[
+    "aol.com",
+    "comcast.net",
+    "gmail.com",
+    "gmx.com",
+    "gmx.de",
+    "googlemail.com",
+    "hotmail.co.uk",
+    "hotmail.com",
+    "hotmail.fr",
+    "icloud.com",
+    "live.com",
+    "mail.com",
+    "mail.ru",
+    "me.com",
+    "msn.com",
+    "outlook.com",
+    "proton.me",
+    "protonmail.com",
+    "web.de",
+    "yahoo.co.uk",
+    "yahoo.com",
+    "yahoo.fr",
+    "yandex.ru",
+];
+
+/// Real providers a single edit away from a popular one, never corrected.
+const KNOWN_DOMAINS: &[&str] = &[
+    "email.com",
+    "hotmail.ca",
+    "hotmail.co.jp",
+    "hotmail.de",
+    "hotmail.es",
+    "hotmail.it",
+    "yahoo.co.in",
+    "yahoo.co.jp",
+    "ymail.com",
+];
+
+/// Returns the popular provider `domain` is probably a misspelling of. Short
+/// domains only tolerate a single edit, longer ones two.
+pub fn suggest_domain(domain: &str) -> Option<&'static str> {
+    if POPULAR_DOMAINS.contains(&domain) || KNOWN_DOMAINS.contains(&domain) {
+        return None;
+    }
+    let max_distance = if domain.chars().count() < 10 { 1 } else { 2 };
+    POPULAR_DOMAINS
+        .iter()
+        .map(|candidate| (edit_distance(domain, candidate), *candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+/// Levenshtein distance where swapping two adjacent characters counts as a
+/// single edit, the most common typing mistake.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut rows = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in rows.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    rows[0] = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            let mut distance = (rows[i - 1][j] + 1)
+                .min(rows[i][j - 1] + 1)
+                .min(rows[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(rows[i - 2][j - 2] + 1);
+            }
+            rows[i][j] = distance;
+        }
+    }
+    rows[a.len()][b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{edit_distance, suggest_domain};
+
+    #[test]
+    fn transpositions_count_as_one_edit() {
+        assert_eq!(edit_distance("hotmial.com", "hotmail.com"), 1);
+        assert_eq!(edit_distance("gmail.com", "gmail.com"), 0);
+        assert_eq!(edit_distance("gmal.com", "gmail.com"), 1);
+    }
+
+    #[test]
+    fn common_misspellings_are_corrected() {
+        assert_eq!(suggest_domain("hotmial.com"), Some("hotmail.com"));
+        assert_eq!(suggest_domain("gmial.com"), Some("gmail.com"));
+        assert_eq!(suggest_domain("yahooo.com"), Some("yahoo.com"));
+        assert_eq!(suggest_domain("outlok.com"), Some("outlook.com"));
+    }
+
+    #[test]
+    fn known_and_unrelated_domains_get_no_suggestion() {
+        assert_eq!(suggest_domain("gmail.com"), None);
+        assert_eq!(suggest_domain("mail.com"), None);
+        assert_eq!(suggest_domain("example.com"), None);
+        assert_eq!(suggest_domain("zero2prod.com"), None);
+    }
+
+    #[test]
+    fn real_providers_close_to_popular_ones_are_not_corrected() {
+        for domain in [
+            "ymail.com",
+            "email.com",
+            "hotmail.de",
+            "hotmail.it",
+            "hotmail.es",
+            "hotmail.ca",
+            "hotmail.co.jp",
+            "yahoo.co.in",
+            "yahoo.co.jp",
+        ] {
+            assert_eq!(suggest_domain(domain), None, "{}", domain);
+        }
+    }
+}
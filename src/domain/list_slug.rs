@@ -0,0 +1,60 @@
+#[derive(Debug)]
+pub struct ListSlug(String);
+
+impl ListSlug {
+    /// The list every subscriber belonged to before lists were introduced.
+    pub const DEFAULT: &'static str = "default";
+
+    pub fn parse(s: String) -> Result<ListSlug, String> {
+        let is_empty = s.is_empty();
+        let is_too_long = s.len() > 64;
+        let is_forbidden_chars = s
+            .chars()
+            .any(|c| !(c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-'));
+        let is_valid = !(is_empty | is_too_long | is_forbidden_chars);
+        match is_valid {
+            true => Ok(Self(s)),
+            false => Err(format!("{} is not a valid list slug.", s)),
+        }
+    }
+}
+
+impl Default for ListSlug {
+    fn default() -> Self {
+        Self(Self::DEFAULT.to_string())
+    }
+}
+
+impl AsRef<str> for ListSlug {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::ListSlug;
+    use claim::{assert_err, assert_ok};
+
+    #[test]
+    fn lowercase_slugs_with_dashes_and_digits_are_valid() {
+        assert_ok!(ListSlug::parse("rust-weekly-2".to_string()));
+    }
+
+    #[test]
+    fn empty_string_is_rejected() {
+        assert_err!(ListSlug::parse("".to_string()));
+    }
+
+    #[test]
+    fn a_slug_longer_than_64_characters_is_rejected() {
+        assert_err!(ListSlug::parse("a".repeat(65)));
+    }
+
+    #[test]
+    fn uppercase_and_whitespace_are_rejected() {
+        for slug in ["Weekly", "rust weekly", "weekly/", "wëekly"] {
+            assert_err!(ListSlug::parse(slug.to_string()));
+        }
+    }
+}
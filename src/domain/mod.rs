@@ -1,7 +1,24 @@
+mod api_scope;
+mod delivery_frequency;
+mod email_domain;
+mod list_slug;
+mod segment_filter;
+mod subscriber_attributes;
 mod subscriber_name;
 mod subscriber_email;
+mod subscriber_status;
+mod subscriber_tag;
+mod user_role;
 mod new_subscriber;
 
-pub use subscriber_name::SubscriberName;
-pub use subscriber_email::SubscriberEmail;
-pub use new_subscriber::NewSubscriber;
+pub use api_scope::ApiScope;
+pub use delivery_frequency::DeliveryFrequency;
+pub use list_slug::ListSlug;
+pub use segment_filter::SegmentFilter;
+pub use subscriber_attributes::{AttributeSchema, SubscriberAttributes};
+pub use subscriber_name::{SubscriberName, SubscriberNameError};
+pub use subscriber_email::{RiskyEmail, RiskyEmailLists, SubscriberEmail, SubscriberEmailError};
+pub use subscriber_status::SubscriberStatus;
+pub use subscriber_tag::SubscriberTag;
+pub use user_role::{Permission, UserRole};
+pub use new_subscriber::{NewSubscriber, NewSubscriberError};
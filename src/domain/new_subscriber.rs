@@ -1,8 +1,75 @@
-use crate::domain::subscriber_name::SubscriberName;
-use crate::domain::subscriber_email::SubscriberEmail;
+use crate::domain::list_slug::ListSlug;
+use crate::domain::subscriber_attributes::SubscriberAttributes;
+use crate::domain::subscriber_name::{SubscriberName, SubscriberNameError};
+use crate::domain::subscriber_email::{SubscriberEmail, SubscriberEmailError};
 
 pub struct NewSubscriber {
     pub email: SubscriberEmail,
     pub name: SubscriberName,
+    pub list: ListSlug,
+    pub attributes: SubscriberAttributes,
 }
 
+
+/// Why a signup could not be turned into a `NewSubscriber`, along with the
+/// form field at fault.
+#[derive(Debug)]
+pub enum NewSubscriberError {
+    Name(SubscriberNameError),
+    Email(SubscriberEmailError),
+    List(String),
+    Attributes(String),
+}
+
+impl NewSubscriberError {
+    /// The form field the error is about.
+    pub fn field(&self) -> &'static str {
+        match self {
+            NewSubscriberError::Name(_) => "name",
+            NewSubscriberError::Email(_) => "email",
+            NewSubscriberError::List(_) => "list",
+            NewSubscriberError::Attributes(_) => "attributes",
+        }
+    }
+
+    /// Machine readable identifier of the rule that failed.
+    pub fn code(&self) -> &'static str {
+        match self {
+            NewSubscriberError::Name(e) => e.code(),
+            NewSubscriberError::Email(e) => e.code(),
+            NewSubscriberError::List(_) | NewSubscriberError::Attributes(_) => "invalid",
+        }
+    }
+}
+
+impl std::fmt::Display for NewSubscriberError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NewSubscriberError::Name(e) => e.fmt(f),
+            NewSubscriberError::Email(e) => e.fmt(f),
+            NewSubscriberError::List(e) | NewSubscriberError::Attributes(e) => f.write_str(e),
+        }
+    }
+}
+
+impl std::error::Error for NewSubscriberError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            NewSubscriberError::Name(e) => Some(e),
+            NewSubscriberError::Email(e) => Some(e),
+            NewSubscriberError::List(_) | NewSubscriberError::Attributes(_) => None,
+        }
+    }
+}
+
+impl From<SubscriberNameError> for NewSubscriberError {
+    fn from(e: SubscriberNameError) -> Self {
+        Self::Name(e)
+    }
+}
+
+impl From<SubscriberEmailError> for NewSubscriberError {
+    fn from(e: SubscriberEmailError) -> Self {
+        Self::Email(e)
+    }
+}
@@ -0,0 +1,457 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use sqlx::{Postgres, QueryBuilder};
+
+use crate::domain::subscriber_attributes::is_valid_attribute_name;
+use crate::domain::SubscriberTag;
+
+/// A subscriber segment, e.g. `tag:beta AND subscribed_at >= 2026-03-01`.
+///
+/// Conditions are `tag:<tag>`, `status:<status>`, `subscribed_at` compared
+/// with `<`, `<=`, `>` or `>=` to a date or a quoted RFC 3339 timestamp, and
+/// `attributes.<name>` either matched with `:` or compared to a number. They
+/// can be combined with `AND`, `OR`, `NOT` and parentheses.
+#[derive(Debug, PartialEq)]
+pub enum SegmentFilter {
+    And(Box<SegmentFilter>, Box<SegmentFilter>),
+    Or(Box<SegmentFilter>, Box<SegmentFilter>),
+    Not(Box<SegmentFilter>),
+    Condition(Condition),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Condition {
+    Tag(SubscriberTag),
+    Status(String),
+    SubscribedAt(Comparison, DateTime<Utc>),
+    AttributeEquals(String, String),
+    AttributeCompare(String, Comparison, f64),
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Comparison {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Comparison {
+    fn as_sql(&self) -> &'static str {
+        match self {
+            Comparison::Lt => "<",
+            Comparison::Le => "<=",
+            Comparison::Gt => ">",
+            Comparison::Ge => ">=",
+        }
+    }
+}
+
+impl SegmentFilter {
+    pub fn parse(s: &str) -> Result<SegmentFilter, String> {
+        let tokens = tokenize(s)?;
+        let mut parser = Parser {
+            tokens,
+            position: 0,
+            depth: 0,
+            conditions: 0,
+        };
+        let filter = parser.parse_or()?;
+        match parser.peek() {
+            None => Ok(filter),
+            Some(token) => Err(format!("Unexpected {} in segment filter.", token)),
+        }
+    }
+
+    /// Appends the filter as a boolean SQL expression over a `subscriptions`
+    /// table aliased as `s`. Every user supplied value is bound as a parameter.
+    pub fn push_sql(&self, query: &mut QueryBuilder<'_, Postgres>) {
+        match self {
+            SegmentFilter::And(left, right) | SegmentFilter::Or(left, right) => {
+                let operator = match self {
+                    SegmentFilter::And(..) => " AND ",
+                    _ => " OR ",
+                };
+                query.push("(");
+                left.push_sql(query);
+                query.push(operator);
+                right.push_sql(query);
+                query.push(")");
+            }
+            SegmentFilter::Not(inner) => {
+                query.push("NOT (");
+                inner.push_sql(query);
+                query.push(")");
+            }
+            SegmentFilter::Condition(Condition::Tag(tag)) => {
+                query.push(
+                    "EXISTS (SELECT 1 FROM subscriber_tags t WHERE t.subscriber_id = s.id AND t.tag = ",
+                );
+                query.push_bind(tag.as_ref().to_owned());
+                query.push(")");
+            }
+            SegmentFilter::Condition(Condition::Status(status)) => {
+                query.push("s.status = ");
+                query.push_bind(status.clone());
+            }
+            SegmentFilter::Condition(Condition::SubscribedAt(comparison, at)) => {
+                query.push(format!("s.subscribed_at {} ", comparison.as_sql()));
+                query.push_bind(*at);
+            }
+            SegmentFilter::Condition(Condition::AttributeEquals(name, value)) => {
+                query.push("s.attributes ->> ");
+                query.push_bind(name.clone());
+                query.push(" = ");
+                query.push_bind(value.clone());
+            }
+            SegmentFilter::Condition(Condition::AttributeCompare(name, comparison, value)) => {
+                // Guard on the JSON type: jsonb orders strings below numbers,
+                // which would otherwise make "abc" smaller than any number.
+                query.push("(jsonb_typeof(s.attributes -> ");
+                query.push_bind(name.clone());
+                query.push(") = 'number' AND (s.attributes ->> ");
+                query.push_bind(name.clone());
+                query.push(format!(")::float8 {} ", comparison.as_sql()));
+                query.push_bind(*value);
+                query.push(")");
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    Colon,
+    Comparison(Comparison),
+    LParen,
+    RParen,
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "'{}'", word),
+            Token::Quoted(value) => write!(f, "\"{}\"", value),
+            Token::Colon => write!(f, "':'"),
+            Token::Comparison(comparison) => write!(f, "'{}'", comparison.as_sql()),
+            Token::LParen => write!(f, "'('"),
+            Token::RParen => write!(f, "')'"),
+        }
+    }
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = s.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' | ')' | ':' => {
+                chars.next();
+                tokens.push(match c {
+                    '(' => Token::LParen,
+                    ')' => Token::RParen,
+                    _ => Token::Colon,
+                });
+            }
+            '<' | '>' => {
+                chars.next();
+                let or_equal = chars.next_if_eq(&'=').is_some();
+                tokens.push(Token::Comparison(match (c, or_equal) {
+                    ('<', false) => Comparison::Lt,
+                    ('<', true) => Comparison::Le,
+                    ('>', false) => Comparison::Gt,
+                    _ => Comparison::Ge,
+                }));
+            }
+            '"' => {
+                chars.next();
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(c) => value.push(c),
+                        None => return Err("Unterminated quoted value in segment filter.".into()),
+                    }
+                }
+                tokens.push(Token::Quoted(value));
+            }
+            c if is_word_char(c) => {
+                let mut word = String::new();
+                while let Some(c) = chars.next_if(|c| is_word_char(*c)) {
+                    word.push(c);
+                }
+                tokens.push(Token::Word(word));
+            }
+            other => return Err(format!("Unexpected character '{}' in segment filter.", other)),
+        }
+    }
+    Ok(tokens)
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '-' | '.' | '+' | '@')
+}
+
+/// Filters are parsed and turned into SQL recursively, these bounds keep a
+/// crafted filter from overflowing the stack.
+const MAX_NESTING: usize = 32;
+const MAX_CONDITIONS: usize = 100;
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    /// `NOT`s and parentheses currently open.
+    depth: usize,
+    conditions: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn next_is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword))
+    }
+
+    fn parse_or(&mut self) -> Result<SegmentFilter, String> {
+        let mut filter = self.parse_and()?;
+        while self.next_is_keyword("OR") {
+            self.next();
+            filter = SegmentFilter::Or(Box::new(filter), Box::new(self.parse_and()?));
+        }
+        Ok(filter)
+    }
+
+    fn parse_and(&mut self) -> Result<SegmentFilter, String> {
+        let mut filter = self.parse_not()?;
+        while self.next_is_keyword("AND") {
+            self.next();
+            filter = SegmentFilter::And(Box::new(filter), Box::new(self.parse_not()?));
+        }
+        Ok(filter)
+    }
+
+    fn nested<T>(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> Result<T, String>,
+    ) -> Result<T, String> {
+        if self.depth == MAX_NESTING {
+            return Err(format!(
+                "Segment filter nests deeper than {} levels.",
+                MAX_NESTING
+            ));
+        }
+        self.depth += 1;
+        let parsed = parse(self);
+        self.depth -= 1;
+        parsed
+    }
+
+    fn parse_not(&mut self) -> Result<SegmentFilter, String> {
+        if self.next_is_keyword("NOT") {
+            self.next();
+            let inner = self.nested(Self::parse_not)?;
+            return Ok(SegmentFilter::Not(Box::new(inner)));
+        }
+        self.parse_primary()
+    }
+
+    fn parse_primary(&mut self) -> Result<SegmentFilter, String> {
+        match self.next() {
+            Some(Token::LParen) => {
+                let filter = self.nested(Self::parse_or)?;
+                match self.next() {
+                    Some(Token::RParen) => Ok(filter),
+                    _ => Err("Missing ')' in segment filter.".into()),
+                }
+            }
+            Some(Token::Word(field)) => {
+                self.conditions += 1;
+                if self.conditions > MAX_CONDITIONS {
+                    return Err(format!(
+                        "Segment filter has more than {} conditions.",
+                        MAX_CONDITIONS
+                    ));
+                }
+                self.parse_condition(&field).map(SegmentFilter::Condition)
+            }
+            Some(token) => Err(format!("Unexpected {} in segment filter.", token)),
+            None => Err("Segment filter ended unexpectedly.".into()),
+        }
+    }
+
+    fn parse_condition(&mut self, field: &str) -> Result<Condition, String> {
+        if let Some(name) = field.strip_prefix("attributes.") {
+            if !is_valid_attribute_name(name) {
+                return Err(format!("{} is not a valid attribute name.", name));
+            }
+            return match self.next() {
+                Some(Token::Colon) => Ok(Condition::AttributeEquals(name.to_owned(), self.value()?)),
+                Some(Token::Comparison(comparison)) => {
+                    let value = self.value()?;
+                    match value.parse::<f64>() {
+                        Ok(number) if number.is_finite() => {
+                            Ok(Condition::AttributeCompare(name.to_owned(), comparison, number))
+                        }
+                        _ => Err(format!("{} is not a number in segment filter.", value)),
+                    }
+                }
+                _ => Err(format!("Expected ':' or a comparison after {} in segment filter.", field)),
+            };
+        }
+        match (field, self.next()) {
+            ("tag", Some(Token::Colon)) => Ok(Condition::Tag(SubscriberTag::parse(self.value()?)?)),
+            ("status", Some(Token::Colon)) => Ok(Condition::Status(self.value()?)),
+            ("subscribed_at", Some(Token::Comparison(comparison))) => {
+                Ok(Condition::SubscribedAt(comparison, parse_timestamp(&self.value()?)?))
+            }
+            ("tag" | "status", _) => Err(format!("Expected ':' after {} in segment filter.", field)),
+            ("subscribed_at", _) => Err("Expected a comparison after subscribed_at in segment filter.".into()),
+            _ => Err(format!("Unknown field '{}' in segment filter.", field)),
+        }
+    }
+
+    fn value(&mut self) -> Result<String, String> {
+        match self.next() {
+            Some(Token::Word(value)) | Some(Token::Quoted(value)) => Ok(value),
+            _ => Err("Expected a value in segment filter.".into()),
+        }
+    }
+}
+
+fn parse_timestamp(s: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(date) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        return Ok(DateTime::from_utc(date.and_hms_opt(0, 0, 0).unwrap(), Utc));
+    }
+    DateTime::parse_from_rfc3339(s)
+        .map(|at| at.with_timezone(&Utc))
+        .map_err(|_| format!("{} is not a valid date in segment filter.", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Comparison, Condition, SegmentFilter};
+    use crate::domain::SubscriberTag;
+    use claim::assert_err;
+    use sqlx::{Execute, Postgres, QueryBuilder};
+
+    fn tag(tag: &str) -> SegmentFilter {
+        SegmentFilter::Condition(Condition::Tag(SubscriberTag::parse(tag.into()).unwrap()))
+    }
+
+    fn sql(filter: &str) -> String {
+        let mut query = QueryBuilder::<Postgres>::new("");
+        SegmentFilter::parse(filter).unwrap().push_sql(&mut query);
+        query.build().sql().to_owned()
+    }
+
+    #[test]
+    fn a_single_tag_is_parsed() {
+        assert_eq!(SegmentFilter::parse("tag:beta").unwrap(), tag("beta"));
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let filter = SegmentFilter::parse("tag:a OR tag:b and tag:c").unwrap();
+        assert_eq!(
+            filter,
+            SegmentFilter::Or(
+                Box::new(tag("a")),
+                Box::new(SegmentFilter::And(Box::new(tag("b")), Box::new(tag("c"))))
+            )
+        );
+    }
+
+    #[test]
+    fn dates_and_timestamps_are_accepted() {
+        let filter = SegmentFilter::parse(
+            r#"subscribed_at >= 2026-03-01 AND subscribed_at < "2026-04-01T12:00:00+02:00""#,
+        )
+        .unwrap();
+        match filter {
+            SegmentFilter::And(left, right) => {
+                assert!(matches!(
+                    *left,
+                    SegmentFilter::Condition(Condition::SubscribedAt(Comparison::Ge, at))
+                        if at.to_rfc3339() == "2026-03-01T00:00:00+00:00"
+                ));
+                assert!(matches!(
+                    *right,
+                    SegmentFilter::Condition(Condition::SubscribedAt(Comparison::Lt, at))
+                        if at.to_rfc3339() == "2026-04-01T10:00:00+00:00"
+                ));
+            }
+            other => panic!("Unexpected filter {:?}", other),
+        }
+    }
+
+    #[test]
+    fn values_are_bound_as_parameters() {
+        assert_eq!(
+            sql("NOT (tag:beta OR status:confirmed) AND subscribed_at > 2026-03-01"),
+            "(NOT ((EXISTS (SELECT 1 FROM subscriber_tags t WHERE t.subscriber_id = s.id AND t.tag = $1) OR s.status = $2)) AND s.subscribed_at > $3)"
+        );
+    }
+
+    #[test]
+    fn attribute_conditions_are_bound_as_parameters() {
+        assert_eq!(
+            sql(r#"attributes.country:"DE" AND attributes.seats >= 10"#),
+            "(s.attributes ->> $1 = $2 AND (jsonb_typeof(s.attributes -> $3) = 'number' AND (s.attributes ->> $4)::float8 >= $5))"
+        );
+    }
+
+    #[test]
+    fn injection_attempts_never_reach_the_sql() {
+        let query = sql(r#"status:"confirmed' OR 1=1 --""#);
+        assert_eq!(query, "s.status = $1");
+    }
+
+    #[test]
+    fn invalid_filters_are_rejected() {
+        for filter in [
+            "",
+            "tag:",
+            "tag beta",
+            "tag:Beta",
+            "name:ursula",
+            "subscribed_at:2026-03-01",
+            "subscribed_at > march",
+            "(tag:beta",
+            "tag:beta tag:alpha",
+            "tag:beta AND",
+            r#"status:"confirmed"#,
+            "status:confirmed;",
+            "attributes.Country:DE",
+            "attributes.:DE",
+            "attributes.seats > many",
+            "attributes.seats",
+        ] {
+            assert_err!(SegmentFilter::parse(filter), "{} should be rejected", filter);
+        }
+    }
+
+    #[test]
+    fn deeply_nested_filters_are_rejected() {
+        let nots = format!("{}tag:beta", "NOT ".repeat(10_000));
+        let parentheses = format!("{}tag:beta{}", "(".repeat(10_000), ")".repeat(10_000));
+        let chain = vec!["tag:beta"; 10_000].join(" AND ");
+
+        for filter in [nots, parentheses, chain] {
+            assert_err!(SegmentFilter::parse(&filter));
+        }
+        let allowed = format!("{}tag:beta{}", "(".repeat(32), ")".repeat(32));
+        assert!(SegmentFilter::parse(&allowed).is_ok());
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde_json::{Map, Value};
+use unicode_segmentation::UnicodeSegmentation;
+
+/// The extra signup fields a list accepts, stored as JSON on the list, e.g.
+/// `{"company": {"type": "string"}, "seats": {"type": "number", "required": true}}`.
+#[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
+pub struct AttributeSchema(BTreeMap<String, AttributeDefinition>);
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AttributeDefinition {
+    #[serde(rename = "type")]
+    kind: AttributeType,
+    #[serde(default)]
+    required: bool,
+}
+
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AttributeType {
+    String,
+    Number,
+    Boolean,
+}
+
+/// Validated custom attributes of a subscriber, stored as a JSONB object.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SubscriberAttributes(Map<String, Value>);
+
+const MAX_ATTRIBUTE_LENGTH: usize = 256;
+
+/// Attribute names double as template placeholders and segment filter fields,
+/// so they are restricted to lowercase identifiers.
+pub fn is_valid_attribute_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+impl AttributeSchema {
+    pub fn parse(value: Value) -> Result<AttributeSchema, String> {
+        let schema: AttributeSchema = serde_json::from_value(value)
+            .map_err(|e| format!("Invalid attribute schema: {}", e))?;
+        match schema.0.keys().find(|name| !is_valid_attribute_name(name)) {
+            Some(name) => Err(format!("{} is not a valid attribute name.", name)),
+            None => Ok(schema),
+        }
+    }
+
+    /// Checks submitted form fields against the schema, converting each value
+    /// to its declared type. Undeclared fields are rejected.
+    pub fn validate(&self, fields: HashMap<String, String>) -> Result<SubscriberAttributes, String> {
+        if let Some(name) = fields.keys().find(|name| !self.0.contains_key(*name)) {
+            return Err(format!("{} is not an accepted field.", name));
+        }
+        let mut attributes = Map::new();
+        for (name, definition) in &self.0 {
+            let raw = match fields.get(name).map(|v| v.trim()).filter(|v| !v.is_empty()) {
+                Some(raw) => raw,
+                None if definition.required => return Err(format!("{} is required.", name)),
+                None => continue,
+            };
+            let value = match definition.kind {
+                AttributeType::String if raw.graphemes(true).count() > MAX_ATTRIBUTE_LENGTH => {
+                    return Err(format!("{} is too long.", name))
+                }
+                AttributeType::String => Value::String(raw.to_owned()),
+                AttributeType::Number => match raw.parse::<i64>() {
+                    Ok(integer) => Value::from(integer),
+                    Err(_) => raw
+                        .parse::<f64>()
+                        .ok()
+                        .and_then(serde_json::Number::from_f64)
+                        .map(Value::Number)
+                        .ok_or_else(|| format!("{} must be a number.", name))?,
+                },
+                AttributeType::Boolean => match raw {
+                    "true" | "on" | "yes" | "1" => Value::Bool(true),
+                    "false" | "off" | "no" | "0" => Value::Bool(false),
+                    _ => return Err(format!("{} must be true or false.", name)),
+                },
+            };
+            attributes.insert(name.clone(), value);
+        }
+        Ok(SubscriberAttributes(attributes))
+    }
+}
+
+impl SubscriberAttributes {
+    /// Wraps attributes read back from the database, which were validated on
+    /// the way in.
+    pub fn from_stored(value: Value) -> SubscriberAttributes {
+        match value {
+            Value::Object(map) => Self(map),
+            _ => Self::default(),
+        }
+    }
+
+    /// The attribute rendered as text, the way templates display it.
+    pub fn get_text(&self, name: &str) -> Option<String> {
+        match self.0.get(name)? {
+            Value::String(s) => Some(s.clone()),
+            Value::Null => None,
+            other => Some(other.to_string()),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        Value::Object(self.0.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AttributeSchema;
+    use claim::{assert_err, assert_ok};
+    use std::collections::HashMap;
+
+    fn schema() -> AttributeSchema {
+        AttributeSchema::parse(serde_json::json!({
+            "company": {"type": "string"},
+            "seats": {"type": "number", "required": true},
+            "beta": {"type": "boolean"},
+        }))
+        .unwrap()
+    }
+
+    fn fields(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn declared_fields_are_converted_to_their_type() {
+        let attributes = schema()
+            .validate(fields(&[("company", "Acme"), ("seats", "12"), ("beta", "on")]))
+            .unwrap();
+        assert_eq!(
+            attributes.to_json(),
+            serde_json::json!({"company": "Acme", "seats": 12, "beta": true})
+        );
+    }
+
+    #[test]
+    fn optional_fields_can_be_left_empty() {
+        let attributes = schema()
+            .validate(fields(&[("company", " "), ("seats", "1.5")]))
+            .unwrap();
+        assert_eq!(attributes.to_json(), serde_json::json!({"seats": 1.5}));
+    }
+
+    #[test]
+    fn missing_required_fields_are_rejected() {
+        assert_err!(schema().validate(fields(&[("company", "Acme")])));
+    }
+
+    #[test]
+    fn undeclared_fields_are_rejected() {
+        assert_err!(schema().validate(fields(&[("seats", "1"), ("favourite_colour", "red")])));
+    }
+
+    #[test]
+    fn values_of_the_wrong_type_are_rejected() {
+        assert_err!(schema().validate(fields(&[("seats", "many")])));
+        assert_err!(schema().validate(fields(&[("seats", "1"), ("beta", "maybe")])));
+    }
+
+    #[test]
+    fn an_empty_schema_accepts_no_fields() {
+        let schema = AttributeSchema::default();
+        assert_ok!(schema.validate(HashMap::new()));
+        assert_err!(schema.validate(fields(&[("company", "Acme")])));
+    }
+
+    #[test]
+    fn schemas_with_invalid_names_or_types_are_rejected() {
+        assert_err!(AttributeSchema::parse(serde_json::json!({"Company": {"type": "string"}})));
+        assert_err!(AttributeSchema::parse(serde_json::json!({"company": {"type": "date"}})));
+        assert_err!(AttributeSchema::parse(serde_json::json!(["company"])));
+    }
+}
@@ -1,27 +1,241 @@
+use crate::domain::email_domain::suggest_domain;
+use std::collections::HashSet;
+use std::path::Path;
 use validator::validate_email;
 
+/// Disposable email domains and role account names `check_risk` looks
+/// addresses up in.
+#[derive(Debug, Default)]
+pub struct RiskyEmailLists {
+    disposable_domains: HashSet<String>,
+    role_accounts: HashSet<String>,
+}
+
+impl RiskyEmailLists {
+    /// One entry per line, lines starting with `#` are ignored.
+    pub fn parse(disposable_domains: &str, role_accounts: &str) -> Self {
+        Self {
+            disposable_domains: parse_list(disposable_domains),
+            role_accounts: parse_list(role_accounts),
+        }
+    }
+
+    pub fn load(disposable_domains: &Path, role_accounts: &Path) -> std::io::Result<Self> {
+        Ok(Self::parse(
+            &std::fs::read_to_string(disposable_domains)?,
+            &std::fs::read_to_string(role_accounts)?,
+        ))
+    }
+}
+
+fn parse_list(list: &str) -> HashSet<String> {
+    list.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Providers known to ignore `+tag` suffixes in the local part, and whether
+/// they ignore dots too.
+const ALIASING_PROVIDERS: &[(&str, bool)] = &[
+    ("gmail.com", true),
+    ("googlemail.com", true),
+    ("outlook.com", false),
+    ("hotmail.com", false),
+    ("live.com", false),
+    ("icloud.com", false),
+    ("fastmail.com", false),
+    ("protonmail.com", false),
+    ("proton.me", false),
+];
+
+/// Longest address SMTP can carry, see RFC 5321 section 4.5.3.
+const MAX_EMAIL_LENGTH: usize = 254;
+
+/// The rule a rejected email address broke.
+#[derive(Debug, PartialEq)]
+pub enum SubscriberEmailError {
+    Empty,
+    TooLong,
+    InvalidSyntax(String),
+}
+
+impl SubscriberEmailError {
+    /// Machine readable identifier of the rule, stable across releases.
+    pub fn code(&self) -> &'static str {
+        match self {
+            SubscriberEmailError::Empty => "empty",
+            SubscriberEmailError::TooLong => "too_long",
+            SubscriberEmailError::InvalidSyntax(_) => "invalid_syntax",
+        }
+    }
+}
+
+impl std::fmt::Display for SubscriberEmailError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SubscriberEmailError::Empty => write!(f, "An email address cannot be empty."),
+            SubscriberEmailError::TooLong => write!(
+                f,
+                "An email address cannot be longer than {} characters.",
+                MAX_EMAIL_LENGTH
+            ),
+            SubscriberEmailError::InvalidSyntax(_) => write!(f, "Not a valid email address."),
+        }
+    }
+}
+
+impl std::error::Error for SubscriberEmailError {}
+
+/// An email address as typed by the subscriber, plus its normalized form:
+/// the domain lowercased and, for internationalized domains, punycoded.
 #[derive(Debug)]
-pub struct SubscriberEmail(String);
+pub struct SubscriberEmail {
+    normalized: String,
+    display: String,
+}
 
 impl SubscriberEmail {
-    pub fn parse(s: String) -> Result<SubscriberEmail, String> {
-        if validate_email(&s) {
-            Ok(Self(s))
-        } else {
-            Err(format!("{} is not a valid subscriber email", s))
+    pub fn parse(s: String) -> Result<SubscriberEmail, SubscriberEmailError> {
+        if s.trim().is_empty() {
+            return Err(SubscriberEmailError::Empty);
+        }
+        if s.chars().count() > MAX_EMAIL_LENGTH {
+            return Err(SubscriberEmailError::TooLong);
+        }
+        if !validate_email(&s) {
+            return Err(SubscriberEmailError::InvalidSyntax(s));
+        }
+        let (local, domain) = match s.rsplit_once('@') {
+            Some(parts) => parts,
+            None => return Err(SubscriberEmailError::InvalidSyntax(s)),
+        };
+        let domain = match idna::domain_to_ascii(domain) {
+            Ok(domain) => domain,
+            Err(_) => return Err(SubscriberEmailError::InvalidSyntax(s)),
+        };
+        Ok(Self {
+            normalized: format!("{}@{}", local, domain),
+            display: s,
+        })
+    }
+
+    /// The normalized domain part of the address.
+    pub fn domain(&self) -> &str {
+        self.normalized
+            .rsplit_once('@')
+            .map(|(_, domain)| domain)
+            .expect("A parsed email always contains an @")
+    }
+
+    /// The address with its domain corrected, when the domain looks like a
+    /// misspelling of a popular provider.
+    pub fn suggestion(&self) -> Option<String> {
+        let (local, _) = self
+            .display
+            .rsplit_once('@')
+            .expect("A parsed email always contains an @");
+        suggest_domain(&self.domain().to_lowercase()).map(|domain| format!("{}@{}", local, domain))
+    }
+
+    /// The address exactly as the subscriber typed it.
+    pub fn display(&self) -> &str {
+        &self.display
+    }
+
+    /// The form used to detect duplicates: the whole address lowercased and,
+    /// with `fold_aliases`, `+tags` (and Gmail dots) removed for providers
+    /// that deliver those to the same mailbox.
+    pub fn canonical(&self, fold_aliases: bool) -> String {
+        let canonical = self.normalized.to_lowercase();
+        let (local, domain) = canonical
+            .rsplit_once('@')
+            .expect("A parsed email always contains an @");
+        let provider = ALIASING_PROVIDERS.iter().find(|(p, _)| *p == domain);
+        match provider {
+            Some((_, ignores_dots)) if fold_aliases => {
+                let local = local.split('+').next().unwrap_or(local);
+                let local = if *ignores_dots {
+                    local.replace('.', "")
+                } else {
+                    local.to_owned()
+                };
+                let domain = if domain == "googlemail.com" {
+                    "gmail.com"
+                } else {
+                    domain
+                };
+                format!("{}@{}", local, domain)
+            }
+            _ => canonical,
+        }
+    }
+
+    /// Checks the address against the disposable-domain and role-account
+    /// lists. Such addresses are valid, callers decide whether to reject or
+    /// merely flag them.
+    pub fn check_risk(&self, lists: &RiskyEmailLists) -> Result<(), RiskyEmail> {
+        let canonical = self.normalized.to_lowercase();
+        let (local, domain) = canonical
+            .rsplit_once('@')
+            .expect("A parsed email always contains an @");
+        let is_disposable = domain
+            .match_indices('.')
+            .map(|(i, _)| &domain[i + 1..])
+            .chain(std::iter::once(domain))
+            .any(|candidate| lists.disposable_domains.contains(candidate));
+        if is_disposable {
+            return Err(RiskyEmail::DisposableDomain(domain.to_owned()));
+        }
+        let local = local.split('+').next().unwrap_or(local);
+        if lists.role_accounts.contains(local) {
+            return Err(RiskyEmail::RoleAccount(local.to_owned()));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RiskyEmail {
+    DisposableDomain(String),
+    RoleAccount(String),
+}
+
+impl RiskyEmail {
+    /// Short label stored alongside flagged subscribers.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RiskyEmail::DisposableDomain(_) => "disposable_domain",
+            RiskyEmail::RoleAccount(_) => "role_account",
         }
     }
 }
 
+impl std::fmt::Display for RiskyEmail {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RiskyEmail::DisposableDomain(domain) => {
+                write!(f, "{} is a disposable email provider.", domain)
+            }
+            RiskyEmail::RoleAccount(local) => {
+                write!(f, "{}@ addresses belong to a role, not a person.", local)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RiskyEmail {}
+
 impl AsRef<str> for SubscriberEmail {
     fn as_ref(&self) -> &str {
-        &self.0
+        &self.normalized
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::SubscriberEmail;
+    use super::{RiskyEmail, RiskyEmailLists, SubscriberEmail, SubscriberEmailError};
     use claim::assert_err;
     use claim::assert_ok;
     use fake::faker::internet::en::SafeEmail;
@@ -31,7 +245,7 @@ mod tests {
     struct ValidEmailFixture(pub String);
 
     impl quickcheck::Arbitrary for ValidEmailFixture {
-        fn arbitrary<G: quickcheck::Gen>(g: &mut G) -> Self {
+        fn arbitrary<G: quickcheck::Gen>(_g: &mut G) -> Self {
             let email = SafeEmail().fake::<String>();
             Self(email)
         }
@@ -62,4 +276,87 @@ mod tests {
         let email = "@domain.com".to_string();
         assert_err!(SubscriberEmail::parse(email));
     }
+
+    #[test]
+    fn errors_identify_the_broken_rule() {
+        assert_eq!(
+            SubscriberEmail::parse(" ".into()).unwrap_err(),
+            SubscriberEmailError::Empty
+        );
+        assert_eq!(
+            SubscriberEmail::parse(format!("{}@example.com", "a".repeat(250))).unwrap_err(),
+            SubscriberEmailError::TooLong
+        );
+        assert_eq!(
+            SubscriberEmail::parse("ursula.example.com".into()).unwrap_err(),
+            SubscriberEmailError::InvalidSyntax("ursula.example.com".into())
+        );
+    }
+
+    #[test]
+    fn the_domain_is_lowercased_but_the_display_form_is_kept() {
+        let email = SubscriberEmail::parse("Ursula.Le.Guin@Example.COM".into()).unwrap();
+        assert_eq!(email.as_ref(), "Ursula.Le.Guin@example.com");
+        assert_eq!(email.display(), "Ursula.Le.Guin@Example.COM");
+    }
+
+    #[test]
+    fn internationalized_domains_are_punycoded() {
+        let email = SubscriberEmail::parse("ursula@bücher.example".into()).unwrap();
+        assert_eq!(email.as_ref(), "ursula@xn--bcher-kva.example");
+    }
+
+    #[test]
+    fn the_canonical_form_ignores_case() {
+        let a = SubscriberEmail::parse("Foo@Example.com".into()).unwrap();
+        let b = SubscriberEmail::parse("foo@example.com".into()).unwrap();
+        assert_eq!(a.canonical(false), b.canonical(false));
+    }
+
+    #[test]
+    fn gmail_aliases_are_folded_only_when_asked() {
+        let email = SubscriberEmail::parse("Ursula.Le.Guin+news@GoogleMail.com".into()).unwrap();
+        assert_eq!(email.canonical(true), "ursulaleguin@gmail.com");
+        assert_eq!(email.canonical(false), "ursula.le.guin+news@googlemail.com");
+    }
+
+    #[test]
+    fn tags_are_folded_but_dots_kept_for_other_providers() {
+        let email = SubscriberEmail::parse("ursula.le.guin+news@outlook.com".into()).unwrap();
+        assert_eq!(email.canonical(true), "ursula.le.guin@outlook.com");
+        let email = SubscriberEmail::parse("ursula+news@example.com".into()).unwrap();
+        assert_eq!(email.canonical(true), "ursula+news@example.com");
+    }
+
+    fn lists() -> RiskyEmailLists {
+        RiskyEmailLists::parse("# Disposable\nmailinator.com\n", "noreply\n")
+    }
+
+    #[test]
+    fn disposable_domains_and_their_subdomains_are_detected() {
+        for address in ["someone@Mailinator.com", "someone@eu.mailinator.com"] {
+            let email = SubscriberEmail::parse(address.into()).unwrap();
+            assert_eq!(
+                email.check_risk(&lists()),
+                Err(RiskyEmail::DisposableDomain(
+                    address.split('@').nth(1).unwrap().to_lowercase()
+                ))
+            );
+        }
+    }
+
+    #[test]
+    fn role_accounts_are_detected_even_with_a_tag() {
+        let email = SubscriberEmail::parse("NoReply+news@example.com".into()).unwrap();
+        assert_eq!(
+            email.check_risk(&lists()),
+            Err(RiskyEmail::RoleAccount("noreply".into()))
+        );
+    }
+
+    #[test]
+    fn personal_addresses_are_not_risky() {
+        let email = SubscriberEmail::parse("ursula@notmailinator.com".into()).unwrap();
+        assert_eq!(email.check_risk(&lists()), Ok(()));
+    }
 }
@@ -1,19 +1,57 @@
 use unicode_segmentation::UnicodeSegmentation;
 
+const FORBIDDEN_CHARACTERS: [char; 9] = ['/', '(', ')', '"', '<', '>', '\\', '{', '}'];
+
 #[derive(Debug)]
 pub struct SubscriberName(String);
 
+/// The rule a rejected subscriber name broke.
+#[derive(Debug, PartialEq)]
+pub enum SubscriberNameError {
+    Empty,
+    TooLong,
+    ForbiddenCharacter(char),
+}
+
+impl SubscriberNameError {
+    /// Machine readable identifier of the rule, stable across releases.
+    pub fn code(&self) -> &'static str {
+        match self {
+            SubscriberNameError::Empty => "empty",
+            SubscriberNameError::TooLong => "too_long",
+            SubscriberNameError::ForbiddenCharacter(_) => "forbidden_character",
+        }
+    }
+}
+
+impl std::fmt::Display for SubscriberNameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SubscriberNameError::Empty => write!(f, "A subscriber name cannot be empty."),
+            SubscriberNameError::TooLong => {
+                write!(f, "A subscriber name cannot be longer than 256 characters.")
+            }
+            SubscriberNameError::ForbiddenCharacter(c) => {
+                write!(f, "A subscriber name cannot contain '{}'.", c)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SubscriberNameError {}
+
 impl SubscriberName {
-    pub fn parse(s: String) -> Result<SubscriberName, String> {
-        let is_empty_or_whitespace = s.trim().is_empty();
-        let is_too_long = s.graphemes(true).count() > 256;
-        let forbidden_chars = ['/', '(', ')', '"', '<', '>', '\\', '{', '}'];
-        let is_forbidden_chars = s.chars().any(|c| forbidden_chars.contains(&c));
-        let is_valid = !(is_too_long | is_empty_or_whitespace | is_forbidden_chars);
-        match is_valid {
-            true => Ok(Self(s)),
-            false => Err(format!("{} is not a valid subscriber name.", s)),
+    pub fn parse(s: String) -> Result<SubscriberName, SubscriberNameError> {
+        if s.trim().is_empty() {
+            return Err(SubscriberNameError::Empty);
+        }
+        if s.graphemes(true).count() > 256 {
+            return Err(SubscriberNameError::TooLong);
         }
+        if let Some(c) = s.chars().find(|c| FORBIDDEN_CHARACTERS.contains(c)) {
+            return Err(SubscriberNameError::ForbiddenCharacter(c));
+        }
+        Ok(Self(s))
     }
     pub fn inner(&self) -> &String {
         &self.0
@@ -34,7 +72,7 @@ impl AsRef<str> for SubscriberName {
 
 #[cfg(test)]
 mod tests {
-    use crate::domain::SubscriberName;
+    use crate::domain::{SubscriberName, SubscriberNameError};
     use claim::{assert_err, assert_ok};
 
     #[test]
@@ -69,6 +107,22 @@ mod tests {
         }
     }
 
+    #[test]
+    fn errors_identify_the_broken_rule() {
+        assert_eq!(
+            SubscriberName::parse(" ".into()).unwrap_err(),
+            SubscriberNameError::Empty
+        );
+        assert_eq!(
+            SubscriberName::parse("a".repeat(257)).unwrap_err(),
+            SubscriberNameError::TooLong
+        );
+        assert_eq!(
+            SubscriberName::parse("Ursula <admin>".into()).unwrap_err(),
+            SubscriberNameError::ForbiddenCharacter('<')
+        );
+    }
+
     #[test]
     fn a_valid_name_is_parsed_successfully() {
         let name = "Ursula Le Guin".to_string();
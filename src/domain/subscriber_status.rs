@@ -0,0 +1,50 @@
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SubscriberStatus {
+    PendingConfirmation,
+    Confirmed,
+    Unsubscribed,
+}
+
+impl SubscriberStatus {
+    pub const ALL: [SubscriberStatus; 3] = [
+        SubscriberStatus::PendingConfirmation,
+        SubscriberStatus::Confirmed,
+        SubscriberStatus::Unsubscribed,
+    ];
+
+    pub fn parse(s: &str) -> Result<SubscriberStatus, String> {
+        match s {
+            "pending_confirmation" => Ok(Self::PendingConfirmation),
+            "confirmed" => Ok(Self::Confirmed),
+            "unsubscribed" => Ok(Self::Unsubscribed),
+            other => Err(format!("{} is not a valid subscriber status.", other)),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SubscriberStatus::PendingConfirmation => "pending_confirmation",
+            SubscriberStatus::Confirmed => "confirmed",
+            SubscriberStatus::Unsubscribed => "unsubscribed",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SubscriberStatus;
+    use claim::assert_err;
+
+    #[test]
+    fn every_status_round_trips() {
+        for status in SubscriberStatus::ALL {
+            assert_eq!(SubscriberStatus::parse(status.as_str()), Ok(status));
+        }
+    }
+
+    #[test]
+    fn unknown_statuses_are_rejected() {
+        assert_err!(SubscriberStatus::parse("pending"));
+        assert_err!(SubscriberStatus::parse("Confirmed"));
+    }
+}
@@ -0,0 +1,53 @@
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubscriberTag(String);
+
+impl SubscriberTag {
+    pub fn parse(s: String) -> Result<SubscriberTag, String> {
+        let is_empty = s.is_empty();
+        let is_too_long = s.len() > 64;
+        let is_forbidden_chars = s
+            .chars()
+            .any(|c| !(c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_'));
+        let is_valid = !(is_empty | is_too_long | is_forbidden_chars);
+        match is_valid {
+            true => Ok(Self(s)),
+            false => Err(format!("{} is not a valid tag.", s)),
+        }
+    }
+}
+
+impl AsRef<str> for SubscriberTag {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::SubscriberTag;
+    use claim::{assert_err, assert_ok};
+
+    #[test]
+    fn lowercase_tags_are_valid() {
+        for tag in ["beta", "early_adopter", "cohort-2"] {
+            assert_ok!(SubscriberTag::parse(tag.to_string()));
+        }
+    }
+
+    #[test]
+    fn empty_string_is_rejected() {
+        assert_err!(SubscriberTag::parse("".to_string()));
+    }
+
+    #[test]
+    fn a_tag_longer_than_64_characters_is_rejected() {
+        assert_err!(SubscriberTag::parse("a".repeat(65)));
+    }
+
+    #[test]
+    fn tags_with_spaces_or_uppercase_are_rejected() {
+        for tag in ["Beta", "two words", "tag:beta"] {
+            assert_err!(SubscriberTag::parse(tag.to_string()));
+        }
+    }
+}
@@ -0,0 +1,89 @@
+/// What an admin user may do, see `UserRole::can`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Permission {
+    /// Read lists, segments, issue analytics and subscribers.
+    View,
+    /// Manage lists, segments and tags, and save issues as drafts.
+    Edit,
+    /// Send issues to subscribers.
+    Publish,
+    DeleteSubscribers,
+    /// Manage admin users and API keys.
+    ManageAccess,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UserRole {
+    Owner,
+    Editor,
+    Viewer,
+}
+
+impl UserRole {
+    pub const ALL: [UserRole; 3] = [UserRole::Owner, UserRole::Editor, UserRole::Viewer];
+
+    pub fn parse(s: &str) -> Result<UserRole, String> {
+        match s {
+            "owner" => Ok(Self::Owner),
+            "editor" => Ok(Self::Editor),
+            "viewer" => Ok(Self::Viewer),
+            other => Err(format!("{} is not a valid role.", other)),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UserRole::Owner => "owner",
+            UserRole::Editor => "editor",
+            UserRole::Viewer => "viewer",
+        }
+    }
+
+    pub fn can(&self, permission: Permission) -> bool {
+        match self {
+            UserRole::Owner => true,
+            UserRole::Editor => matches!(permission, Permission::View | Permission::Edit),
+            UserRole::Viewer => permission == Permission::View,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Permission, UserRole};
+    use claim::assert_err;
+
+    #[test]
+    fn every_role_round_trips() {
+        for role in UserRole::ALL {
+            assert_eq!(UserRole::parse(role.as_str()), Ok(role));
+        }
+    }
+
+    #[test]
+    fn unknown_roles_are_rejected() {
+        assert_err!(UserRole::parse("admin"));
+        assert_err!(UserRole::parse("Owner"));
+    }
+
+    #[test]
+    fn only_owners_can_publish_delete_and_manage_access() {
+        for permission in [
+            Permission::Publish,
+            Permission::DeleteSubscribers,
+            Permission::ManageAccess,
+        ] {
+            assert!(UserRole::Owner.can(permission));
+            assert!(!UserRole::Editor.can(permission));
+            assert!(!UserRole::Viewer.can(permission));
+        }
+    }
+
+    #[test]
+    fn editors_can_edit_and_viewers_can_only_view() {
+        assert!(UserRole::Editor.can(Permission::Edit));
+        assert!(UserRole::Editor.can(Permission::View));
+        assert!(!UserRole::Viewer.can(Permission::Edit));
+        assert!(UserRole::Viewer.can(Permission::View));
+    }
+}
@@ -0,0 +1,209 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use trust_dns_resolver::error::{ResolveError, ResolveErrorKind};
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// Checks that the domain of an email address can receive mail at all.
+#[async_trait::async_trait]
+pub trait DomainVerifier: Send + Sync {
+    /// `Ok(false)` means the domain definitely cannot receive email, errors
+    /// mean the answer is unknown.
+    async fn accepts_mail(&self, domain: &str) -> Result<bool, ResolveError>;
+}
+
+/// Looks for MX records, falling back to A/AAAA records as mail servers do
+/// when a domain has no MX.
+pub struct DnsDomainVerifier {
+    resolver: TokioAsyncResolver,
+}
+
+impl DnsDomainVerifier {
+    pub fn from_system_conf() -> Result<Self, ResolveError> {
+        Ok(Self {
+            resolver: TokioAsyncResolver::tokio_from_system_conf()?,
+        })
+    }
+}
+
+fn is_no_records(e: &ResolveError) -> bool {
+    matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. })
+}
+
+#[async_trait::async_trait]
+impl DomainVerifier for DnsDomainVerifier {
+    #[tracing::instrument(name = "Looking up mail servers", skip(self))]
+    async fn accepts_mail(&self, domain: &str) -> Result<bool, ResolveError> {
+        // A trailing dot stops the resolver from appending search domains.
+        let fqdn = format!("{}.", domain);
+        match self.resolver.mx_lookup(fqdn.as_str()).await {
+            Ok(mx) if mx.iter().next().is_some() => return Ok(true),
+            Ok(_) => {}
+            Err(e) if is_no_records(&e) => {}
+            Err(e) => return Err(e),
+        }
+        match self.resolver.lookup_ip(fqdn.as_str()).await {
+            Ok(ips) => Ok(ips.iter().next().is_some()),
+            Err(e) if is_no_records(&e) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Accepts exactly the domains it was given, for tests and for running
+/// without network access.
+pub struct InMemoryDomainVerifier {
+    domains: HashSet<String>,
+}
+
+impl InMemoryDomainVerifier {
+    pub fn new<I, S>(domains: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            domains: domains.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl DomainVerifier for InMemoryDomainVerifier {
+    async fn accepts_mail(&self, domain: &str) -> Result<bool, ResolveError> {
+        Ok(self.domains.contains(domain))
+    }
+}
+
+/// Used when domain verification is switched off.
+pub struct AcceptAllDomains;
+
+#[async_trait::async_trait]
+impl DomainVerifier for AcceptAllDomains {
+    async fn accepts_mail(&self, _domain: &str) -> Result<bool, ResolveError> {
+        Ok(true)
+    }
+}
+
+/// Remembers answers of another verifier for `ttl`. Failed lookups are not
+/// cached, and neither are new answers while the cache is full of fresh ones.
+pub struct CachedDomainVerifier<V> {
+    inner: V,
+    ttl: Duration,
+    capacity: usize,
+    cache: Mutex<HashMap<String, (bool, Instant)>>,
+}
+
+/// Expired answers are dropped once there are this many domains, which is
+/// also as many as the cache holds.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+impl<V: DomainVerifier> CachedDomainVerifier<V> {
+    pub fn new(inner: V, ttl: Duration) -> Self {
+        Self::with_capacity(inner, ttl, PRUNE_THRESHOLD)
+    }
+
+    fn with_capacity(inner: V, ttl: Duration, capacity: usize) -> Self {
+        Self {
+            inner,
+            ttl,
+            capacity,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<V: DomainVerifier> DomainVerifier for CachedDomainVerifier<V> {
+    async fn accepts_mail(&self, domain: &str) -> Result<bool, ResolveError> {
+        if let Some((accepts, expires_at)) = self.cache.lock().unwrap().get(domain) {
+            if *expires_at > Instant::now() {
+                return Ok(*accepts);
+            }
+        }
+        let accepts = self.inner.accepts_mail(domain).await?;
+        let now = Instant::now();
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= self.capacity {
+            cache.retain(|_, (_, expires_at)| *expires_at > now);
+        }
+        if cache.len() < self.capacity || cache.contains_key(domain) {
+            cache.insert(domain.to_owned(), (accepts, now + self.ttl));
+        }
+        Ok(accepts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CachedDomainVerifier, DomainVerifier, InMemoryDomainVerifier};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use trust_dns_resolver::error::ResolveError;
+
+    struct CountingVerifier {
+        inner: InMemoryDomainVerifier,
+        lookups: AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl DomainVerifier for CountingVerifier {
+        async fn accepts_mail(&self, domain: &str) -> Result<bool, ResolveError> {
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            self.inner.accepts_mail(domain).await
+        }
+    }
+
+    fn counting() -> CountingVerifier {
+        CountingVerifier {
+            inner: InMemoryDomainVerifier::new(["example.com"]),
+            lookups: AtomicUsize::new(0),
+        }
+    }
+
+    #[tokio::test]
+    async fn answers_are_cached_both_ways() {
+        let verifier = CachedDomainVerifier::new(counting(), Duration::from_secs(60));
+
+        for _ in 0..3 {
+            assert!(verifier.accepts_mail("example.com").await.unwrap());
+            assert!(!verifier.accepts_mail("gmial.com").await.unwrap());
+        }
+
+        assert_eq!(verifier.inner.lookups.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn expired_answers_are_looked_up_again() {
+        let verifier = CachedDomainVerifier::new(counting(), Duration::ZERO);
+
+        verifier.accepts_mail("example.com").await.unwrap();
+        verifier.accepts_mail("example.com").await.unwrap();
+
+        assert_eq!(verifier.inner.lookups.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn expired_answers_are_pruned_when_the_cache_is_full() {
+        let verifier = CachedDomainVerifier::with_capacity(counting(), Duration::ZERO, 2);
+
+        for domain in ["a.com", "b.com", "c.com", "d.com"] {
+            verifier.accepts_mail(domain).await.unwrap();
+        }
+
+        assert!(verifier.cache.lock().unwrap().len() <= 2);
+    }
+
+    #[tokio::test]
+    async fn fresh_answers_are_kept_when_the_cache_is_full() {
+        let verifier = CachedDomainVerifier::with_capacity(counting(), Duration::from_secs(60), 2);
+
+        for domain in ["a.com", "b.com", "c.com", "a.com", "b.com"] {
+            verifier.accepts_mail(domain).await.unwrap();
+        }
+
+        assert_eq!(verifier.cache.lock().unwrap().len(), 2);
+        assert_eq!(verifier.inner.lookups.load(Ordering::SeqCst), 3);
+    }
+}
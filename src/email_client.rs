@@ -44,7 +44,7 @@ impl EmailClient {
         html_content: &str,
         text: &str,
     ) -> Result<(), reqwest::Error> {
-        let url = format!("{}", self.base_url);
+        let url = format!("{}/email", self.base_url);
         let request_body = SendEmailRequest {
             from: self.sender.as_ref(),
             to: recipent.as_ref(),
@@ -69,7 +69,7 @@ impl EmailClient {
 mod tests{
     use crate::domain::SubscriberEmail;
     use crate::email_client::EmailClient;
-    use claim::{assert_err,assert_ok};
+    use claim::assert_err;
     use fake::faker::internet::en::SafeEmail;
     use fake::faker::lorem::en::Paragraph;
     use fake::{Fake,Faker};
@@ -0,0 +1,128 @@
+use crate::domain::SubscriberAttributes;
+
+/// Per-subscriber values available to issue templates as `{{ name }}`,
+/// `{{ email }}`, `{{ preferences_url }}` and `{{ attributes.<name> }}`.
+pub struct TemplateContext<'a> {
+    pub name: &'a str,
+    pub email: &'a str,
+    pub preferences_url: &'a str,
+    pub attributes: &'a SubscriberAttributes,
+}
+
+impl TemplateContext<'_> {
+    fn lookup(&self, placeholder: &str) -> Option<String> {
+        match placeholder {
+            "name" => Some(self.name.to_owned()),
+            "email" => Some(self.email.to_owned()),
+            "preferences_url" => Some(self.preferences_url.to_owned()),
+            _ => self
+                .attributes
+                .get_text(placeholder.strip_prefix("attributes.")?),
+        }
+    }
+}
+
+/// Substitutes placeholders in the HTML body, escaping the inserted values.
+pub fn render_html(template: &str, context: &TemplateContext) -> String {
+    render(template, context, escape_html)
+}
+
+/// Substitutes placeholders in the plain text body.
+pub fn render_text(template: &str, context: &TemplateContext) -> String {
+    render(template, context, str::to_owned)
+}
+
+/// Unknown placeholders render as an empty string, unterminated ones are kept
+/// verbatim.
+fn render<F>(template: &str, context: &TemplateContext, escape: F) -> String
+where
+    F: Fn(&str) -> String,
+{
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        output.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = match rest.find("}}") {
+            Some(end) => end,
+            None => break,
+        };
+        let placeholder = rest[2..end].trim();
+        if let Some(value) = context.lookup(placeholder) {
+            output.push_str(&escape(&value));
+        }
+        rest = &rest[end + 2..];
+    }
+    output.push_str(rest);
+    output
+}
+
+pub fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render_html, render_text, TemplateContext};
+    use crate::domain::SubscriberAttributes;
+
+    fn attributes() -> SubscriberAttributes {
+        SubscriberAttributes::from_stored(serde_json::json!({"company": "Smith & Co", "seats": 3}))
+    }
+
+    #[test]
+    fn placeholders_are_substituted() {
+        let attributes = attributes();
+        let context = TemplateContext {
+            name: "Ursula",
+            email: "ursula@example.com",
+            preferences_url: "http://localhost/preferences/abc",
+            attributes: &attributes,
+        };
+        assert_eq!(
+            render_text("Hi {{name}} ({{ email }}) from {{ attributes.company }}, {{attributes.seats}} seats. {{ preferences_url }}", &context),
+            "Hi Ursula (ursula@example.com) from Smith & Co, 3 seats. http://localhost/preferences/abc"
+        );
+    }
+
+    #[test]
+    fn html_values_are_escaped() {
+        let attributes = attributes();
+        let context = TemplateContext {
+            name: "<b>Ursula</b>",
+            email: "ursula@example.com",
+            preferences_url: "http://localhost/preferences/abc",
+            attributes: &attributes,
+        };
+        assert_eq!(
+            render_html("<p>{{ name }} - {{ attributes.company }}</p>", &context),
+            "<p>&lt;b&gt;Ursula&lt;/b&gt; - Smith &amp; Co</p>"
+        );
+    }
+
+    #[test]
+    fn unknown_placeholders_render_empty_and_unterminated_ones_are_kept() {
+        let attributes = SubscriberAttributes::default();
+        let context = TemplateContext {
+            name: "Ursula",
+            email: "ursula@example.com",
+            preferences_url: "http://localhost/preferences/abc",
+            attributes: &attributes,
+        };
+        assert_eq!(
+            render_text("a{{ attributes.missing }}b{{ unknown }}c {{ name", &context),
+            "abc {{ name"
+        );
+    }
+}
@@ -1,6 +1,15 @@
+pub mod authentication;
 pub mod configuration;
 pub mod email_client;
+pub mod email_template;
+pub mod maintenance;
 pub mod routes;
 pub mod startup;
 pub mod telemetry;
+pub mod tokens;
 pub mod domain;
+pub mod tracking;
+pub mod domain_verification;
+pub mod rate_limiting;
+pub mod signup_protection;
+pub mod subscription_cleanup;
@@ -1,8 +1,14 @@
 use newsletter::startup::run;
 use std::net::TcpListener;
+use std::sync::Arc;
 use newsletter::configuration::get_configuration;
 use newsletter::telemetry:: {init_subscriber,get_subscriber};
 use newsletter::email_client::EmailClient;
+use newsletter::domain_verification::{AcceptAllDomains, CachedDomainVerifier, DnsDomainVerifier, DomainVerifier};
+use newsletter::subscription_cleanup::{refresh_canonical_emails, run_cleanup_worker};
+use newsletter::maintenance::run_maintenance_worker;
+use newsletter::rate_limiting::{rate_limiter, TrustedProxies};
+use newsletter::authentication::{encrypt_plaintext_totp_secrets, ensure_initial_owner};
 use sqlx::postgres::PgPoolOptions;
 
 #[tokio::main]
@@ -19,6 +25,24 @@ async fn main() -> std::io::Result<()> {
         .connect_lazy_with(configuration.database.with_db());
     let address = format!("{}:{}", configuration.application.host,configuration.application.port);
     let listener = TcpListener::bind(address)?;
-    run(listener, connection_pool,email_client)?.await
+    let domain_verifier: Arc<dyn DomainVerifier> = if configuration.subscriptions.verify_email_domains {
+        let dns = DnsDomainVerifier::from_system_conf().expect("Failed to read the system DNS configuration.");
+        Arc::new(CachedDomainVerifier::new(dns, configuration.subscriptions.domain_verification_cache()))
+    } else {
+        Arc::new(AcceptAllDomains)
+    };
+    let rate_limiter = rate_limiter(&configuration.subscriptions.rate_limiting, connection_pool.clone());
+    if let Err(e) = ensure_initial_owner(&connection_pool, &configuration.admin).await {
+        tracing::error!(error.cause_chain = ?e, "Failed to create the initial owner");
+    }
+    if let Err(e) = encrypt_plaintext_totp_secrets(&connection_pool, &configuration.admin.totp_cipher()).await {
+        tracing::error!(error.cause_chain = ?e, "Failed to encrypt plaintext TOTP secrets");
+    }
+    if let Err(e) = refresh_canonical_emails(&connection_pool, configuration.subscriptions.fold_email_aliases).await {
+        tracing::error!(error.cause_chain = ?e, "Failed to refresh canonical emails");
+    }
+    tokio::spawn(run_cleanup_worker(connection_pool.clone(), configuration.subscriptions.clone()));
+    tokio::spawn(run_maintenance_worker(connection_pool.clone(), configuration.subscriptions.cleanup_interval()));
+    run(listener, connection_pool,email_client,configuration.application.base_url,configuration.subscriptions,domain_verifier,rate_limiter,configuration.application.api_docs,configuration.admin,TrustedProxies(configuration.application.trusted_proxies))?.await
 }
 
@@ -0,0 +1,23 @@
+use std::time::Duration;
+
+use sqlx::PgPool;
+
+use crate::rate_limiting::purge_expired_rate_limits;
+use crate::signup_protection::purge_used_form_tokens;
+
+/// Purges expired rate limit counters and used form tokens every `period`,
+/// forever. Failures are logged and retried on the next tick.
+pub async fn run_maintenance_worker(pool: PgPool, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        match purge_expired_rate_limits(&pool).await {
+            Ok(purged) => tracing::info!(purged, "Purged expired rate limit counters"),
+            Err(e) => tracing::error!("Failed to purge expired rate limit counters: {:?}", e),
+        }
+        match purge_used_form_tokens(&pool).await {
+            Ok(purged) => tracing::info!(purged, "Purged expired used form tokens"),
+            Err(e) => tracing::error!("Failed to purge expired used form tokens: {:?}", e),
+        }
+    }
+}
@@ -0,0 +1,403 @@
+use std::collections::HashMap;
+use std::future::{ready, Future, Ready};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{web, HttpMessage, HttpRequest};
+use chrono::Utc;
+use sqlx::PgPool;
+
+use crate::configuration::{RateLimitSettings, RateLimitStore, SubscriptionSettings};
+use crate::routes::too_many_requests;
+
+/// At most `requests` per `window`, counted in fixed windows starting with
+/// the first request.
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimit {
+    pub requests: u32,
+    pub window: Duration,
+}
+
+/// Counts requests per key.
+#[async_trait::async_trait]
+pub trait RateLimiter: Send + Sync {
+    /// Counts a request for `key`. Returns how long until the window resets
+    /// if the request goes over `limit`.
+    async fn hit(&self, key: &str, limit: RateLimit) -> Result<Option<Duration>, sqlx::Error>;
+}
+
+/// Counts in the memory of this instance, every instance allows the full
+/// limit.
+#[derive(Default)]
+pub struct InMemoryRateLimiter {
+    /// Hits and the end of the window, per key.
+    windows: Mutex<HashMap<String, (u32, Instant)>>,
+}
+
+/// Expired windows are dropped once there are this many keys.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+impl InMemoryRateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait::async_trait]
+impl RateLimiter for InMemoryRateLimiter {
+    async fn hit(&self, key: &str, limit: RateLimit) -> Result<Option<Duration>, sqlx::Error> {
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+        if windows.len() >= PRUNE_THRESHOLD {
+            windows.retain(|_, (_, ends_at)| *ends_at > now);
+        }
+        let (hits, ends_at) = windows
+            .entry(key.to_owned())
+            .and_modify(|(hits, ends_at)| {
+                if *ends_at <= now {
+                    *hits = 0;
+                    *ends_at = now + limit.window;
+                }
+            })
+            .or_insert((0, now + limit.window));
+        *hits += 1;
+        Ok((*hits > limit.requests).then(|| *ends_at - now))
+    }
+}
+
+/// Counts in Postgres, the limit holds across all instances.
+pub struct PostgresRateLimiter {
+    pool: PgPool,
+}
+
+impl PostgresRateLimiter {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait::async_trait]
+impl RateLimiter for PostgresRateLimiter {
+    #[tracing::instrument(name = "Counting a rate limited request", skip(self))]
+    async fn hit(&self, key: &str, limit: RateLimit) -> Result<Option<Duration>, sqlx::Error> {
+        let now = Utc::now();
+        let window = chrono::Duration::from_std(limit.window)
+            .expect("Rate limit windows fit a chrono::Duration");
+        let counter = sqlx::query!(
+            r#"
+            INSERT INTO rate_limit_counters (key, hits, expires_at)
+            VALUES ($1, 1, $3)
+            ON CONFLICT (key) DO UPDATE SET
+                hits = CASE WHEN rate_limit_counters.expires_at <= $2
+                    THEN 1 ELSE rate_limit_counters.hits + 1 END,
+                expires_at = CASE WHEN rate_limit_counters.expires_at <= $2
+                    THEN EXCLUDED.expires_at ELSE rate_limit_counters.expires_at END
+            RETURNING hits, expires_at
+            "#,
+            key,
+            now,
+            now + window
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+        if counter.hits as i64 <= limit.requests as i64 {
+            return Ok(None);
+        }
+        Ok(Some(
+            (counter.expires_at - now).to_std().unwrap_or_default(),
+        ))
+    }
+}
+
+/// Drops the counters of windows that ended, run by the cleanup worker.
+#[tracing::instrument(name = "Purging expired rate limit counters", skip(pool))]
+pub async fn purge_expired_rate_limits(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    sqlx::query!(
+        "DELETE FROM rate_limit_counters WHERE expires_at <= $1",
+        Utc::now()
+    )
+    .execute(pool)
+    .await
+    .map(|done| done.rows_affected())
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })
+}
+
+/// The limiter the settings ask for.
+pub fn rate_limiter(settings: &RateLimitSettings, pool: PgPool) -> Arc<dyn RateLimiter> {
+    match settings.store {
+        RateLimitStore::Memory => Arc::new(InMemoryRateLimiter::new()),
+        RateLimitStore::Postgres => Arc::new(PostgresRateLimiter::new(pool)),
+    }
+}
+
+/// Proxies whose forwarding headers are believed, see `client_ip`.
+#[derive(Clone, Debug, Default)]
+pub struct TrustedProxies(pub Vec<IpAddr>);
+
+/// The address limits and lockouts are counted for. That is the TCP peer,
+/// unless it is one of the `TrustedProxies` of the app: then it is the
+/// client the proxy names in `Forwarded` or `X-Forwarded-For`, which the
+/// proxy has to overwrite rather than append to. Anyone else could send
+/// those headers to dodge the per-address limits.
+pub fn client_ip(req: &HttpRequest) -> String {
+    let peer = match req.peer_addr() {
+        Some(addr) => addr.ip(),
+        None => return "unknown".into(),
+    };
+    let behind_proxy = req
+        .app_data::<web::Data<TrustedProxies>>()
+        .is_some_and(|proxies| proxies.0.contains(&peer));
+    let client = if behind_proxy {
+        req.connection_info()
+            .realip_remote_addr()
+            .and_then(parse_forwarded_ip)
+            .unwrap_or(peer)
+    } else {
+        peer
+    };
+    address_key(client)
+}
+
+/// Proxies name the client with or without a port, IPv6 in brackets or not.
+fn parse_forwarded_ip(forwarded: &str) -> Option<IpAddr> {
+    forwarded
+        .parse::<SocketAddr>()
+        .map(|addr| addr.ip())
+        .or_else(|_| forwarded.trim_matches(|c| c == '[' || c == ']').parse())
+        .ok()
+}
+
+/// IPv6 clients are usually handed a whole /64, all of it counts as one
+/// address.
+fn address_key(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => ip.to_string(),
+            None => {
+                let [a, b, c, d, ..] = ip.segments();
+                format!("{}/64", Ipv6Addr::new(a, b, c, d, 0, 0, 0, 0))
+            }
+        },
+    }
+}
+
+/// The domain of the signup email, lowercased. Form and JSON bodies both
+/// carry the address in `email`.
+fn signup_domain(req: &ServiceRequest, body: &[u8]) -> Option<String> {
+    #[derive(serde::Deserialize)]
+    struct Signup {
+        email: String,
+    }
+    let signup: Signup = match req.content_type() {
+        "application/x-www-form-urlencoded" => serde_urlencoded::from_bytes(body).ok()?,
+        "application/json" => serde_json::from_slice(body).ok()?,
+        _ => return None,
+    };
+    let (_, domain) = signup.email.trim().rsplit_once('@')?;
+    Some(domain.to_lowercase()).filter(|domain| !domain.is_empty())
+}
+
+/// Second-level labels under which country code domains are registered,
+/// as in `example.co.uk` or `example.com.au`.
+const SECOND_LEVEL_SUFFIXES: &[&str] = &["ac", "co", "com", "edu", "gov", "ne", "net", "or", "org"];
+
+/// The part of `domain` its owner registered, so random subdomains of a
+/// wildcard MX all share one limit. Without the public suffix list this
+/// keeps the last two labels, or three under a known second-level suffix of
+/// a country code.
+fn registrable_domain(domain: &str) -> &str {
+    let labels: Vec<&str> = domain.trim_end_matches('.').rsplit('.').collect();
+    let kept = match labels.as_slice() {
+        [tld, second, _, ..] if tld.len() == 2 && SECOND_LEVEL_SUFFIXES.contains(second) => 3,
+        _ => 2,
+    };
+    if labels.len() <= kept {
+        return domain;
+    }
+    let dropped: usize = labels[kept..].iter().map(|label| label.len() + 1).sum();
+    &domain[dropped..]
+}
+
+/// Limits signups per client address and per registrable email domain,
+/// except shared webmail domains, with the `RateLimiter` and
+/// `SubscriptionSettings` of the app. Answers 429 with `Retry-After` once a
+/// limit is hit, a failing limiter lets the signup through.
+pub struct SignupRateLimit;
+
+impl<S, B> Transform<S, ServiceRequest> for SignupRateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = SignupRateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(SignupRateLimitMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct SignupRateLimitMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for SignupRateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        Box::pin(async move {
+            let limiter = req.app_data::<web::Data<dyn RateLimiter>>().cloned();
+            let settings = req.app_data::<web::Data<SubscriptionSettings>>().cloned();
+            if let (Some(limiter), Some(settings)) = (limiter, settings) {
+                let body = req.extract::<web::Bytes>().await?;
+                let domain = signup_domain(&req, &body);
+                let ip_address = client_ip(req.request());
+                // The handler reads the body again.
+                let (_, mut payload) = actix_http::h1::Payload::create(true);
+                payload.unread_data(body);
+                req.set_payload(payload.into());
+                let settings = &settings.rate_limiting;
+                let mut checks = vec![(format!("signup:ip:{}", ip_address), settings.per_ip())];
+                let domain = domain.as_deref().map(registrable_domain);
+                // Webmail providers are shared by too many people, a limit on
+                // them would let one bot turn everyone else away.
+                if let Some(domain) = domain
+                    .filter(|domain| !settings.shared_email_domains.iter().any(|d| d == *domain))
+                {
+                    checks.push((format!("signup:domain:{}", domain), settings.per_domain()));
+                }
+                for (key, limit) in checks {
+                    match limiter.hit(&key, limit).await {
+                        Ok(None) => {}
+                        Ok(Some(wait)) => {
+                            tracing::warn!(key = %key, "Rate limiting signups");
+                            let response =
+                                too_many_requests("Too many signups, try again later.", wait);
+                            return Ok(req.into_response(response).map_into_right_body());
+                        }
+                        Err(e) => {
+                            tracing::warn!(error.cause_chain = ?e, "Failed to rate limit a signup");
+                        }
+                    }
+                }
+            }
+            service
+                .call(req)
+                .await
+                .map(ServiceResponse::map_into_left_body)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        address_key, parse_forwarded_ip, registrable_domain, InMemoryRateLimiter, RateLimit,
+        RateLimiter,
+    };
+    use std::net::IpAddr;
+    use std::time::Duration;
+
+    const LIMIT: RateLimit = RateLimit {
+        requests: 2,
+        window: Duration::from_secs(60),
+    };
+
+    #[tokio::test]
+    async fn requests_over_the_limit_wait_for_the_window() {
+        let limiter = InMemoryRateLimiter::new();
+
+        assert_eq!(None, limiter.hit("a", LIMIT).await.unwrap());
+        assert_eq!(None, limiter.hit("a", LIMIT).await.unwrap());
+        let wait = limiter.hit("a", LIMIT).await.unwrap().unwrap();
+
+        assert!(wait > Duration::from_secs(59) && wait <= LIMIT.window);
+    }
+
+    #[tokio::test]
+    async fn keys_are_counted_separately() {
+        let limiter = InMemoryRateLimiter::new();
+
+        limiter.hit("a", LIMIT).await.unwrap();
+        limiter.hit("a", LIMIT).await.unwrap();
+
+        assert_eq!(None, limiter.hit("b", LIMIT).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn the_count_starts_over_in_a_new_window() {
+        let limiter = InMemoryRateLimiter::new();
+        let limit = RateLimit {
+            requests: 1,
+            window: Duration::ZERO,
+        };
+
+        limiter.hit("a", limit).await.unwrap();
+
+        assert_eq!(None, limiter.hit("a", limit).await.unwrap());
+    }
+
+    #[test]
+    fn ipv6_addresses_count_per_64() {
+        let key = |ip: &str| address_key(ip.parse::<IpAddr>().unwrap());
+
+        assert_eq!("2001:db8:1:2::/64", key("2001:db8:1:2:aaaa::1"));
+        assert_eq!(key("2001:db8:1:2::1"), key("2001:db8:1:2:ffff::2"));
+        assert_ne!(key("2001:db8:1:2::1"), key("2001:db8:1:3::1"));
+        assert_eq!("192.0.2.1", key("::ffff:192.0.2.1"));
+        assert_eq!("192.0.2.1", key("192.0.2.1"));
+    }
+
+    #[test]
+    fn subdomains_share_the_registrable_domain() {
+        assert_eq!("example.com", registrable_domain("example.com"));
+        assert_eq!("example.com", registrable_domain("x7f3.mail.example.com"));
+        assert_eq!("example.co.uk", registrable_domain("a1.example.co.uk"));
+        assert_eq!("example.com.au", registrable_domain("example.com.au"));
+        assert_eq!("yahoo.co.kr", registrable_domain("yahoo.co.kr"));
+        assert_eq!("localhost", registrable_domain("localhost"));
+    }
+
+    #[test]
+    fn forwarded_addresses_are_parsed_with_or_without_a_port() {
+        let expected = |ip: &str| Some(ip.parse::<IpAddr>().unwrap());
+
+        assert_eq!(expected("192.0.2.1"), parse_forwarded_ip("192.0.2.1"));
+        assert_eq!(expected("192.0.2.1"), parse_forwarded_ip("192.0.2.1:4711"));
+        assert_eq!(
+            expected("2001:db8::1"),
+            parse_forwarded_ip("[2001:db8::1]:4711")
+        );
+        assert_eq!(expected("2001:db8::1"), parse_forwarded_ip("[2001:db8::1]"));
+        assert_eq!(expected("2001:db8::1"), parse_forwarded_ip("2001:db8::1"));
+        assert_eq!(None, parse_forwarded_ip("unknown"));
+    }
+}
@@ -0,0 +1,38 @@
+use actix_web::{web, HttpResponse};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::authentication::AdminUser;
+use crate::routes::{delete_subscriber_by_id, Problem};
+
+/// Deletes the subscriber for good. Deliveries and tracking events stay
+/// behind under a pseudonym so issue stats keep adding up. Only owners may
+/// do this.
+#[utoipa::path(
+    delete,
+    path = "/admin/subscribers/{subscriber_id}",
+    tag = "subscribers",
+    security(("session" = [])),
+    params(("subscriber_id" = Uuid, Path)),
+    responses(
+        (status = 204, description = "The subscriber is gone"),
+        (status = 404, description = "Unknown subscriber"),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The role lacks the permission", body = Problem, content_type = "application/problem+json")
+    )
+)]
+#[tracing::instrument(name = "Deleting a subscriber as an admin", skip(user, pool), fields(username = %user.username))]
+pub async fn delete_admin_subscriber(
+    user: AdminUser,
+    subscriber_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+) -> HttpResponse {
+    match delete_subscriber_by_id(&pool, *subscriber_id).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().finish(),
+        Err(e) => {
+            tracing::error!("Failed to execute query: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
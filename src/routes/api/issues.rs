@@ -0,0 +1,40 @@
+use actix_web::{web, HttpResponse};
+use sqlx::PgPool;
+
+use crate::authentication::ApiKey;
+use crate::domain::ApiScope;
+use crate::email_client::EmailClient;
+use crate::routes::{create_issue, IssueData, Problem, PublishedIssue, ValidationProblem};
+use crate::startup::ApplicationBaseUrl;
+
+/// `POST /admin/issues` for machine clients such as a CMS, drafts included.
+#[utoipa::path(
+    post,
+    path = "/api/v1/issues",
+    tag = "issues",
+    security(("api_key" = ["issues:publish"])),
+    request_body = IssueData,
+    responses(
+        (status = 200, description = "The issue was sent", body = PublishedIssue),
+        (status = 400, description = "Unknown list or segment", body = ValidationProblem, content_type = "application/problem+json"),
+        (status = 500, description = "Some emails failed, the issue stays unpublished", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing, unknown or revoked API key", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The key lacks the scope", body = Problem, content_type = "application/problem+json")
+    )
+)]
+#[tracing::instrument(
+    name = "Publishing a newsletter issue through the API",
+    skip(api_key, body, pool, email_client, base_url)
+)]
+pub async fn publish_issue_with_api_key(
+    api_key: ApiKey,
+    body: web::Json<IssueData>,
+    pool: web::Data<PgPool>,
+    email_client: web::Data<EmailClient>,
+    base_url: web::Data<ApplicationBaseUrl>,
+) -> HttpResponse {
+    if let Err(response) = api_key.require(ApiScope::IssuesPublish) {
+        return response;
+    }
+    create_issue(body.into_inner(), &pool, &email_client, &base_url).await
+}
@@ -0,0 +1,36 @@
+//! The versioned JSON API under `/api/v1`. Every error, including the ones
+//! raised by extractors, is answered with a problem document.
+
+mod issues;
+mod subscribers;
+
+pub use issues::*;
+pub use subscribers::*;
+
+use actix_web::error::{InternalError, JsonPayloadError, PathError, QueryPayloadError};
+use actix_web::{web, HttpRequest};
+
+use crate::routes::{FieldError, Problem, ValidationProblem};
+
+pub fn json_config() -> web::JsonConfig {
+    web::JsonConfig::default().error_handler(|err: JsonPayloadError, _: &HttpRequest| {
+        let problem =
+            ValidationProblem::new(vec![FieldError::new("body", "malformed", err.to_string())]);
+        InternalError::from_response(err, problem.error_response()).into()
+    })
+}
+
+pub fn query_config() -> web::QueryConfig {
+    web::QueryConfig::default().error_handler(|err: QueryPayloadError, _: &HttpRequest| {
+        let problem =
+            ValidationProblem::new(vec![FieldError::new("query", "malformed", err.to_string())]);
+        InternalError::from_response(err, problem.error_response()).into()
+    })
+}
+
+pub fn path_config() -> web::PathConfig {
+    web::PathConfig::default().error_handler(|err: PathError, _: &HttpRequest| {
+        let problem = Problem::not_found("No resource matches this path.");
+        InternalError::from_response(err, problem.error_response()).into()
+    })
+}
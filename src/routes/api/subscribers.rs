@@ -0,0 +1,579 @@
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::authentication::ApiKey;
+use crate::configuration::SubscriptionSettings;
+use crate::domain::{ApiScope, RiskyEmailLists, SubscriberEmail, SubscriberName, SubscriberStatus};
+use crate::domain_verification::DomainVerifier;
+use crate::routes::{check_email, FieldError, Problem, ValidationProblem};
+use crate::subscription_cleanup::delete_subscribers;
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 100;
+/// Postgres error code of a unique constraint violation.
+const UNIQUE_VIOLATION: &str = "23505";
+
+#[derive(serde::Deserialize, Debug, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SubscriberQuery {
+    /// `pending_confirmation`, `confirmed` or `unsubscribed`.
+    status: Option<String>,
+    /// Case-insensitive substring of the email address.
+    email: Option<String>,
+    /// `next_cursor` of the previous page.
+    cursor: Option<String>,
+    /// Between 1 and 100, 50 by default.
+    limit: Option<i64>,
+}
+
+/// Omitted fields are left alone.
+#[derive(serde::Deserialize, Debug, utoipa::ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct SubscriberUpdate {
+    name: Option<String>,
+    email: Option<String>,
+    /// `confirmed` or `unsubscribed`.
+    status: Option<String>,
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+pub struct Subscriber {
+    id: Uuid,
+    email: String,
+    name: String,
+    status: String,
+    subscribed_at: DateTime<Utc>,
+    flagged_as: Option<String>,
+    #[schema(value_type = Object)]
+    attributes: serde_json::Value,
+    tags: Vec<String>,
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct SubscriberPage {
+    subscribers: Vec<Subscriber>,
+    /// Absent on the last page.
+    next_cursor: Option<String>,
+}
+
+/// Position after the last subscriber of a page, subscribers are listed
+/// newest first.
+struct Cursor {
+    subscribed_at: DateTime<Utc>,
+    id: Uuid,
+}
+
+impl Cursor {
+    fn parse(s: &str) -> Option<Cursor> {
+        let (micros, id) = s.split_once('.')?;
+        let subscribed_at = NaiveDateTime::from_timestamp_micros(micros.parse().ok()?)?;
+        Some(Cursor {
+            subscribed_at: DateTime::from_utc(subscribed_at, Utc),
+            id: Uuid::parse_str(id).ok()?,
+        })
+    }
+
+    fn encode(&self) -> String {
+        format!("{}.{}", self.subscribed_at.timestamp_micros(), self.id)
+    }
+}
+
+struct ValidUpdate {
+    name: Option<SubscriberName>,
+    email: Option<SubscriberEmail>,
+    /// What the new email is flagged as by the risk checks.
+    flagged_as: Option<&'static str>,
+    status: Option<SubscriberStatus>,
+}
+
+enum UpdateOutcome {
+    Updated,
+    NotFound,
+    EmailTaken,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/subscribers",
+    tag = "subscribers",
+    params(SubscriberQuery),
+    security(("api_key" = ["subscribers:read"])),
+    responses(
+        (status = 200, description = "A page of subscribers, newest first", body = SubscriberPage),
+        (status = 400, description = "Invalid filter, cursor or limit", body = ValidationProblem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing, unknown or revoked API key", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The key lacks the scope", body = Problem, content_type = "application/problem+json")
+    )
+)]
+#[tracing::instrument(name = "Listing subscribers", skip(api_key, query, pool))]
+pub async fn list_subscribers(
+    api_key: ApiKey,
+    query: web::Query<SubscriberQuery>,
+    pool: web::Data<PgPool>,
+) -> HttpResponse {
+    if let Err(response) = api_key.require(ApiScope::SubscribersRead) {
+        return response;
+    }
+    let query = query.into_inner();
+    let mut errors = Vec::new();
+    let status = match query
+        .status
+        .as_deref()
+        .map(SubscriberStatus::parse)
+        .transpose()
+    {
+        Ok(status) => status,
+        Err(e) => {
+            errors.push(FieldError::new("status", "invalid", e));
+            None
+        }
+    };
+    let cursor = match query
+        .cursor
+        .as_deref()
+        .map(|c| Cursor::parse(c).ok_or(()))
+        .transpose()
+    {
+        Ok(cursor) => cursor,
+        Err(()) => {
+            errors.push(FieldError::new(
+                "cursor",
+                "invalid",
+                "Not a valid page cursor.",
+            ));
+            None
+        }
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+        errors.push(FieldError::new(
+            "limit",
+            "out_of_range",
+            format!("The limit must be between 1 and {}.", MAX_PAGE_SIZE),
+        ));
+    }
+    if !errors.is_empty() {
+        return ValidationProblem::new(errors).error_response();
+    }
+
+    // One extra row tells us whether there is a next page.
+    let subscribers = sqlx::query_as!(
+        Subscriber,
+        r#"
+        SELECT
+            s.id, s.email, s.name, s.status, s.subscribed_at, s.flagged_as, s.attributes,
+            ARRAY(
+                SELECT t.tag FROM subscriber_tags t WHERE t.subscriber_id = s.id ORDER BY t.tag
+            ) AS "tags!"
+        FROM subscriptions s
+        WHERE ($1::text IS NULL OR s.status = $1)
+            AND ($2::text IS NULL OR strpos(lower(s.email), lower($2)) > 0)
+            AND ($3::timestamptz IS NULL OR (s.subscribed_at, s.id) < ($3, $4::uuid))
+        ORDER BY s.subscribed_at DESC, s.id DESC
+        LIMIT $5
+        "#,
+        status.map(|s| s.as_str()),
+        query.email,
+        cursor.as_ref().map(|c| c.subscribed_at),
+        cursor.as_ref().map(|c| c.id),
+        limit + 1
+    )
+    .fetch_all(pool.get_ref())
+    .await;
+    let mut subscribers = match subscribers {
+        Ok(subscribers) => subscribers,
+        Err(e) => {
+            tracing::error!("Failed to execute query: {:?}", e);
+            return Problem::internal_error().error_response();
+        }
+    };
+    let next_cursor = if subscribers.len() as i64 > limit {
+        subscribers.truncate(limit as usize);
+        subscribers.last().map(|last| {
+            Cursor {
+                subscribed_at: last.subscribed_at,
+                id: last.id,
+            }
+            .encode()
+        })
+    } else {
+        None
+    };
+    HttpResponse::Ok().json(SubscriberPage {
+        subscribers,
+        next_cursor,
+    })
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/subscribers/{subscriber_id}",
+    tag = "subscribers",
+    params(("subscriber_id" = Uuid, Path)),
+    security(("api_key" = ["subscribers:read"])),
+    responses(
+        (status = 200, body = Subscriber),
+        (status = 404, description = "Unknown subscriber", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing, unknown or revoked API key", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The key lacks the scope", body = Problem, content_type = "application/problem+json")
+    )
+)]
+#[tracing::instrument(name = "Fetching a subscriber", skip(api_key, pool))]
+pub async fn fetch_subscriber(
+    api_key: ApiKey,
+    subscriber_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+) -> HttpResponse {
+    if let Err(response) = api_key.require(ApiScope::SubscribersRead) {
+        return response;
+    }
+    subscriber_response(&pool, *subscriber_id).await
+}
+
+/// A new email address goes through the risk and domain checks of a
+/// signup, apart from the typo check. API clients are trusted otherwise: the
+/// subscriber keeps their status and is not asked to confirm the new address.
+#[utoipa::path(
+    patch,
+    path = "/api/v1/subscribers/{subscriber_id}",
+    tag = "subscribers",
+    params(("subscriber_id" = Uuid, Path)),
+    security(("api_key" = ["subscribers:write"])),
+    request_body = SubscriberUpdate,
+    responses(
+        (status = 200, description = "The updated subscriber", body = Subscriber),
+        (status = 400, description = "Invalid update", body = ValidationProblem, content_type = "application/problem+json"),
+        (status = 404, description = "Unknown subscriber", body = Problem, content_type = "application/problem+json"),
+        (status = 409, description = "Another subscriber uses the new email address", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing, unknown or revoked API key", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The key lacks the scope", body = Problem, content_type = "application/problem+json")
+    )
+)]
+#[tracing::instrument(
+    name = "Updating a subscriber",
+    skip(api_key, body, pool, settings, domain_verifier, risky_email_lists)
+)]
+pub async fn patch_subscriber(
+    api_key: ApiKey,
+    subscriber_id: web::Path<Uuid>,
+    body: web::Json<SubscriberUpdate>,
+    pool: web::Data<PgPool>,
+    settings: web::Data<SubscriptionSettings>,
+    domain_verifier: web::Data<dyn DomainVerifier>,
+    risky_email_lists: web::Data<RiskyEmailLists>,
+) -> HttpResponse {
+    if let Err(response) = api_key.require(ApiScope::SubscribersWrite) {
+        return response;
+    }
+    let update = match validate_update(
+        body.into_inner(),
+        &settings,
+        domain_verifier.get_ref(),
+        &risky_email_lists,
+    )
+    .await
+    {
+        Ok(update) => update,
+        Err(problem) => return problem.error_response(),
+    };
+    match update_subscriber(&pool, *subscriber_id, &update, settings.fold_email_aliases).await {
+        Ok(UpdateOutcome::Updated) => subscriber_response(&pool, *subscriber_id).await,
+        Ok(UpdateOutcome::NotFound) => subscriber_not_found(),
+        Ok(UpdateOutcome::EmailTaken) => Problem::new(
+            StatusCode::CONFLICT,
+            "Another subscriber already uses this email address.",
+        )
+        .error_response(),
+        Err(e) => {
+            tracing::error!("Failed to execute query: {:?}", e);
+            Problem::internal_error().error_response()
+        }
+    }
+}
+
+/// Deletes the subscriber for good. Their delivery and tracking history is
+/// kept anonymously, issue statistics do not change.
+#[utoipa::path(
+    delete,
+    path = "/api/v1/subscribers/{subscriber_id}",
+    tag = "subscribers",
+    params(("subscriber_id" = Uuid, Path)),
+    security(("api_key" = ["subscribers:write"])),
+    responses(
+        (status = 204, description = "The subscriber is gone"),
+        (status = 404, description = "Unknown subscriber", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing, unknown or revoked API key", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The key lacks the scope", body = Problem, content_type = "application/problem+json")
+    )
+)]
+#[tracing::instrument(name = "Deleting a subscriber", skip(api_key, pool))]
+pub async fn delete_subscriber(
+    api_key: ApiKey,
+    subscriber_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+) -> HttpResponse {
+    if let Err(response) = api_key.require(ApiScope::SubscribersWrite) {
+        return response;
+    }
+    match delete_subscriber_by_id(&pool, *subscriber_id).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => subscriber_not_found(),
+        Err(e) => {
+            tracing::error!("Failed to execute query: {:?}", e);
+            Problem::internal_error().error_response()
+        }
+    }
+}
+
+async fn validate_update(
+    update: SubscriberUpdate,
+    settings: &SubscriptionSettings,
+    domain_verifier: &dyn DomainVerifier,
+    risky_email_lists: &RiskyEmailLists,
+) -> Result<ValidUpdate, ValidationProblem> {
+    let mut errors = Vec::new();
+    let name = match update.name.map(SubscriberName::parse).transpose() {
+        Ok(name) => name,
+        Err(e) => {
+            errors.push(FieldError::new("name", e.code(), e.to_string()));
+            None
+        }
+    };
+    let mut flagged_as = None;
+    let email = match update.email.map(SubscriberEmail::parse).transpose() {
+        Ok(Some(email)) => {
+            match check_email(&email, true, settings, domain_verifier, risky_email_lists).await {
+                Ok(flag) => {
+                    flagged_as = flag;
+                    Some(email)
+                }
+                Err(error) => {
+                    errors.push(error);
+                    None
+                }
+            }
+        }
+        Ok(None) => None,
+        Err(e) => {
+            errors.push(FieldError::new("email", e.code(), e.to_string()));
+            None
+        }
+    };
+    let status = match update
+        .status
+        .as_deref()
+        .map(SubscriberStatus::parse)
+        .transpose()
+    {
+        Ok(Some(SubscriberStatus::PendingConfirmation)) => {
+            errors.push(FieldError::new(
+                "status",
+                "invalid",
+                "Only the subscriber can confirm a subscription.",
+            ));
+            None
+        }
+        Ok(status) => status,
+        Err(e) => {
+            errors.push(FieldError::new("status", "invalid", e));
+            None
+        }
+    };
+    if !errors.is_empty() {
+        return Err(ValidationProblem::new(errors));
+    }
+    Ok(ValidUpdate {
+        name,
+        email,
+        flagged_as,
+        status,
+    })
+}
+
+async fn subscriber_response(pool: &PgPool, subscriber_id: Uuid) -> HttpResponse {
+    match get_subscriber_by_id(pool, subscriber_id).await {
+        Ok(Some(subscriber)) => HttpResponse::Ok().json(subscriber),
+        Ok(None) => subscriber_not_found(),
+        Err(_) => Problem::internal_error().error_response(),
+    }
+}
+
+fn subscriber_not_found() -> HttpResponse {
+    Problem::not_found("There is no subscriber with this id.").error_response()
+}
+
+async fn get_subscriber_by_id(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+) -> Result<Option<Subscriber>, sqlx::Error> {
+    sqlx::query_as!(
+        Subscriber,
+        r#"
+        SELECT
+            s.id, s.email, s.name, s.status, s.subscribed_at, s.flagged_as, s.attributes,
+            ARRAY(
+                SELECT t.tag FROM subscriber_tags t WHERE t.subscriber_id = s.id ORDER BY t.tag
+            ) AS "tags!"
+        FROM subscriptions s
+        WHERE s.id = $1
+        "#,
+        subscriber_id
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })
+}
+
+async fn update_subscriber(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+    update: &ValidUpdate,
+    fold_aliases: bool,
+) -> Result<UpdateOutcome, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    let current = sqlx::query!(
+        "SELECT email, status FROM subscriptions WHERE id = $1 FOR UPDATE",
+        subscriber_id
+    )
+    .fetch_optional(&mut transaction)
+    .await?;
+    let current = match current {
+        Some(current) => current,
+        None => return Ok(UpdateOutcome::NotFound),
+    };
+    if let Some(name) = &update.name {
+        sqlx::query!(
+            "UPDATE subscriptions SET name = $1 WHERE id = $2",
+            name.as_ref(),
+            subscriber_id
+        )
+        .execute(&mut transaction)
+        .await?;
+    }
+    if let Some(email) = &update.email {
+        if email.display() != current.email
+            && !change_email(
+                &mut transaction,
+                subscriber_id,
+                &current.email,
+                email,
+                update.flagged_as,
+                fold_aliases,
+            )
+            .await?
+        {
+            return Ok(UpdateOutcome::EmailTaken);
+        }
+    }
+    if let Some(status) = update.status {
+        change_status(&mut transaction, subscriber_id, &current.status, status).await?;
+    }
+    transaction.commit().await?;
+    Ok(UpdateOutcome::Updated)
+}
+
+/// Returns `false` if another subscriber already uses the address, the
+/// transaction cannot be used any further then.
+async fn change_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    previous_email: &str,
+    email: &SubscriberEmail,
+    flagged_as: Option<&str>,
+    fold_aliases: bool,
+) -> Result<bool, sqlx::Error> {
+    let updated = sqlx::query!(
+        r#"
+        UPDATE subscriptions SET email = $1, canonical_email = $2, flagged_as = $3
+        WHERE id = $4
+        "#,
+        email.display(),
+        email.canonical(fold_aliases),
+        flagged_as,
+        subscriber_id
+    )
+    .execute(&mut *transaction)
+    .await;
+    match updated {
+        Ok(_) => {}
+        Err(sqlx::Error::Database(e)) if e.code().as_deref() == Some(UNIQUE_VIOLATION) => {
+            return Ok(false)
+        }
+        Err(e) => return Err(e),
+    }
+    sqlx::query!(
+        r#"
+        INSERT INTO subscriber_email_history (id, subscriber_id, previous_email, new_email, changed_at)
+        VALUES ($1, $2, $3, $4, $5)
+        "#,
+        Uuid::new_v4(),
+        subscriber_id,
+        previous_email,
+        email.display(),
+        Utc::now()
+    )
+    .execute(&mut *transaction)
+    .await?;
+    Ok(true)
+}
+
+/// Confirming restores every list of an unsubscribed subscriber and the
+/// pending ones of anybody else, unsubscribing leaves every list.
+async fn change_status(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    current_status: &str,
+    status: SubscriberStatus,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE subscriptions SET status = $1 WHERE id = $2",
+        status.as_str(),
+        subscriber_id
+    )
+    .execute(&mut *transaction)
+    .await?;
+    let was_unsubscribed = current_status == SubscriberStatus::Unsubscribed.as_str();
+    match status {
+        SubscriberStatus::Confirmed => sqlx::query!(
+            r#"
+            UPDATE list_memberships SET status = 'confirmed'
+            WHERE subscriber_id = $1 AND (status = 'pending_confirmation' OR $2)
+            "#,
+            subscriber_id,
+            was_unsubscribed
+        ),
+        SubscriberStatus::Unsubscribed => sqlx::query!(
+            "UPDATE list_memberships SET status = 'unsubscribed' WHERE subscriber_id = $1",
+            subscriber_id
+        ),
+        SubscriberStatus::PendingConfirmation => return Ok(()),
+    }
+    .execute(&mut *transaction)
+    .await?;
+    Ok(())
+}
+
+pub async fn delete_subscriber_by_id(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+) -> Result<bool, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    let exists = sqlx::query!(
+        "SELECT id FROM subscriptions WHERE id = $1 FOR UPDATE",
+        subscriber_id
+    )
+    .fetch_optional(&mut transaction)
+    .await?
+    .is_some();
+    if exists {
+        delete_subscribers(&mut transaction, &[subscriber_id]).await?;
+    }
+    transaction.commit().await?;
+    Ok(exists)
+}
@@ -0,0 +1,172 @@
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::authentication::generate_api_key;
+use crate::domain::ApiScope;
+use crate::routes::{FieldError, Problem, ValidationProblem};
+
+#[derive(serde::Deserialize, Debug, utoipa::ToSchema)]
+pub struct ApiKeyData {
+    /// Who or what the key is for, e.g. `cms`.
+    name: String,
+    scopes: Vec<String>,
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct CreatedApiKey {
+    api_key_id: Uuid,
+    /// Shown only once, store it right away.
+    key: String,
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct ApiKeySummary {
+    api_key_id: Uuid,
+    name: String,
+    key_prefix: String,
+    scopes: Vec<String>,
+    created_at: DateTime<Utc>,
+    last_used_at: Option<DateTime<Utc>>,
+    revoked_at: Option<DateTime<Utc>>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/api_keys",
+    tag = "api keys",
+    security(("session" = [])),
+    request_body = ApiKeyData,
+    responses(
+        (status = 200, description = "The key was created", body = CreatedApiKey),
+        (status = 400, description = "Missing name or unknown scope", body = ValidationProblem, content_type = "application/problem+json"),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The role lacks the permission", body = Problem, content_type = "application/problem+json")
+    )
+)]
+#[tracing::instrument(name = "Creating an API key", skip(body, pool), fields(api_key_name = %body.name))]
+pub async fn create_api_key(body: web::Json<ApiKeyData>, pool: web::Data<PgPool>) -> HttpResponse {
+    let body = body.into_inner();
+    if body.name.trim().is_empty() {
+        return ValidationProblem::new(vec![FieldError::new(
+            "name",
+            "missing",
+            "An API key needs a name.",
+        )])
+        .error_response();
+    }
+    let scopes = match body
+        .scopes
+        .iter()
+        .map(|s| ApiScope::parse(s).map(|scope| scope.as_str().to_owned()))
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(scopes) if scopes.is_empty() => {
+            return ValidationProblem::new(vec![FieldError::new(
+                "scopes",
+                "missing",
+                "An API key needs at least one scope.",
+            )])
+            .error_response()
+        }
+        Ok(scopes) => scopes,
+        Err(err) => {
+            return ValidationProblem::new(vec![FieldError::new("scopes", "invalid", err)])
+                .error_response()
+        }
+    };
+    let generated = generate_api_key();
+    let api_key_id = Uuid::new_v4();
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO api_keys (api_key_id, name, key_prefix, key_hash, scopes, created_at)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        "#,
+        api_key_id,
+        body.name,
+        generated.key_prefix,
+        generated.key_hash,
+        &scopes[..],
+        Utc::now()
+    )
+    .execute(pool.get_ref())
+    .await;
+    match result {
+        Ok(_) => HttpResponse::Ok().json(CreatedApiKey {
+            api_key_id,
+            key: generated.key,
+        }),
+        Err(e) => {
+            tracing::error!("Failed to execute query: {:?}", e);
+            Problem::internal_error().error_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/api_keys",
+    tag = "api keys",
+    security(("session" = [])),
+    responses(
+        (status = 200, description = "Every key, newest first", body = Vec<ApiKeySummary>),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The role lacks the permission", body = Problem, content_type = "application/problem+json")
+    )
+)]
+#[tracing::instrument(name = "Listing API keys", skip(pool))]
+pub async fn get_api_keys(pool: web::Data<PgPool>) -> HttpResponse {
+    let keys = sqlx::query_as!(
+        ApiKeySummary,
+        r#"
+        SELECT api_key_id, name, key_prefix, scopes, created_at, last_used_at, revoked_at
+        FROM api_keys
+        ORDER BY created_at DESC
+        "#
+    )
+    .fetch_all(pool.get_ref())
+    .await;
+    match keys {
+        Ok(keys) => HttpResponse::Ok().json(keys),
+        Err(e) => {
+            tracing::error!("Failed to execute query: {:?}", e);
+            Problem::internal_error().error_response()
+        }
+    }
+}
+
+/// Revoking is permanent, revoking a revoked key again is a no-op.
+#[utoipa::path(
+    delete,
+    path = "/admin/api_keys/{api_key_id}",
+    tag = "api keys",
+    security(("session" = [])),
+    params(("api_key_id" = Uuid, Path)),
+    responses(
+        (status = 200, description = "The key no longer authenticates"),
+        (status = 404, description = "Unknown key", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The role lacks the permission", body = Problem, content_type = "application/problem+json")
+    )
+)]
+#[tracing::instrument(name = "Revoking an API key", skip(pool))]
+pub async fn revoke_api_key(api_key_id: web::Path<Uuid>, pool: web::Data<PgPool>) -> HttpResponse {
+    let result = sqlx::query!(
+        "UPDATE api_keys SET revoked_at = COALESCE(revoked_at, $2) WHERE api_key_id = $1",
+        *api_key_id,
+        Utc::now()
+    )
+    .execute(pool.get_ref())
+    .await;
+    match result {
+        Ok(done) if done.rows_affected() == 0 => {
+            Problem::not_found("There is no API key with this id.").error_response()
+        }
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(e) => {
+            tracing::error!("Failed to execute query: {:?}", e);
+            Problem::internal_error().error_response()
+        }
+    }
+}
@@ -0,0 +1,301 @@
+use actix_web::http::header::ContentType;
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::Utc;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::configuration::{RiskyEmailPolicy, SubscriptionSettings};
+use crate::domain::{RiskyEmailLists, SubscriberEmail};
+use crate::domain_verification::DomainVerifier;
+use crate::email_client::EmailClient;
+use crate::routes::{
+    csrf_rejected, csrf_token_matches, get_subscriber_id_from_preference_token, html_page,
+    verify_domain,
+};
+use crate::startup::ApplicationBaseUrl;
+use crate::tokens::generate_token;
+
+#[derive(serde::Deserialize, Debug)]
+pub struct EmailChangeForm {
+    email: String,
+    #[serde(default)]
+    csrf_token: String,
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct ConfirmEmailChangeParameters {
+    token: String,
+}
+
+#[tracing::instrument(
+    name = "Requesting an email address change",
+    skip(req, token, form, pool, email_client, base_url, settings, domain_verifier, risky_email_lists),
+    fields(new_email = %form.email)
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn request_email_change(
+    req: HttpRequest,
+    token: web::Path<String>,
+    form: web::Form<EmailChangeForm>,
+    pool: web::Data<PgPool>,
+    email_client: web::Data<EmailClient>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    settings: web::Data<SubscriptionSettings>,
+    domain_verifier: web::Data<dyn DomainVerifier>,
+    risky_email_lists: web::Data<RiskyEmailLists>,
+) -> HttpResponse {
+    if !csrf_token_matches(&req, &form.csrf_token) {
+        return csrf_rejected();
+    }
+    let subscriber_id = match get_subscriber_id_from_preference_token(&pool, &token).await {
+        Ok(Some(subscriber_id)) => subscriber_id,
+        Ok(None) => return HttpResponse::NotFound().finish(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    let new_email = match SubscriberEmail::parse(form.into_inner().email) {
+        Ok(email) => email,
+        Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+    };
+    let flagged_as = match new_email.check_risk(&risky_email_lists) {
+        Ok(()) => None,
+        Err(risk) => match settings.risky_email_policy(&risk) {
+            RiskyEmailPolicy::Reject => return HttpResponse::BadRequest().body(risk.to_string()),
+            RiskyEmailPolicy::Flag => {
+                tracing::warn!(flagged_as = risk.as_str(), "Flagging a risky email change");
+                Some(risk.as_str())
+            }
+            RiskyEmailPolicy::Allow => None,
+        },
+    };
+    if let Err(err) = verify_domain(domain_verifier.get_ref(), &new_email).await {
+        return HttpResponse::BadRequest().body(err);
+    }
+    let canonical_email = new_email.canonical(settings.fold_email_aliases);
+    match email_in_use(&pool, &canonical_email, subscriber_id).await {
+        Ok(false) => {}
+        Ok(true) => {
+            return HttpResponse::Conflict().body("This email address is already subscribed.")
+        }
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    }
+    let email_change_token = generate_token();
+    if store_email_change_request(
+        &pool,
+        subscriber_id,
+        &new_email,
+        flagged_as,
+        &email_change_token,
+        settings.confirmation_token_ttl(),
+    )
+    .await
+    .is_err()
+    {
+        return HttpResponse::InternalServerError().finish();
+    }
+    if send_email_change_confirmation(&email_client, &new_email, &base_url.0, &email_change_token)
+        .await
+        .is_err()
+    {
+        return HttpResponse::InternalServerError().finish();
+    }
+    HttpResponse::Ok().content_type(ContentType::html()).body(html_page(
+        "Check your inbox",
+        "<p>We sent a confirmation link to your new address. Your email will change once you follow it.</p>",
+    ))
+}
+
+#[tracing::instrument(
+    name = "Confirming an email address change",
+    skip(parameters, pool, settings)
+)]
+pub async fn confirm_email_change(
+    parameters: web::Query<ConfirmEmailChangeParameters>,
+    pool: web::Data<PgPool>,
+    settings: web::Data<SubscriptionSettings>,
+) -> HttpResponse {
+    match apply_email_change(&pool, &parameters.token, settings.fold_email_aliases).await {
+        Ok(EmailChangeOutcome::Changed) => HttpResponse::Ok()
+            .content_type(ContentType::html())
+            .body(html_page(
+                "Email address updated",
+                "<p>Future issues will be sent to your new address.</p>",
+            )),
+        Ok(EmailChangeOutcome::UnknownToken) => HttpResponse::NotFound().finish(),
+        Ok(EmailChangeOutcome::Expired) => HttpResponse::Gone()
+            .content_type(ContentType::html())
+            .body(html_page(
+                "Link expired",
+                "<p>This confirmation link has expired. Request the change again from your preferences.</p>",
+            )),
+        Ok(EmailChangeOutcome::AddressTaken) => {
+            HttpResponse::Conflict().body("This email address is already subscribed.")
+        }
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+enum EmailChangeOutcome {
+    Changed,
+    UnknownToken,
+    Expired,
+    AddressTaken,
+}
+
+/// Whether another subscriber already uses the address.
+async fn email_in_use(
+    pool: &PgPool,
+    canonical_email: &str,
+    subscriber_id: Uuid,
+) -> Result<bool, sqlx::Error> {
+    let row = sqlx::query!(
+        "SELECT id FROM subscriptions WHERE canonical_email = $1 AND id <> $2",
+        canonical_email,
+        subscriber_id
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(row.is_some())
+}
+
+async fn store_email_change_request(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+    new_email: &SubscriberEmail,
+    flagged_as: Option<&str>,
+    email_change_token: &str,
+    token_ttl: chrono::Duration,
+) -> Result<(), sqlx::Error> {
+    let now = Utc::now();
+    sqlx::query!(
+        r#"
+        INSERT INTO email_change_requests (
+            email_change_token, subscriber_id, new_email, flagged_as, requested_at, expires_at
+        )
+        VALUES ($1, $2, $3, $4, $5, $6)
+        "#,
+        email_change_token,
+        subscriber_id,
+        new_email.display(),
+        flagged_as,
+        now,
+        now + token_ttl
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+#[tracing::instrument(
+    name = "Sending an email change confirmation",
+    skip(email_client, new_email, base_url, email_change_token)
+)]
+async fn send_email_change_confirmation(
+    email_client: &EmailClient,
+    new_email: &SubscriberEmail,
+    base_url: &str,
+    email_change_token: &str,
+) -> Result<(), reqwest::Error> {
+    let confirmation_link = format!(
+        "{}/preferences/email/confirm?token={}",
+        base_url, email_change_token
+    );
+    let html_body = format!(
+        "Please confirm your new email address.<br />Click <a href=\"{}\">here</a> to confirm.",
+        confirmation_link
+    );
+    let text_body = format!(
+        "Please confirm your new email address.\nVisit {} to confirm.",
+        confirmation_link
+    );
+    email_client
+        .send_email(
+            new_email,
+            "Confirm your new email address",
+            &html_body,
+            &text_body,
+        )
+        .await
+        .map_err(|e| {
+            tracing::error!(error.cause_chain = ?e, "Failed to send email change confirmation");
+            e
+        })
+}
+
+/// Swaps the address in a single transaction, keeping the previous one in
+/// `subscriber_email_history`, and discards any other pending request.
+async fn apply_email_change(
+    pool: &PgPool,
+    email_change_token: &str,
+    fold_aliases: bool,
+) -> Result<EmailChangeOutcome, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    let request = sqlx::query!(
+        r#"
+        SELECT r.subscriber_id, r.new_email, r.flagged_as, r.expires_at, s.email AS previous_email
+        FROM email_change_requests r
+        JOIN subscriptions s ON s.id = r.subscriber_id
+        WHERE r.email_change_token = $1
+        FOR UPDATE
+        "#,
+        email_change_token
+    )
+    .fetch_optional(&mut transaction)
+    .await?;
+    let request = match request {
+        Some(request) => request,
+        None => return Ok(EmailChangeOutcome::UnknownToken),
+    };
+    if request.expires_at < Utc::now() {
+        return Ok(EmailChangeOutcome::Expired);
+    }
+    let canonical_email = SubscriberEmail::parse(request.new_email.clone())
+        .map_err(|e| sqlx::Error::Decode(e.into()))?
+        .canonical(fold_aliases);
+    let updated = sqlx::query!(
+        r#"
+        UPDATE subscriptions SET email = $1, canonical_email = $2, flagged_as = $4
+        WHERE id = $3
+            AND NOT EXISTS (
+                SELECT 1 FROM subscriptions WHERE canonical_email = $2 AND id <> $3
+            )
+        "#,
+        request.new_email,
+        canonical_email,
+        request.subscriber_id,
+        request.flagged_as
+    )
+    .execute(&mut transaction)
+    .await?;
+    if updated.rows_affected() == 0 {
+        return Ok(EmailChangeOutcome::AddressTaken);
+    }
+    sqlx::query!(
+        r#"
+        INSERT INTO subscriber_email_history (id, subscriber_id, previous_email, new_email, changed_at)
+        VALUES ($1, $2, $3, $4, $5)
+        "#,
+        Uuid::new_v4(),
+        request.subscriber_id,
+        request.previous_email,
+        request.new_email,
+        Utc::now()
+    )
+    .execute(&mut transaction)
+    .await?;
+    sqlx::query!(
+        "DELETE FROM email_change_requests WHERE subscriber_id = $1",
+        request.subscriber_id
+    )
+    .execute(&mut transaction)
+    .await?;
+    transaction.commit().await?;
+    Ok(EmailChangeOutcome::Changed)
+}
@@ -0,0 +1,16 @@
+/// Wraps `body` in the minimal HTML document used by subscriber-facing pages.
+pub fn html_page(title: &str, body: &str) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>{title}</title>
+</head>
+<body>
+    <h1>{title}</h1>
+    {body}
+</body>
+</html>"#
+    )
+}
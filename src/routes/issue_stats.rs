@@ -0,0 +1,229 @@
+use actix_web::{web, HttpResponse};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::routes::Problem;
+
+#[derive(serde::Deserialize, Debug, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct StatsQuery {
+    #[serde(default)]
+    format: StatsFormat,
+}
+
+#[derive(serde::Deserialize, Debug, Default, PartialEq, utoipa::ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum StatsFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+#[derive(serde::Serialize, Debug, utoipa::ToSchema)]
+pub struct IssueStats {
+    issue_id: Uuid,
+    /// Every delivery attempt, whether the email provider took it or not.
+    sent: i64,
+    /// Accepted by the email provider and not reported as bounced since.
+    delivered: i64,
+    /// Always 0 for now, bounce reports of the email provider are not
+    /// ingested yet.
+    bounced: i64,
+    opened: UniqueAndTotal,
+    clicked: UniqueAndTotal,
+    unsubscribed: i64,
+    top_links: Vec<LinkStats>,
+}
+
+#[derive(serde::Serialize, Debug, utoipa::ToSchema)]
+pub struct UniqueAndTotal {
+    unique: i64,
+    total: i64,
+}
+
+#[derive(serde::Serialize, Debug, utoipa::ToSchema)]
+pub struct LinkStats {
+    url: String,
+    unique: i64,
+    total: i64,
+}
+
+const TOP_LINKS_LIMIT: i64 = 10;
+
+#[utoipa::path(
+    get,
+    path = "/admin/issues/{issue_id}/stats",
+    tag = "issues",
+    security(("session" = [])),
+    params(("issue_id" = Uuid, Path), StatsQuery),
+    responses(
+        (status = 200, description = "Delivery, open, click and unsubscribe counts", content(
+            (IssueStats = "application/json"),
+            (String = "text/csv")
+        )),
+        (status = 404, description = "Unknown issue"),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The role lacks the permission", body = Problem, content_type = "application/problem+json")
+    )
+)]
+#[tracing::instrument(name = "Computing issue analytics", skip(pool))]
+pub async fn issue_stats(
+    issue_id: web::Path<Uuid>,
+    query: web::Query<StatsQuery>,
+    pool: web::Data<PgPool>,
+) -> HttpResponse {
+    let stats = match get_issue_stats(&pool, *issue_id).await {
+        Ok(Some(stats)) => stats,
+        Ok(None) => return HttpResponse::NotFound().finish(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    match query.format {
+        StatsFormat::Json => HttpResponse::Ok().json(stats),
+        StatsFormat::Csv => HttpResponse::Ok()
+            .content_type("text/csv; charset=utf-8")
+            .body(stats.to_csv()),
+    }
+}
+
+impl IssueStats {
+    /// One `metric,url,value` row per counter, followed by a `link_clicks`
+    /// and `link_unique_clicks` row for each top link.
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("metric,url,value\n");
+        let counters = [
+            ("sent", self.sent),
+            ("delivered", self.delivered),
+            ("bounced", self.bounced),
+            ("opened_unique", self.opened.unique),
+            ("opened_total", self.opened.total),
+            ("clicked_unique", self.clicked.unique),
+            ("clicked_total", self.clicked.total),
+            ("unsubscribed", self.unsubscribed),
+        ];
+        for (metric, value) in counters {
+            csv.push_str(&format!("{},,{}\n", metric, value));
+        }
+        for link in &self.top_links {
+            let url = escape_csv_field(&link.url);
+            csv.push_str(&format!("link_clicks,{},{}\n", url, link.total));
+            csv.push_str(&format!("link_unique_clicks,{},{}\n", url, link.unique));
+        }
+        csv
+    }
+}
+
+fn escape_csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+async fn get_issue_stats(pool: &PgPool, issue_id: Uuid) -> Result<Option<IssueStats>, sqlx::Error> {
+    let issue = sqlx::query!(
+        "SELECT newsletter_issue_id FROM newsletter_issues WHERE newsletter_issue_id = $1",
+        issue_id
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(log_query_error)?;
+    if issue.is_none() {
+        return Ok(None);
+    }
+
+    let deliveries = sqlx::query!(
+        r#"
+        SELECT
+            COUNT(*) AS "sent!",
+            COUNT(*) FILTER (WHERE status = 'sent') AS "delivered!",
+            COUNT(*) FILTER (WHERE status = 'bounced') AS "bounced!"
+        FROM issue_deliveries
+        WHERE newsletter_issue_id = $1
+        "#,
+        issue_id
+    )
+    .fetch_one(pool)
+    .await
+    .map_err(log_query_error)?;
+
+    let events = sqlx::query!(
+        r#"
+        SELECT
+            COUNT(DISTINCT subscriber_id) FILTER (WHERE kind = 'open') AS "unique_opens!",
+            COUNT(*) FILTER (WHERE kind = 'open') AS "total_opens!",
+            COUNT(DISTINCT subscriber_id) FILTER (WHERE kind = 'click') AS "unique_clicks!",
+            COUNT(*) FILTER (WHERE kind = 'click') AS "total_clicks!",
+            COUNT(DISTINCT subscriber_id) FILTER (WHERE kind = 'unsubscribe') AS "unsubscribed!"
+        FROM tracking_events
+        WHERE newsletter_issue_id = $1
+        "#,
+        issue_id
+    )
+    .fetch_one(pool)
+    .await
+    .map_err(log_query_error)?;
+
+    let top_links = sqlx::query_as!(
+        LinkStats,
+        r#"
+        SELECT
+            url AS "url!",
+            COUNT(DISTINCT subscriber_id) AS "unique!",
+            COUNT(*) AS "total!"
+        FROM tracking_events
+        WHERE newsletter_issue_id = $1 AND kind = 'click' AND url IS NOT NULL
+        GROUP BY url
+        ORDER BY 3 DESC, 1
+        LIMIT $2
+        "#,
+        issue_id,
+        TOP_LINKS_LIMIT
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(log_query_error)?;
+
+    Ok(Some(IssueStats {
+        issue_id,
+        sent: deliveries.sent,
+        delivered: deliveries.delivered,
+        bounced: deliveries.bounced,
+        opened: UniqueAndTotal {
+            unique: events.unique_opens,
+            total: events.total_opens,
+        },
+        clicked: UniqueAndTotal {
+            unique: events.unique_clicks,
+            total: events.total_clicks,
+        },
+        unsubscribed: events.unsubscribed,
+        top_links,
+    }))
+}
+
+fn log_query_error(e: sqlx::Error) -> sqlx::Error {
+    tracing::error!("Failed to execute query: {:?}", e);
+    e
+}
+
+#[cfg(test)]
+mod tests {
+    use super::escape_csv_field;
+
+    #[test]
+    fn plain_fields_are_not_quoted() {
+        assert_eq!(
+            escape_csv_field("https://example.com/a"),
+            "https://example.com/a"
+        );
+    }
+
+    #[test]
+    fn fields_with_commas_and_quotes_are_quoted() {
+        assert_eq!(
+            escape_csv_field(r#"https://example.com/?q=a,"b""#),
+            r#""https://example.com/?q=a,""b""""#
+        );
+    }
+}
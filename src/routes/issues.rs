@@ -0,0 +1,482 @@
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse};
+use chrono::Utc;
+use sqlx::{PgPool, Postgres, QueryBuilder, Transaction};
+use uuid::Uuid;
+
+use crate::authentication::AdminUser;
+use crate::domain::{
+    ListSlug, Permission, SubscriberAttributes, SubscriberEmail, SubscriberEmailError,
+};
+use crate::email_client::EmailClient;
+use crate::email_template::{render_html, render_text, TemplateContext};
+use crate::routes::{
+    get_list_id, get_or_create_preference_token, get_segment, get_segment_by_id, FieldError,
+    Problem, Segment, ValidationProblem,
+};
+use crate::startup::ApplicationBaseUrl;
+use crate::tracking::render_tracked_html;
+
+/// Postgres error code of a `NOWAIT` lock that is held elsewhere.
+const LOCK_NOT_AVAILABLE: &str = "55P03";
+
+#[derive(serde::Deserialize, Debug, utoipa::ToSchema)]
+pub struct IssueData {
+    title: String,
+    content: Content,
+    /// Slug of the list to send to, the default list when omitted.
+    list: Option<String>,
+    /// Name of a saved segment narrowing down the recipients within the list.
+    segment: Option<String>,
+    /// Privacy-sensitive issues can opt out of open and click tracking.
+    #[serde(default = "default_tracking")]
+    tracking: bool,
+    /// Save without sending, see `POST /admin/issues/{issue_id}/publish`.
+    #[serde(default)]
+    draft: bool,
+}
+
+#[derive(serde::Deserialize, Debug, utoipa::ToSchema)]
+pub struct Content {
+    html: String,
+    text: String,
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+pub struct PublishedIssue {
+    issue_id: Uuid,
+}
+
+fn default_tracking() -> bool {
+    true
+}
+
+struct StoredIssue {
+    issue_id: Uuid,
+    list_id: Uuid,
+    segment_id: Option<Uuid>,
+    title: String,
+    text_content: String,
+    html_content: String,
+    tracking_enabled: bool,
+}
+
+struct ConfirmedSubscriber {
+    id: Uuid,
+    email: SubscriberEmail,
+    name: String,
+    attributes: SubscriberAttributes,
+}
+
+/// Issues are sent right away unless saved as a draft, only owners may send.
+/// A failed delivery leaves the issue unpublished for a retry through
+/// `POST /admin/issues/{issue_id}/publish`.
+#[utoipa::path(
+    post,
+    path = "/admin/issues",
+    tag = "issues",
+    security(("session" = [])),
+    request_body = IssueData,
+    responses(
+        (status = 200, description = "The issue was sent or saved as a draft", body = PublishedIssue),
+        (status = 400, description = "Unknown list or segment", body = ValidationProblem, content_type = "application/problem+json"),
+        (status = 500, description = "Some emails failed, the issue stays unpublished", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The role lacks the permission", body = Problem, content_type = "application/problem+json")
+    )
+)]
+#[tracing::instrument(
+    name = "Publishing a newsletter issue",
+    skip(user, body, pool, email_client, base_url),
+    fields(issue_title = %body.title, draft = body.draft)
+)]
+pub async fn publish_issue(
+    user: AdminUser,
+    body: web::Json<IssueData>,
+    pool: web::Data<PgPool>,
+    email_client: web::Data<EmailClient>,
+    base_url: web::Data<ApplicationBaseUrl>,
+) -> HttpResponse {
+    // `AdminAccess` checked `Edit`, which is all a draft needs.
+    if !body.draft {
+        if let Err(response) = user.require(Permission::Publish) {
+            return response;
+        }
+    }
+    create_issue(body.into_inner(), &pool, &email_client, &base_url).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/issues/{issue_id}/publish",
+    tag = "issues",
+    security(("session" = [])),
+    params(("issue_id" = Uuid, Path)),
+    responses(
+        (status = 200, description = "The draft or the rest of a failed delivery was sent", body = PublishedIssue),
+        (status = 404, description = "Unknown issue", body = Problem, content_type = "application/problem+json"),
+        (status = 409, description = "The issue was already published or is being sent", body = Problem, content_type = "application/problem+json"),
+        (status = 500, description = "Some emails failed, the issue stays unpublished", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The role lacks the permission", body = Problem, content_type = "application/problem+json")
+    )
+)]
+#[tracing::instrument(name = "Publishing a draft", skip(pool, email_client, base_url))]
+pub async fn publish_draft(
+    issue_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+    email_client: web::Data<EmailClient>,
+    base_url: web::Data<ApplicationBaseUrl>,
+) -> HttpResponse {
+    send_issue(&pool, *issue_id, &email_client, &base_url).await
+}
+
+/// Stores the issue and, unless it is a draft, sends it.
+pub async fn create_issue(
+    body: IssueData,
+    pool: &PgPool,
+    email_client: &EmailClient,
+    base_url: &ApplicationBaseUrl,
+) -> HttpResponse {
+    let list = match body.list.clone().map(ListSlug::parse).transpose() {
+        Ok(list) => list.unwrap_or_default(),
+        Err(err) => {
+            return ValidationProblem::new(vec![FieldError::new("list", "invalid", err)])
+                .error_response()
+        }
+    };
+    let list_id = match get_list_id(pool, &list).await {
+        Ok(Some(list_id)) => list_id,
+        Ok(None) => {
+            return ValidationProblem::new(vec![FieldError::new(
+                "list",
+                "unknown",
+                format!("{} is not a known list.", list.as_ref()),
+            )])
+            .error_response()
+        }
+        Err(_) => return Problem::internal_error().error_response(),
+    };
+    let segment_id = match &body.segment {
+        Some(name) => match get_segment(pool, name).await {
+            Ok(Some(segment)) => Some(segment.segment_id),
+            Ok(None) => {
+                return ValidationProblem::new(vec![FieldError::new(
+                    "segment",
+                    "unknown",
+                    format!("{} is not a known segment.", name),
+                )])
+                .error_response()
+            }
+            Err(_) => return Problem::internal_error().error_response(),
+        },
+        None => None,
+    };
+    let issue_id = match insert_issue(pool, &body, list_id, segment_id).await {
+        Ok(issue_id) => issue_id,
+        Err(_) => return Problem::internal_error().error_response(),
+    };
+    if body.draft {
+        return HttpResponse::Ok().json(PublishedIssue { issue_id });
+    }
+    send_issue(pool, issue_id, email_client, base_url).await
+}
+
+/// Sends an unpublished issue to the subscribers it has not reached yet and
+/// flags it as published once every email went out. After a failure the
+/// issue stays unpublished, so `POST /admin/issues/{issue_id}/publish` picks
+/// the delivery up where it stopped.
+async fn send_issue(
+    pool: &PgPool,
+    issue_id: Uuid,
+    email_client: &EmailClient,
+    base_url: &ApplicationBaseUrl,
+) -> HttpResponse {
+    let mut transaction = match pool.begin().await {
+        Ok(transaction) => transaction,
+        Err(_) => return Problem::internal_error().error_response(),
+    };
+    let issue = match lock_unpublished(&mut transaction, issue_id).await {
+        Ok(UnpublishedIssue::Locked(issue)) => issue,
+        Ok(UnpublishedIssue::AlreadyPublished) => {
+            return Problem::new(StatusCode::CONFLICT, "This issue was already published.")
+                .error_response()
+        }
+        Ok(UnpublishedIssue::BeingSent) => {
+            return Problem::new(StatusCode::CONFLICT, "This issue is being sent right now.")
+                .error_response()
+        }
+        Ok(UnpublishedIssue::UnknownIssue) => {
+            return Problem::not_found("There is no issue with this id.").error_response()
+        }
+        Err(_) => return Problem::internal_error().error_response(),
+    };
+    let segment = match issue.segment_id {
+        Some(segment_id) => match get_segment_by_id(pool, segment_id).await {
+            Ok(segment) => segment,
+            Err(_) => return Problem::internal_error().error_response(),
+        },
+        None => None,
+    };
+    let failed = match deliver_issue(&issue, segment.as_ref(), pool, email_client, base_url).await {
+        Ok(failed) => failed,
+        Err(_) => return Problem::internal_error().error_response(),
+    };
+    if failed > 0 {
+        return Problem::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!(
+                "Issue {} could not be sent to {} subscribers, publish it again to retry.",
+                issue_id, failed
+            ),
+        )
+        .error_response();
+    }
+    match mark_published(transaction, issue_id).await {
+        Ok(()) => HttpResponse::Ok().json(PublishedIssue { issue_id }),
+        Err(_) => Problem::internal_error().error_response(),
+    }
+}
+
+/// Sends the issue to its pending recipients, returning how many sends
+/// failed.
+async fn deliver_issue(
+    issue: &StoredIssue,
+    segment: Option<&Segment>,
+    pool: &PgPool,
+    email_client: &EmailClient,
+    base_url: &ApplicationBaseUrl,
+) -> Result<usize, sqlx::Error> {
+    let issue_id = issue.issue_id;
+    let subscribers = get_pending_recipients(pool, issue, segment).await?;
+    let mut failed = 0;
+    for subscriber in subscribers {
+        let subscriber = match subscriber {
+            Ok(subscriber) => subscriber,
+            Err(error) => {
+                tracing::warn!(error = %error, "Skipping a confirmed subscriber with invalid stored contact details");
+                continue;
+            }
+        };
+        let token = get_or_create_preference_token(pool, subscriber.id).await?;
+        let preferences_url = format!("{}/preferences/{}?issue={}", base_url.0, token, issue_id);
+        let context = TemplateContext {
+            name: &subscriber.name,
+            email: subscriber.email.as_ref(),
+            preferences_url: &preferences_url,
+            attributes: &subscriber.attributes,
+        };
+        let html = render_html(&issue.html_content, &context);
+        let text = render_text(&issue.text_content, &context);
+        let html = if issue.tracking_enabled {
+            render_tracked_html(pool, &base_url.0, issue_id, subscriber.id, &html).await?
+        } else {
+            html
+        };
+        let status = match email_client
+            .send_email(&subscriber.email, &issue.title, &html, &text)
+            .await
+        {
+            Ok(_) => "sent",
+            Err(e) => {
+                tracing::error!(error.cause_chain = ?e, "Failed to send issue to {}", subscriber.email.as_ref());
+                failed += 1;
+                "failed"
+            }
+        };
+        record_delivery(pool, issue_id, subscriber.id, status).await?;
+    }
+    Ok(failed)
+}
+
+enum UnpublishedIssue {
+    Locked(StoredIssue),
+    AlreadyPublished,
+    /// Another request holds the lock and is sending the issue.
+    BeingSent,
+    UnknownIssue,
+}
+
+/// Locks the row of an unpublished issue until the transaction ends, so
+/// concurrent requests cannot send it twice. `NO KEY UPDATE` still lets the
+/// delivery insert rows referencing the issue.
+async fn lock_unpublished(
+    transaction: &mut Transaction<'_, Postgres>,
+    issue_id: Uuid,
+) -> Result<UnpublishedIssue, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"
+        SELECT
+            newsletter_issue_id AS issue_id, list_id, segment_id, title, text_content,
+            html_content, tracking_enabled, published_at
+        FROM newsletter_issues
+        WHERE newsletter_issue_id = $1
+        FOR NO KEY UPDATE NOWAIT
+        "#,
+        issue_id
+    )
+    .fetch_optional(&mut *transaction)
+    .await;
+    let row = match row {
+        Ok(row) => row,
+        Err(sqlx::Error::Database(e)) if e.code().as_deref() == Some(LOCK_NOT_AVAILABLE) => {
+            return Ok(UnpublishedIssue::BeingSent)
+        }
+        Err(e) => {
+            tracing::error!("Failed to execute query: {:?}", e);
+            return Err(e);
+        }
+    };
+    Ok(match row {
+        None => UnpublishedIssue::UnknownIssue,
+        Some(row) if row.published_at.is_some() => UnpublishedIssue::AlreadyPublished,
+        Some(row) => UnpublishedIssue::Locked(StoredIssue {
+            issue_id: row.issue_id,
+            list_id: row.list_id,
+            segment_id: row.segment_id,
+            title: row.title,
+            text_content: row.text_content,
+            html_content: row.html_content,
+            tracking_enabled: row.tracking_enabled,
+        }),
+    })
+}
+
+async fn mark_published(
+    mut transaction: Transaction<'_, Postgres>,
+    issue_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE newsletter_issues SET published_at = $2 WHERE newsletter_issue_id = $1",
+        issue_id,
+        Utc::now()
+    )
+    .execute(&mut transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    transaction.commit().await
+}
+
+async fn insert_issue(
+    pool: &PgPool,
+    issue: &IssueData,
+    list_id: Uuid,
+    segment_id: Option<Uuid>,
+) -> Result<Uuid, sqlx::Error> {
+    let issue_id = Uuid::new_v4();
+    sqlx::query!(
+        r#"
+        INSERT INTO newsletter_issues (
+            newsletter_issue_id, list_id, segment_id, title, text_content, html_content,
+            tracking_enabled
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        "#,
+        issue_id,
+        list_id,
+        segment_id,
+        issue.title,
+        issue.content.text,
+        issue.content.html,
+        issue.tracking
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(issue_id)
+}
+
+/// A retried delivery replaces the outcome of the previous attempt.
+async fn record_delivery(
+    pool: &PgPool,
+    issue_id: Uuid,
+    subscriber_id: Uuid,
+    status: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO issue_deliveries (newsletter_issue_id, subscriber_id, status, sent_at)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (newsletter_issue_id, subscriber_id) DO UPDATE
+            SET status = EXCLUDED.status, sent_at = EXCLUDED.sent_at
+        "#,
+        issue_id,
+        subscriber_id,
+        status,
+        Utc::now()
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+/// Confirmed subscribers of the issue's list the issue has not been sent to
+/// yet, so a delivery cut short can be picked up where it stopped. Weekly
+/// and monthly readers are skipped while their last issue is more recent
+/// than that, they get at most one issue per week or month.
+async fn get_pending_recipients(
+    pool: &PgPool,
+    issue: &StoredIssue,
+    segment: Option<&Segment>,
+) -> Result<Vec<Result<ConfirmedSubscriber, SubscriberEmailError>>, sqlx::Error> {
+    let mut query = QueryBuilder::<Postgres>::new(
+        r#"
+        SELECT s.id, s.email, s.name, s.attributes
+        FROM subscriptions s
+        JOIN list_memberships m ON m.subscriber_id = s.id
+        WHERE m.status = 'confirmed' AND s.status = 'confirmed'
+            AND NOT EXISTS (
+                SELECT 1 FROM issue_deliveries d
+                WHERE d.subscriber_id = s.id AND d.status = 'sent'
+                    AND d.newsletter_issue_id = "#,
+    );
+    query.push_bind(issue.issue_id);
+    query.push(
+        r#"
+            )
+            AND NOT EXISTS (
+                SELECT 1 FROM issue_deliveries d
+                WHERE d.subscriber_id = s.id AND d.status = 'sent'
+                    AND d.sent_at > now() - CASE s.delivery_frequency
+                        WHEN 'weekly' THEN interval '1 week'
+                        WHEN 'monthly' THEN interval '1 month'
+                    END
+            )
+            AND m.list_id = "#,
+    );
+    query.push_bind(issue.list_id);
+    if let Some(segment) = segment {
+        query.push(" AND ");
+        segment.filter.push_sql(&mut query);
+    }
+    let rows = query
+        .build_query_as::<(Uuid, String, String, serde_json::Value)>()
+        .fetch_all(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+    let subscribers = rows
+        .into_iter()
+        .map(|(id, email, name, attributes)| {
+            SubscriberEmail::parse(email).map(|email| ConfirmedSubscriber {
+                id,
+                email,
+                name,
+                attributes: SubscriberAttributes::from_stored(attributes),
+            })
+        })
+        .collect();
+    Ok(subscribers)
+}
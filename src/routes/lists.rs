@@ -0,0 +1,163 @@
+use actix_web::{web, HttpResponse};
+use chrono::Utc;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::domain::{AttributeSchema, ListSlug};
+use crate::routes::Problem;
+
+#[derive(serde::Deserialize, Debug, utoipa::ToSchema)]
+pub struct ListData {
+    slug: String,
+    name: String,
+    /// Extra signup fields the list accepts, see `AttributeSchema`.
+    #[schema(value_type = Option<Object>)]
+    attribute_schema: Option<serde_json::Value>,
+}
+
+pub struct MailingList {
+    pub list_id: Uuid,
+    pub attribute_schema: AttributeSchema,
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct CreatedList {
+    list_id: Uuid,
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct ListSummary {
+    list_id: Uuid,
+    slug: String,
+    name: String,
+    confirmed_subscribers: i64,
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/lists",
+    tag = "lists",
+    security(("session" = [])),
+    request_body = ListData,
+    responses(
+        (status = 200, description = "The list was created", body = CreatedList),
+        (status = 400, description = "Invalid slug, name or attribute schema", body = String, content_type = "text/plain"),
+        (status = 409, description = "The slug is taken"),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The role lacks the permission", body = Problem, content_type = "application/problem+json")
+    )
+)]
+#[tracing::instrument(name = "Creating a mailing list", skip(body, pool), fields(list_slug = %body.slug))]
+pub async fn create_list(body: web::Json<ListData>, pool: web::Data<PgPool>) -> HttpResponse {
+    let body = body.into_inner();
+    let slug = match ListSlug::parse(body.slug) {
+        Ok(slug) => slug,
+        Err(err) => return HttpResponse::BadRequest().body(err),
+    };
+    if body.name.trim().is_empty() {
+        return HttpResponse::BadRequest().body("A list needs a name.");
+    }
+    let attribute_schema = match body
+        .attribute_schema
+        .map(AttributeSchema::parse)
+        .transpose()
+    {
+        Ok(schema) => schema.unwrap_or_default(),
+        Err(err) => return HttpResponse::BadRequest().body(err),
+    };
+    let list_id = Uuid::new_v4();
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO lists (list_id, slug, name, attribute_schema, created_at)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (slug) DO NOTHING
+        "#,
+        list_id,
+        slug.as_ref(),
+        body.name,
+        serde_json::to_value(&attribute_schema).expect("Attribute schemas always serialize"),
+        Utc::now()
+    )
+    .execute(pool.get_ref())
+    .await;
+    match result {
+        Ok(done) if done.rows_affected() == 0 => HttpResponse::Conflict().finish(),
+        Ok(_) => HttpResponse::Ok().json(CreatedList { list_id }),
+        Err(e) => {
+            tracing::error!("Failed to execute query: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/lists",
+    tag = "lists",
+    security(("session" = [])),
+    responses(
+        (status = 200, description = "Every list, by slug", body = Vec<ListSummary>),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The role lacks the permission", body = Problem, content_type = "application/problem+json")
+    )
+)]
+#[tracing::instrument(name = "Listing mailing lists", skip(pool))]
+pub async fn get_lists(pool: web::Data<PgPool>) -> HttpResponse {
+    let lists = sqlx::query_as!(
+        ListSummary,
+        r#"
+        SELECT
+            l.list_id,
+            l.slug,
+            l.name,
+            COUNT(m.subscriber_id) FILTER (WHERE m.status = 'confirmed') AS "confirmed_subscribers!"
+        FROM lists l
+        LEFT JOIN list_memberships m ON m.list_id = l.list_id
+        GROUP BY l.list_id
+        ORDER BY l.slug
+        "#
+    )
+    .fetch_all(pool.get_ref())
+    .await;
+    match lists {
+        Ok(lists) => HttpResponse::Ok().json(lists),
+        Err(e) => {
+            tracing::error!("Failed to execute query: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+pub async fn get_list_id(pool: &PgPool, slug: &ListSlug) -> Result<Option<Uuid>, sqlx::Error> {
+    let row = sqlx::query!("SELECT list_id FROM lists WHERE slug = $1", slug.as_ref())
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+    Ok(row.map(|r| r.list_id))
+}
+
+/// Looks up a list together with the schema its signup form is validated
+/// against.
+pub async fn get_list(pool: &PgPool, slug: &ListSlug) -> Result<Option<MailingList>, sqlx::Error> {
+    let row = sqlx::query!(
+        "SELECT list_id, attribute_schema FROM lists WHERE slug = $1",
+        slug.as_ref()
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    row.map(|r| {
+        AttributeSchema::parse(r.attribute_schema).map(|attribute_schema| MailingList {
+            list_id: r.list_id,
+            attribute_schema,
+        })
+    })
+    .transpose()
+    .map_err(|e| sqlx::Error::Decode(e.into()))
+}
@@ -0,0 +1,223 @@
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::{DateTime, Duration, Utc};
+use secrecy::Secret;
+use sqlx::PgPool;
+
+use crate::authentication::{
+    begin_login_attempt, clear_failed_logins, create_session, end_session, forget_login_attempt,
+    record_failed_login, totp_enabled, validate_credentials, verify_second_factor, AdminUser,
+    LoginAttempt, PendingLogin, SecondFactor, TotpCipher,
+};
+use crate::configuration::AdminSettings;
+use crate::rate_limiting::client_ip;
+use crate::routes::{too_many_requests, Problem};
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+pub struct Credentials {
+    username: String,
+    #[schema(value_type = String)]
+    password: Secret<String>,
+}
+
+/// How long an enrolled user has to enter the code after the password.
+const SECOND_FACTOR_MINUTES: i64 = 5;
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+pub struct SecondFactorData {
+    /// The current code from the authenticator app.
+    code: Option<String>,
+    /// One of the recovery codes, instead of `code`.
+    recovery_code: Option<String>,
+}
+
+impl SecondFactorData {
+    pub fn into_factor(self) -> Result<SecondFactor, &'static str> {
+        match (self.code, self.recovery_code) {
+            (Some(code), None) => Ok(SecondFactor::Totp(code)),
+            (None, Some(code)) => Ok(SecondFactor::RecoveryCode(code)),
+            _ => Err("Send either a code or a recovery code."),
+        }
+    }
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct SessionResponse {
+    /// Send as `Authorization: Bearer <session_token>` to `/admin` routes.
+    session_token: String,
+    expires_at: DateTime<Utc>,
+    /// The token is only good for `POST /login/two_factor` until then.
+    second_factor_required: bool,
+}
+
+#[utoipa::path(
+    post,
+    path = "/login",
+    tag = "authentication",
+    request_body = Credentials,
+    responses(
+        (status = 200, description = "A new admin session, or a pending one if two-factor authentication is on", body = SessionResponse),
+        (status = 401, description = "Unknown username or wrong password", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Too many failed logins for the username or from the address, retry after the `Retry-After` seconds", body = Problem, content_type = "application/problem+json")
+    )
+)]
+#[tracing::instrument(
+    name = "Logging in",
+    skip(req, credentials, pool, settings),
+    fields(username = %credentials.username, ip_address = tracing::field::Empty)
+)]
+pub async fn login(
+    req: HttpRequest,
+    credentials: web::Json<Credentials>,
+    pool: web::Data<PgPool>,
+    settings: web::Data<AdminSettings>,
+) -> HttpResponse {
+    let credentials = credentials.into_inner();
+    let throttling = &settings.login_throttling;
+    let ip_address = client_ip(&req);
+    tracing::Span::current().record("ip_address", tracing::field::display(&ip_address));
+    // Checked and recorded before the password, a correct guess must not
+    // get through either.
+    let attempt_id =
+        match begin_login_attempt(&pool, throttling, &credentials.username, &ip_address).await {
+            Ok(LoginAttempt::Started(attempt_id)) => attempt_id,
+            Ok(LoginAttempt::Throttled { wait, failures }) => {
+                tracing::warn!(
+                    username_failures = failures.username_failures,
+                    ip_failures = failures.ip_failures,
+                    "Refusing a login after too many failures"
+                );
+                return too_many_requests(
+                    "Too many failed logins, try again later.",
+                    wait.to_std().unwrap_or_default(),
+                );
+            }
+            Err(_) => return Problem::internal_error().error_response(),
+        };
+    let user_id =
+        match validate_credentials(&pool, &credentials.username, credentials.password).await {
+            Ok(Some(user_id)) => user_id,
+            Ok(None) => {
+                tracing::warn!("Failed login");
+                return Problem::new(StatusCode::UNAUTHORIZED, "Invalid username or password.")
+                    .error_response();
+            }
+            Err(_) => return Problem::internal_error().error_response(),
+        };
+    let second_factor_required = match totp_enabled(&pool, user_id).await {
+        Ok(enabled) => enabled,
+        Err(_) => return Problem::internal_error().error_response(),
+    };
+    // With two-factor authentication on, only the code clears the failures,
+    // otherwise the password would reset the count of wrong codes.
+    let forgotten = if second_factor_required {
+        forget_login_attempt(&pool, attempt_id).await
+    } else {
+        clear_failed_logins(&pool, &credentials.username, &ip_address).await
+    };
+    if forgotten.is_err() {
+        return Problem::internal_error().error_response();
+    }
+    let ttl = if second_factor_required {
+        Duration::minutes(SECOND_FACTOR_MINUTES)
+    } else {
+        settings.session_ttl()
+    };
+    match create_session(&pool, user_id, ttl, second_factor_required).await {
+        Ok(session) => HttpResponse::Ok().json(SessionResponse {
+            session_token: session.token,
+            expires_at: session.expires_at,
+            second_factor_required,
+        }),
+        Err(_) => Problem::internal_error().error_response(),
+    }
+}
+
+/// The second login step of users with two-factor authentication. A wrong
+/// code ends the pending login, guessing needs the password every time.
+#[utoipa::path(
+    post,
+    path = "/login/two_factor",
+    tag = "authentication",
+    security(("session" = [])),
+    request_body = SecondFactorData,
+    responses(
+        (status = 200, description = "A full admin session, the pending one is gone", body = SessionResponse),
+        (status = 400, description = "Neither or both codes were sent", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Wrong code, or no login waiting for one", body = Problem, content_type = "application/problem+json")
+    )
+)]
+#[tracing::instrument(
+    name = "Verifying the second login step",
+    skip(req, login, body, pool, settings, cipher),
+    fields(username = %login.0.username)
+)]
+pub async fn login_second_factor(
+    req: HttpRequest,
+    login: PendingLogin,
+    body: web::Json<SecondFactorData>,
+    pool: web::Data<PgPool>,
+    settings: web::Data<AdminSettings>,
+    cipher: web::Data<TotpCipher>,
+) -> HttpResponse {
+    let PendingLogin(user) = login;
+    let factor = match body.into_inner().into_factor() {
+        Ok(factor) => factor,
+        Err(err) => return Problem::new(StatusCode::BAD_REQUEST, err).error_response(),
+    };
+    let verified = match verify_second_factor(&pool, &cipher, user.user_id, &factor).await {
+        Ok(verified) => verified,
+        Err(_) => return Problem::internal_error().error_response(),
+    };
+    if end_session(&pool, &user).await.is_err() {
+        return Problem::internal_error().error_response();
+    }
+    let ip_address = client_ip(&req);
+    if !verified {
+        tracing::warn!(ip_address = %ip_address, "Failed second login step");
+        return match record_failed_login(
+            &pool,
+            &settings.login_throttling,
+            &user.username,
+            &ip_address,
+        )
+        .await
+        {
+            Ok(()) => Problem::new(StatusCode::UNAUTHORIZED, "Invalid code, log in again.")
+                .error_response(),
+            Err(_) => Problem::internal_error().error_response(),
+        };
+    }
+    if clear_failed_logins(&pool, &user.username, &ip_address)
+        .await
+        .is_err()
+    {
+        return Problem::internal_error().error_response();
+    }
+    match create_session(&pool, user.user_id, settings.session_ttl(), false).await {
+        Ok(session) => HttpResponse::Ok().json(SessionResponse {
+            session_token: session.token,
+            expires_at: session.expires_at,
+            second_factor_required: false,
+        }),
+        Err(_) => Problem::internal_error().error_response(),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/logout",
+    tag = "authentication",
+    security(("session" = [])),
+    responses(
+        (status = 200, description = "The session token no longer works"),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json")
+    )
+)]
+#[tracing::instrument(name = "Logging out", skip(user, pool), fields(username = %user.username))]
+pub async fn logout(user: AdminUser, pool: web::Data<PgPool>) -> HttpResponse {
+    match end_session(&pool, &user).await {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(_) => Problem::internal_error().error_response(),
+    }
+}
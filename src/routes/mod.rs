@@ -1,5 +1,43 @@
+mod admin_subscribers;
+mod api;
+mod api_keys;
+mod email_change;
 mod health_check;
+mod html;
+mod issue_stats;
+mod issues;
+mod lists;
+mod login;
+mod openapi;
+mod password;
+mod preferences;
+mod problem;
+mod segments;
+mod subscriber_tags;
 mod subscriptions;
+mod subscriptions_confirm;
+mod tracking;
+mod two_factor;
+mod users;
 
+pub use admin_subscribers::*;
+pub use api::*;
+pub use api_keys::*;
+pub use email_change::*;
 pub use health_check::*;
+pub use html::*;
+pub use issue_stats::*;
+pub use issues::*;
+pub use lists::*;
+pub use login::*;
+pub use openapi::*;
+pub use password::*;
+pub use preferences::*;
+pub use problem::*;
+pub use segments::*;
+pub use subscriber_tags::*;
 pub use subscriptions::*;
+pub use subscriptions_confirm::*;
+pub use tracking::*;
+pub use two_factor::*;
+pub use users::*;
@@ -0,0 +1,114 @@
+use actix_web::http::header::ContentType;
+use actix_web::HttpResponse;
+use utoipa::openapi::security::{Http, HttpAuthScheme, SecurityScheme};
+use utoipa::openapi::OpenApi as OpenApiDocument;
+use utoipa::{Modify, OpenApi};
+
+/// The OpenAPI document of every route meant for programmatic clients,
+/// generated from the handlers and their request and response types.
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "newsletter",
+        description = "Signups, double opt-in and subscriber management."
+    ),
+    modifiers(&WithoutLicense, &BearerSecurity),
+    paths(
+        super::health_check::health_check,
+        super::subscriptions::subscribe,
+        super::subscriptions::signup_challenge,
+        super::subscriptions_confirm::confirm,
+        super::subscriptions_confirm::resend_confirmation,
+        super::login::login,
+        super::login::login_second_factor,
+        super::login::logout,
+        super::two_factor::enroll_two_factor,
+        super::two_factor::confirm_two_factor,
+        super::two_factor::disable_two_factor,
+        super::password::change_own_password,
+        super::password::request_password_reset,
+        super::users::get_admin_users,
+        super::users::create_admin_user,
+        super::users::change_user_role,
+        super::lists::get_lists,
+        super::lists::create_list,
+        super::api_keys::get_api_keys,
+        super::api_keys::create_api_key,
+        super::api_keys::revoke_api_key,
+        super::issues::publish_issue,
+        super::issues::publish_draft,
+        super::issue_stats::issue_stats,
+        super::segments::get_segments,
+        super::segments::create_segment,
+        super::admin_subscribers::delete_admin_subscriber,
+        super::subscriber_tags::add_subscriber_tags,
+        super::subscriber_tags::remove_subscriber_tag,
+        super::api::publish_issue_with_api_key,
+        super::api::list_subscribers,
+        super::api::fetch_subscriber,
+        super::api::patch_subscriber,
+        super::api::delete_subscriber,
+    ),
+    components(schemas(super::problem::Problem)),
+    tags(
+        (name = "subscriptions", description = "Public signup and double opt-in"),
+        (name = "authentication", description = "Admin sessions"),
+        (name = "users", description = "Admin users and their roles"),
+        (name = "lists", description = "Mailing list administration"),
+        (name = "api keys", description = "Keys for machine clients"),
+        (name = "issues", description = "Publishing issues and their stats"),
+        (name = "segments", description = "Saved subscriber filters"),
+        (name = "subscribers", description = "Subscriber management and tags"),
+    )
+)]
+pub struct ApiDoc;
+
+/// The crate has no license, utoipa would otherwise copy the empty
+/// `CARGO_PKG_LICENSE` into the document.
+struct WithoutLicense;
+
+impl Modify for WithoutLicense {
+    fn modify(&self, openapi: &mut OpenApiDocument) {
+        openapi.info.license = None;
+    }
+}
+
+/// Routes under `/api/v1` expect an API key as a bearer token, routes under
+/// `/admin` a session token from `/login`.
+struct BearerSecurity;
+
+impl Modify for BearerSecurity {
+    fn modify(&self, openapi: &mut OpenApiDocument) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "api_key",
+            SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+        );
+        components.add_security_scheme(
+            "session",
+            SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+        );
+    }
+}
+
+pub async fn openapi_json() -> HttpResponse {
+    HttpResponse::Ok().json(ApiDoc::openapi())
+}
+
+/// An interactive reference for `/openapi.json`, the viewer itself is loaded
+/// from a CDN.
+pub async fn api_docs() -> HttpResponse {
+    HttpResponse::Ok().content_type(ContentType::html()).body(
+        r#"<!doctype html>
+<html>
+<head>
+    <meta charset="utf-8" />
+    <title>newsletter API</title>
+</head>
+<body>
+    <script id="api-reference" data-url="/openapi.json"></script>
+    <script src="https://cdn.jsdelivr.net/npm/@scalar/api-reference"></script>
+</body>
+</html>"#,
+    )
+}
@@ -0,0 +1,384 @@
+use actix_web::http::header::ContentType;
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::Utc;
+use secrecy::{ExposeSecret, Secret};
+use sqlx::{PgPool, Postgres, Transaction};
+use tracing::Instrument;
+use uuid::Uuid;
+
+use crate::authentication::{
+    change_password, hash_token, set_password, validate_credentials, AdminUser,
+};
+use crate::configuration::AdminSettings;
+use crate::domain::SubscriberEmail;
+use crate::email_client::EmailClient;
+use crate::email_template::escape_html;
+use crate::rate_limiting::{client_ip, RateLimiter};
+use crate::routes::{html_page, validate_password, Problem};
+use crate::startup::ApplicationBaseUrl;
+use crate::tokens::generate_token;
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+pub struct PasswordChangeData {
+    #[schema(value_type = String)]
+    current_password: Secret<String>,
+    #[schema(value_type = String)]
+    new_password: Secret<String>,
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+pub struct PasswordResetRequest {
+    username: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct PasswordResetForm {
+    new_password: Secret<String>,
+}
+
+/// Every other session of the user ends, the one making the change stays
+/// logged in.
+#[utoipa::path(
+    put,
+    path = "/admin/account/password",
+    tag = "authentication",
+    security(("session" = [])),
+    request_body = PasswordChangeData,
+    responses(
+        (status = 200, description = "The password was changed"),
+        (status = 400, description = "Wrong current password, or the new one is too weak", body = String, content_type = "text/plain"),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json")
+    )
+)]
+#[tracing::instrument(name = "Changing the own password", skip(user, body, pool), fields(username = %user.username))]
+pub async fn change_own_password(
+    user: AdminUser,
+    body: web::Json<PasswordChangeData>,
+    pool: web::Data<PgPool>,
+) -> HttpResponse {
+    let body = body.into_inner();
+    match validate_credentials(&pool, &user.username, body.current_password.clone()).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return HttpResponse::BadRequest().body("The current password is wrong."),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    }
+    if let Err(err) = validate_password(&body.new_password, &user.username) {
+        return HttpResponse::BadRequest().body(err);
+    }
+    if body.new_password.expose_secret() == body.current_password.expose_secret() {
+        return HttpResponse::BadRequest()
+            .body("The new password must differ from the current one.");
+    }
+    match change_password(&pool, user.user_id, body.new_password, Some(&user)).await {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+/// Emails a reset link if the user exists and has an email address. The
+/// answer is always the same and comes before the lookup, neither it nor its
+/// timing tells which usernames exist. Requests over the per-address or
+/// per-username limit are dropped silently.
+#[utoipa::path(
+    post,
+    path = "/password_reset",
+    tag = "authentication",
+    request_body = PasswordResetRequest,
+    responses((status = 200, description = "A reset link is on its way if the user has an email address"))
+)]
+#[tracing::instrument(
+    name = "Requesting a password reset",
+    skip(req, body, pool, email_client, base_url, settings, limiter),
+    fields(username = %body.username)
+)]
+pub async fn request_password_reset(
+    req: HttpRequest,
+    body: web::Json<PasswordResetRequest>,
+    pool: web::Data<PgPool>,
+    email_client: web::Data<EmailClient>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    settings: web::Data<AdminSettings>,
+    limiter: web::Data<dyn RateLimiter>,
+) -> HttpResponse {
+    let username = body.into_inner().username;
+    let limits = &settings.password_reset_rate_limiting;
+    let checks = [
+        (
+            format!("password_reset:ip:{}", client_ip(&req)),
+            limits.per_ip(),
+        ),
+        (
+            format!("password_reset:username:{}", username.to_lowercase()),
+            limits.per_username(),
+        ),
+    ];
+    for (key, limit) in checks {
+        match limiter.hit(&key, limit).await {
+            Ok(None) => {}
+            Ok(Some(_)) => {
+                tracing::warn!(key = %key, "Rate limiting password resets");
+                return HttpResponse::Ok().finish();
+            }
+            Err(e) => {
+                tracing::warn!(error.cause_chain = ?e, "Failed to rate limit a password reset");
+            }
+        }
+    }
+    tokio::spawn(
+        send_reset_link(pool, email_client, base_url, settings, username)
+            .instrument(tracing::Span::current()),
+    );
+    HttpResponse::Ok().finish()
+}
+
+/// Runs after the response went out, failures are only logged.
+async fn send_reset_link(
+    pool: web::Data<PgPool>,
+    email_client: web::Data<EmailClient>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    settings: web::Data<AdminSettings>,
+    username: String,
+) {
+    let (user_id, email) = match get_user_email(&pool, &username).await {
+        Ok(Some(user)) => user,
+        Ok(None) | Err(_) => return,
+    };
+    let token = generate_token();
+    if store_reset_token(&pool, user_id, &token, &settings)
+        .await
+        .is_err()
+    {
+        return;
+    }
+    if let Err(e) = send_reset_email(&email_client, &email, &base_url.0, &token, &settings).await {
+        tracing::error!(error.cause_chain = ?e, "Failed to send a password reset email");
+    }
+}
+
+#[tracing::instrument(name = "Showing the password reset form", skip(token, pool))]
+pub async fn password_reset_form(
+    token: web::Path<String>,
+    pool: web::Data<PgPool>,
+) -> HttpResponse {
+    match reset_token_is_valid(&pool, &token).await {
+        Ok(true) => render_reset_form(None),
+        Ok(false) => unknown_reset_token(),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+/// Logs the user out everywhere, the token cannot be used again. Spending
+/// the token and storing the password commit together, a failure leaves the
+/// link usable.
+#[tracing::instrument(name = "Resetting a password", skip(token, form, pool))]
+pub async fn reset_password(
+    token: web::Path<String>,
+    form: web::Form<PasswordResetForm>,
+    pool: web::Data<PgPool>,
+) -> HttpResponse {
+    let user = match get_reset_token_user(&pool, &token).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return unknown_reset_token(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    let new_password = form.into_inner().new_password;
+    if let Err(err) = validate_password(&new_password, &user.username) {
+        return render_reset_form(Some(&err));
+    }
+    let mut transaction = match pool.begin().await {
+        Ok(transaction) => transaction,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    match use_reset_token(&mut transaction, &token).await {
+        Ok(true) => {}
+        Ok(false) => return unknown_reset_token(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    }
+    if set_password(&mut transaction, user.user_id, new_password, None)
+        .await
+        .is_err()
+    {
+        return HttpResponse::InternalServerError().finish();
+    }
+    match transaction.commit().await {
+        Ok(()) => HttpResponse::Ok()
+            .content_type(ContentType::html())
+            .body(html_page(
+                "Password changed",
+                "<p>Log in with your new password.</p>",
+            )),
+        Err(e) => {
+            tracing::error!("Failed to execute query: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+fn render_reset_form(error: Option<&str>) -> HttpResponse {
+    let message = error
+        .map(|e| format!("<p><strong>{}</strong></p>", escape_html(e)))
+        .unwrap_or_default();
+    let body = format!(
+        r#"{message}<form method="post">
+        <label>New password <input type="password" name="new_password" autocomplete="new-password" required></label>
+        <button type="submit">Change password</button>
+    </form>"#
+    );
+    let mut response = if error.is_some() {
+        HttpResponse::BadRequest()
+    } else {
+        HttpResponse::Ok()
+    };
+    response
+        .content_type(ContentType::html())
+        .body(html_page("Choose a new password", &body))
+}
+
+fn unknown_reset_token() -> HttpResponse {
+    HttpResponse::NotFound()
+        .content_type(ContentType::html())
+        .body(html_page(
+            "Link expired",
+            "<p>This reset link is unknown, expired or was already used. Request a new one.</p>",
+        ))
+}
+
+async fn get_user_email(
+    pool: &PgPool,
+    username: &str,
+) -> Result<Option<(Uuid, SubscriberEmail)>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT user_id, email AS "email!" FROM users WHERE username = $1 AND email IS NOT NULL"#,
+        username
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(row.and_then(|r| match SubscriberEmail::parse(r.email) {
+        Ok(email) => Some((r.user_id, email)),
+        Err(error) => {
+            tracing::warn!(error = %error, "Skipping a password reset for an invalid stored email");
+            None
+        }
+    }))
+}
+
+async fn store_reset_token(
+    pool: &PgPool,
+    user_id: Uuid,
+    token: &str,
+    settings: &AdminSettings,
+) -> Result<(), sqlx::Error> {
+    let now = Utc::now();
+    sqlx::query!(
+        r#"
+        INSERT INTO password_reset_tokens (token_hash, user_id, created_at, expires_at)
+        VALUES ($1, $2, $3, $4)
+        "#,
+        hash_token(token),
+        user_id,
+        now,
+        now + settings.password_reset_ttl()
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+#[tracing::instrument(
+    name = "Send a password reset email",
+    skip(email_client, recipient, base_url, token, settings)
+)]
+async fn send_reset_email(
+    email_client: &EmailClient,
+    recipient: &SubscriberEmail,
+    base_url: &str,
+    token: &str,
+    settings: &AdminSettings,
+) -> Result<(), reqwest::Error> {
+    let reset_link = format!("{}/password_reset/{}", base_url, token);
+    let minutes = settings.password_reset_ttl_minutes;
+    let html_body = format!(
+        "Someone asked to reset your password.<br />Click <a href=\"{}\">here</a> to choose a new one, the link works once within {} minutes.<br />If it was not you, ignore this email.",
+        reset_link, minutes
+    );
+    let text_body = format!(
+        "Someone asked to reset your password.\nVisit {} to choose a new one, the link works once within {} minutes.\nIf it was not you, ignore this email.",
+        reset_link, minutes
+    );
+    email_client
+        .send_email(recipient, "Reset your password", &html_body, &text_body)
+        .await
+        .map_err(|e| {
+            tracing::error!(error.cause_chain = ?e, "Failed to send a password reset email");
+            e
+        })
+}
+
+async fn reset_token_is_valid(pool: &PgPool, token: &str) -> Result<bool, sqlx::Error> {
+    Ok(get_reset_token_user(pool, token).await?.is_some())
+}
+
+struct ResetTokenUser {
+    user_id: Uuid,
+    username: String,
+}
+
+async fn get_reset_token_user(
+    pool: &PgPool,
+    token: &str,
+) -> Result<Option<ResetTokenUser>, sqlx::Error> {
+    sqlx::query_as!(
+        ResetTokenUser,
+        r#"
+        SELECT u.user_id, u.username
+        FROM password_reset_tokens t
+        JOIN users u ON u.user_id = t.user_id
+        WHERE t.token_hash = $1 AND t.used_at IS NULL AND t.expires_at > $2
+        "#,
+        hash_token(token),
+        Utc::now()
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })
+}
+
+/// Marks the token used, only one of several concurrent requests wins. The
+/// user's other reset links stop working as well.
+async fn use_reset_token(
+    transaction: &mut Transaction<'_, Postgres>,
+    token: &str,
+) -> Result<bool, sqlx::Error> {
+    let now = Utc::now();
+    let used = sqlx::query!(
+        r#"
+        UPDATE password_reset_tokens SET used_at = $2
+        WHERE user_id = (
+            SELECT user_id FROM password_reset_tokens
+            WHERE token_hash = $1 AND used_at IS NULL AND expires_at > $2
+        )
+        AND used_at IS NULL
+        RETURNING token_hash
+        "#,
+        hash_token(token),
+        now
+    )
+    .fetch_all(&mut *transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(used.iter().any(|row| row.token_hash == hash_token(token)))
+}
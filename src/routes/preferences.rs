@@ -0,0 +1,444 @@
+use std::collections::HashMap;
+
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::http::header::ContentType;
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::Utc;
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::domain::{DeliveryFrequency, ListSlug, SubscriberName};
+use crate::email_template::escape_html;
+use crate::routes::html_page;
+use crate::startup::ApplicationBaseUrl;
+use crate::tokens::generate_token;
+
+/// The preference forms carry a random token that has to match this cookie,
+/// which a form posted from another site cannot do.
+const CSRF_COOKIE: &str = "preferences_csrf";
+
+#[derive(serde::Deserialize, Debug)]
+pub struct PreferencesQuery {
+    /// Set when the page was reached from an issue, to attribute unsubscribes.
+    issue: Option<Uuid>,
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct PreferencesForm {
+    name: String,
+    frequency: String,
+    #[serde(default)]
+    csrf_token: String,
+    /// One `list.<slug>` checkbox per list the subscriber wants to receive.
+    #[serde(flatten)]
+    fields: HashMap<String, String>,
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct UnsubscribeForm {
+    issue: Option<Uuid>,
+    #[serde(default)]
+    csrf_token: String,
+}
+
+struct Preferences {
+    name: String,
+    frequency: DeliveryFrequency,
+    lists: Vec<ListChoice>,
+}
+
+struct ListChoice {
+    slug: String,
+    name: String,
+    member: bool,
+}
+
+#[tracing::instrument(
+    name = "Showing subscriber preferences",
+    skip(req, token, pool, base_url)
+)]
+pub async fn preferences_form(
+    req: HttpRequest,
+    token: web::Path<String>,
+    query: web::Query<PreferencesQuery>,
+    pool: web::Data<PgPool>,
+    base_url: web::Data<ApplicationBaseUrl>,
+) -> HttpResponse {
+    let subscriber_id = match get_subscriber_id_from_preference_token(&pool, &token).await {
+        Ok(Some(subscriber_id)) => subscriber_id,
+        Ok(None) => return HttpResponse::NotFound().finish(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    let preferences = match get_preferences(&pool, subscriber_id).await {
+        Ok(preferences) => preferences,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    let csrf_token = req
+        .cookie(CSRF_COOKIE)
+        .map(|cookie| cookie.value().to_owned())
+        .unwrap_or_else(generate_token);
+    let mut response = render_page(&token, &csrf_token, query.issue, &preferences, None);
+    let cookie = Cookie::build(CSRF_COOKIE, csrf_token)
+        .path("/preferences")
+        .http_only(true)
+        .secure(base_url.0.starts_with("https://"))
+        .same_site(SameSite::Strict)
+        .finish();
+    if let Err(e) = response.add_cookie(&cookie) {
+        tracing::error!(error.cause_chain = ?e, "Failed to set the CSRF cookie");
+        return HttpResponse::InternalServerError().finish();
+    }
+    response
+}
+
+/// Whether a posted preference form carries the token of the CSRF cookie.
+pub fn csrf_token_matches(req: &HttpRequest, submitted: &str) -> bool {
+    match req.cookie(CSRF_COOKIE) {
+        Some(cookie) => !submitted.is_empty() && cookie.value() == submitted,
+        None => false,
+    }
+}
+
+pub fn csrf_rejected() -> HttpResponse {
+    HttpResponse::Forbidden().body("The form expired, reload the page and try again.")
+}
+
+#[tracing::instrument(name = "Saving subscriber preferences", skip(req, token, form, pool))]
+pub async fn save_preferences(
+    req: HttpRequest,
+    token: web::Path<String>,
+    form: web::Form<PreferencesForm>,
+    pool: web::Data<PgPool>,
+) -> HttpResponse {
+    if !csrf_token_matches(&req, &form.csrf_token) {
+        return csrf_rejected();
+    }
+    let subscriber_id = match get_subscriber_id_from_preference_token(&pool, &token).await {
+        Ok(Some(subscriber_id)) => subscriber_id,
+        Ok(None) => return HttpResponse::NotFound().finish(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    let form = form.into_inner();
+    let name = match SubscriberName::parse(form.name) {
+        Ok(name) => name,
+        Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+    };
+    let frequency = match DeliveryFrequency::parse(&form.frequency) {
+        Ok(frequency) => frequency,
+        Err(err) => return HttpResponse::BadRequest().body(err),
+    };
+    let chosen_lists = match form
+        .fields
+        .into_keys()
+        .filter_map(|field| field.strip_prefix("list.").map(str::to_owned))
+        .map(ListSlug::parse)
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(lists) => lists,
+        Err(err) => return HttpResponse::BadRequest().body(err),
+    };
+
+    match apply_preferences(&pool, subscriber_id, &name, frequency, &chosen_lists).await {
+        Ok(None) => {}
+        Ok(Some(unknown)) => {
+            return HttpResponse::BadRequest().body(format!("{} is not a known list.", unknown))
+        }
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    }
+    match get_preferences(&pool, subscriber_id).await {
+        Ok(preferences) => render_page(&token, &form.csrf_token, None, &preferences, Some("Your preferences have been saved.")),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[tracing::instrument(name = "Unsubscribing from everything", skip(req, token, form, pool))]
+pub async fn unsubscribe_all(
+    req: HttpRequest,
+    token: web::Path<String>,
+    form: web::Form<UnsubscribeForm>,
+    pool: web::Data<PgPool>,
+) -> HttpResponse {
+    if !csrf_token_matches(&req, &form.csrf_token) {
+        return csrf_rejected();
+    }
+    let subscriber_id = match get_subscriber_id_from_preference_token(&pool, &token).await {
+        Ok(Some(subscriber_id)) => subscriber_id,
+        Ok(None) => return HttpResponse::NotFound().finish(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    if unsubscribe_subscriber(&pool, subscriber_id, form.issue).await.is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(html_page("Unsubscribed", "<p>You have been unsubscribed from every list.</p>"))
+}
+
+/// Returns the preference token of a subscriber, creating it on first use.
+pub async fn get_or_create_preference_token(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+) -> Result<String, sqlx::Error> {
+    // The no-op update makes RETURNING yield the existing token, even when
+    // a concurrent call inserted it first.
+    let row = sqlx::query!(
+        r#"
+        INSERT INTO preference_tokens (preference_token, subscriber_id)
+        VALUES ($1, $2)
+        ON CONFLICT (subscriber_id) DO UPDATE
+            SET preference_token = preference_tokens.preference_token
+        RETURNING preference_token
+        "#,
+        generate_token(),
+        subscriber_id
+    )
+    .fetch_one(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(row.preference_token)
+}
+
+pub async fn get_subscriber_id_from_preference_token(
+    pool: &PgPool,
+    token: &str,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let row = sqlx::query!(
+        "SELECT subscriber_id FROM preference_tokens WHERE preference_token = $1",
+        token
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(row.map(|r| r.subscriber_id))
+}
+
+async fn get_preferences(pool: &PgPool, subscriber_id: Uuid) -> Result<Preferences, sqlx::Error> {
+    let subscriber = sqlx::query!(
+        "SELECT name, delivery_frequency FROM subscriptions WHERE id = $1",
+        subscriber_id
+    )
+    .fetch_one(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    let lists = sqlx::query_as!(
+        ListChoice,
+        r#"
+        SELECT l.slug, l.name, COALESCE(m.status = 'confirmed', false) AS "member!"
+        FROM lists l
+        LEFT JOIN list_memberships m ON m.list_id = l.list_id AND m.subscriber_id = $1
+        ORDER BY l.name
+        "#,
+        subscriber_id
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(Preferences {
+        name: subscriber.name,
+        frequency: DeliveryFrequency::parse(&subscriber.delivery_frequency).unwrap_or_default(),
+        lists,
+    })
+}
+
+/// Returns the first chosen slug that does not exist, in which case nothing
+/// is committed.
+async fn apply_preferences(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+    name: &SubscriberName,
+    frequency: DeliveryFrequency,
+    chosen_lists: &[ListSlug],
+) -> Result<Option<String>, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    update_subscriber(&mut transaction, subscriber_id, name, frequency).await?;
+    if let Some(unknown) = update_memberships(&mut transaction, subscriber_id, chosen_lists).await? {
+        return Ok(Some(unknown));
+    }
+    transaction.commit().await?;
+    Ok(None)
+}
+
+async fn update_subscriber(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    name: &SubscriberName,
+    frequency: DeliveryFrequency,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE subscriptions SET name = $1, delivery_frequency = $2 WHERE id = $3",
+        name.as_ref(),
+        frequency.as_str(),
+        subscriber_id
+    )
+    .execute(transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+/// Joins the chosen lists and leaves every other one.
+async fn update_memberships(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    chosen_lists: &[ListSlug],
+) -> Result<Option<String>, sqlx::Error> {
+    let slugs: Vec<String> = chosen_lists.iter().map(|l| l.as_ref().to_owned()).collect();
+    let known = sqlx::query!("SELECT slug FROM lists WHERE slug = ANY($1)", &slugs[..])
+        .fetch_all(&mut *transaction)
+        .await?;
+    if let Some(unknown) = slugs.iter().find(|s| !known.iter().any(|k| &k.slug == *s)) {
+        return Ok(Some(unknown.clone()));
+    }
+    sqlx::query!(
+        r#"
+        INSERT INTO list_memberships (list_id, subscriber_id, status, subscribed_at)
+        SELECT list_id, $1, 'confirmed', $2 FROM lists WHERE slug = ANY($3)
+        ON CONFLICT (list_id, subscriber_id) DO UPDATE
+        SET status = 'confirmed'
+        WHERE list_memberships.status <> 'confirmed'
+        "#,
+        subscriber_id,
+        Utc::now(),
+        &slugs[..]
+    )
+    .execute(&mut *transaction)
+    .await?;
+    sqlx::query!(
+        r#"
+        UPDATE list_memberships SET status = 'unsubscribed'
+        WHERE subscriber_id = $1
+            AND list_id NOT IN (SELECT list_id FROM lists WHERE slug = ANY($2))
+        "#,
+        subscriber_id,
+        &slugs[..]
+    )
+    .execute(&mut *transaction)
+    .await?;
+    if !slugs.is_empty() {
+        sqlx::query!(
+            "UPDATE subscriptions SET status = 'confirmed' WHERE id = $1 AND status = 'unsubscribed'",
+            subscriber_id
+        )
+        .execute(&mut *transaction)
+        .await?;
+    }
+    Ok(None)
+}
+
+async fn unsubscribe_subscriber(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+    issue_id: Option<Uuid>,
+) -> Result<(), sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    sqlx::query!(
+        "UPDATE list_memberships SET status = 'unsubscribed' WHERE subscriber_id = $1",
+        subscriber_id
+    )
+    .execute(&mut transaction)
+    .await?;
+    sqlx::query!(
+        "UPDATE subscriptions SET status = 'unsubscribed' WHERE id = $1",
+        subscriber_id
+    )
+    .execute(&mut transaction)
+    .await?;
+    if let Some(issue_id) = issue_id {
+        // Only attribute the unsubscribe to an issue the subscriber received.
+        sqlx::query!(
+            r#"
+            INSERT INTO tracking_events (id, newsletter_issue_id, subscriber_id, kind, occurred_at)
+            SELECT $1, newsletter_issue_id, subscriber_id, 'unsubscribe', $2
+            FROM issue_deliveries
+            WHERE newsletter_issue_id = $3 AND subscriber_id = $4
+            "#,
+            Uuid::new_v4(),
+            Utc::now(),
+            issue_id,
+            subscriber_id
+        )
+        .execute(&mut transaction)
+        .await?;
+    }
+    transaction.commit().await.map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })
+}
+
+fn render_page(
+    token: &str,
+    csrf_token: &str,
+    issue: Option<Uuid>,
+    preferences: &Preferences,
+    notice: Option<&str>,
+) -> HttpResponse {
+    let token = escape_html(token);
+    let csrf = format!(
+        r#"<input type="hidden" name="csrf_token" value="{}" />"#,
+        escape_html(csrf_token)
+    );
+    let mut lists = String::new();
+    for list in &preferences.lists {
+        lists.push_str(&format!(
+            r#"<label><input type="checkbox" name="list.{}"{} /> {}</label><br />"#,
+            escape_html(&list.slug),
+            if list.member { " checked" } else { "" },
+            escape_html(&list.name),
+        ));
+    }
+    let mut frequencies = String::new();
+    for frequency in DeliveryFrequency::ALL {
+        frequencies.push_str(&format!(
+            r#"<option value="{0}"{1}>{0}</option>"#,
+            frequency.as_str(),
+            if frequency == preferences.frequency { " selected" } else { "" },
+        ));
+    }
+    let issue = issue
+        .map(|issue| format!(r#"<input type="hidden" name="issue" value="{}" />"#, issue))
+        .unwrap_or_default();
+    let notice = notice
+        .map(|notice| format!("<p><i>{}</i></p>", notice))
+        .unwrap_or_default();
+    let body = format!(
+        r#"{notice}
+<form action="/preferences/{token}" method="post">
+    {csrf}
+    <label>Name <input type="text" name="name" value="{name}" /></label><br />
+    <fieldset><legend>Lists</legend>{lists}</fieldset>
+    <label>Delivery frequency <select name="frequency">{frequencies}</select></label><br />
+    <small>Weekly and monthly send at most one issue a week or a month, the ones in between are skipped.</small><br />
+    <button type="submit">Save preferences</button>
+</form>
+<form action="/preferences/{token}/email" method="post">
+    {csrf}
+    <label>New email address <input type="email" name="email" /></label>
+    <button type="submit">Change email address</button>
+</form>
+<form action="/preferences/{token}/unsubscribe" method="post">
+    {csrf}
+    {issue}
+    <button type="submit">Unsubscribe from everything</button>
+</form>"#,
+        name = escape_html(&preferences.name),
+    );
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(html_page("Your preferences", &body))
+}
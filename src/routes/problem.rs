@@ -0,0 +1,111 @@
+use std::time::Duration;
+
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::HttpResponse;
+
+/// One invalid input field, `code` is meant for machines and `message` for
+/// people.
+#[derive(serde::Serialize, Debug, utoipa::ToSchema)]
+pub struct FieldError {
+    pub field: String,
+    pub code: &'static str,
+    pub message: String,
+    /// A corrected value the client may offer to the user.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub did_you_mean: Option<String>,
+}
+
+impl FieldError {
+    pub fn new(field: impl Into<String>, code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            field: field.into(),
+            code,
+            message: message.into(),
+            did_you_mean: None,
+        }
+    }
+}
+
+/// An RFC 7807 problem document for input that failed validation.
+#[derive(serde::Serialize, Debug, utoipa::ToSchema)]
+pub struct ValidationProblem {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    title: &'static str,
+    status: u16,
+    errors: Vec<FieldError>,
+}
+
+impl ValidationProblem {
+    pub fn new(errors: Vec<FieldError>) -> Self {
+        Self {
+            kind: "about:blank#validation-error",
+            title: "Your request parameters didn't validate.",
+            status: StatusCode::BAD_REQUEST.as_u16(),
+            errors,
+        }
+    }
+
+    pub fn errors(&self) -> &[FieldError] {
+        &self.errors
+    }
+
+    pub fn error_response(&self) -> HttpResponse {
+        HttpResponse::BadRequest()
+            .content_type("application/problem+json")
+            .json(self)
+    }
+}
+
+/// An RFC 7807 problem document for everything but validation errors.
+#[derive(serde::Serialize, Debug, utoipa::ToSchema)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    title: &'static str,
+    status: u16,
+    detail: String,
+    #[serde(skip)]
+    status_code: StatusCode,
+}
+
+impl Problem {
+    pub fn new(status: StatusCode, detail: impl Into<String>) -> Self {
+        Self {
+            kind: "about:blank",
+            title: status.canonical_reason().unwrap_or("Error"),
+            status: status.as_u16(),
+            detail: detail.into(),
+            status_code: status,
+        }
+    }
+
+    pub fn not_found(detail: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, detail)
+    }
+
+    pub fn internal_error() -> Self {
+        Self::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Something went wrong on our side.",
+        )
+    }
+
+    pub fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code)
+            .content_type("application/problem+json")
+            .json(self)
+    }
+}
+
+/// A 429 with `Retry-After` in whole seconds, rounded up: retrying a moment
+/// too early would fail again.
+pub fn too_many_requests(detail: impl Into<String>, wait: Duration) -> HttpResponse {
+    let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    let mut response = Problem::new(StatusCode::TOO_MANY_REQUESTS, detail).error_response();
+    response
+        .headers_mut()
+        .insert(header::RETRY_AFTER, HeaderValue::from(seconds.max(1)));
+    response
+}
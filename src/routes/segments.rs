@@ -0,0 +1,173 @@
+use actix_web::{web, HttpResponse};
+use chrono::Utc;
+use sqlx::{PgPool, Postgres, QueryBuilder, Row};
+use uuid::Uuid;
+
+use crate::domain::SegmentFilter;
+use crate::routes::Problem;
+
+#[derive(serde::Deserialize, Debug, utoipa::ToSchema)]
+pub struct SegmentData {
+    name: String,
+    filter: String,
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct CreatedSegment {
+    segment_id: Uuid,
+    matching_subscribers: i64,
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct SegmentSummary {
+    segment_id: Uuid,
+    name: String,
+    filter: String,
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/segments",
+    tag = "segments",
+    security(("session" = [])),
+    request_body = SegmentData,
+    responses(
+        (status = 200, description = "The segment was saved", body = CreatedSegment),
+        (status = 400, description = "Missing name or invalid filter", body = String, content_type = "text/plain"),
+        (status = 409, description = "The name is taken"),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The role lacks the permission", body = Problem, content_type = "application/problem+json")
+    )
+)]
+#[tracing::instrument(name = "Saving a segment", skip(body, pool), fields(segment_name = %body.name))]
+pub async fn create_segment(body: web::Json<SegmentData>, pool: web::Data<PgPool>) -> HttpResponse {
+    if body.name.trim().is_empty() {
+        return HttpResponse::BadRequest().body("A segment needs a name.");
+    }
+    let filter = match SegmentFilter::parse(&body.filter) {
+        Ok(filter) => filter,
+        Err(err) => return HttpResponse::BadRequest().body(err),
+    };
+    let matching_subscribers = match count_matching_subscribers(&pool, &filter).await {
+        Ok(count) => count,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    let segment_id = Uuid::new_v4();
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO segments (segment_id, name, filter, created_at)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (name) DO NOTHING
+        "#,
+        segment_id,
+        body.name,
+        body.filter,
+        Utc::now()
+    )
+    .execute(pool.get_ref())
+    .await;
+    match result {
+        Ok(done) if done.rows_affected() == 0 => HttpResponse::Conflict().finish(),
+        Ok(_) => HttpResponse::Ok().json(CreatedSegment {
+            segment_id,
+            matching_subscribers,
+        }),
+        Err(e) => {
+            tracing::error!("Failed to execute query: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/segments",
+    tag = "segments",
+    security(("session" = [])),
+    responses(
+        (status = 200, description = "Every segment, by name", body = Vec<SegmentSummary>),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The role lacks the permission", body = Problem, content_type = "application/problem+json")
+    )
+)]
+#[tracing::instrument(name = "Listing segments", skip(pool))]
+pub async fn get_segments(pool: web::Data<PgPool>) -> HttpResponse {
+    let segments = sqlx::query_as!(
+        SegmentSummary,
+        "SELECT segment_id, name, filter FROM segments ORDER BY name"
+    )
+    .fetch_all(pool.get_ref())
+    .await;
+    match segments {
+        Ok(segments) => HttpResponse::Ok().json(segments),
+        Err(e) => {
+            tracing::error!("Failed to execute query: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+pub struct Segment {
+    pub segment_id: Uuid,
+    pub filter: SegmentFilter,
+}
+
+/// Looks up a saved segment by name. Stored filters were validated when the
+/// segment was saved, so failing to parse one again is reported as an error.
+pub async fn get_segment(pool: &PgPool, name: &str) -> Result<Option<Segment>, sqlx::Error> {
+    let row = sqlx::query!(
+        "SELECT segment_id, filter FROM segments WHERE name = $1",
+        name
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    row.map(|r| {
+        SegmentFilter::parse(&r.filter).map(|filter| Segment {
+            segment_id: r.segment_id,
+            filter,
+        })
+    })
+    .transpose()
+    .map_err(|e| sqlx::Error::Decode(e.into()))
+}
+
+pub async fn get_segment_by_id(
+    pool: &PgPool,
+    segment_id: Uuid,
+) -> Result<Option<Segment>, sqlx::Error> {
+    let row = sqlx::query!(
+        "SELECT segment_id, filter FROM segments WHERE segment_id = $1",
+        segment_id
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    row.map(|r| {
+        SegmentFilter::parse(&r.filter).map(|filter| Segment {
+            segment_id: r.segment_id,
+            filter,
+        })
+    })
+    .transpose()
+    .map_err(|e| sqlx::Error::Decode(e.into()))
+}
+
+async fn count_matching_subscribers(
+    pool: &PgPool,
+    filter: &SegmentFilter,
+) -> Result<i64, sqlx::Error> {
+    let mut query = QueryBuilder::<Postgres>::new("SELECT COUNT(*) FROM subscriptions s WHERE ");
+    filter.push_sql(&mut query);
+    let row = query.build().fetch_one(pool).await.map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    row.try_get(0)
+}
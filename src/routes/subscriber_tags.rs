@@ -0,0 +1,115 @@
+use actix_web::{web, HttpResponse};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::domain::SubscriberTag;
+use crate::routes::Problem;
+
+#[derive(serde::Deserialize, Debug, utoipa::ToSchema)]
+pub struct TagsData {
+    tags: Vec<String>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/subscribers/{subscriber_id}/tags",
+    tag = "subscribers",
+    security(("session" = [])),
+    params(("subscriber_id" = Uuid, Path)),
+    request_body = TagsData,
+    responses(
+        (status = 200, description = "The subscriber has the tags"),
+        (status = 400, description = "Invalid tag", body = String, content_type = "text/plain"),
+        (status = 404, description = "Unknown subscriber"),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The role lacks the permission", body = Problem, content_type = "application/problem+json")
+    )
+)]
+#[tracing::instrument(name = "Tagging a subscriber", skip(body, pool))]
+pub async fn add_subscriber_tags(
+    subscriber_id: web::Path<Uuid>,
+    body: web::Json<TagsData>,
+    pool: web::Data<PgPool>,
+) -> HttpResponse {
+    let tags = match body
+        .into_inner()
+        .tags
+        .into_iter()
+        .map(SubscriberTag::parse)
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(tags) => tags,
+        Err(err) => return HttpResponse::BadRequest().body(err),
+    };
+    let tags: Vec<String> = tags.iter().map(|t| t.as_ref().to_owned()).collect();
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO subscriber_tags (subscriber_id, tag)
+        SELECT id, tag FROM subscriptions, UNNEST($2::text[]) AS tag
+        WHERE id = $1
+        ON CONFLICT (subscriber_id, tag) DO NOTHING
+        "#,
+        *subscriber_id,
+        &tags[..]
+    )
+    .execute(pool.get_ref())
+    .await;
+    match result {
+        Ok(_) => match subscriber_exists(&pool, *subscriber_id).await {
+            Ok(true) => HttpResponse::Ok().finish(),
+            Ok(false) => HttpResponse::NotFound().finish(),
+            Err(_) => HttpResponse::InternalServerError().finish(),
+        },
+        Err(e) => {
+            tracing::error!("Failed to execute query: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/admin/subscribers/{subscriber_id}/tags/{tag}",
+    tag = "subscribers",
+    security(("session" = [])),
+    params(("subscriber_id" = Uuid, Path), ("tag" = String, Path)),
+    responses(
+        (status = 200, description = "The tag was removed"),
+        (status = 404, description = "Unknown subscriber or tag"),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The role lacks the permission", body = Problem, content_type = "application/problem+json")
+    )
+)]
+#[tracing::instrument(name = "Removing a subscriber tag", skip(pool))]
+pub async fn remove_subscriber_tag(
+    path: web::Path<(Uuid, String)>,
+    pool: web::Data<PgPool>,
+) -> HttpResponse {
+    let (subscriber_id, tag) = path.into_inner();
+    let result = sqlx::query!(
+        "DELETE FROM subscriber_tags WHERE subscriber_id = $1 AND tag = $2",
+        subscriber_id,
+        tag
+    )
+    .execute(pool.get_ref())
+    .await;
+    match result {
+        Ok(done) if done.rows_affected() == 0 => HttpResponse::NotFound().finish(),
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(e) => {
+            tracing::error!("Failed to execute query: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+async fn subscriber_exists(pool: &PgPool, subscriber_id: Uuid) -> Result<bool, sqlx::Error> {
+    let row = sqlx::query!("SELECT id FROM subscriptions WHERE id = $1", subscriber_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+    Ok(row.is_some())
+}
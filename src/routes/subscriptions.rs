@@ -1,64 +1,634 @@
-use std::println;
+use std::collections::HashMap;
 
-use actix_web::{web, HttpResponse};
+use actix_web::http::header::{self, ContentType};
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
 use serde;
-use sqlx::PgPool;
-use chrono::Utc;
+use sqlx::{PgPool, Postgres, Transaction};
+use chrono::{Duration, Utc};
 use uuid::Uuid;
 use tracing;
-use crate::domain::{SubscriberName,NewSubscriber, SubscriberEmail};
+use crate::configuration::{RiskyEmailPolicy, SubscriptionSettings};
+use crate::domain_verification::DomainVerifier;
+use crate::domain::{AttributeSchema, ListSlug, SubscriberName,NewSubscriber, NewSubscriberError, RiskyEmailLists, SubscriberEmail};
 use crate::email_client::EmailClient; 
+use crate::email_template::escape_html;
+use crate::signup_protection::{spend_form_token, CheckedFormToken, SignupChallenge, SpamCheckError, SpamFields};
+use crate::routes::{get_list, html_page, FieldError, Problem, ValidationProblem};
+use crate::startup::ApplicationBaseUrl;
+use crate::tokens::generate_token;
 
-#[derive(serde::Deserialize)]
-#[derive(Debug)]
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+#[derive(Debug, Default)]
 pub struct FormData {
+    /// Missing fields are reported like empty ones.
+    #[serde(default)]
+    email: String,
+    #[serde(default)]
+    name: String,
+    /// Slug of the list to join, the default list when omitted.
+    list: Option<String>,
+    /// From `GET /subscriptions/challenge`, when the environment checks it.
+    form_token: Option<String>,
+    /// The nonce solving the proof of work, when the environment asks for one.
+    proof_of_work: Option<String>,
+    /// `true` signs up with an address whose domain looks like a typo.
+    keep_email: Option<String>,
+    /// Any other field, validated against the attribute schema of the list.
+    #[serde(flatten)]
+    #[schema(additional_properties)]
+    attributes: HashMap<String, String>,
+}
+
+/// The JSON flavour of `FormData`, custom attributes are nested and may be
+/// numbers.
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+#[derive(Debug)]
+pub struct JsonData {
+    #[serde(default)]
     email: String,
+    #[serde(default)]
     name: String,
+    list: Option<String>,
+    form_token: Option<String>,
+    proof_of_work: Option<String>,
+    #[serde(default)]
+    keep_email: bool,
+    #[serde(default)]
+    #[schema(value_type = HashMap<String, Object>)]
+    attributes: HashMap<String, serde_json::Value>,
+}
+
+impl From<JsonData> for FormData {
+    fn from(data: JsonData) -> Self {
+        let attributes = data
+            .attributes
+            .into_iter()
+            .map(|(name, value)| match value {
+                serde_json::Value::String(value) => (name, value),
+                other => (name, other.to_string()),
+            })
+            .collect();
+        let keep_email = data.keep_email.then(|| "true".to_owned());
+        Self { email: data.email, name: data.name, list: data.list, form_token: data.form_token, proof_of_work: data.proof_of_work, keep_email, attributes }
+    }
+}
+
+/// The same for every accepted signup, so the response does not tell
+/// whether an address is already subscribed.
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct SubscriptionResponse {
+    message: &'static str,
+}
+
+const CHECK_YOUR_INBOX: &str = "We sent you an email to confirm your subscription.";
+
+/// Signup bodies are small, the default limit of `web::Bytes` is 256 KB.
+pub const SIGNUP_PAYLOAD_LIMIT: usize = 16 * 1024;
+
+/// A signup that passed validation.
+struct ValidSignup {
+    new_subscriber: NewSubscriber,
+    list_id: Uuid,
+    flagged_as: Option<&'static str>,
 }
 
+/// How the signup was posted, which also decides how we answer.
+#[derive(Clone, Copy, PartialEq)]
+enum Client {
+    Html,
+    Form,
+    Json,
+}
+
+#[utoipa::path(
+    post,
+    path = "/subscriptions",
+    tag = "subscriptions",
+    request_body(content(
+        (FormData = "application/x-www-form-urlencoded"),
+        (JsonData = "application/json")
+    )),
+    responses(
+        (status = 200, description = "A confirmation email is on its way, unless the address already confirmed the list. Only JSON requests get a body back.", body = SubscriptionResponse),
+        (status = 400, description = "The signup did not validate, or failed the spam checks", body = ValidationProblem, content_type = "application/problem+json"),
+        (status = 413, description = "The body is larger than 16 KB"),
+        (status = 415, description = "The body is neither a form nor JSON"),
+        (status = 429, description = "Too many signups from the address or for the email domain, retry after the `Retry-After` seconds", body = Problem, content_type = "application/problem+json")
+    )
+)]
 #[tracing::instrument(
     name = "Saving new subscriber details in the database",
-    skip( form,pool,email_client)
+    skip( request,body,pool,email_client,base_url,settings,domain_verifier,risky_email_lists)
     fields(
-        subscriber_email = %form.email,
-        subscriber_name = %form.name
+        subscriber_email = tracing::field::Empty,
+        subscriber_name = tracing::field::Empty
     )
 )]
-pub async fn subscribe(form:web::Form<FormData>,pool:web::Data<PgPool>, email_client:web::Data<EmailClient>) ->HttpResponse{
-    let name = match SubscriberName::parse(form.0.name){
-        Ok(name) => name,
-        Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+#[allow(clippy::too_many_arguments)]
+pub async fn subscribe(request: HttpRequest, body: web::Bytes, pool:web::Data<PgPool>, email_client:web::Data<EmailClient>, base_url: web::Data<ApplicationBaseUrl>, settings: web::Data<SubscriptionSettings>, domain_verifier: web::Data<dyn DomainVerifier>, risky_email_lists: web::Data<RiskyEmailLists>) ->HttpResponse{
+    let mime_type = match request.mime_type() {
+        Ok(Some(mime_type)) => mime_type,
+        _ => return HttpResponse::UnsupportedMediaType().finish(),
+    };
+    let (form, client) = match mime_type.essence_str() {
+        "application/x-www-form-urlencoded" => {
+            let client = if accepts_html(&request) { Client::Html } else { Client::Form };
+            (serde_urlencoded::from_bytes::<FormData>(&body).map_err(|e| e.to_string()), client)
+        }
+        "application/json" => (serde_json::from_slice::<JsonData>(&body).map(FormData::from).map_err(|e| e.to_string()), Client::Json),
+        _ => return HttpResponse::UnsupportedMediaType().finish(),
     };
-    let email = match SubscriberEmail::parse(form.0.email) {
-        Ok(email) => email,
-        Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+    let mut form = match form {
+        Ok(form) => form,
+        Err(e) => return ValidationProblem::new(vec![FieldError::new("body", "malformed", e)]).error_response(),
     };
-    let new_subscriber = NewSubscriber { email, name } ;
+    let span = tracing::Span::current();
+    span.record("subscriber_email", tracing::field::display(&form.email));
+    span.record("subscriber_name", tracing::field::display(&form.name));
+    let form_token = match check_spam(&mut form, &settings) {
+        Ok(form_token) => form_token,
+        Err(e) => return reject_spam(&e, &form, client, &settings),
+    };
+
+    let signup = match validate_signup(&form, &pool, &settings, domain_verifier.get_ref(), &risky_email_lists).await {
+        Ok(Ok(signup)) => signup,
+        Ok(Err(problem)) if client == Client::Html => return signup_form_page(&form, &problem, &settings),
+        Ok(Err(problem)) => return problem.error_response(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    // Spent once the signup is valid, API clients can fix a typo and resend.
+    if let Some(form_token) = form_token {
+        match spend_form_token(&pool, &form_token).await {
+            Ok(true) => {}
+            Ok(false) => return reject_spam(&SpamCheckError::FormTokenUsed, &form, client, &settings),
+            Err(_) => return HttpResponse::InternalServerError().finish(),
+        }
+    }
+    let new_subscriber = signup.new_subscriber;
 
-    match insert_subscriber(&pool, &new_subscriber).await {
-       Ok(_) => HttpResponse::Ok().finish(),
-       Err(err) => {
-            HttpResponse::Ok().finish()
-       },
+    let subscription_token = match register_subscriber(&pool, &new_subscriber, signup.list_id, signup.flagged_as, &settings).await {
+        Ok(SubscriptionOutcome::AlreadyConfirmed { .. }) => return signup_response(client),
+        Ok(SubscriptionOutcome::PendingConfirmation { subscription_token, .. }) => subscription_token,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
     };
-    if email_client.send_email(&new_subscriber.email, "welcom", "welcoem", "welcome").await.is_err(){
-        println!("error is atsend email");
+    if send_confirmation_email(&email_client, &new_subscriber.email, &base_url.0, &subscription_token).await.is_err(){
+        return HttpResponse::InternalServerError().finish();
     }
-    HttpResponse::Ok().finish()
+    signup_response(client)
 }
 
-pub async fn insert_subscriber(pool:&PgPool, new_subscriber: &NewSubscriber,) -> Result<(), sqlx::Error>{
-    let request_id = Uuid::new_v4();
-    sqlx::query!(
+/// The honeypot, form token and proof of work checks. The honeypot is
+/// parsed as a custom attribute, it is taken out before those are validated.
+fn check_spam(form: &mut FormData, settings: &SubscriptionSettings) -> Result<Option<CheckedFormToken>, SpamCheckError> {
+    let honeypot = settings.spam_protection.take_honeypot(&mut form.attributes);
+    let fields = SpamFields {
+        email: &form.email,
+        form_token: form.form_token.as_deref(),
+        proof_of_work: form.proof_of_work.as_deref(),
+        honeypot: honeypot.as_deref(),
+    };
+    settings.spam_protection.check(&fields, Utc::now())
+}
+
+fn reject_spam(e: &SpamCheckError, form: &FormData, client: Client, settings: &SubscriptionSettings) -> HttpResponse {
+    tracing::warn!(reason = e.code(), "Rejecting a signup as spam");
+    let problem = ValidationProblem::new(vec![FieldError::new(e.field(), e.code(), e.to_string())]);
+    match client {
+        Client::Html => signup_form_page(form, &problem, settings),
+        _ => problem.error_response(),
+    }
+}
+
+/// The signup form for browsers.
+#[tracing::instrument(name = "Showing the signup form", skip(settings))]
+pub async fn signup_form(settings: web::Data<SubscriptionSettings>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(html_page("Subscribe", &signup_form_html(&FormData::default(), None, &settings)))
+}
+
+/// For signup forms served from elsewhere and for API clients: a fresh form
+/// token along with what the spam checks expect.
+#[utoipa::path(
+    get,
+    path = "/subscriptions/challenge",
+    tag = "subscriptions",
+    responses((status = 200, description = "Send `form_token`, the solved `proof_of_work` and an empty honeypot field along with the signup", body = SignupChallenge))
+)]
+#[tracing::instrument(name = "Handing out a signup challenge", skip(settings))]
+pub async fn signup_challenge(settings: web::Data<SubscriptionSettings>) -> HttpResponse {
+    HttpResponse::Ok().json(settings.spam_protection.challenge(Utc::now()))
+}
+
+/// Browsers posting the signup form get HTML back, API clients JSON.
+fn accepts_html(request: &HttpRequest) -> bool {
+    request
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .map(|accept| accept.contains("text/html"))
+        .unwrap_or(false)
+}
+
+/// The same answer whatever the signup led to.
+fn signup_response(client: Client) -> HttpResponse {
+    match client {
+        Client::Json => HttpResponse::Ok().json(SubscriptionResponse { message: CHECK_YOUR_INBOX }),
+        Client::Form => HttpResponse::Ok().finish(),
+        Client::Html => HttpResponse::Ok().content_type(ContentType::html()).body(html_page(
+            "Check your inbox",
+            &format!("<p>{}</p>", CHECK_YOUR_INBOX),
+        )),
+    }
+}
+
+/// Checks every field of the signup, reporting all the problems at once
+/// rather than stopping at the first one.
+async fn validate_signup(form: &FormData, pool: &PgPool, settings: &SubscriptionSettings, domain_verifier: &dyn DomainVerifier, risky_email_lists: &RiskyEmailLists) -> Result<Result<ValidSignup, ValidationProblem>, sqlx::Error>{
+    let mut errors = Vec::new();
+    let name = SubscriberName::parse(form.name.clone())
+        .map_err(|e| errors.push(FieldError::new("name", e.code(), e.to_string())))
+        .ok();
+    let mut flagged_as = None;
+    let email = match SubscriberEmail::parse(form.email.clone()) {
+        Ok(email) => match check_email(&email, form.keep_email.as_deref() == Some("true"), settings, domain_verifier, risky_email_lists).await {
+            Ok(flag) => {
+                flagged_as = flag;
+                Some(email)
+            }
+            Err(error) => {
+                errors.push(error);
+                None
+            }
+        },
+        Err(e) => {
+            errors.push(FieldError::new("email", e.code(), e.to_string()));
+            None
+        }
+    };
+    let list = form.list.clone().map(ListSlug::parse).transpose()
+        .map_err(|e| errors.push(FieldError::new("list", "invalid", e)))
+        .ok()
+        .map(Option::unwrap_or_default);
+    let mut mailing_list = None;
+    if let Some(list) = &list {
+        match get_list(pool, list).await? {
+            Some(found) => mailing_list = Some(found),
+            None => errors.push(FieldError::new("list", "unknown", format!("{} is not a known list.", list.as_ref()))),
+        }
+    }
+    let attributes = mailing_list.as_ref().and_then(|mailing_list| {
+        mailing_list.attribute_schema.validate(form.attributes.clone())
+            .map_err(|e| errors.push(FieldError::new("attributes", "invalid", e)))
+            .ok()
+    });
+    match (name, email, list, mailing_list, attributes) {
+        (Some(name), Some(email), Some(list), Some(mailing_list), Some(attributes)) if errors.is_empty() => Ok(Ok(ValidSignup {
+            new_subscriber: NewSubscriber { email, name, list, attributes },
+            list_id: mailing_list.list_id,
+            flagged_as,
+        })),
+        _ => Ok(Err(ValidationProblem::new(errors))),
+    }
+}
+
+/// Typo, risk and domain checks on a syntactically valid email. Returns
+/// what the subscriber should be flagged as, if anything. A likely typo is
+/// only reported until the subscriber says to `keep_email` as typed.
+pub async fn check_email(email: &SubscriberEmail, keep_email: bool, settings: &SubscriptionSettings, domain_verifier: &dyn DomainVerifier, risky_email_lists: &RiskyEmailLists) -> Result<Option<&'static str>, FieldError>{
+    if let Some(suggestion) = email.suggestion().filter(|_| !keep_email) {
+        let mut error = FieldError::new("email", "typo", format!("{} looks like a typo.", email.domain()));
+        error.did_you_mean = Some(suggestion);
+        return Err(error);
+    }
+    let flagged_as = match email.check_risk(risky_email_lists) {
+        Ok(()) => None,
+        Err(risk) => match settings.risky_email_policy(&risk) {
+            RiskyEmailPolicy::Reject => return Err(FieldError::new("email", risk.as_str(), risk.to_string())),
+            RiskyEmailPolicy::Flag => {
+                tracing::warn!(flagged_as = risk.as_str(), "Flagging a risky signup");
+                Some(risk.as_str())
+            }
+            RiskyEmailPolicy::Allow => None,
+        },
+    };
+    if let Err(err) = verify_domain(domain_verifier, email).await {
+        return Err(FieldError::new("email", "no_mail_server", err));
+    }
+    Ok(flagged_as)
+}
+
+/// Renders the signup form again with the submitted values and an error next
+/// to each invalid field.
+fn signup_form_page(form: &FormData, problem: &ValidationProblem, settings: &SubscriptionSettings) -> HttpResponse {
+    HttpResponse::BadRequest()
+        .content_type(ContentType::html())
+        .body(html_page("Subscribe", &signup_form_html(form, Some(problem), settings)))
+}
+
+/// Solves the proof of work when the form is submitted, `DIFFICULTY` is
+/// replaced with the number of leading zero bits.
+const PROOF_OF_WORK_SCRIPT: &str = r#"<script>
+document.getElementById("signup-form").addEventListener("submit", async (event) => {
+    const form = event.target;
+    const nonce = form.elements["proof_of_work"];
+    if (nonce.value) return;
+    event.preventDefault();
+    const challenge = form.elements["form_token"].value + ":" + form.elements["email"].value.trim().toLowerCase();
+    const encoder = new TextEncoder();
+    for (let candidate = 0; ; candidate++) {
+        const hash = new Uint8Array(await crypto.subtle.digest("SHA-256", encoder.encode(challenge + ":" + candidate)));
+        let bits = 0;
+        for (const byte of hash) {
+            bits += Math.clz32(byte) - 24;
+            if (byte !== 0) break;
+        }
+        if (bits >= DIFFICULTY) {
+            nonce.value = candidate;
+            break;
+        }
+    }
+    form.submit();
+});
+</script>"#;
+
+fn signup_form_html(form: &FormData, problem: Option<&ValidationProblem>, settings: &SubscriptionSettings) -> String {
+    let error_for = |field: &str| {
+        problem
+            .map(ValidationProblem::errors)
+            .unwrap_or_default()
+            .iter()
+            .filter(|error| error.field == field)
+            .map(|error| match &error.did_you_mean {
+                Some(suggestion) => format!(
+                    r#"<p class="error">Did you mean {}?</p>
+    <label><input type="checkbox" name="keep_email" value="true" /> No, {} is right</label><br />"#,
+                    escape_html(suggestion),
+                    escape_html(&form.email)
+                ),
+                None => format!(r#"<p class="error">{}</p>"#, escape_html(&error.message)),
+            })
+            .collect::<String>()
+    };
+    let mut attributes: Vec<_> = form.attributes.iter().collect();
+    attributes.sort();
+    let attribute_inputs: String = attributes
+        .into_iter()
+        .map(|(name, value)| {
+            format!(
+                r#"<label>{0} <input type="text" name="{0}" value="{1}" /></label><br />"#,
+                escape_html(name),
+                escape_html(value)
+            )
+        })
+        .collect();
+    let list_input = form
+        .list
+        .as_ref()
+        .map(|list| format!(r#"<input type="hidden" name="list" value="{}" />"#, escape_html(list)))
+        .unwrap_or_default();
+    let challenge = settings.spam_protection.challenge(Utc::now());
+    let form_token_input = challenge
+        .form_token
+        .map(|token| format!(r#"<input type="hidden" name="form_token" value="{}" />"#, escape_html(&token)))
+        .unwrap_or_default();
+    let proof_of_work = if challenge.proof_of_work_difficulty > 0 {
+        (
+            r#"<input type="hidden" name="proof_of_work" value="" />"#,
+            PROOF_OF_WORK_SCRIPT.replace("DIFFICULTY", &challenge.proof_of_work_difficulty.to_string()),
+        )
+    } else {
+        ("", String::new())
+    };
+    format!(
+        r#"<form id="signup-form" action="/subscriptions" method="post">
+    {form_token_input}{proof_of_work_input}{form_error}
+    <div style="position: absolute; left: -10000px;" aria-hidden="true">
+        <label>Leave this empty <input type="text" name="{honeypot}" value="" tabindex="-1" autocomplete="off" /></label>
+    </div>
+    {list_input}{list_error}
+    <label>Name <input type="text" name="name" value="{name}" /></label><br />
+    {name_error}
+    <label>Email <input type="email" name="email" value="{email}" /></label><br />
+    {email_error}
+    {attribute_inputs}{attributes_error}
+    <button type="submit">Subscribe</button>
+</form>{proof_of_work_script}"#,
+        proof_of_work_input = proof_of_work.0,
+        proof_of_work_script = proof_of_work.1,
+        form_error = [error_for("form"), error_for("form_token"), error_for("proof_of_work")].concat(),
+        honeypot = escape_html(&challenge.honeypot_field),
+        list_error = error_for("list"),
+        name = escape_html(&form.name),
+        name_error = error_for("name"),
+        email = escape_html(&form.email),
+        email_error = error_for("email"),
+        attributes_error = error_for("attributes"),
+    )
+}
+
+/// Rejects domains that cannot receive email. Lookups that fail are let
+/// through, a flaky resolver should not block signups.
+pub async fn verify_domain(domain_verifier: &dyn DomainVerifier, email: &SubscriberEmail) -> Result<(), String>{
+    match domain_verifier.accepts_mail(email.domain()).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(format!("{} does not accept email.", email.domain())),
+        Err(e) => {
+            tracing::warn!(error.cause_chain = ?e, "Failed to verify the email domain");
+            Ok(())
+        }
+    }
+}
+
+/// What a signup led to: confirmed members of the list are not asked to
+/// confirm again, everybody else gets a (new) confirmation email.
+pub enum SubscriptionOutcome {
+    AlreadyConfirmed { subscriber_id: Uuid },
+    PendingConfirmation { subscriber_id: Uuid, subscription_token: String },
+}
+
+/// Records a signup for `list_id`, whether the email is new, still waiting
+/// for confirmation, already confirmed or previously unsubscribed. Emails
+/// are matched on their canonical form, `flagged_as` is only recorded for new
+/// subscribers.
+///
+/// Joining a list always takes a confirmation, also for confirmed
+/// subscribers joining another list or coming back to one they left.
+pub async fn register_subscriber(pool:&PgPool, new_subscriber: &NewSubscriber, list_id: Uuid, flagged_as: Option<&str>, settings: &SubscriptionSettings) -> Result<SubscriptionOutcome, sqlx::Error>{
+    let canonical_email = new_subscriber.email.canonical(settings.fold_email_aliases);
+    let token_ttl = settings.confirmation_token_ttl();
+    let mut transaction = pool.begin().await?;
+    if let Some(subscriber_id) = insert_subscriber(&mut transaction, new_subscriber, &canonical_email, flagged_as).await? {
+        insert_membership(&mut transaction, list_id, subscriber_id, "pending_confirmation").await?;
+        let subscription_token = generate_token();
+        store_token(&mut transaction, subscriber_id, &subscription_token, token_ttl).await?;
+        transaction.commit().await?;
+        return Ok(SubscriptionOutcome::PendingConfirmation { subscriber_id, subscription_token });
+    }
+    // The address is taken, possibly by a signup that raced this one and
+    // was committed in the meantime.
+    let subscriber = sqlx::query!(
+        "SELECT id, status FROM subscriptions WHERE canonical_email = $1 FOR UPDATE",
+        canonical_email)
+        .fetch_one(&mut transaction)
+        .await
+        .map_err(|e|{
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+    let outcome = match subscriber.status.as_str() {
+        "confirmed" | "pending_confirmation" => {
+            merge_attributes(&mut transaction, subscriber.id, new_subscriber).await?;
+            let joined = insert_membership(&mut transaction, list_id, subscriber.id, "pending_confirmation").await?;
+            if joined || subscriber.status == "pending_confirmation" {
+                let subscription_token = pending_token(&mut transaction, subscriber.id, token_ttl).await?;
+                SubscriptionOutcome::PendingConfirmation { subscriber_id: subscriber.id, subscription_token }
+            } else {
+                SubscriptionOutcome::AlreadyConfirmed { subscriber_id: subscriber.id }
+            }
+        }
+        _ => {
+            // Unsubscribed readers start over with a fresh double opt-in.
+            sqlx::query!(
+                r#"
+                UPDATE subscriptions
+                SET status = 'pending_confirmation', name = $2, attributes = attributes || $3
+                WHERE id = $1
+                "#,
+                subscriber.id,
+                new_subscriber.name.as_ref(),
+                new_subscriber.attributes.to_json())
+                .execute(&mut transaction)
+                .await
+                .map_err(|e|{
+                    tracing::error!("Failed to execute query: {:?}", e);
+                    e
+                })?;
+            insert_membership(&mut transaction, list_id, subscriber.id, "pending_confirmation").await?;
+            sqlx::query!("DELETE FROM subscription_tokens WHERE subscriber_id = $1", subscriber.id)
+                .execute(&mut transaction)
+                .await
+                .map_err(|e|{
+                    tracing::error!("Failed to execute query: {:?}", e);
+                    e
+                })?;
+            let subscription_token = generate_token();
+            store_token(&mut transaction, subscriber.id, &subscription_token, token_ttl).await?;
+            SubscriptionOutcome::PendingConfirmation { subscriber_id: subscriber.id, subscription_token }
+        }
+    };
+    transaction.commit().await?;
+    Ok(outcome)
+}
+
+/// The unexpired token of a pending subscriber, so repeated signups resend
+/// the same link, or a new one.
+async fn pending_token(transaction: &mut Transaction<'_, Postgres>, subscriber_id: Uuid, token_ttl: Duration) -> Result<String, sqlx::Error>{
+    if let Some(subscription_token) = get_unexpired_token(transaction, subscriber_id).await? {
+        return Ok(subscription_token);
+    }
+    let subscription_token = generate_token();
+    store_token(transaction, subscriber_id, &subscription_token, token_ttl).await?;
+    Ok(subscription_token)
+}
+
+/// Stores the email as typed, along with the canonical form used to spot
+/// duplicates. Returns `None` if the canonical form is taken. The conflict
+/// target is left out on purpose: a racing signup of the exact same address
+/// may trip the unique index on `email` first.
+async fn insert_subscriber(transaction: &mut Transaction<'_, Postgres>, new_subscriber: &NewSubscriber, canonical_email: &str, flagged_as: Option<&str>) -> Result<Option<Uuid>, sqlx::Error>{
+    let inserted = sqlx::query!(
         r#"
-        INSERT INTO subscriptions (id, email, name, subscribed_at, status)
-        VALUES ($1, $2, $3, $4, 'confirmed')
+        INSERT INTO subscriptions (id, email, canonical_email, name, subscribed_at, status, attributes, flagged_as)
+        VALUES ($1, $2, $3, $4, $5, 'pending_confirmation', $6, $7)
+        ON CONFLICT DO NOTHING
+        RETURNING id
         "#,
-        request_id,
-        new_subscriber.email.as_ref(),
+        Uuid::new_v4(),
+        new_subscriber.email.display(),
+        canonical_email,
         new_subscriber.name.as_ref(),
+        Utc::now(),
+        new_subscriber.attributes.to_json(),
+        flagged_as)
+        .fetch_optional(&mut *transaction)
+        .await
+        .map_err(|e|{
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+    Ok(inserted.map(|r| r.id))
+}
+
+async fn merge_attributes(transaction: &mut Transaction<'_, Postgres>, subscriber_id: Uuid, new_subscriber: &NewSubscriber) -> Result<(), sqlx::Error>{
+    sqlx::query!(
+        "UPDATE subscriptions SET attributes = attributes || $2 WHERE id = $1",
+        subscriber_id,
+        new_subscriber.attributes.to_json())
+        .execute(&mut *transaction)
+        .await
+        .map_err(|e|{
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+    Ok(())
+}
+
+/// Adds the subscriber to a list, memberships that are already confirmed are
+/// left alone. Returns whether the membership was added or changed.
+async fn insert_membership(transaction: &mut Transaction<'_, Postgres>, list_id: Uuid, subscriber_id: Uuid, status: &str) -> Result<bool, sqlx::Error>{
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO list_memberships (list_id, subscriber_id, status, subscribed_at)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (list_id, subscriber_id) DO UPDATE
+        SET status = EXCLUDED.status, subscribed_at = EXCLUDED.subscribed_at
+        WHERE list_memberships.status <> 'confirmed'
+        "#,
+        list_id,
+        subscriber_id,
+        status,
         Utc::now())
-        .execute(pool)
+        .execute(&mut *transaction)
+        .await
+        .map_err(|e|{
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+    Ok(result.rows_affected() > 0)
+}
+
+async fn get_unexpired_token(transaction: &mut Transaction<'_, Postgres>, subscriber_id: Uuid) -> Result<Option<String>, sqlx::Error>{
+    let row = sqlx::query!(
+        r#"
+        SELECT subscription_token FROM subscription_tokens
+        WHERE subscriber_id = $1 AND expires_at > $2
+        ORDER BY expires_at DESC
+        LIMIT 1
+        "#,
+        subscriber_id,
+        Utc::now())
+        .fetch_optional(&mut *transaction)
+        .await
+        .map_err(|e|{
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+    Ok(row.map(|r| r.subscription_token))
+}
+
+pub async fn store_token(transaction: &mut Transaction<'_, Postgres>, subscriber_id: Uuid, subscription_token: &str, token_ttl: Duration) -> Result<(), sqlx::Error>{
+    let created_at = Utc::now();
+    sqlx::query!(
+        r#"
+        INSERT INTO subscription_tokens (subscription_token, subscriber_id, created_at, expires_at)
+        VALUES ($1, $2, $3, $4)
+        "#,
+        subscription_token,
+        subscriber_id,
+        created_at,
+        created_at + token_ttl)
+        .execute(&mut *transaction)
         .await
         .map_err(|e|{
             tracing::error!("Failed to execute query: {:?}", e);
@@ -67,12 +637,47 @@ pub async fn insert_subscriber(pool:&PgPool, new_subscriber: &NewSubscriber,) ->
     Ok(())
 }
 
+#[tracing::instrument(
+    name = "Send a confirmation email to a new subscriber",
+    skip(email_client, recipient, base_url, subscription_token)
+)]
+pub async fn send_confirmation_email(
+    email_client: &EmailClient,
+    recipient: &SubscriberEmail,
+    base_url: &str,
+    subscription_token: &str,
+) -> Result<(), reqwest::Error> {
+    let confirmation_link = format!(
+        "{}/subscriptions/confirm?subscription_token={}",
+        base_url, subscription_token
+    );
+    let html_body = format!(
+        "Welcome to our newsletter!<br />Click <a href=\"{}\">here</a> to confirm your subscription.",
+        confirmation_link
+    );
+    let text_body = format!(
+        "Welcome to our newsletter!\nVisit {} to confirm your subscription.",
+        confirmation_link
+    );
+    email_client
+        .send_email(recipient, "Welcome!", &html_body, &text_body)
+        .await
+        .map_err(|e| {
+            tracing::error!(error.cause_chain = ?e, "Failed to send a confirmation email");
+            e
+        })
+}
+
+/// Extra fields are checked against an empty schema, use the list's
+/// `AttributeSchema` directly to accept custom attributes.
 impl TryFrom<FormData> for NewSubscriber{
-    type Error = String;
+    type Error = NewSubscriberError;
     fn try_from(value: FormData) -> Result<Self, Self::Error> {
         let name = SubscriberName::parse(value.name)?;
         let email = SubscriberEmail::parse(value.email)?;
-        Ok(Self{name, email})
+        let list = value.list.map(ListSlug::parse).transpose().map_err(NewSubscriberError::List)?.unwrap_or_default();
+        let attributes = AttributeSchema::default().validate(value.attributes).map_err(NewSubscriberError::Attributes)?;
+        Ok(Self{name, email, list, attributes})
     }
 }
 
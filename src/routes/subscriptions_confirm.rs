@@ -0,0 +1,254 @@
+use actix_web::http::header::ContentType;
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::configuration::SubscriptionSettings;
+use crate::domain::SubscriberEmail;
+use crate::email_client::EmailClient;
+use crate::email_template::escape_html;
+use crate::routes::{html_page, send_confirmation_email, store_token};
+use crate::startup::ApplicationBaseUrl;
+use crate::tokens::generate_token;
+
+#[derive(serde::Deserialize, Debug, utoipa::IntoParams, utoipa::ToSchema)]
+#[into_params(parameter_in = Query)]
+pub struct Parameters {
+    subscription_token: String,
+}
+
+#[utoipa::path(
+    get,
+    path = "/subscriptions/confirm",
+    tag = "subscriptions",
+    params(Parameters),
+    responses(
+        (status = 200, description = "The subscription is confirmed", content_type = "text/html"),
+        (status = 401, description = "Unknown or purged token, the page points to the signup form", content_type = "text/html"),
+        (status = 410, description = "The link expired, the page offers to send a new one", content_type = "text/html")
+    )
+)]
+#[tracing::instrument(name = "Confirm a pending subscriber", skip(parameters, pool))]
+pub async fn confirm(parameters: web::Query<Parameters>, pool: web::Data<PgPool>) -> HttpResponse {
+    let token = match get_subscription_token(&pool, &parameters.subscription_token).await {
+        Ok(Some(token)) => token,
+        Ok(None) => return unknown_link_page(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    if token.expires_at < Utc::now() {
+        return expired_link_page(&parameters.subscription_token);
+    }
+    if confirm_subscriber(&pool, token.subscriber_id)
+        .await
+        .is_err()
+    {
+        return HttpResponse::InternalServerError().finish();
+    }
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(html_page(
+            "Subscription confirmed",
+            "<p>Thanks for confirming, the next issue is on its way.</p>",
+        ))
+}
+
+/// Sends a fresh confirmation link to whoever the (usually expired) token
+/// was issued to, as long as they still have to confirm.
+#[utoipa::path(
+    post,
+    path = "/subscriptions/confirm/resend",
+    tag = "subscriptions",
+    request_body(content = Parameters, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 200, description = "A new link was sent, or there is nothing left to confirm", content_type = "text/html"),
+        (status = 401, description = "Unknown or purged token, the page points to the signup form", content_type = "text/html")
+    )
+)]
+#[tracing::instrument(
+    name = "Resend a confirmation email",
+    skip(form, pool, email_client, base_url, settings)
+)]
+pub async fn resend_confirmation(
+    form: web::Form<Parameters>,
+    pool: web::Data<PgPool>,
+    email_client: web::Data<EmailClient>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    settings: web::Data<SubscriptionSettings>,
+) -> HttpResponse {
+    let token = match get_subscription_token(&pool, &form.subscription_token).await {
+        Ok(Some(token)) => token,
+        Ok(None) => return unknown_link_page(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    let pending = match get_pending_email(&pool, token.subscriber_id).await {
+        Ok(pending) => pending,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    let email = match pending.map(SubscriberEmail::parse).transpose() {
+        Ok(Some(email)) => email,
+        Ok(None) => {
+            return HttpResponse::Ok()
+                .content_type(ContentType::html())
+                .body(html_page(
+                    "Nothing to confirm",
+                    "<p>This subscription does not need to be confirmed anymore.</p>",
+                ))
+        }
+        Err(e) => {
+            tracing::error!("Stored subscriber email is invalid: {}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    let subscription_token = generate_token();
+    if rotate_token(
+        &pool,
+        token.subscriber_id,
+        &subscription_token,
+        settings.confirmation_token_ttl(),
+    )
+    .await
+    .is_err()
+    {
+        return HttpResponse::InternalServerError().finish();
+    }
+    if send_confirmation_email(&email_client, &email, &base_url.0, &subscription_token)
+        .await
+        .is_err()
+    {
+        return HttpResponse::InternalServerError().finish();
+    }
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(html_page(
+            "Check your inbox",
+            "<p>We sent you a new confirmation link.</p>",
+        ))
+}
+
+fn expired_link_page(subscription_token: &str) -> HttpResponse {
+    let body = format!(
+        r#"<p>This confirmation link has expired.</p>
+<form action="/subscriptions/confirm/resend" method="post">
+    <input type="hidden" name="subscription_token" value="{}" />
+    <button type="submit">Send me a new link</button>
+</form>"#,
+        escape_html(subscription_token)
+    );
+    HttpResponse::Gone()
+        .content_type(ContentType::html())
+        .body(html_page("Link expired", &body))
+}
+
+/// Tokens are gone once the cleanup worker purged them, readers following
+/// such a link are sent back to the signup form.
+fn unknown_link_page() -> HttpResponse {
+    HttpResponse::Unauthorized()
+        .content_type(ContentType::html())
+        .body(html_page(
+            "Link expired",
+            r#"<p>This confirmation link is no longer valid.</p>
+<p><a href="/subscriptions">Sign up again</a> to get a new one.</p>"#,
+        ))
+}
+
+/// Confirms the subscriber along with every list they asked to join while
+/// their confirmation was pending.
+#[tracing::instrument(name = "Mark subscriber as confirmed", skip(pool))]
+pub async fn confirm_subscriber(pool: &PgPool, subscriber_id: Uuid) -> Result<(), sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    sqlx::query!(
+        "UPDATE subscriptions SET status = 'confirmed' WHERE id = $1 AND status = 'pending_confirmation'",
+        subscriber_id
+    )
+    .execute(&mut transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    sqlx::query!(
+        r#"
+        UPDATE list_memberships SET status = 'confirmed'
+        WHERE subscriber_id = $1 AND status = 'pending_confirmation'
+        "#,
+        subscriber_id
+    )
+    .execute(&mut transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+pub struct SubscriptionToken {
+    pub subscriber_id: Uuid,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[tracing::instrument(name = "Get subscription token", skip(pool, subscription_token))]
+pub async fn get_subscription_token(
+    pool: &PgPool,
+    subscription_token: &str,
+) -> Result<Option<SubscriptionToken>, sqlx::Error> {
+    sqlx::query_as!(
+        SubscriptionToken,
+        "SELECT subscriber_id, expires_at FROM subscription_tokens WHERE subscription_token = $1",
+        subscription_token
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })
+}
+
+async fn get_pending_email(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+) -> Result<Option<String>, sqlx::Error> {
+    let row = sqlx::query!(
+        "SELECT email FROM subscriptions WHERE id = $1 AND status = 'pending_confirmation'",
+        subscriber_id
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(row.map(|r| r.email))
+}
+
+/// Replaces every token of the subscriber, so earlier links stop working.
+async fn rotate_token(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+    subscription_token: &str,
+    token_ttl: chrono::Duration,
+) -> Result<(), sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    sqlx::query!(
+        "DELETE FROM subscription_tokens WHERE subscriber_id = $1",
+        subscriber_id
+    )
+    .execute(&mut transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    store_token(
+        &mut transaction,
+        subscriber_id,
+        subscription_token,
+        token_ttl,
+    )
+    .await?;
+    transaction.commit().await?;
+    Ok(())
+}
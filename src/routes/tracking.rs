@@ -0,0 +1,98 @@
+use actix_web::http::header::{CacheControl, CacheDirective, LOCATION};
+use actix_web::{web, HttpResponse};
+use chrono::Utc;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::tracking::TRACKING_PIXEL;
+
+struct TrackingToken {
+    newsletter_issue_id: Uuid,
+    subscriber_id: Uuid,
+    url: Option<String>,
+}
+
+#[tracing::instrument(name = "Tracking an issue open", skip(pool))]
+pub async fn track_open(token: web::Path<String>, pool: web::Data<PgPool>) -> HttpResponse {
+    // Always answer with the pixel so a stale or forged token never shows a
+    // broken image in the reader's mail client.
+    if let Ok(Some(tracking_token)) = get_tracking_token(&pool, &token).await {
+        if tracking_token.url.is_none() {
+            let _ = insert_tracking_event(&pool, &tracking_token, "open").await;
+        }
+    }
+    HttpResponse::Ok()
+        .content_type("image/gif")
+        .insert_header(CacheControl(vec![
+            CacheDirective::NoCache,
+            CacheDirective::NoStore,
+            CacheDirective::MustRevalidate,
+        ]))
+        .body(TRACKING_PIXEL)
+}
+
+#[tracing::instrument(name = "Tracking a link click", skip(pool))]
+pub async fn track_click(token: web::Path<String>, pool: web::Data<PgPool>) -> HttpResponse {
+    let tracking_token = match get_tracking_token(&pool, &token).await {
+        Ok(Some(tracking_token)) => tracking_token,
+        Ok(None) => return HttpResponse::NotFound().finish(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    let url = match &tracking_token.url {
+        Some(url) => url.clone(),
+        None => return HttpResponse::NotFound().finish(),
+    };
+    if insert_tracking_event(&pool, &tracking_token, "click")
+        .await
+        .is_err()
+    {
+        return HttpResponse::InternalServerError().finish();
+    }
+    HttpResponse::Found()
+        .insert_header((LOCATION, url))
+        .finish()
+}
+
+async fn get_tracking_token(pool: &PgPool, token: &str) -> Result<Option<TrackingToken>, sqlx::Error> {
+    sqlx::query_as!(
+        TrackingToken,
+        r#"
+        SELECT newsletter_issue_id, subscriber_id, url
+        FROM tracking_tokens
+        WHERE tracking_token = $1
+        "#,
+        token
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })
+}
+
+async fn insert_tracking_event(
+    pool: &PgPool,
+    tracking_token: &TrackingToken,
+    kind: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO tracking_events (id, newsletter_issue_id, subscriber_id, kind, url, occurred_at)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        "#,
+        Uuid::new_v4(),
+        tracking_token.newsletter_issue_id,
+        tracking_token.subscriber_id,
+        kind,
+        tracking_token.url,
+        Utc::now()
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
@@ -0,0 +1,125 @@
+use actix_web::{web, HttpResponse};
+use sqlx::PgPool;
+
+use crate::authentication::{
+    confirm_totp_enrollment, disable_totp, start_totp_enrollment, verify_second_factor, AdminUser,
+    TotpCipher,
+};
+use crate::routes::{Problem, SecondFactorData};
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct TotpEnrollmentResponse {
+    /// Base32, for typing into the app by hand.
+    secret: String,
+    /// The `otpauth://` URI to scan.
+    provisioning_uri: String,
+    /// `provisioning_uri` as a QR code, missing if the URI is too long for
+    /// one.
+    qr_code_svg: Option<String>,
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+pub struct TotpCodeData {
+    code: String,
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct RecoveryCodes {
+    /// Shown only once, each one replaces a code from the app for one login.
+    recovery_codes: Vec<String>,
+}
+
+/// Starts enrollment for the logged-in user. Logins keep working with the
+/// password alone until the enrollment is confirmed, which ends the other
+/// sessions of the user.
+#[utoipa::path(
+    post,
+    path = "/admin/account/two_factor",
+    tag = "authentication",
+    security(("session" = [])),
+    responses(
+        (status = 200, description = "A new secret to add to an authenticator app", body = TotpEnrollmentResponse),
+        (status = 409, description = "Two-factor authentication is already on"),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json")
+    )
+)]
+#[tracing::instrument(name = "Enrolling in two-factor authentication", skip(user, pool, cipher), fields(username = %user.username))]
+pub async fn enroll_two_factor(
+    user: AdminUser,
+    pool: web::Data<PgPool>,
+    cipher: web::Data<TotpCipher>,
+) -> HttpResponse {
+    match start_totp_enrollment(&pool, &cipher, user.user_id, &user.username).await {
+        Ok(Some(enrollment)) => HttpResponse::Ok().json(TotpEnrollmentResponse {
+            secret: enrollment.secret,
+            provisioning_uri: enrollment.provisioning_uri,
+            qr_code_svg: enrollment.qr_code_svg,
+        }),
+        Ok(None) => HttpResponse::Conflict().body("Two-factor authentication is already on."),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/account/two_factor/confirm",
+    tag = "authentication",
+    security(("session" = [])),
+    request_body = TotpCodeData,
+    responses(
+        (status = 200, description = "Two-factor authentication is on", body = RecoveryCodes),
+        (status = 400, description = "Wrong code or no enrollment started", body = String, content_type = "text/plain"),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json")
+    )
+)]
+#[tracing::instrument(name = "Confirming two-factor enrollment", skip(user, body, pool, cipher), fields(username = %user.username))]
+pub async fn confirm_two_factor(
+    user: AdminUser,
+    body: web::Json<TotpCodeData>,
+    pool: web::Data<PgPool>,
+    cipher: web::Data<TotpCipher>,
+) -> HttpResponse {
+    match confirm_totp_enrollment(&pool, &cipher, &user, &body.code).await {
+        Ok(Some(recovery_codes)) => HttpResponse::Ok().json(RecoveryCodes { recovery_codes }),
+        Ok(None) => {
+            HttpResponse::BadRequest().body("The code is wrong or no enrollment was started.")
+        }
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+/// Needs a current code or a recovery code, a stolen session alone cannot
+/// turn two-factor authentication off.
+#[utoipa::path(
+    delete,
+    path = "/admin/account/two_factor",
+    tag = "authentication",
+    security(("session" = [])),
+    request_body = SecondFactorData,
+    responses(
+        (status = 200, description = "Logins need the password only"),
+        (status = 400, description = "Wrong code, or two-factor authentication is off", body = String, content_type = "text/plain"),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json")
+    )
+)]
+#[tracing::instrument(name = "Disabling two-factor authentication", skip(user, body, pool, cipher), fields(username = %user.username))]
+pub async fn disable_two_factor(
+    user: AdminUser,
+    body: web::Json<SecondFactorData>,
+    pool: web::Data<PgPool>,
+    cipher: web::Data<TotpCipher>,
+) -> HttpResponse {
+    let factor = match body.into_inner().into_factor() {
+        Ok(factor) => factor,
+        Err(err) => return HttpResponse::BadRequest().body(err),
+    };
+    match verify_second_factor(&pool, &cipher, user.user_id, &factor).await {
+        Ok(true) => {}
+        Ok(false) => return HttpResponse::BadRequest().body("The code is wrong."),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    }
+    match disable_totp(&pool, user.user_id).await {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
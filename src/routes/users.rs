@@ -0,0 +1,248 @@
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Utc};
+use secrecy::{ExposeSecret, Secret};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::authentication::create_user;
+use crate::domain::{SubscriberEmail, UserRole};
+use crate::routes::Problem;
+
+const MIN_PASSWORD_LENGTH: usize = 12;
+const MAX_PASSWORD_LENGTH: usize = 128;
+/// Keeps the `otpauth://` URI of two-factor enrollment short enough for a
+/// QR code.
+const MAX_USERNAME_LENGTH: usize = 64;
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+pub struct UserData {
+    username: String,
+    #[schema(value_type = String)]
+    password: Secret<String>,
+    /// `owner`, `editor` or `viewer`.
+    role: String,
+    /// Where password reset links go.
+    email: Option<String>,
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+pub struct RoleData {
+    role: String,
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct CreatedUser {
+    user_id: Uuid,
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct UserSummary {
+    user_id: Uuid,
+    username: String,
+    role: String,
+    email: Option<String>,
+    two_factor_enabled: bool,
+    created_at: DateTime<Utc>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/users",
+    tag = "users",
+    security(("session" = [])),
+    request_body = UserData,
+    responses(
+        (status = 200, description = "The user was created", body = CreatedUser),
+        (status = 400, description = "Invalid username, password, role or email", body = String, content_type = "text/plain"),
+        (status = 409, description = "The username is taken"),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The role lacks the permission", body = Problem, content_type = "application/problem+json")
+    )
+)]
+#[tracing::instrument(name = "Adding an admin user", skip(body, pool), fields(new_username = %body.username))]
+pub async fn create_admin_user(body: web::Json<UserData>, pool: web::Data<PgPool>) -> HttpResponse {
+    let body = body.into_inner();
+    if body.username.trim().is_empty() {
+        return HttpResponse::BadRequest().body("A user needs a username.");
+    }
+    if body.username.chars().count() > MAX_USERNAME_LENGTH {
+        return HttpResponse::BadRequest().body(format!(
+            "Usernames are at most {} characters long.",
+            MAX_USERNAME_LENGTH
+        ));
+    }
+    if let Err(err) = validate_password(&body.password, &body.username) {
+        return HttpResponse::BadRequest().body(err);
+    }
+    let role = match UserRole::parse(&body.role) {
+        Ok(role) => role,
+        Err(err) => return HttpResponse::BadRequest().body(err),
+    };
+    let email = match body.email.map(SubscriberEmail::parse).transpose() {
+        Ok(email) => email,
+        Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+    };
+    match create_user(&pool, &body.username, body.password, role, email.as_ref()).await {
+        Ok(Some(user_id)) => HttpResponse::Ok().json(CreatedUser { user_id }),
+        Ok(None) => HttpResponse::Conflict().finish(),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/users",
+    tag = "users",
+    security(("session" = [])),
+    responses(
+        (status = 200, description = "Every admin user, by username", body = Vec<UserSummary>),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The role lacks the permission", body = Problem, content_type = "application/problem+json")
+    )
+)]
+#[tracing::instrument(name = "Listing admin users", skip(pool))]
+pub async fn get_admin_users(pool: web::Data<PgPool>) -> HttpResponse {
+    let users = sqlx::query_as!(
+        UserSummary,
+        r#"
+        SELECT
+            user_id, username, role, email,
+            totp_enabled_at IS NOT NULL AS "two_factor_enabled!", created_at
+        FROM users
+        ORDER BY username
+        "#
+    )
+    .fetch_all(pool.get_ref())
+    .await;
+    match users {
+        Ok(users) => HttpResponse::Ok().json(users),
+        Err(e) => {
+            tracing::error!("Failed to execute query: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// There is always at least one owner left, demoting the last one fails.
+#[utoipa::path(
+    put,
+    path = "/admin/users/{user_id}/role",
+    tag = "users",
+    security(("session" = [])),
+    params(("user_id" = Uuid, Path)),
+    request_body = RoleData,
+    responses(
+        (status = 200, description = "The role was changed"),
+        (status = 400, description = "Unknown role", body = String, content_type = "text/plain"),
+        (status = 404, description = "Unknown user"),
+        (status = 409, description = "This is the last owner"),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The role lacks the permission", body = Problem, content_type = "application/problem+json")
+    )
+)]
+#[tracing::instrument(name = "Changing the role of an admin user", skip(body, pool))]
+pub async fn change_user_role(
+    user_id: web::Path<Uuid>,
+    body: web::Json<RoleData>,
+    pool: web::Data<PgPool>,
+) -> HttpResponse {
+    let role = match UserRole::parse(&body.role) {
+        Ok(role) => role,
+        Err(err) => return HttpResponse::BadRequest().body(err),
+    };
+    match update_role(&pool, *user_id, role).await {
+        Ok(RoleChange::Changed) => HttpResponse::Ok().finish(),
+        Ok(RoleChange::UnknownUser) => HttpResponse::NotFound().finish(),
+        Ok(RoleChange::LastOwner) => {
+            HttpResponse::Conflict().body("There has to be an owner left.")
+        }
+        Err(e) => {
+            tracing::error!("Failed to execute query: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Length is what makes a password strong, the username is the first thing
+/// an attacker tries.
+pub fn validate_password(password: &Secret<String>, username: &str) -> Result<(), String> {
+    let password = password.expose_secret();
+    let length = password.chars().count();
+    if !(MIN_PASSWORD_LENGTH..=MAX_PASSWORD_LENGTH).contains(&length) {
+        return Err(format!(
+            "A password needs between {} and {} characters.",
+            MIN_PASSWORD_LENGTH, MAX_PASSWORD_LENGTH
+        ));
+    }
+    let username = username.trim().to_lowercase();
+    if !username.is_empty() && password.to_lowercase().contains(&username) {
+        return Err("A password must not contain the username.".into());
+    }
+    Ok(())
+}
+
+enum RoleChange {
+    Changed,
+    UnknownUser,
+    LastOwner,
+}
+
+async fn update_role(
+    pool: &PgPool,
+    user_id: Uuid,
+    role: UserRole,
+) -> Result<RoleChange, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    // Locking every owner serializes concurrent demotions.
+    let owners: Vec<Uuid> =
+        sqlx::query!("SELECT user_id FROM users WHERE role = 'owner' FOR UPDATE")
+            .fetch_all(&mut transaction)
+            .await?
+            .into_iter()
+            .map(|r| r.user_id)
+            .collect();
+    if role != UserRole::Owner && owners == [user_id] {
+        return Ok(RoleChange::LastOwner);
+    }
+    let updated = sqlx::query!(
+        "UPDATE users SET role = $1 WHERE user_id = $2",
+        role.as_str(),
+        user_id
+    )
+    .execute(&mut transaction)
+    .await?;
+    if updated.rows_affected() == 0 {
+        return Ok(RoleChange::UnknownUser);
+    }
+    transaction.commit().await?;
+    Ok(RoleChange::Changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::validate_password;
+    use claim::{assert_err, assert_ok};
+    use secrecy::Secret;
+
+    fn password(s: &str) -> Secret<String> {
+        Secret::new(s.to_owned())
+    }
+
+    #[test]
+    fn passwords_must_not_contain_the_username() {
+        assert_err!(validate_password(
+            &password("my name is Ursula!"),
+            " ursula "
+        ));
+        assert_ok!(validate_password(
+            &password("my name is Ursula!"),
+            "le guin"
+        ));
+    }
+
+    #[test]
+    fn an_empty_username_does_not_reject_every_password() {
+        assert_ok!(validate_password(&password("a long enough password"), ""));
+        assert_ok!(validate_password(&password("a long enough password"), "  "));
+    }
+}
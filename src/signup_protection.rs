@@ -0,0 +1,442 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Duration, Utc};
+use hmac::{Hmac, Mac};
+use secrecy::ExposeSecret;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+
+use crate::configuration::SpamProtectionSettings;
+use crate::tokens::generate_token;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// What a client needs to post a signup: the form token to send back as
+/// `form_token`, the field to leave empty and how many leading zero bits
+/// the proof of work needs.
+#[derive(serde::Serialize, utoipa::ToSchema)]
+pub struct SignupChallenge {
+    /// Send back unchanged, `null` when the environment does not check it.
+    pub form_token: Option<String>,
+    /// A form field that must stay empty, bots fill in every field.
+    pub honeypot_field: String,
+    /// Find a `proof_of_work` nonce so that SHA-256 of
+    /// `<form_token>:<email>:<nonce>` starts with this many zero bits, with
+    /// the email trimmed and lowercased. 0 means none is needed.
+    pub proof_of_work_difficulty: u32,
+}
+
+/// The spam defence fields of a signup, and the email the proof of work is
+/// bound to.
+pub struct SpamFields<'a> {
+    pub email: &'a str,
+    pub form_token: Option<&'a str>,
+    pub proof_of_work: Option<&'a str>,
+    pub honeypot: Option<&'a str>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SpamCheckError {
+    HoneypotFilled,
+    MissingFormToken,
+    InvalidFormToken,
+    FilledTooFast,
+    FormExpired,
+    FormTokenUsed,
+    MissingProofOfWork,
+    InvalidProofOfWork,
+}
+
+impl SpamCheckError {
+    /// The signup field the problem is reported on.
+    pub fn field(&self) -> &'static str {
+        match self {
+            SpamCheckError::HoneypotFilled => "form",
+            SpamCheckError::MissingFormToken
+            | SpamCheckError::InvalidFormToken
+            | SpamCheckError::FilledTooFast
+            | SpamCheckError::FormExpired
+            | SpamCheckError::FormTokenUsed => "form_token",
+            SpamCheckError::MissingProofOfWork | SpamCheckError::InvalidProofOfWork => {
+                "proof_of_work"
+            }
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            SpamCheckError::HoneypotFilled => "automated",
+            SpamCheckError::MissingFormToken => "missing",
+            SpamCheckError::InvalidFormToken | SpamCheckError::InvalidProofOfWork => "invalid",
+            SpamCheckError::FilledTooFast => "too_fast",
+            SpamCheckError::FormExpired => "expired",
+            SpamCheckError::FormTokenUsed => "used",
+            SpamCheckError::MissingProofOfWork => "missing",
+        }
+    }
+}
+
+impl std::fmt::Display for SpamCheckError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let message = match self {
+            SpamCheckError::HoneypotFilled => "The signup looks automated.",
+            SpamCheckError::MissingFormToken => "Load the signup form before posting it.",
+            SpamCheckError::InvalidFormToken => "The form token is not one of ours.",
+            SpamCheckError::FilledTooFast => "The form was sent too fast, try again.",
+            SpamCheckError::FormExpired => "The form expired, reload it and try again.",
+            SpamCheckError::FormTokenUsed => "The form was already sent, reload it and try again.",
+            SpamCheckError::MissingProofOfWork => "The proof of work is missing.",
+            SpamCheckError::InvalidProofOfWork => "The proof of work is wrong.",
+        };
+        f.write_str(message)
+    }
+}
+
+/// A form token that passed the checks. It is good for a single signup,
+/// spend it with `spend_form_token` before acting on one.
+#[derive(Debug, PartialEq)]
+pub struct CheckedFormToken {
+    pub nonce: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl SpamProtectionSettings {
+    /// Signups need a form token once either the fill time or a proof of
+    /// work is checked, the token is the proof of work challenge.
+    pub fn requires_form_token(&self) -> bool {
+        self.min_fill_seconds > 0 || self.proof_of_work_difficulty > 0
+    }
+
+    pub fn challenge(&self, now: DateTime<Utc>) -> SignupChallenge {
+        SignupChallenge {
+            form_token: self
+                .requires_form_token()
+                .then(|| self.issue_form_token(now)),
+            honeypot_field: self.honeypot_field.clone(),
+            proof_of_work_difficulty: self.proof_of_work_difficulty,
+        }
+    }
+
+    fn mac(&self) -> HmacSha256 {
+        HmacSha256::new_from_slice(self.form_secret.expose_secret().as_bytes())
+            .expect("HMAC takes keys of any length")
+    }
+
+    /// `<unix timestamp>.<random nonce>.<hex HMAC of both>`, so the time
+    /// the form was handed out cannot be moved back and every token can be
+    /// told apart.
+    pub fn issue_form_token(&self, now: DateTime<Utc>) -> String {
+        let payload = format!("{}.{}", now.timestamp(), generate_token());
+        let mut mac = self.mac();
+        mac.update(payload.as_bytes());
+        format!("{}.{:x}", payload, mac.finalize().into_bytes())
+    }
+
+    fn check_form_token(
+        &self,
+        token: &str,
+        now: DateTime<Utc>,
+    ) -> Result<CheckedFormToken, SpamCheckError> {
+        let (payload, signature) = token
+            .rsplit_once('.')
+            .ok_or(SpamCheckError::InvalidFormToken)?;
+        let signature = decode_hex(signature).ok_or(SpamCheckError::InvalidFormToken)?;
+        let mut mac = self.mac();
+        mac.update(payload.as_bytes());
+        mac.verify_slice(&signature)
+            .map_err(|_| SpamCheckError::InvalidFormToken)?;
+        let (issued_at, nonce) = payload
+            .split_once('.')
+            .ok_or(SpamCheckError::InvalidFormToken)?;
+        let issued_at: i64 = issued_at
+            .parse()
+            .map_err(|_| SpamCheckError::InvalidFormToken)?;
+        let age = Duration::seconds(now.timestamp() - issued_at);
+        if age < Duration::seconds(self.min_fill_seconds) {
+            return Err(SpamCheckError::FilledTooFast);
+        }
+        let ttl = Duration::hours(self.form_token_ttl_hours);
+        if age > ttl {
+            return Err(SpamCheckError::FormExpired);
+        }
+        Ok(CheckedFormToken {
+            nonce: nonce.to_owned(),
+            expires_at: now - age + ttl,
+        })
+    }
+
+    /// Runs every check the environment asks for. The form token, if one
+    /// is checked, still has to be spent.
+    pub fn check(
+        &self,
+        fields: &SpamFields,
+        now: DateTime<Utc>,
+    ) -> Result<Option<CheckedFormToken>, SpamCheckError> {
+        if fields.honeypot.is_some_and(|value| !value.is_empty()) {
+            return Err(SpamCheckError::HoneypotFilled);
+        }
+        if !self.requires_form_token() {
+            return Ok(None);
+        }
+        let token = fields
+            .form_token
+            .filter(|token| !token.is_empty())
+            .ok_or(SpamCheckError::MissingFormToken)?;
+        let checked = self.check_form_token(token, now)?;
+        if self.proof_of_work_difficulty > 0 {
+            let nonce = fields
+                .proof_of_work
+                .filter(|nonce| !nonce.is_empty())
+                .ok_or(SpamCheckError::MissingProofOfWork)?;
+            let challenge = proof_of_work_challenge(token, fields.email);
+            if !proof_of_work_valid(&challenge, nonce, self.proof_of_work_difficulty) {
+                return Err(SpamCheckError::InvalidProofOfWork);
+            }
+        }
+        Ok(Some(checked))
+    }
+
+    /// Takes the honeypot out of the custom attributes it was parsed into.
+    pub fn take_honeypot(&self, attributes: &mut HashMap<String, String>) -> Option<String> {
+        attributes.remove(&self.honeypot_field)
+    }
+}
+
+/// Marks the form token as used. `false` if it was already, a replayed
+/// form is rejected with `SpamCheckError::FormTokenUsed`.
+#[tracing::instrument(name = "Spending a form token", skip(pool))]
+pub async fn spend_form_token(
+    pool: &PgPool,
+    token: &CheckedFormToken,
+) -> Result<bool, sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO used_form_tokens (nonce, expires_at) VALUES ($1, $2)
+        ON CONFLICT (nonce) DO NOTHING
+        "#,
+        token.nonce,
+        token.expires_at
+    )
+    .execute(pool)
+    .await
+    .map(|done| done.rows_affected() == 1)
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })
+}
+
+/// Drops used tokens that expired anyway, run by the cleanup worker.
+#[tracing::instrument(name = "Purging expired used form tokens", skip(pool))]
+pub async fn purge_used_form_tokens(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    sqlx::query!(
+        "DELETE FROM used_form_tokens WHERE expires_at <= $1",
+        Utc::now()
+    )
+    .execute(pool)
+    .await
+    .map(|done| done.rows_affected())
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+/// What the proof of work of a signup hashes, so a solution is only good
+/// for one form and one address.
+pub fn proof_of_work_challenge(form_token: &str, email: &str) -> String {
+    format!("{}:{}", form_token, email.trim().to_lowercase())
+}
+
+/// Whether SHA-256 of `<challenge>:<nonce>` starts with `difficulty` zero
+/// bits. Each extra bit doubles the work for the client, checking is a
+/// single hash.
+pub fn proof_of_work_valid(challenge: &str, nonce: &str, difficulty: u32) -> bool {
+    let hash = Sha256::digest(format!("{}:{}", challenge, nonce).as_bytes());
+    leading_zero_bits(&hash) >= difficulty
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        leading_zero_bits, proof_of_work_challenge, proof_of_work_valid, SpamCheckError, SpamFields,
+    };
+    use crate::configuration::SpamProtectionSettings;
+    use chrono::{Duration, Utc};
+    use secrecy::Secret;
+
+    fn settings(proof_of_work_difficulty: u32) -> SpamProtectionSettings {
+        SpamProtectionSettings {
+            honeypot_field: "website".into(),
+            form_secret: Secret::new("a secret".into()),
+            min_fill_seconds: 3,
+            form_token_ttl_hours: 24,
+            proof_of_work_difficulty,
+        }
+    }
+
+    fn fields<'a>(form_token: &'a str, proof_of_work: Option<&'a str>) -> SpamFields<'a> {
+        SpamFields {
+            email: "ursula@example.com",
+            form_token: Some(form_token),
+            proof_of_work,
+            honeypot: None,
+        }
+    }
+
+    fn solve(challenge: &str, difficulty: u32) -> String {
+        (0u64..)
+            .map(|nonce| nonce.to_string())
+            .find(|nonce| proof_of_work_valid(challenge, nonce, difficulty))
+            .unwrap()
+    }
+
+    #[test]
+    fn a_form_sent_after_the_minimum_fill_time_passes() {
+        let settings = settings(0);
+        let token = settings.issue_form_token(Utc::now() - Duration::seconds(5));
+        assert!(settings
+            .check(&fields(&token, None), Utc::now())
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn forms_sent_too_fast_or_too_late_fail() {
+        let settings = settings(0);
+        let fast = settings.issue_form_token(Utc::now() - Duration::seconds(1));
+        let late = settings.issue_form_token(Utc::now() - Duration::hours(25));
+        assert_eq!(
+            Err(SpamCheckError::FilledTooFast),
+            settings.check(&fields(&fast, None), Utc::now())
+        );
+        assert_eq!(
+            Err(SpamCheckError::FormExpired),
+            settings.check(&fields(&late, None), Utc::now())
+        );
+    }
+
+    #[test]
+    fn backdated_or_foreign_tokens_fail() {
+        let settings = settings(0);
+        let token = settings.issue_form_token(Utc::now());
+        let (_, nonce_and_signature) = token.split_once('.').unwrap();
+        let backdated = format!("{}.{}", Utc::now().timestamp() - 60, nonce_and_signature);
+        let mut other = self::settings(0);
+        other.form_secret = Secret::new("another secret".into());
+        let foreign = other.issue_form_token(Utc::now() - Duration::seconds(60));
+        for token in [backdated.as_str(), foreign.as_str(), "garbage", "1.zz"] {
+            assert_eq!(
+                Err(SpamCheckError::InvalidFormToken),
+                settings.check(&fields(token, None), Utc::now()),
+                "{} was accepted",
+                token
+            );
+        }
+    }
+
+    #[test]
+    fn a_filled_honeypot_fails_even_without_other_checks() {
+        let mut settings = settings(0);
+        settings.min_fill_seconds = 0;
+        let fields = SpamFields {
+            email: "ursula@example.com",
+            form_token: None,
+            proof_of_work: None,
+            honeypot: Some("http://spam.example.com"),
+        };
+        assert_eq!(
+            Err(SpamCheckError::HoneypotFilled),
+            settings.check(&fields, Utc::now())
+        );
+    }
+
+    #[test]
+    fn tokens_differ_and_expire_with_the_form() {
+        let settings = settings(0);
+        let issued_at = Utc::now() - Duration::seconds(5);
+        let first = settings.issue_form_token(issued_at);
+        let second = settings.issue_form_token(issued_at);
+
+        let checked = |token: &str| {
+            settings
+                .check(&fields(token, None), Utc::now())
+                .unwrap()
+                .unwrap()
+        };
+
+        assert_ne!(checked(&first).nonce, checked(&second).nonce);
+        assert_eq!(
+            issued_at.timestamp() + 24 * 3600,
+            checked(&first).expires_at.timestamp()
+        );
+    }
+
+    #[test]
+    fn the_proof_of_work_must_match_the_token_and_email() {
+        let settings = settings(8);
+        let token = settings.issue_form_token(Utc::now() - Duration::seconds(5));
+        let nonce = solve(&proof_of_work_challenge(&token, " Ursula@Example.com"), 8);
+        let other_email = SpamFields {
+            email: "le-guin@example.com",
+            ..fields(&token, Some(&nonce))
+        };
+        let wrong_nonce = (0u64..)
+            .map(|n| n.to_string())
+            .find(|n| {
+                !proof_of_work_valid(&proof_of_work_challenge(&token, "ursula@example.com"), n, 8)
+            })
+            .unwrap();
+
+        assert!(settings
+            .check(&fields(&token, Some(&nonce)), Utc::now())
+            .is_ok());
+        assert_eq!(
+            Err(SpamCheckError::MissingProofOfWork),
+            settings.check(&fields(&token, None), Utc::now())
+        );
+        assert_eq!(
+            Err(SpamCheckError::InvalidProofOfWork),
+            settings.check(&fields(&token, Some(&wrong_nonce)), Utc::now())
+        );
+        // One nonce in 256 solves the other address too.
+        if !proof_of_work_valid(
+            &proof_of_work_challenge(&token, "le-guin@example.com"),
+            &nonce,
+            8,
+        ) {
+            assert_eq!(
+                Err(SpamCheckError::InvalidProofOfWork),
+                settings.check(&other_email, Utc::now())
+            );
+        }
+    }
+
+    #[test]
+    fn zero_bits_are_counted_across_bytes() {
+        assert_eq!(0, leading_zero_bits(&[0b1000_0000]));
+        assert_eq!(12, leading_zero_bits(&[0, 0b0000_1111, 0]));
+        assert_eq!(16, leading_zero_bits(&[0, 0]));
+    }
+}
@@ -1,26 +1,167 @@
+use crate::authentication::AdminAccess;
+use crate::configuration::{AdminSettings, SubscriptionSettings};
+use crate::domain_verification::DomainVerifier;
 use crate::email_client::EmailClient;
-use crate::routes::{health_check, subscribe};
+use crate::rate_limiting::{RateLimiter, SignupRateLimit, TrustedProxies};
+use crate::routes::{
+    add_subscriber_tags, api_docs, change_own_password, change_user_role, confirm,
+    confirm_email_change, confirm_two_factor, create_admin_user, create_api_key, create_list,
+    create_segment, delete_admin_subscriber, delete_subscriber, disable_two_factor,
+    enroll_two_factor, fetch_subscriber, get_admin_users, get_api_keys, get_lists, get_segments,
+    health_check, issue_stats, json_config, list_subscribers, login, login_second_factor, logout,
+    openapi_json, password_reset_form, patch_subscriber, path_config, preferences_form,
+    publish_draft, publish_issue, publish_issue_with_api_key, query_config, remove_subscriber_tag,
+    request_email_change, request_password_reset, resend_confirmation, reset_password,
+    revoke_api_key, save_preferences, signup_challenge, signup_form, subscribe, track_click,
+    track_open, unsubscribe_all, SIGNUP_PAYLOAD_LIMIT,
+};
 use actix_web::dev::Server;
 use actix_web::{web, App, HttpServer};
 use sqlx::PgPool;
 use std::net::TcpListener;
+use std::sync::Arc;
 use tracing_actix_web::TracingLogger;
 
+/// Public URL of the application, used to build links back to it in emails.
+pub struct ApplicationBaseUrl(pub String);
+
+#[allow(clippy::too_many_arguments)]
 pub fn run(
     listener: TcpListener,
     db_pool: PgPool,
     email_client: EmailClient,
+    base_url: String,
+    subscription_settings: SubscriptionSettings,
+    domain_verifier: Arc<dyn DomainVerifier>,
+    rate_limiter: Arc<dyn RateLimiter>,
+    api_docs_enabled: bool,
+    admin_settings: AdminSettings,
+    trusted_proxies: TrustedProxies,
 ) -> Result<Server, std::io::Error> {
     let db_pool = web::Data::new(db_pool);
     let email_client = web::Data::new(email_client);
+    let base_url = web::Data::new(ApplicationBaseUrl(base_url));
+    let risky_email_lists = web::Data::new(subscription_settings.risky_email_lists()?);
+    let subscription_settings = web::Data::new(subscription_settings);
+    let domain_verifier: web::Data<dyn DomainVerifier> = web::Data::from(domain_verifier);
+    let rate_limiter: web::Data<dyn RateLimiter> = web::Data::from(rate_limiter);
+    let totp_cipher = web::Data::new(admin_settings.totp_cipher());
+    let admin_settings = web::Data::new(admin_settings);
+    let trusted_proxies = web::Data::new(trusted_proxies);
     let server = HttpServer::new(move || {
-        App::new()
+        let app = App::new()
             .wrap(TracingLogger::default())
             .route("/health_check", web::get().to(health_check))
-            .route("/subscriptions", web::post().to(subscribe))
+            .route("/openapi.json", web::get().to(openapi_json))
+            .service(
+                web::resource("/subscriptions")
+                    .app_data(web::PayloadConfig::new(SIGNUP_PAYLOAD_LIMIT))
+                    .route(web::get().to(signup_form))
+                    .route(web::post().to(subscribe).wrap(SignupRateLimit)),
+            )
+            .route("/subscriptions/challenge", web::get().to(signup_challenge))
+            .route("/subscriptions/confirm", web::get().to(confirm))
+            .route(
+                "/subscriptions/confirm/resend",
+                web::post().to(resend_confirmation),
+            )
+            .route("/login", web::post().to(login))
+            .route("/login/two_factor", web::post().to(login_second_factor))
+            .route("/logout", web::post().to(logout))
+            .route("/password_reset", web::post().to(request_password_reset))
+            .route(
+                "/password_reset/{token}",
+                web::get().to(password_reset_form),
+            )
+            .route("/password_reset/{token}", web::post().to(reset_password))
+            .service(
+                web::scope("/admin")
+                    .wrap(AdminAccess)
+                    .route("/account/password", web::put().to(change_own_password))
+                    .route("/account/two_factor", web::post().to(enroll_two_factor))
+                    .route("/account/two_factor", web::delete().to(disable_two_factor))
+                    .route(
+                        "/account/two_factor/confirm",
+                        web::post().to(confirm_two_factor),
+                    )
+                    .route("/users", web::get().to(get_admin_users))
+                    .route("/users", web::post().to(create_admin_user))
+                    .route("/users/{user_id}/role", web::put().to(change_user_role))
+                    .route("/issues", web::post().to(publish_issue))
+                    .route("/issues/{issue_id}/publish", web::post().to(publish_draft))
+                    .route("/issues/{issue_id}/stats", web::get().to(issue_stats))
+                    .route("/api_keys", web::get().to(get_api_keys))
+                    .route("/api_keys", web::post().to(create_api_key))
+                    .route("/api_keys/{api_key_id}", web::delete().to(revoke_api_key))
+                    .route("/lists", web::get().to(get_lists))
+                    .route("/lists", web::post().to(create_list))
+                    .route("/segments", web::get().to(get_segments))
+                    .route("/segments", web::post().to(create_segment))
+                    .route(
+                        "/subscribers/{subscriber_id}",
+                        web::delete().to(delete_admin_subscriber),
+                    )
+                    .route(
+                        "/subscribers/{subscriber_id}/tags",
+                        web::post().to(add_subscriber_tags),
+                    )
+                    .route(
+                        "/subscribers/{subscriber_id}/tags/{tag}",
+                        web::delete().to(remove_subscriber_tag),
+                    ),
+            )
+            .route(
+                "/preferences/email/confirm",
+                web::get().to(confirm_email_change),
+            )
+            .route("/preferences/{token}", web::get().to(preferences_form))
+            .route("/preferences/{token}", web::post().to(save_preferences))
+            .route(
+                "/preferences/{token}/email",
+                web::post().to(request_email_change),
+            )
+            .route(
+                "/preferences/{token}/unsubscribe",
+                web::post().to(unsubscribe_all),
+            )
+            .service(
+                web::scope("/api/v1")
+                    .app_data(json_config())
+                    .app_data(query_config())
+                    .app_data(path_config())
+                    .route("/issues", web::post().to(publish_issue_with_api_key))
+                    .route("/subscribers", web::get().to(list_subscribers))
+                    .route(
+                        "/subscribers/{subscriber_id}",
+                        web::get().to(fetch_subscriber),
+                    )
+                    .route(
+                        "/subscribers/{subscriber_id}",
+                        web::patch().to(patch_subscriber),
+                    )
+                    .route(
+                        "/subscribers/{subscriber_id}",
+                        web::delete().to(delete_subscriber),
+                    ),
+            )
+            .route("/t/o/{token}.gif", web::get().to(track_open))
+            .route("/t/c/{token}", web::get().to(track_click))
             // Register the connection as part of the application state
             .app_data(db_pool.clone())
             .app_data(email_client.clone())
+            .app_data(base_url.clone())
+            .app_data(subscription_settings.clone())
+            .app_data(domain_verifier.clone())
+            .app_data(risky_email_lists.clone())
+            .app_data(rate_limiter.clone())
+            .app_data(admin_settings.clone())
+            .app_data(totp_cipher.clone())
+            .app_data(trusted_proxies.clone());
+        if api_docs_enabled {
+            app.route("/docs", web::get().to(api_docs))
+        } else {
+            app
+        }
     })
     .listen(listener)?
     .run();
@@ -0,0 +1,198 @@
+use chrono::{Duration, Utc};
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::configuration::SubscriptionSettings;
+use crate::domain::SubscriberEmail;
+
+#[derive(Debug, PartialEq)]
+pub struct CleanupReport {
+    pub expired_tokens: u64,
+    pub purged_subscribers: u64,
+}
+
+/// Runs `cleanup_unconfirmed` every `cleanup_interval`, forever. Failures
+/// are logged and retried on the next tick.
+pub async fn run_cleanup_worker(pool: PgPool, settings: SubscriptionSettings) {
+    let mut interval = tokio::time::interval(settings.cleanup_interval());
+    loop {
+        interval.tick().await;
+        match cleanup_unconfirmed(&pool, settings.unconfirmed_retention()).await {
+            Ok(report) => tracing::info!(
+                expired_tokens = report.expired_tokens,
+                purged_subscribers = report.purged_subscribers,
+                "Cleaned up unconfirmed subscriptions"
+            ),
+            Err(e) => tracing::error!("Failed to clean up unconfirmed subscriptions: {:?}", e),
+        }
+    }
+}
+
+/// Deletes confirmation tokens that expired more than `retention` ago and
+/// purges subscribers that never confirmed, nor asked for a new link, within
+/// `retention`. Recently expired tokens are kept so their link still offers
+/// to send a new one.
+///
+/// Subscribers that already received an issue are kept, they confirmed at
+/// some point and are only pending again after re-subscribing.
+#[tracing::instrument(name = "Cleaning up unconfirmed subscriptions", skip(pool))]
+pub async fn cleanup_unconfirmed(
+    pool: &PgPool,
+    retention: Duration,
+) -> Result<CleanupReport, sqlx::Error> {
+    let cutoff = Utc::now() - retention;
+    let mut transaction = pool.begin().await?;
+    let purged: Vec<Uuid> = sqlx::query!(
+        r#"
+        SELECT s.id FROM subscriptions s
+        WHERE s.status = 'pending_confirmation'
+            AND s.subscribed_at < $1
+            AND NOT EXISTS (
+                SELECT 1 FROM subscription_tokens t
+                WHERE t.subscriber_id = s.id AND t.created_at >= $1
+            )
+            AND NOT EXISTS (SELECT 1 FROM issue_deliveries d WHERE d.subscriber_id = s.id)
+        FOR UPDATE
+        "#,
+        cutoff
+    )
+    .fetch_all(&mut transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?
+    .into_iter()
+    .map(|r| r.id)
+    .collect();
+    let expired_tokens = sqlx::query!(
+        "DELETE FROM subscription_tokens WHERE expires_at < $1 OR subscriber_id = ANY($2)",
+        cutoff,
+        &purged[..]
+    )
+    .execute(&mut transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?
+    .rows_affected();
+    delete_subscribers(&mut transaction, &purged).await?;
+    transaction.commit().await?;
+    Ok(CleanupReport {
+        expired_tokens,
+        purged_subscribers: purged.len() as u64,
+    })
+}
+
+/// Recomputes the canonical email of subscribers stored under a different
+/// `fold_aliases` setting, e.g. after toggling `fold_email_aliases`.
+/// Subscribers whose new form is already taken keep their current one.
+#[tracing::instrument(name = "Refreshing canonical emails", skip(pool))]
+pub async fn refresh_canonical_emails(
+    pool: &PgPool,
+    fold_aliases: bool,
+) -> Result<u64, sqlx::Error> {
+    let subscribers = sqlx::query!("SELECT id, email, canonical_email FROM subscriptions")
+        .fetch_all(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+    let mut refreshed = 0;
+    for subscriber in subscribers {
+        let canonical_email = match SubscriberEmail::parse(subscriber.email) {
+            Ok(email) => email.canonical(fold_aliases),
+            Err(_) => continue,
+        };
+        if canonical_email == subscriber.canonical_email {
+            continue;
+        }
+        refreshed += sqlx::query!(
+            r#"
+            UPDATE subscriptions SET canonical_email = $2
+            WHERE id = $1
+                AND NOT EXISTS (SELECT 1 FROM subscriptions WHERE canonical_email = $2)
+            "#,
+            subscriber.id,
+            canonical_email
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?
+        .rows_affected();
+    }
+    Ok(refreshed)
+}
+
+/// Deletes subscribers together with every row that identifies them. Their
+/// delivery and tracking history is kept for the issue statistics, under a
+/// random id per subscriber.
+pub async fn delete_subscribers(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_ids: &[Uuid],
+) -> Result<(), sqlx::Error> {
+    let pseudonyms: Vec<Uuid> = subscriber_ids.iter().map(|_| Uuid::new_v4()).collect();
+    for query in [
+        sqlx::query!(
+            r#"
+            UPDATE issue_deliveries d SET subscriber_id = p.pseudonym
+            FROM unnest($1::uuid[], $2::uuid[]) AS p(id, pseudonym)
+            WHERE d.subscriber_id = p.id
+            "#,
+            subscriber_ids,
+            &pseudonyms[..]
+        ),
+        sqlx::query!(
+            r#"
+            UPDATE tracking_events e SET subscriber_id = p.pseudonym
+            FROM unnest($1::uuid[], $2::uuid[]) AS p(id, pseudonym)
+            WHERE e.subscriber_id = p.id
+            "#,
+            subscriber_ids,
+            &pseudonyms[..]
+        ),
+        sqlx::query!(
+            "DELETE FROM subscription_tokens WHERE subscriber_id = ANY($1)",
+            subscriber_ids
+        ),
+        sqlx::query!(
+            "DELETE FROM list_memberships WHERE subscriber_id = ANY($1)",
+            subscriber_ids
+        ),
+        sqlx::query!(
+            "DELETE FROM subscriber_tags WHERE subscriber_id = ANY($1)",
+            subscriber_ids
+        ),
+        sqlx::query!(
+            "DELETE FROM preference_tokens WHERE subscriber_id = ANY($1)",
+            subscriber_ids
+        ),
+        sqlx::query!(
+            "DELETE FROM email_change_requests WHERE subscriber_id = ANY($1)",
+            subscriber_ids
+        ),
+        sqlx::query!(
+            "DELETE FROM subscriber_email_history WHERE subscriber_id = ANY($1)",
+            subscriber_ids
+        ),
+        sqlx::query!(
+            "DELETE FROM tracking_tokens WHERE subscriber_id = ANY($1)",
+            subscriber_ids
+        ),
+        sqlx::query!(
+            "DELETE FROM subscriptions WHERE id = ANY($1)",
+            subscriber_ids
+        ),
+    ] {
+        query.execute(&mut *transaction).await.map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+    }
+    Ok(())
+}
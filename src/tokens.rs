@@ -0,0 +1,11 @@
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+
+/// A random, URL-safe token for links sent to subscribers.
+pub fn generate_token() -> String {
+    let mut rng = thread_rng();
+    std::iter::repeat_with(|| rng.sample(Alphanumeric))
+        .map(char::from)
+        .take(25)
+        .collect()
+}
@@ -0,0 +1,221 @@
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::tokens::generate_token;
+
+/// A transparent 1x1 GIF, served by the open tracking pixel.
+pub const TRACKING_PIXEL: &[u8] = &[
+    0x47, 0x49, 0x46, 0x38, 0x39, 0x61, 0x01, 0x00, 0x01, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0xff, 0xff, 0xff, 0x21, 0xf9, 0x04, 0x01, 0x00, 0x00, 0x00, 0x00, 0x2c, 0x00, 0x00,
+    0x00, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x02, 0x02, 0x44, 0x01, 0x00, 0x3b,
+];
+
+/// Replaces every absolute `http(s)` link in an `href` attribute with the
+/// value returned by `rewrite`, or keeps it if that is `None`. The attribute
+/// name may be in any case and
+/// have whitespace around `=`. Relative links, anchors and `mailto:` are
+/// left untouched, and so are attributes merely ending in `href`, like
+/// `data-href`.
+pub fn rewrite_links<F>(html: &str, mut rewrite: F) -> String
+where
+    F: FnMut(&str) -> Option<String>,
+{
+    // ASCII lowercasing keeps every byte where it was.
+    let lowercase = html.to_ascii_lowercase();
+    let mut output = String::with_capacity(html.len());
+    let mut copied = 0;
+    let mut search_from = 0;
+    while let Some(found) = lowercase[search_from..].find("href") {
+        let name_start = search_from + found;
+        search_from = name_start + "href".len();
+        let is_attribute = html[..name_start]
+            .chars()
+            .next_back()
+            .is_some_and(|c| c.is_ascii_whitespace());
+        if !is_attribute {
+            continue;
+        }
+        let value_start = match quoted_value_start(html, search_from) {
+            Some(value_start) => value_start,
+            None => continue,
+        };
+        let quote = html[value_start..].chars().next().unwrap();
+        let url_start = value_start + 1;
+        let url_end = match html[url_start..].find(quote) {
+            Some(length) => url_start + length,
+            None => break,
+        };
+        let url = &html[url_start..url_end];
+        if url.starts_with("http://") || url.starts_with("https://") {
+            if let Some(rewritten) = rewrite(&url.replace("&amp;", "&")) {
+                output.push_str(&html[copied..url_start]);
+                output.push_str(&rewritten);
+                copied = url_end;
+            }
+        }
+        search_from = url_end;
+    }
+    output.push_str(&html[copied..]);
+    output
+}
+
+/// Where the quoted value of an attribute whose name ends at `name_end`
+/// starts, `None` if the name is not followed by `=` and a quote.
+fn quoted_value_start(html: &str, name_end: usize) -> Option<usize> {
+    let value = html[name_end..]
+        .trim_start_matches(|c: char| c.is_ascii_whitespace())
+        .strip_prefix('=')?
+        .trim_start_matches(|c: char| c.is_ascii_whitespace());
+    value
+        .starts_with(['"', '\''])
+        .then(|| html.len() - value.len())
+}
+
+/// Inserts an invisible image pointing at `pixel_url` right before the
+/// closing `</body>` tag, or at the end of the document if there is none.
+pub fn append_pixel(html: &str, pixel_url: &str) -> String {
+    let pixel = format!(
+        r#"<img src="{}" width="1" height="1" alt="" style="display:none" />"#,
+        pixel_url
+    );
+    match html.to_ascii_lowercase().rfind("</body>") {
+        Some(index) => format!("{}{}{}", &html[..index], pixel, &html[index..]),
+        None => format!("{}{}", html, pixel),
+    }
+}
+
+/// Renders the HTML body of an issue for a single subscriber, routing links
+/// through `/t/c/{token}` and appending the `/t/o/{token}.gif` pixel.
+/// Links to the subscriber's preferences carry their secret token, they are
+/// left alone so it never ends up in the tracking tables or the stats.
+#[tracing::instrument(name = "Rendering tracked issue", skip(pool, base_url, html))]
+pub async fn render_tracked_html(
+    pool: &PgPool,
+    base_url: &str,
+    newsletter_issue_id: Uuid,
+    subscriber_id: Uuid,
+    html: &str,
+) -> Result<String, sqlx::Error> {
+    let mut links = Vec::new();
+    let preferences_prefix = format!("{}/preferences/", base_url);
+    let html = rewrite_links(html, |url| {
+        if url.starts_with(&preferences_prefix) {
+            return None;
+        }
+        let token = generate_token();
+        let tracked_url = format!("{}/t/c/{}", base_url, token);
+        links.push((token, Some(url.to_owned())));
+        Some(tracked_url)
+    });
+    let pixel_token = generate_token();
+    let html = append_pixel(&html, &format!("{}/t/o/{}.gif", base_url, pixel_token));
+    links.push((pixel_token, None));
+
+    for (token, url) in links {
+        sqlx::query!(
+            r#"
+            INSERT INTO tracking_tokens (tracking_token, newsletter_issue_id, subscriber_id, url)
+            VALUES ($1, $2, $3, $4)
+            "#,
+            token,
+            newsletter_issue_id,
+            subscriber_id,
+            url
+        )
+        .execute(pool)
+        .await?;
+    }
+    Ok(html)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{append_pixel, rewrite_links};
+
+    #[test]
+    fn absolute_links_are_rewritten() {
+        let html = r#"<a href="https://example.com/a?x=1&amp;y=2">a</a>"#;
+        let rewritten = rewrite_links(html, |url| Some(format!("tracked:{}", url)));
+        assert_eq!(
+            rewritten,
+            r#"<a href="tracked:https://example.com/a?x=1&y=2">a</a>"#
+        );
+    }
+
+    #[test]
+    fn single_quoted_links_are_rewritten() {
+        let html = "<a href='http://example.com'>a</a>";
+        let rewritten = rewrite_links(html, |_| Some("tracked".into()));
+        assert_eq!(rewritten, "<a href='tracked'>a</a>");
+    }
+
+    #[test]
+    fn relative_and_mailto_links_are_left_untouched() {
+        let html = r##"<a href="#top">a</a><a href="mailto:a@b.com">b</a><a href=/x>c</a>"##;
+        let rewritten = rewrite_links(html, |_| panic!("should not rewrite"));
+        assert_eq!(rewritten, html);
+    }
+
+    #[test]
+    fn only_href_attributes_are_rewritten() {
+        let html = r#"<a data-href="https://example.com/a" href="https://example.com/b">a</a>"#;
+        let rewritten = rewrite_links(html, |url| Some(format!("tracked:{}", url)));
+        assert_eq!(
+            rewritten,
+            r#"<a data-href="https://example.com/a" href="tracked:https://example.com/b">a</a>"#
+        );
+    }
+
+    #[test]
+    fn the_attribute_name_may_be_uppercase() {
+        let html = r#"<A HREF="https://example.com/a">a</A><a Href='https://example.com/b'>b</a>"#;
+        let rewritten = rewrite_links(html, |url| Some(format!("tracked:{}", url)));
+        assert_eq!(
+            rewritten,
+            r#"<A HREF="tracked:https://example.com/a">a</A><a Href='tracked:https://example.com/b'>b</a>"#
+        );
+    }
+
+    #[test]
+    fn whitespace_around_the_equals_sign_is_allowed() {
+        let html =
+            "<a href = \"https://example.com/a\">a</a><a\nhref=\n'https://example.com/b'>b</a>";
+        let rewritten = rewrite_links(html, |url| Some(format!("tracked:{}", url)));
+        assert_eq!(
+            rewritten,
+            "<a href = \"tracked:https://example.com/a\">a</a><a\nhref=\n'tracked:https://example.com/b'>b</a>"
+        );
+    }
+
+    #[test]
+    fn longer_attribute_names_are_left_untouched() {
+        let html = r#"<link hreflang="https://example.com/a" href="https://example.com/b">"#;
+        let rewritten = rewrite_links(html, |url| Some(format!("tracked:{}", url)));
+        assert_eq!(
+            rewritten,
+            r#"<link hreflang="https://example.com/a" href="tracked:https://example.com/b">"#
+        );
+    }
+
+    #[test]
+    fn links_the_rewrite_declines_are_kept() {
+        let html = r#"<a href="https://example.com/a?x=1&amp;y=2">a</a>"#;
+        let rewritten = rewrite_links(html, |_| None);
+        assert_eq!(rewritten, html);
+    }
+
+    #[test]
+    fn pixel_is_inserted_before_closing_body() {
+        let html = "<html><BODY><p>hi</p></BODY></html>";
+        let rendered = append_pixel(html, "http://localhost/t/o/abc.gif");
+        assert!(rendered.ends_with(
+            r#"<img src="http://localhost/t/o/abc.gif" width="1" height="1" alt="" style="display:none" /></BODY></html>"#
+        ));
+    }
+
+    #[test]
+    fn pixel_is_appended_when_there_is_no_body() {
+        let rendered = append_pixel("<p>hi</p>", "pixel");
+        assert!(rendered.starts_with("<p>hi</p><img src=\"pixel\""));
+    }
+}
@@ -0,0 +1,354 @@
+use crate::helpers::{spawn_app, TestApp, TestUser};
+use crate::openapi::registered_routes;
+use newsletter::authentication::admin_route_access;
+use newsletter::domain::UserRole;
+use reqwest::Method;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+fn issue_body(draft: bool) -> serde_json::Value {
+    serde_json::json!({
+        "title": "Newsletter title",
+        "content": {"text": "Plain text", "html": "<p>HTML</p>"},
+        "draft": draft,
+    })
+}
+
+fn request_as(app: &TestApp, token: &str, method: Method, path: &str) -> reqwest::RequestBuilder {
+    reqwest::Client::new()
+        .request(method, format!("{}{}", app.address, path))
+        .bearer_auth(token)
+}
+
+async fn create_confirmed_subscriber(app: &TestApp) -> uuid::Uuid {
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+    app.post_confirmed_subscription("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await
+        .error_for_status()
+        .unwrap();
+    sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .id
+}
+
+#[tokio::test]
+async fn viewers_can_read_but_not_change_anything() {
+    let app = spawn_app().await;
+    let token = app.login_as(UserRole::Viewer).await;
+
+    let lists = request_as(&app, &token, Method::GET, "/admin/lists")
+        .send()
+        .await
+        .unwrap();
+    let create = request_as(&app, &token, Method::POST, "/admin/lists")
+        .json(&serde_json::json!({"slug": "rust-weekly", "name": "Rust Weekly"}))
+        .send()
+        .await
+        .unwrap();
+    let keys = request_as(&app, &token, Method::GET, "/admin/api_keys")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(200, lists.status().as_u16());
+    assert_eq!(403, create.status().as_u16());
+    assert_eq!("application/problem+json", create.headers()["Content-Type"]);
+    assert_eq!(403, keys.status().as_u16());
+}
+
+#[tokio::test]
+async fn editors_can_draft_issues_but_not_publish_them() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let token = app.login_as(UserRole::Editor).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    let draft = request_as(&app, &token, Method::POST, "/admin/issues")
+        .json(&issue_body(true))
+        .send()
+        .await
+        .unwrap();
+    let published = request_as(&app, &token, Method::POST, "/admin/issues")
+        .json(&issue_body(false))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(200, draft.status().as_u16());
+    assert_eq!(403, published.status().as_u16());
+    let draft: serde_json::Value = draft.json().await.unwrap();
+    let publish = request_as(
+        &app,
+        &token,
+        Method::POST,
+        &format!(
+            "/admin/issues/{}/publish",
+            draft["issue_id"].as_str().unwrap()
+        ),
+    )
+    .send()
+    .await
+    .unwrap();
+    assert_eq!(403, publish.status().as_u16());
+}
+
+#[tokio::test]
+async fn owners_can_publish_a_draft_once() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    let draft: serde_json::Value = app
+        .post_issues(issue_body(true))
+        .await
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let publish_path = format!(
+        "/admin/issues/{}/publish",
+        draft["issue_id"].as_str().unwrap()
+    );
+
+    let first = app
+        .admin_request(Method::POST, &publish_path)
+        .send()
+        .await
+        .unwrap();
+    let second = app
+        .admin_request(Method::POST, &publish_path)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(200, first.status().as_u16());
+    assert_eq!(409, second.status().as_u16());
+}
+
+#[tokio::test]
+async fn publishing_an_unknown_draft_is_a_404() {
+    let app = spawn_app().await;
+
+    let response = app
+        .admin_request(
+            Method::POST,
+            &format!("/admin/issues/{}/publish", uuid::Uuid::new_v4()),
+        )
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(404, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn only_owners_can_delete_subscribers() {
+    let app = spawn_app().await;
+    let subscriber_id = create_confirmed_subscriber(&app).await;
+    let editor = app.login_as(UserRole::Editor).await;
+    let subscriber_path = format!("/admin/subscribers/{}", subscriber_id);
+
+    let as_editor = request_as(&app, &editor, Method::DELETE, &subscriber_path)
+        .send()
+        .await
+        .unwrap();
+    let as_owner = app
+        .admin_request(Method::DELETE, &subscriber_path)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(403, as_editor.status().as_u16());
+    assert_eq!(204, as_owner.status().as_u16());
+    let remaining = sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(remaining.is_empty());
+}
+
+#[tokio::test]
+async fn owners_can_add_users_and_change_roles() {
+    let app = spawn_app().await;
+
+    let created = app
+        .admin_request(Method::POST, "/admin/users")
+        .json(&serde_json::json!({
+            "username": "editor",
+            "password": "correct horse battery staple",
+            "role": "editor",
+        }))
+        .send()
+        .await
+        .unwrap();
+    let duplicate = app
+        .admin_request(Method::POST, "/admin/users")
+        .json(&serde_json::json!({
+            "username": "editor",
+            "password": "correct horse battery staple",
+            "role": "viewer",
+        }))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(200, created.status().as_u16());
+    assert_eq!(409, duplicate.status().as_u16());
+    let created: serde_json::Value = created.json().await.unwrap();
+    let promoted = app
+        .admin_request(
+            Method::PUT,
+            &format!("/admin/users/{}/role", created["user_id"].as_str().unwrap()),
+        )
+        .json(&serde_json::json!({"role": "owner"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(200, promoted.status().as_u16());
+    let role = sqlx::query!("SELECT role FROM users WHERE username = 'editor'")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .role;
+    assert_eq!("owner", role);
+}
+
+#[tokio::test]
+async fn short_passwords_are_rejected() {
+    let app = spawn_app().await;
+
+    let response = app
+        .admin_request(Method::POST, "/admin/users")
+        .json(&serde_json::json!({"username": "editor", "password": "short", "role": "editor"}))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(400, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn overlong_usernames_are_rejected() {
+    let app = spawn_app().await;
+
+    let response = app
+        .admin_request(Method::POST, "/admin/users")
+        .json(&serde_json::json!({
+            "username": "a".repeat(65),
+            "password": "correct horse battery staple",
+            "role": "editor",
+        }))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(400, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn the_last_owner_cannot_be_demoted() {
+    let app = spawn_app().await;
+    let owner_id = sqlx::query!("SELECT user_id FROM users WHERE role = 'owner'")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .user_id;
+
+    let response = app
+        .admin_request(Method::PUT, &format!("/admin/users/{}/role", owner_id))
+        .json(&serde_json::json!({"role": "viewer"}))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(409, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn the_initial_owner_is_created_from_configuration() {
+    let app = spawn_app().await;
+    let settings = newsletter::configuration::AdminSettings {
+        session_ttl_hours: 1,
+        initial_owner_username: "first-owner".into(),
+        initial_owner_password: secrecy::Secret::new("a very long password".into()),
+        initial_owner_email: Some("owner@example.com".into()),
+        password_reset_ttl_minutes: 60,
+        ..newsletter::configuration::get_configuration().unwrap().admin
+    };
+    sqlx::query!("DELETE FROM user_sessions")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    sqlx::query!("DELETE FROM users")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    newsletter::authentication::ensure_initial_owner(&app.db_pool, &settings)
+        .await
+        .unwrap();
+    newsletter::authentication::ensure_initial_owner(&app.db_pool, &settings)
+        .await
+        .unwrap();
+
+    let owner = TestUser {
+        username: "first-owner".into(),
+        password: "a very long password".into(),
+        role: UserRole::Owner,
+    };
+    owner.login(&app).await;
+    let users = sqlx::query!("SELECT role, email FROM users")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(1, users.len());
+    assert_eq!("owner", users[0].role);
+    assert_eq!(Some("owner@example.com"), users[0].email.as_deref());
+}
+
+#[test]
+fn every_admin_route_has_a_required_permission() {
+    let missing: Vec<_> = registered_routes()
+        .into_iter()
+        .filter(|(_, path)| path.starts_with("/admin/"))
+        .filter(|(method, path)| admin_route_access(&method.to_uppercase(), path).is_none())
+        .collect();
+
+    assert!(
+        missing.is_empty(),
+        "Add these routes to ADMIN_ROUTES: {:?}",
+        missing
+    );
+}
+
+#[tokio::test]
+async fn roles_are_checked_before_the_body_is_read() {
+    let app = spawn_app().await;
+    let token = app.login_as(UserRole::Viewer).await;
+
+    let response = request_as(&app, &token, Method::POST, "/admin/segments")
+        .header("Content-Type", "application/json")
+        .body("not json")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(403, response.status().as_u16());
+}
@@ -0,0 +1,231 @@
+use crate::helpers::{spawn_app, TestApp};
+use newsletter::domain::UserRole;
+use reqwest::Method;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+async fn create_key(app: &TestApp, scopes: &[&str]) -> serde_json::Value {
+    app.post_api_keys(serde_json::json!({"name": "cms", "scopes": scopes}))
+        .await
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap()
+}
+
+async fn get_subscribers_with(app: &TestApp, authorization: Option<&str>) -> reqwest::Response {
+    let mut request = reqwest::Client::new().get(format!("{}/api/v1/subscribers", app.address));
+    if let Some(authorization) = authorization {
+        request = request.header("Authorization", authorization);
+    }
+    request.send().await.unwrap()
+}
+
+#[tokio::test]
+async fn api_requests_without_a_valid_key_are_rejected() {
+    let app = spawn_app().await;
+
+    for authorization in [
+        None,
+        Some("Bearer nl_not-a-real-key"),
+        Some("Basic dXNlcjpwYXNz"),
+    ] {
+        let response = get_subscribers_with(&app, authorization).await;
+
+        assert_eq!(401, response.status().as_u16());
+        assert_eq!("Bearer", response.headers()["WWW-Authenticate"]);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["status"], 401);
+    }
+}
+
+#[tokio::test]
+async fn keys_only_grant_their_scopes() {
+    let app = spawn_app().await;
+    let created = create_key(&app, &["issues:publish"]).await;
+    let key = created["key"].as_str().unwrap();
+
+    let response = get_subscribers_with(&app, Some(&format!("Bearer {}", key))).await;
+
+    assert_eq!(403, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(
+        body["detail"],
+        "This API key lacks the subscribers:read scope."
+    );
+}
+
+#[tokio::test]
+async fn revoked_keys_stop_working() {
+    let app = spawn_app().await;
+    let created = create_key(&app, &["subscribers:read"]).await;
+    let authorization = format!("Bearer {}", created["key"].as_str().unwrap());
+    assert_eq!(
+        200,
+        get_subscribers_with(&app, Some(&authorization))
+            .await
+            .status()
+            .as_u16()
+    );
+
+    let response = app
+        .admin_request(
+            reqwest::Method::DELETE,
+            &format!(
+                "/admin/api_keys/{}",
+                created["api_key_id"].as_str().unwrap()
+            ),
+        )
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(200, response.status().as_u16());
+    assert_eq!(
+        401,
+        get_subscribers_with(&app, Some(&authorization))
+            .await
+            .status()
+            .as_u16()
+    );
+}
+
+#[tokio::test]
+async fn keys_are_stored_hashed_and_listed_with_their_last_use() {
+    let app = spawn_app().await;
+    let created = create_key(&app, &["subscribers:read"]).await;
+    let key = created["key"].as_str().unwrap();
+    get_subscribers_with(&app, Some(&format!("Bearer {}", key)))
+        .await
+        .error_for_status()
+        .unwrap();
+
+    let keys: serde_json::Value = app
+        .admin_request(reqwest::Method::GET, "/admin/api_keys")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    let listed = keys
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|k| k["api_key_id"] == created["api_key_id"])
+        .unwrap();
+    assert_eq!(listed["name"], "cms");
+    assert_eq!(listed["scopes"], serde_json::json!(["subscribers:read"]));
+    assert!(key.starts_with(listed["key_prefix"].as_str().unwrap()));
+    assert!(listed["last_used_at"].is_string());
+    assert!(listed["revoked_at"].is_null());
+    assert!(!keys.to_string().contains(key));
+    let stored = sqlx::query!("SELECT key_hash FROM api_keys")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(stored.iter().all(|k| k.key_hash != key));
+}
+
+#[tokio::test]
+async fn unknown_scopes_are_rejected() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_api_keys(serde_json::json!({"name": "cms", "scopes": ["everything"]}))
+        .await;
+
+    assert_eq!(400, response.status().as_u16());
+    assert_eq!(
+        "application/problem+json",
+        response.headers()["Content-Type"]
+    );
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["errors"][0]["field"], "scopes");
+}
+
+#[tokio::test]
+async fn api_issue_errors_are_problem_documents() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_api_issues(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>",
+            },
+            "list": "no-such-list",
+        }))
+        .await;
+
+    assert_eq!(400, response.status().as_u16());
+    assert_eq!(
+        "application/problem+json",
+        response.headers()["Content-Type"]
+    );
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["errors"][0]["field"], "list");
+    assert_eq!(body["errors"][0]["code"], "unknown");
+}
+
+#[tokio::test]
+async fn issues_can_be_published_with_an_api_key() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.post_confirmed_subscription("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+
+    let response = app
+        .post_api_issues(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>",
+            },
+        }))
+        .await;
+
+    assert_eq!(200, response.status().as_u16());
+    let deliveries = sqlx::query!("SELECT status FROM issue_deliveries")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(deliveries.len(), 1);
+}
+
+#[tokio::test]
+async fn managing_keys_needs_an_owner_session() {
+    let app = spawn_app().await;
+    let editor = app.login_as(UserRole::Editor).await;
+    let key_id = uuid::Uuid::new_v4();
+    let routes = [
+        (Method::GET, "/admin/api_keys".to_string()),
+        (Method::POST, "/admin/api_keys".to_string()),
+        (Method::DELETE, format!("/admin/api_keys/{}", key_id)),
+    ];
+
+    for (method, path) in routes {
+        let request = || {
+            reqwest::Client::new()
+                .request(method.clone(), format!("{}{}", app.address, path))
+                .json(&serde_json::json!({"name": "cms", "scopes": ["subscribers:read"]}))
+        };
+        let anonymous = request().send().await.unwrap();
+        let as_editor = request().bearer_auth(&editor).send().await.unwrap();
+
+        assert_eq!(401, anonymous.status().as_u16(), "{} {}", method, path);
+        assert_eq!(403, as_editor.status().as_u16(), "{} {}", method, path);
+    }
+    let keys = sqlx::query!("SELECT COUNT(*) AS \"count!\" FROM api_keys WHERE name = 'cms'")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(0, keys.count);
+}
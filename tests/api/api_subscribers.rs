@@ -0,0 +1,392 @@
+use crate::helpers::{spawn_app, TestApp};
+use uuid::Uuid;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+async fn mount_email_mock(app: &TestApp) {
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+}
+
+async fn subscriber_id(app: &TestApp, email: &str) -> String {
+    sqlx::query!("SELECT id FROM subscriptions WHERE email = $1", email)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch subscriber.")
+        .id
+        .to_string()
+}
+
+fn emails(page: &serde_json::Value) -> Vec<&str> {
+    page["subscribers"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|s| s["email"].as_str().unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn subscribers_are_paginated_newest_first() {
+    let app = spawn_app().await;
+    mount_email_mock(&app).await;
+    for name in ["a", "b", "c", "d", "e"] {
+        app.post_subscriptions(format!("name={}&email={}%40example.com", name, name))
+            .await
+            .error_for_status()
+            .unwrap();
+    }
+
+    let mut seen = Vec::new();
+    let mut query = "limit=2".to_owned();
+    loop {
+        let response = app.get_api_subscribers(&query).await;
+        assert_eq!(200, response.status().as_u16());
+        let page: serde_json::Value = response.json().await.unwrap();
+        seen.extend(emails(&page).into_iter().map(str::to_owned));
+        match page["next_cursor"].as_str() {
+            Some(cursor) => query = format!("limit=2&cursor={}", cursor),
+            None => break,
+        }
+    }
+
+    assert_eq!(
+        seen,
+        vec![
+            "e@example.com",
+            "d@example.com",
+            "c@example.com",
+            "b@example.com",
+            "a@example.com"
+        ]
+    );
+}
+
+#[tokio::test]
+async fn subscribers_can_be_filtered_by_status_and_email() {
+    let app = spawn_app().await;
+    mount_email_mock(&app).await;
+    app.post_confirmed_subscription("name=le%20guin&email=ursula%40example.com".into())
+        .await;
+    app.post_subscriptions("name=butler&email=octavia%40example.com".into())
+        .await;
+    app.post_subscriptions("name=jemisin&email=NK%40gmail.com".into())
+        .await;
+
+    let page: serde_json::Value = app
+        .get_api_subscribers("status=confirmed")
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(emails(&page), vec!["ursula@example.com"]);
+
+    let page: serde_json::Value = app
+        .get_api_subscribers("status=pending_confirmation&email=EXAMPLE")
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(emails(&page), vec!["octavia@example.com"]);
+
+    let page: serde_json::Value = app
+        .get_api_subscribers("email=nk%40")
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(emails(&page), vec!["NK@gmail.com"]);
+}
+
+#[tokio::test]
+async fn invalid_list_parameters_are_reported_as_problem_json() {
+    let app = spawn_app().await;
+
+    let response = app
+        .get_api_subscribers("status=pending&cursor=nope&limit=500")
+        .await;
+
+    assert_eq!(400, response.status().as_u16());
+    assert_eq!(
+        "application/problem+json",
+        response.headers()["Content-Type"].to_str().unwrap()
+    );
+    let body: serde_json::Value = response.json().await.unwrap();
+    let fields: Vec<_> = body["errors"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| e["field"].as_str().unwrap())
+        .collect();
+    assert_eq!(fields, vec!["status", "cursor", "limit"]);
+}
+
+#[tokio::test]
+async fn a_subscriber_can_be_fetched_by_id() {
+    let app = spawn_app().await;
+    mount_email_mock(&app).await;
+    app.post_subscriptions("name=le%20guin&email=ursula%40example.com".into())
+        .await;
+    app.post_subscriber_tags("ursula@example.com", &["vip"])
+        .await;
+    let id = subscriber_id(&app, "ursula@example.com").await;
+
+    let response = app.get_api_subscriber(&id).await;
+
+    assert_eq!(200, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["id"], id);
+    assert_eq!(body["email"], "ursula@example.com");
+    assert_eq!(body["name"], "le guin");
+    assert_eq!(body["status"], "pending_confirmation");
+    assert_eq!(body["tags"], serde_json::json!(["vip"]));
+}
+
+#[tokio::test]
+async fn unknown_subscribers_are_a_404_problem() {
+    let app = spawn_app().await;
+    let id = Uuid::new_v4().to_string();
+
+    for response in [
+        app.get_api_subscriber(&id).await,
+        app.patch_api_subscriber(&id, serde_json::json!({"name": "x"}))
+            .await,
+        app.delete_api_subscriber(&id).await,
+        app.get_api_subscriber("not-a-uuid").await,
+    ] {
+        assert_eq!(404, response.status().as_u16());
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["status"], 404);
+    }
+}
+
+#[tokio::test]
+async fn a_subscriber_can_be_updated() {
+    let app = spawn_app().await;
+    mount_email_mock(&app).await;
+    app.post_confirmed_subscription("name=le%20guin&email=ursula%40example.com".into())
+        .await;
+    let id = subscriber_id(&app, "ursula@example.com").await;
+
+    let response = app
+        .patch_api_subscriber(
+            &id,
+            serde_json::json!({
+                "name": "Ursula K. Le Guin",
+                "email": "Ursula@gmail.com",
+                "status": "unsubscribed",
+            }),
+        )
+        .await;
+
+    assert_eq!(200, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["name"], "Ursula K. Le Guin");
+    assert_eq!(body["email"], "Ursula@gmail.com");
+    assert_eq!(body["status"], "unsubscribed");
+    let memberships = sqlx::query!("SELECT status FROM list_memberships")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(memberships.iter().all(|m| m.status == "unsubscribed"));
+    let history = sqlx::query!("SELECT previous_email, new_email FROM subscriber_email_history")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(history.previous_email, "ursula@example.com");
+    assert_eq!(history.new_email, "Ursula@gmail.com");
+}
+
+#[tokio::test]
+async fn updates_are_validated() {
+    let app = spawn_app().await;
+    mount_email_mock(&app).await;
+    app.post_subscriptions("name=le%20guin&email=ursula%40example.com".into())
+        .await;
+    app.post_subscriptions("name=butler&email=octavia%40example.com".into())
+        .await;
+    let id = subscriber_id(&app, "ursula@example.com").await;
+
+    let response = app
+        .patch_api_subscriber(
+            &id,
+            serde_json::json!({"name": "", "email": "nope", "status": "pending_confirmation"}),
+        )
+        .await;
+    assert_eq!(400, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    let fields: Vec<_> = body["errors"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| e["field"].as_str().unwrap())
+        .collect();
+    assert_eq!(fields, vec!["name", "email", "status"]);
+
+    let response = app
+        .patch_api_subscriber(&id, serde_json::json!({"email": "OCTAVIA@example.com"}))
+        .await;
+    assert_eq!(409, response.status().as_u16());
+
+    let response = app
+        .patch_api_subscriber(&id, serde_json::json!({"nickname": "ursula"}))
+        .await;
+    assert_eq!(400, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["errors"][0]["field"], "body");
+}
+
+#[tokio::test]
+async fn new_emails_go_through_the_signup_checks() {
+    let app = spawn_app().await;
+    mount_email_mock(&app).await;
+    app.post_confirmed_subscription("name=le%20guin&email=ursula%40example.com".into())
+        .await;
+    let id = subscriber_id(&app, "ursula@example.com").await;
+
+    for (email, code) in [
+        ("ursula@mailinator.com", "disposable_domain"),
+        ("ursula@no-mail.invalid", "no_mail_server"),
+    ] {
+        let response = app
+            .patch_api_subscriber(&id, serde_json::json!({ "email": email }))
+            .await;
+
+        assert_eq!(400, response.status().as_u16());
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["errors"][0]["code"], code);
+    }
+
+    let response = app
+        .patch_api_subscriber(&id, serde_json::json!({"email": "info@example.com"}))
+        .await;
+
+    assert_eq!(200, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["flagged_as"], "role_account");
+    assert_eq!(body["status"], "confirmed");
+}
+
+#[tokio::test]
+async fn deleting_a_subscriber_keeps_the_issue_stats() {
+    let app = spawn_app().await;
+    mount_email_mock(&app).await;
+    app.post_confirmed_subscription("name=le%20guin&email=ursula%40example.com".into())
+        .await;
+    app.post_issues(serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": r#"<p>Read <a href="https://example.com/story">this</a></p>"#,
+        },
+        "tracking": true,
+    }))
+    .await
+    .error_for_status()
+    .unwrap();
+    let id = subscriber_id(&app, "ursula@example.com").await;
+    let issue_id = sqlx::query!("SELECT newsletter_issue_id FROM newsletter_issues")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .newsletter_issue_id;
+    let tracking_token =
+        sqlx::query!("SELECT tracking_token FROM tracking_tokens WHERE url IS NULL")
+            .fetch_one(&app.db_pool)
+            .await
+            .unwrap()
+            .tracking_token;
+    reqwest::get(format!("{}/t/o/{}.gif", app.address, tracking_token))
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    let response = app.delete_api_subscriber(&id).await;
+
+    assert_eq!(204, response.status().as_u16());
+    assert_eq!(404, app.get_api_subscriber(&id).await.status().as_u16());
+    let remaining = sqlx::query!(
+        r#"
+        SELECT
+            (SELECT COUNT(*) FROM issue_deliveries) AS "deliveries!",
+            (SELECT COUNT(*) FROM tracking_events) AS "events!",
+            (SELECT COUNT(*) FROM tracking_tokens) AS "tracking_tokens!",
+            (SELECT COUNT(*) FROM issue_deliveries WHERE subscriber_id = $1) AS "identified!"
+        "#,
+        Uuid::parse_str(&id).unwrap()
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(
+        (
+            remaining.deliveries,
+            remaining.events,
+            remaining.tracking_tokens,
+            remaining.identified
+        ),
+        (1, 1, 0, 0)
+    );
+    let stats = app
+        .get_issue_stats(&issue_id.to_string(), "json")
+        .await
+        .json::<serde_json::Value>()
+        .await
+        .unwrap();
+    assert_eq!(1, stats["sent"]);
+    assert_eq!(1, stats["opened"]["unique"]);
+}
+
+#[tokio::test]
+async fn email_updates_to_an_address_in_use_are_a_409() {
+    let app = spawn_app().await;
+    mount_email_mock(&app).await;
+    for email in ["ursula%40example.com", "octavia%40example.com"] {
+        app.post_subscriptions(format!("name=reader&email={}", email))
+            .await
+            .error_for_status()
+            .unwrap();
+    }
+    let id = subscriber_id(&app, "ursula@example.com").await;
+
+    let response = app
+        .patch_api_subscriber(
+            &id,
+            serde_json::json!({"name": "Ursula", "email": "Octavia@example.com"}),
+        )
+        .await;
+
+    assert_eq!(409, response.status().as_u16());
+    let saved = sqlx::query!("SELECT name, email FROM subscriptions WHERE email LIKE 'ursula%'")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(
+        ("reader", "ursula@example.com"),
+        (saved.name.as_str(), saved.email.as_str())
+    );
+}
+
+#[tokio::test]
+async fn every_subscriber_route_needs_an_api_key() {
+    let app = spawn_app().await;
+    let subscriber = format!("{}/api/v1/subscribers/{}", app.address, Uuid::new_v4());
+    let client = reqwest::Client::new();
+
+    for request in [
+        client.get(format!("{}/api/v1/subscribers", app.address)),
+        client.get(&subscriber),
+        client
+            .patch(&subscriber)
+            .json(&serde_json::json!({"name": "x"})),
+        client.delete(&subscriber),
+    ] {
+        let response = request.send().await.unwrap();
+
+        assert_eq!(401, response.status().as_u16());
+    }
+}
@@ -0,0 +1,139 @@
+use crate::helpers::{spawn_app, TestApp};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+async fn create_list_with_schema(app: &TestApp) {
+    app.post_lists(serde_json::json!({
+        "slug": "b2b",
+        "name": "B2B updates",
+        "attribute_schema": {
+            "company": {"type": "string", "required": true},
+            "seats": {"type": "number"},
+        },
+    }))
+    .await
+    .error_for_status()
+    .unwrap();
+}
+
+#[tokio::test]
+async fn declared_extra_fields_are_stored_as_attributes() {
+    let app = spawn_app().await;
+    create_list_with_schema(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_subscriptions(
+            "name=le%20guin&email=ursula_le_guin%40gmail.com&list=b2b&company=Acme&seats=12".into(),
+        )
+        .await;
+
+    assert_eq!(200, response.status().as_u16());
+    let saved = sqlx::query!("SELECT attributes FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(serde_json::json!({"company": "Acme", "seats": 12}), saved.attributes);
+}
+
+#[tokio::test]
+async fn extra_fields_that_do_not_match_the_schema_are_rejected() {
+    let app = spawn_app().await;
+    create_list_with_schema(&app).await;
+    let test_cases = vec![
+        ("list=b2b", "missing a required field"),
+        ("list=b2b&company=Acme&seats=many", "a field of the wrong type"),
+        ("list=b2b&company=Acme&favourite_colour=red", "an undeclared field"),
+        ("company=Acme", "a field the default list does not declare"),
+    ];
+
+    for (extra, description) in test_cases {
+        let response = app
+            .post_subscriptions(format!("name=le%20guin&email=ursula_le_guin%40gmail.com&{}", extra))
+            .await;
+        assert_eq!(
+            400,
+            response.status().as_u16(),
+            "The API did not return a 400 when the payload had {}.",
+            description
+        );
+    }
+}
+
+#[tokio::test]
+async fn attributes_are_available_to_templates_and_segments() {
+    let app = spawn_app().await;
+    create_list_with_schema(&app).await;
+    {
+        let _guard = Mock::given(path("/email"))
+            .and(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount_as_scoped(&app.email_server)
+            .await;
+        app.post_confirmed_subscription("name=a&email=a%40example.com&list=b2b&company=Acme&seats=50".into())
+            .await;
+        app.post_confirmed_subscription("name=b&email=b%40example.com&list=b2b&company=Initech&seats=5".into())
+            .await;
+    }
+    app.post_segments(serde_json::json!({"name": "large accounts", "filter": "attributes.seats >= 10"}))
+        .await
+        .error_for_status()
+        .unwrap();
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_issues(serde_json::json!({
+        "title": "Renewals",
+        "content": {
+            "text": "Hi {{ name }}, {{ attributes.company }} has {{ attributes.seats }} seats",
+            "html": "<p>Hi {{ name }} from {{ attributes.company }}</p>",
+        },
+        "list": "b2b",
+        "segment": "large accounts",
+        "tracking": false,
+    }))
+    .await
+    .error_for_status()
+    .unwrap();
+
+    let request = app.email_server.received_requests().await.unwrap().pop().unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+    assert_eq!("a@example.com", body["To"]);
+    assert_eq!("Hi a, Acme has 50 seats", body["Text"]);
+    assert_eq!("<p>Hi a from Acme</p>", body["HtmlBody"]);
+}
+
+#[tokio::test]
+async fn json_signups_can_send_numeric_attributes() {
+    let app = spawn_app().await;
+    create_list_with_schema(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_subscriptions_json(serde_json::json!({
+            "name": "le guin",
+            "email": "ursula_le_guin@gmail.com",
+            "list": "b2b",
+            "attributes": {"company": "Acme", "seats": 12},
+        }))
+        .await;
+
+    assert_eq!(200, response.status().as_u16());
+    let saved = sqlx::query!("SELECT attributes FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(serde_json::json!({"company": "Acme", "seats": 12}), saved.attributes);
+}
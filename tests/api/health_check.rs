@@ -6,7 +6,7 @@ async fn health_check_works(){
        .await;
    let client = reqwest::Client::new();
    let response = client
-       .get(&format!("{}/health_check", &app.address, ))
+       .get(format!("{}/health_check", &app.address, ))
        .send()
        .await
        .expect("failed to execute request");
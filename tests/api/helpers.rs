@@ -1,15 +1,23 @@
 use std::net::TcpListener;
-use std::{println, format};
+use std::sync::Arc;
+use newsletter::authentication::compute_password_hash;
+use newsletter::domain::UserRole;
+use newsletter::domain_verification::InMemoryDomainVerifier;
 use newsletter::email_client::EmailClient;
 use once_cell::sync::Lazy;
+use secrecy::Secret;
 use newsletter::{startup::run, configuration::DatabaseSettings};
-use newsletter::configuration::{get_configuration, self};
+use newsletter::configuration::{get_configuration, Settings};
+use newsletter::rate_limiting::{rate_limiter, TrustedProxies};
 use newsletter::telemetry::{get_subscriber, init_subscriber};
 use sqlx::{Connection, Executor, PgConnection, PgPool};
 use uuid::Uuid;
 use wiremock::MockServer;
 
 
+/// Domains the test resolver knows about, any other one "has no MX record".
+pub const TEST_DOMAINS: &[&str] = &["example.com", "gmail.com", "outlook.com", "yahoo.co.kr"];
+
 // make sure tracing only run once
 static TRACING: Lazy<()> = Lazy::new(|| {
     let default_filter_level = "info".to_string();
@@ -18,7 +26,7 @@ static TRACING: Lazy<()> = Lazy::new(|| {
         let subscriber = get_subscriber("test".into(), "debug".into(),std::io::stdout);
         init_subscriber(subscriber);
     }else{
-        let subscriber = get_subscriber(subscriber_name.into(),default_filter_level.into(),std::io::sink);
+        let subscriber = get_subscriber(subscriber_name,default_filter_level,std::io::sink);
         init_subscriber(subscriber);
     }
 });
@@ -27,47 +35,283 @@ pub struct TestApp {
     pub address: String,
     pub db_pool: PgPool,
     pub email_server : MockServer,
+    /// Holds every scope, sent by the `/api/v1` helpers.
+    pub api_key: String,
+    /// Session of an owner, sent by the `/admin` helpers.
+    pub admin_token: String,
+}
+
+/// An admin user stored straight in the database, bypassing `/admin/users`.
+pub struct TestUser {
+    pub username: String,
+    pub password: String,
+    pub role: UserRole,
+}
+
+impl TestUser {
+    pub fn generate(role: UserRole) -> Self {
+        Self {
+            username: Uuid::new_v4().to_string(),
+            password: Uuid::new_v4().to_string(),
+            role,
+        }
+    }
+
+    pub async fn store(&self, pool: &PgPool) -> Uuid {
+        let password_hash = compute_password_hash(&Secret::new(self.password.clone()));
+        sqlx::query!(
+            r#"
+            INSERT INTO users (user_id, username, password_hash, role, created_at)
+            VALUES ($1, $2, $3, $4, now())
+            RETURNING user_id
+            "#,
+            Uuid::new_v4(),
+            self.username,
+            password_hash,
+            self.role.as_str()
+        )
+        .fetch_one(pool)
+        .await
+        .expect("Failed to store test user.")
+        .user_id
+    }
+
+    /// Logs in and returns the session token.
+    pub async fn login(&self, app: &TestApp) -> String {
+        let body: serde_json::Value = app
+            .post_login(&serde_json::json!({"username": self.username, "password": self.password}))
+            .await
+            .error_for_status()
+            .expect("Failed to log in.")
+            .json()
+            .await
+            .unwrap();
+        body["session_token"].as_str().unwrap().to_owned()
+    }
 }
 
 impl TestApp {
+    /// A request to an `/admin` route on behalf of the owner.
+    pub fn admin_request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        reqwest::Client::new()
+            .request(method, format!("{}{}", self.address, path))
+            .bearer_auth(&self.admin_token)
+    }
+
+    /// Stores a user with `role` and returns a session token for it.
+    pub async fn login_as(&self, role: UserRole) -> String {
+        let user = TestUser::generate(role);
+        user.store(&self.db_pool).await;
+        user.login(self).await
+    }
+
+    pub async fn post_login(&self, body: &serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/login", self.address))
+            .json(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
     pub async fn post_subscriptions(&self, body:String) -> reqwest::Response{
        reqwest::Client::new()
-           .post(&format!("{}/subscriptions",self.address))
+           .post(format!("{}/subscriptions",self.address))
            .header("Content-Type","application/x-www-form-urlencoded")
            .body(body)
            .send()
            .await
            .expect("Failed to execute request.")
     }
+
+    pub async fn post_subscriptions_json(&self, body: serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/subscriptions", self.address))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    /// Signs up and follows the confirmation link if a confirmation email
+    /// was sent, a mock for `/email` has to be mounted already.
+    pub async fn post_confirmed_subscription(&self, body:String) -> reqwest::Response{
+       let sent_before = self.email_server.received_requests().await.unwrap().len();
+       let response = self.post_subscriptions(body).await;
+       let requests = self.email_server.received_requests().await.unwrap();
+       if let Some(email_request) = requests.get(sent_before..).and_then(|r| r.last()) {
+           reqwest::get(self.get_confirmation_link(email_request))
+               .await
+               .expect("Failed to execute request.")
+               .error_for_status()
+               .unwrap();
+       }
+       response
+    }
+
+    /// Extracts the confirmation link from the plain text body of an email.
+    pub fn get_confirmation_link(&self, email_request: &wiremock::Request) -> String{
+       let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+       let text = body["Text"].as_str().unwrap();
+       let start = text.find(&self.address).expect("No confirmation link found");
+       text[start..].split_whitespace().next().unwrap().to_owned()
+    }
+
+    pub async fn post_issues(&self, body: serde_json::Value) -> reqwest::Response{
+       reqwest::Client::new()
+           .post(format!("{}/admin/issues",self.address))
+           .bearer_auth(&self.admin_token)
+           .json(&body)
+           .send()
+           .await
+           .expect("Failed to execute request.")
+    }
+
+    pub async fn post_lists(&self, body: serde_json::Value) -> reqwest::Response{
+       reqwest::Client::new()
+           .post(format!("{}/admin/lists",self.address))
+           .bearer_auth(&self.admin_token)
+           .json(&body)
+           .send()
+           .await
+           .expect("Failed to execute request.")
+    }
+
+    pub async fn post_segments(&self, body: serde_json::Value) -> reqwest::Response{
+       reqwest::Client::new()
+           .post(format!("{}/admin/segments",self.address))
+           .bearer_auth(&self.admin_token)
+           .json(&body)
+           .send()
+           .await
+           .expect("Failed to execute request.")
+    }
+
+    pub async fn post_subscriber_tags(&self, email: &str, tags: &[&str]) -> reqwest::Response{
+       let subscriber = sqlx::query!("SELECT id FROM subscriptions WHERE email = $1", email)
+           .fetch_one(&self.db_pool)
+           .await
+           .expect("Failed to fetch subscriber.");
+       reqwest::Client::new()
+           .post(format!("{}/admin/subscribers/{}/tags",self.address,subscriber.id))
+           .bearer_auth(&self.admin_token)
+           .json(&serde_json::json!({ "tags": tags }))
+           .send()
+           .await
+           .expect("Failed to execute request.")
+    }
+
+    pub async fn get_api_subscribers(&self, query: &str) -> reqwest::Response{
+       reqwest::Client::new()
+           .get(format!("{}/api/v1/subscribers?{}",self.address,query))
+           .bearer_auth(&self.api_key)
+           .send()
+           .await
+           .expect("Failed to execute request.")
+    }
+
+    pub async fn get_api_subscriber(&self, subscriber_id: &str) -> reqwest::Response{
+       reqwest::Client::new()
+           .get(format!("{}/api/v1/subscribers/{}",self.address,subscriber_id))
+           .bearer_auth(&self.api_key)
+           .send()
+           .await
+           .expect("Failed to execute request.")
+    }
+
+    pub async fn patch_api_subscriber(&self, subscriber_id: &str, body: serde_json::Value) -> reqwest::Response{
+       reqwest::Client::new()
+           .patch(format!("{}/api/v1/subscribers/{}",self.address,subscriber_id))
+           .bearer_auth(&self.api_key)
+           .json(&body)
+           .send()
+           .await
+           .expect("Failed to execute request.")
+    }
+
+    pub async fn delete_api_subscriber(&self, subscriber_id: &str) -> reqwest::Response{
+       reqwest::Client::new()
+           .delete(format!("{}/api/v1/subscribers/{}",self.address,subscriber_id))
+           .bearer_auth(&self.api_key)
+           .send()
+           .await
+           .expect("Failed to execute request.")
+    }
+
+    pub async fn post_api_issues(&self, body: serde_json::Value) -> reqwest::Response{
+       reqwest::Client::new()
+           .post(format!("{}/api/v1/issues",self.address))
+           .bearer_auth(&self.api_key)
+           .json(&body)
+           .send()
+           .await
+           .expect("Failed to execute request.")
+    }
+
+    pub async fn post_api_keys(&self, body: serde_json::Value) -> reqwest::Response{
+       reqwest::Client::new()
+           .post(format!("{}/admin/api_keys",self.address))
+           .bearer_auth(&self.admin_token)
+           .json(&body)
+           .send()
+           .await
+           .expect("Failed to execute request.")
+    }
+
+    pub async fn get_issue_stats(&self, issue_id: &str, format: &str) -> reqwest::Response{
+       reqwest::Client::new()
+           .get(format!("{}/admin/issues/{}/stats?format={}",self.address,issue_id,format))
+           .bearer_auth(&self.admin_token)
+           .send()
+           .await
+           .expect("Failed to execute request.")
+    }
 }
 
-pub async fn spawn_app() -> TestApp{ 
+pub async fn spawn_app() -> TestApp{
+    spawn_app_with(|_| {}).await
+}
+
+/// Like `spawn_app`, with the configuration adjusted by `configure` first.
+pub async fn spawn_app_with(configure: impl FnOnce(&mut Settings)) -> TestApp{
     Lazy::force(&TRACING);
     let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind random port");
     let port = listener.local_addr().unwrap().port();
+    let email_server = MockServer::start().await;
     let mut configuration = get_configuration().expect("Failed to read configuration.");
     configuration.database.database_name = Uuid::new_v4().to_string();
+    configuration.email_client.base_url = email_server.uri();
+    configure(&mut configuration);
     let connection = configue_database(&configuration.database).await;
     let sender_email = configuration.email_client.sender().expect("Invalid sender email address");
     let db_pool = connection;
-    println!("port: {}",port);
-    
+    let address = format!("http://127.0.0.1:{}",port);
+
     let timeout = configuration.email_client.timeout();
+    let domain_verifier = InMemoryDomainVerifier::new(TEST_DOMAINS.iter().copied());
     let email_client = EmailClient::new(configuration.email_client.base_url,sender_email,configuration.email_client.authorization_token,timeout);
-    let email_server = MockServer::start().await;
-    let server = run(listener,db_pool.clone(),email_client).expect("expected to bind address");
-    let _ = tokio::spawn(server);
-    let address = format!("http://127.0.0.1:{}",port);
-    let configuration = {
-        let mut c = get_configuration().expect("failed to read configuration");
-        c.email_client.base_url = email_server.uri();
-    };
-    println!("address: {:?}",configuration);
-    TestApp{
+    let rate_limiter = rate_limiter(&configuration.subscriptions.rate_limiting, db_pool.clone());
+    let server = run(listener,db_pool.clone(),email_client,address.clone(),configuration.subscriptions,Arc::new(domain_verifier),rate_limiter,configuration.application.api_docs,configuration.admin,TrustedProxies(configuration.application.trusted_proxies)).expect("expected to bind address");
+    tokio::spawn(server);
+    let mut app = TestApp{
         address,
         db_pool, 
-        email_server
-    }
+        email_server,
+        api_key: String::new(),
+        admin_token: String::new(),
+    };
+    app.admin_token = app.login_as(UserRole::Owner).await;
+    let created: serde_json::Value = app
+        .post_api_keys(serde_json::json!({
+            "name": "tests",
+            "scopes": ["subscribers:read", "subscribers:write", "issues:publish"],
+        }))
+        .await
+        .json()
+        .await
+        .expect("Failed to create an API key.");
+    app.api_key = created["key"].as_str().unwrap().to_owned();
+    app
 }
 
 pub async fn configue_database(config:&DatabaseSettings) ->PgPool{
@@ -75,7 +319,7 @@ pub async fn configue_database(config:&DatabaseSettings) ->PgPool{
         .await
         .expect("Failed to connect to Postgres.");
     connection
-        .execute(&*format!(r#"CREATE DATABASE "{}";"#,config.database_name).as_str())
+        .execute(format!(r#"CREATE DATABASE "{}";"#,config.database_name).as_str())
         .await
         .expect("Failed to create database.");
     let connection_pool = PgPool::connect_with(config.with_db()).await.expect("failed to connect to postgres");
@@ -0,0 +1,303 @@
+use crate::helpers::{spawn_app, TestApp};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+async fn create_confirmed_subscriber(app: &TestApp) {
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .named("Create confirmed subscriber")
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+    app.post_confirmed_subscription("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await
+        .error_for_status()
+        .unwrap();
+}
+
+fn issue_body(tracking: bool) -> serde_json::Value {
+    serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": r#"<html><body><p>Read <a href="https://example.com/story">this</a></p></body></html>"#,
+        },
+        "tracking": tracking,
+    })
+}
+
+async fn html_of_last_email(app: &TestApp) -> String {
+    let request = app.email_server.received_requests().await.unwrap().pop().unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+    body["HtmlBody"].as_str().unwrap().to_owned()
+}
+
+fn extract_link(html: &str, prefix: &str) -> String {
+    let start = html.find(prefix).expect("No tracked link found");
+    let end = html[start..].find('"').unwrap();
+    html[start..start + end].to_owned()
+}
+
+#[tokio::test]
+async fn issues_are_delivered_to_confirmed_subscribers() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app.post_issues(issue_body(true)).await;
+
+    assert_eq!(200, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert!(body["issue_id"].is_string());
+}
+
+#[tokio::test]
+async fn tracked_issues_record_opens_and_redirect_clicks() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.post_issues(issue_body(true)).await.error_for_status().unwrap();
+    let html = html_of_last_email(&app).await;
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+
+    let pixel = client
+        .get(extract_link(&html, &format!("{}/t/o/", app.address)))
+        .send()
+        .await
+        .unwrap();
+    let click = client
+        .get(extract_link(&html, &format!("{}/t/c/", app.address)))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(200, pixel.status().as_u16());
+    assert_eq!("image/gif", pixel.headers()["Content-Type"]);
+    assert_eq!(302, click.status().as_u16());
+    assert_eq!("https://example.com/story", click.headers()["Location"]);
+    let events = sqlx::query!("SELECT kind, url FROM tracking_events ORDER BY occurred_at")
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Failed to fetch tracking events.");
+    assert_eq!(2, events.len());
+    assert_eq!("open", events[0].kind);
+    assert_eq!("click", events[1].kind);
+    assert_eq!(Some("https://example.com/story"), events[1].url.as_deref());
+}
+
+#[tokio::test]
+async fn preferences_links_are_not_tracked() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    let mut body = issue_body(true);
+    body["content"]["html"] = r#"<html><body><a href="https://example.com/story">Read</a> <a href="{{ preferences_url }}">Preferences</a></body></html>"#.into();
+
+    app.post_issues(body).await.error_for_status().unwrap();
+
+    let html = html_of_last_email(&app).await;
+    assert!(html.contains(&format!(r#"href="{}/preferences/"#, app.address)));
+    let urls = sqlx::query!("SELECT url FROM tracking_tokens WHERE url IS NOT NULL")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(1, urls.len());
+    assert_eq!(Some("https://example.com/story"), urls[0].url.as_deref());
+}
+
+#[tokio::test]
+async fn issues_can_opt_out_of_tracking() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    app.post_issues(issue_body(false)).await.error_for_status().unwrap();
+
+    let html = html_of_last_email(&app).await;
+    assert!(html.contains(r#"href="https://example.com/story""#));
+    assert!(!html.contains("/t/o/"));
+    let tokens = sqlx::query!("SELECT tracking_token FROM tracking_tokens")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(tokens.is_empty());
+}
+
+#[tokio::test]
+async fn unknown_click_tokens_are_rejected_with_404() {
+    let app = spawn_app().await;
+
+    let response = reqwest::get(format!("{}/t/c/not-a-token", app.address))
+        .await
+        .unwrap();
+
+    assert_eq!(404, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn issue_stats_aggregate_deliveries_opens_and_clicks() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    let response = app.post_issues(issue_body(true)).await;
+    let issue_id = response.json::<serde_json::Value>().await.unwrap()["issue_id"]
+        .as_str()
+        .unwrap()
+        .to_owned();
+    let html = html_of_last_email(&app).await;
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+    for link in [
+        extract_link(&html, &format!("{}/t/o/", app.address)),
+        extract_link(&html, &format!("{}/t/o/", app.address)),
+        extract_link(&html, &format!("{}/t/c/", app.address)),
+    ] {
+        client.get(link).send().await.unwrap();
+    }
+    // A subscriber the email provider refused.
+    sqlx::query!(
+        r#"
+        INSERT INTO issue_deliveries (newsletter_issue_id, subscriber_id, status, sent_at)
+        VALUES ($1, $2, 'failed', now())
+        "#,
+        issue_id.parse::<uuid::Uuid>().unwrap(),
+        uuid::Uuid::new_v4()
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    let stats: serde_json::Value = app.get_issue_stats(&issue_id, "json").await.json().await.unwrap();
+    let csv = app.get_issue_stats(&issue_id, "csv").await.text().await.unwrap();
+
+    assert_eq!(2, stats["sent"]);
+    assert_eq!(1, stats["delivered"]);
+    assert_eq!(0, stats["bounced"]);
+    assert_eq!(serde_json::json!({"unique": 1, "total": 2}), stats["opened"]);
+    assert_eq!(serde_json::json!({"unique": 1, "total": 1}), stats["clicked"]);
+    assert_eq!(0, stats["unsubscribed"]);
+    assert_eq!(
+        serde_json::json!([{"url": "https://example.com/story", "unique": 1, "total": 1}]),
+        stats["top_links"]
+    );
+    assert!(csv.starts_with("metric,url,value\nsent,,2\ndelivered,,1\nbounced,,0\n"));
+    assert!(csv.contains("opened_total,,2\n"));
+    assert!(csv.contains("link_clicks,https://example.com/story,1\n"));
+}
+
+#[tokio::test]
+async fn stats_for_an_unknown_issue_are_a_404() {
+    let app = spawn_app().await;
+
+    let response = app.get_issue_stats(&uuid::Uuid::new_v4().to_string(), "json").await;
+
+    assert_eq!(404, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn subscribers_already_sent_an_issue_are_skipped() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let mut body = issue_body(false);
+    body["draft"] = true.into();
+    let issue_id = app.post_issues(body).await.json::<serde_json::Value>().await.unwrap()["issue_id"]
+        .as_str()
+        .unwrap()
+        .parse::<uuid::Uuid>()
+        .unwrap();
+    // A previous attempt got as far as this subscriber.
+    sqlx::query!(
+        r#"
+        INSERT INTO issue_deliveries (newsletter_issue_id, subscriber_id, status, sent_at)
+        SELECT $1, id, 'sent', now() FROM subscriptions
+        "#,
+        issue_id
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .admin_request(reqwest::Method::POST, &format!("/admin/issues/{}/publish", issue_id))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(200, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn failed_deliveries_leave_the_issue_unpublished_for_a_retry() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let failing = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount_as_scoped(&app.email_server)
+        .await;
+
+    let response = app.post_issues(issue_body(false)).await;
+
+    assert_eq!(500, response.status().as_u16());
+    let issue = sqlx::query!("SELECT newsletter_issue_id, published_at FROM newsletter_issues")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(issue.published_at.is_none());
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert!(problem["detail"].as_str().unwrap().contains(&issue.newsletter_issue_id.to_string()));
+
+    drop(failing);
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    let retry = app
+        .admin_request(reqwest::Method::POST, &format!("/admin/issues/{}/publish", issue.newsletter_issue_id))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(200, retry.status().as_u16());
+    let published_at = sqlx::query!("SELECT published_at FROM newsletter_issues")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .published_at;
+    assert!(published_at.is_some());
+}
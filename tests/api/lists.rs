@@ -0,0 +1,186 @@
+use crate::helpers::spawn_app;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+#[tokio::test]
+async fn lists_can_be_created_and_listed() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_lists(serde_json::json!({"slug": "rust-weekly", "name": "Rust Weekly"}))
+        .await;
+    let duplicate = app
+        .post_lists(serde_json::json!({"slug": "rust-weekly", "name": "Again"}))
+        .await;
+
+    assert_eq!(200, response.status().as_u16());
+    assert_eq!(409, duplicate.status().as_u16());
+    let lists: serde_json::Value = app
+        .admin_request(reqwest::Method::GET, "/admin/lists")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let slugs: Vec<_> = lists
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|l| l["slug"].as_str().unwrap())
+        .collect();
+    assert_eq!(vec!["default", "rust-weekly"], slugs);
+}
+
+#[tokio::test]
+async fn subscribing_to_an_unknown_list_is_rejected() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com&list=nope".into())
+        .await;
+
+    assert_eq!(400, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn the_same_email_can_join_several_lists() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.post_lists(serde_json::json!({"slug": "rust-weekly", "name": "Rust Weekly"}))
+        .await;
+
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    app.post_confirmed_subscription(body.into()).await;
+    let response = app
+        .post_confirmed_subscription(format!("{}&list=rust-weekly", body))
+        .await;
+
+    assert_eq!(200, response.status().as_u16());
+    let memberships = sqlx::query!(
+        r#"
+        SELECT l.slug, m.status
+        FROM list_memberships m
+        JOIN lists l ON l.list_id = m.list_id
+        ORDER BY l.slug
+        "#
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .expect("Failed to fetch memberships.");
+    assert_eq!(2, memberships.len());
+    assert_eq!("default", memberships[0].slug);
+    assert_eq!("rust-weekly", memberships[1].slug);
+    assert_eq!("confirmed", memberships[1].status);
+}
+
+#[tokio::test]
+async fn confirmed_subscribers_confirm_every_list_they_join() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(3)
+        .mount(&app.email_server)
+        .await;
+    app.post_lists(serde_json::json!({"slug": "rust-weekly", "name": "Rust Weekly"}))
+        .await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    app.post_confirmed_subscription(body.into()).await;
+
+    let joined = app
+        .post_subscriptions(format!("{}&list=rust-weekly", body))
+        .await;
+    sqlx::query!(
+        r#"
+        UPDATE list_memberships SET status = 'unsubscribed'
+        WHERE list_id = (SELECT list_id FROM lists WHERE slug = 'default')
+        "#
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    let rejoined = app.post_subscriptions(body.into()).await;
+
+    assert_eq!(200, joined.status().as_u16());
+    assert_eq!(200, rejoined.status().as_u16());
+    let memberships = sqlx::query!(
+        r#"
+        SELECT l.slug, m.status
+        FROM list_memberships m
+        JOIN lists l ON l.list_id = m.list_id
+        ORDER BY l.slug
+        "#
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .expect("Failed to fetch memberships.");
+    assert_eq!("pending_confirmation", memberships[0].status);
+    assert_eq!("pending_confirmation", memberships[1].status);
+}
+
+#[tokio::test]
+async fn simultaneous_signups_of_the_same_email_both_succeed() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    let (first, second) = tokio::join!(
+        app.post_subscriptions(body.into()),
+        app.post_subscriptions(body.into())
+    );
+
+    assert_eq!(200, first.status().as_u16());
+    assert_eq!(200, second.status().as_u16());
+    let saved = sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(1, saved.len());
+}
+
+#[tokio::test]
+async fn issues_are_only_sent_to_members_of_the_target_list() {
+    let app = spawn_app().await;
+    app.post_lists(serde_json::json!({"slug": "rust-weekly", "name": "Rust Weekly"}))
+        .await;
+    {
+        let _guard = Mock::given(path("/email"))
+            .and(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(2)
+            .mount_as_scoped(&app.email_server)
+            .await;
+        app.post_confirmed_subscription("name=a&email=a%40example.com&list=rust-weekly".into())
+            .await;
+        app.post_confirmed_subscription("name=b&email=b%40example.com".into())
+            .await;
+    }
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_issues(serde_json::json!({
+            "title": "Rust Weekly #1",
+            "content": {"text": "Hello", "html": "<p>Hello</p>"},
+            "list": "rust-weekly",
+        }))
+        .await;
+
+    assert_eq!(200, response.status().as_u16());
+    let request = app.email_server.received_requests().await.unwrap().pop().unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+    assert_eq!("a@example.com", body["To"]);
+}
@@ -0,0 +1,290 @@
+use crate::helpers::{spawn_app, TestApp, TestUser};
+use newsletter::domain::UserRole;
+
+#[tokio::test]
+async fn login_returns_a_session_token_for_valid_credentials() {
+    let app = spawn_app().await;
+    let user = TestUser::generate(UserRole::Viewer);
+    user.store(&app.db_pool).await;
+
+    let response = app
+        .post_login(&serde_json::json!({"username": user.username, "password": user.password}))
+        .await;
+
+    assert_eq!(200, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    let token = body["session_token"].as_str().unwrap();
+    assert!(body["expires_at"].is_string());
+    let stored = sqlx::query!("SELECT session_token_hash FROM user_sessions")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(stored.iter().all(|s| s.session_token_hash != token));
+}
+
+#[tokio::test]
+async fn login_rejects_a_wrong_password_or_unknown_user() {
+    let app = spawn_app().await;
+    let user = TestUser::generate(UserRole::Owner);
+    user.store(&app.db_pool).await;
+    let test_cases = vec![
+        (user.username.as_str(), "not the password", "wrong password"),
+        ("nobody", user.password.as_str(), "unknown user"),
+    ];
+
+    for (username, password, description) in test_cases {
+        let response = app
+            .post_login(&serde_json::json!({"username": username, "password": password}))
+            .await;
+
+        assert_eq!(
+            401,
+            response.status().as_u16(),
+            "The API did not reject a login with a {}.",
+            description
+        );
+        assert_eq!(
+            "application/problem+json",
+            response.headers()["Content-Type"]
+        );
+    }
+}
+
+#[tokio::test]
+async fn admin_routes_require_a_session() {
+    let app = spawn_app().await;
+
+    let response = reqwest::get(format!("{}/admin/lists", app.address))
+        .await
+        .unwrap();
+
+    assert_eq!(401, response.status().as_u16());
+    assert_eq!("Bearer", response.headers()["WWW-Authenticate"]);
+}
+
+#[tokio::test]
+async fn logging_out_ends_the_session() {
+    let app = spawn_app().await;
+    let token = app.login_as(UserRole::Viewer).await;
+    let client = reqwest::Client::new();
+
+    let response = client
+        .post(format!("{}/logout", app.address))
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(200, response.status().as_u16());
+    let lists = client
+        .get(format!("{}/admin/lists", app.address))
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(401, lists.status().as_u16());
+}
+
+#[tokio::test]
+async fn expired_sessions_are_rejected() {
+    let app = spawn_app().await;
+    let token = app.login_as(UserRole::Owner).await;
+    sqlx::query!("UPDATE user_sessions SET expires_at = now() - interval '1 minute'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let response = reqwest::Client::new()
+        .get(format!("{}/admin/lists", app.address))
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(401, response.status().as_u16());
+}
+
+/// Stores `count` failed logins of `username` from `ip_address`, made just now.
+async fn seed_failed_logins(app: &TestApp, username: &str, ip_address: &str, count: i32) {
+    sqlx::query!(
+        r#"
+        INSERT INTO failed_logins (username, ip_address, attempted_at)
+        SELECT $1, $2, now() FROM generate_series(1, $3)
+        "#,
+        username,
+        ip_address,
+        count
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+}
+
+async fn failed_login_count(app: &TestApp) -> i64 {
+    sqlx::query!(r#"SELECT count(*) AS "count!" FROM failed_logins"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .count
+}
+
+fn retry_after(response: &reqwest::Response) -> i64 {
+    response.headers()["Retry-After"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn failed_logins_are_recorded_and_cleared_by_a_success() {
+    let app = spawn_app().await;
+    let user = TestUser::generate(UserRole::Viewer);
+    user.store(&app.db_pool).await;
+    let wrong = serde_json::json!({"username": user.username, "password": "not the password"});
+
+    app.post_login(&wrong).await;
+    app.post_login(&wrong).await;
+    assert_eq!(2, failed_login_count(&app).await);
+    user.login(&app).await;
+
+    assert_eq!(0, failed_login_count(&app).await);
+}
+
+#[tokio::test]
+async fn a_success_only_clears_the_failures_of_its_address() {
+    let app = spawn_app().await;
+    let user = TestUser::generate(UserRole::Viewer);
+    user.store(&app.db_pool).await;
+    seed_failed_logins(&app, &user.username, "192.0.2.1", 1).await;
+    seed_failed_logins(&app, &user.username, "127.0.0.1", 1).await;
+
+    user.login(&app).await;
+
+    let remaining = sqlx::query!("SELECT ip_address FROM failed_logins")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(1, remaining.len());
+    assert_eq!("192.0.2.1", remaining[0].ip_address);
+}
+
+#[tokio::test]
+async fn repeated_failures_make_the_next_login_wait() {
+    let app = spawn_app().await;
+    let user = TestUser::generate(UserRole::Viewer);
+    user.store(&app.db_pool).await;
+    seed_failed_logins(&app, &user.username, "127.0.0.1", 5).await;
+
+    let response = app
+        .post_login(&serde_json::json!({"username": user.username, "password": user.password}))
+        .await;
+
+    assert_eq!(429, response.status().as_u16());
+    let wait = retry_after(&response);
+    assert!((1..=4).contains(&wait), "Unexpected Retry-After {}", wait);
+    assert_eq!(5, failed_login_count(&app).await);
+}
+
+#[tokio::test]
+async fn too_many_failures_lock_the_username() {
+    let app = spawn_app().await;
+    let user = TestUser::generate(UserRole::Viewer);
+    user.store(&app.db_pool).await;
+    let other = TestUser::generate(UserRole::Viewer);
+    other.store(&app.db_pool).await;
+    seed_failed_logins(&app, &user.username, "127.0.0.1", 10).await;
+
+    let locked = app
+        .post_login(&serde_json::json!({"username": user.username, "password": user.password}))
+        .await;
+    let unaffected = app
+        .post_login(&serde_json::json!({"username": other.username, "password": other.password}))
+        .await;
+
+    assert_eq!(429, locked.status().as_u16());
+    assert!(retry_after(&locked) > 14 * 60);
+    assert_eq!(200, unaffected.status().as_u16());
+}
+
+#[tokio::test]
+async fn failures_from_another_address_do_not_lock_the_username() {
+    let app = spawn_app().await;
+    let user = TestUser::generate(UserRole::Viewer);
+    user.store(&app.db_pool).await;
+    seed_failed_logins(&app, &user.username, "192.0.2.1", 10).await;
+    sqlx::query!("UPDATE failed_logins SET attempted_at = now() - interval '2 minutes'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let response = app
+        .post_login(&serde_json::json!({"username": user.username, "password": user.password}))
+        .await;
+
+    assert_eq!(200, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn parallel_guesses_are_throttled_like_sequential_ones() {
+    let app = spawn_app().await;
+    let user = TestUser::generate(UserRole::Viewer);
+    user.store(&app.db_pool).await;
+    let wrong = serde_json::json!({"username": user.username, "password": "not the password"});
+
+    let responses = tokio::join!(
+        app.post_login(&wrong),
+        app.post_login(&wrong),
+        app.post_login(&wrong),
+        app.post_login(&wrong),
+        app.post_login(&wrong),
+        app.post_login(&wrong)
+    );
+
+    let statuses = [
+        responses.0.status().as_u16(),
+        responses.1.status().as_u16(),
+        responses.2.status().as_u16(),
+        responses.3.status().as_u16(),
+        responses.4.status().as_u16(),
+        responses.5.status().as_u16(),
+    ];
+    // Only the free failures get to check the password.
+    assert_eq!(3, statuses.iter().filter(|status| **status == 401).count());
+    assert_eq!(3, statuses.iter().filter(|status| **status == 429).count());
+    assert_eq!(3, failed_login_count(&app).await);
+}
+
+#[tokio::test]
+async fn too_many_failures_lock_the_address() {
+    let app = spawn_app().await;
+    let user = TestUser::generate(UserRole::Viewer);
+    user.store(&app.db_pool).await;
+    for i in 0..50 {
+        seed_failed_logins(&app, &format!("guess-{}", i), "127.0.0.1", 1).await;
+    }
+
+    let response = app
+        .post_login(&serde_json::json!({"username": user.username, "password": user.password}))
+        .await;
+
+    assert_eq!(429, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn failures_outside_the_window_do_not_count() {
+    let app = spawn_app().await;
+    let user = TestUser::generate(UserRole::Viewer);
+    user.store(&app.db_pool).await;
+    seed_failed_logins(&app, &user.username, "127.0.0.1", 10).await;
+    sqlx::query!("UPDATE failed_logins SET attempted_at = now() - interval '16 minutes'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let response = app
+        .post_login(&serde_json::json!({"username": user.username, "password": user.password}))
+        .await;
+
+    assert_eq!(200, response.status().as_u16());
+}
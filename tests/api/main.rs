@@ -1,3 +1,18 @@
 mod helpers;
+mod admin_access;
+mod api_keys;
+mod api_subscribers;
+mod attributes;
 mod health_check;
+mod issues;
+mod lists;
+mod login;
+mod openapi;
+mod password;
+mod preferences;
+mod rate_limiting;
+mod segments;
+mod signup_protection;
+mod subscription_cleanup;
 mod subscriptions;
+mod two_factor;
@@ -0,0 +1,137 @@
+use crate::helpers::spawn_app;
+use newsletter::routes::ApiDoc;
+use utoipa::OpenApi;
+
+const CHECKED_IN_SPEC: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/openapi.json");
+const STARTUP_SOURCE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/startup.rs");
+
+/// Routes serving HTML pages, tracking pixels or the docs themselves, which
+/// programmatic clients have no use for.
+const UNDOCUMENTED_ROUTES: &[(&str, &str)] = &[
+    ("get", "/openapi.json"),
+    ("get", "/docs"),
+    ("get", "/subscriptions"),
+    ("get", "/password_reset/{token}"),
+    ("post", "/password_reset/{token}"),
+    ("get", "/preferences/email/confirm"),
+    ("get", "/preferences/{token}"),
+    ("post", "/preferences/{token}"),
+    ("post", "/preferences/{token}/email"),
+    ("post", "/preferences/{token}/unsubscribe"),
+    ("get", "/t/o/{token}.gif"),
+    ("get", "/t/c/{token}"),
+];
+
+/// Run with `UPDATE_OPENAPI=1` to regenerate the checked-in spec.
+#[test]
+fn checked_in_openapi_spec_matches_the_generated_one() {
+    let generated = ApiDoc::openapi().to_pretty_json().unwrap() + "\n";
+    if std::env::var("UPDATE_OPENAPI").is_ok() {
+        std::fs::write(CHECKED_IN_SPEC, &generated).expect("Failed to write openapi.json.");
+    }
+    let checked_in =
+        std::fs::read_to_string(CHECKED_IN_SPEC).expect("Failed to read openapi.json.");
+    assert!(
+        checked_in == generated,
+        "openapi.json is out of date, regenerate it with `UPDATE_OPENAPI=1 cargo test openapi`."
+    );
+}
+
+#[test]
+fn every_registered_route_is_documented() {
+    let spec = serde_json::to_value(ApiDoc::openapi()).unwrap();
+    let routes = registered_routes();
+    assert!(routes.contains(&("post".into(), "/api/v1/issues".into())));
+    assert!(routes.contains(&("post".into(), "/subscriptions".into())));
+
+    let undocumented: Vec<_> = routes
+        .iter()
+        .filter(|(method, path)| !spec["paths"][path][method].is_object())
+        .filter(|(method, path)| !UNDOCUMENTED_ROUTES.contains(&(method.as_str(), path.as_str())))
+        .collect();
+    assert!(
+        undocumented.is_empty(),
+        "Add these routes to ApiDoc or to UNDOCUMENTED_ROUTES: {:?}",
+        undocumented
+    );
+    for (method, path) in UNDOCUMENTED_ROUTES {
+        assert!(
+            routes.contains(&(method.to_string(), path.to_string())),
+            "{} {} is no longer registered.",
+            method,
+            path
+        );
+    }
+}
+
+/// Reads the `(method, path)` pairs out of the route registrations in
+/// `startup.rs`, prefixing the paths of scopes and resources.
+pub fn registered_routes() -> Vec<(String, String)> {
+    let source: String = std::fs::read_to_string(STARTUP_SOURCE)
+        .expect("Failed to read startup.rs.")
+        .lines()
+        .filter(|line| !line.trim_start().starts_with("//"))
+        .flat_map(|line| line.chars())
+        .filter(|c| !c.is_whitespace())
+        .collect();
+    let quoted = |s: &str| s[..s.find('"').unwrap()].to_owned();
+    let method = |s: &str| s[..s.find('(').unwrap()].to_owned();
+    let mut routes = Vec::new();
+    // The paren depth each scope or resource was opened at, with its path.
+    let mut prefixes: Vec<(usize, String)> = Vec::new();
+    let mut depth = 0;
+    for (i, c) in source.char_indices() {
+        let rest = &source[i..];
+        let prefix: String = prefixes.iter().map(|(_, p)| p.as_str()).collect();
+        if let Some(rest) = rest
+            .strip_prefix("web::scope(\"")
+            .or_else(|| rest.strip_prefix("web::resource(\""))
+        {
+            prefixes.push((depth, quoted(rest)));
+        } else if let Some(rest) = rest.strip_prefix(".route(\"") {
+            let path = quoted(rest);
+            let rest = rest[path.len()..].strip_prefix("\",web::").unwrap();
+            routes.push((method(rest), prefix + &path));
+        } else if let Some(rest) = rest.strip_prefix(".route(web::") {
+            routes.push((method(rest), prefix));
+        }
+        match c {
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                prefixes.retain(|(opened_at, _)| *opened_at <= depth);
+            }
+            _ => {}
+        }
+    }
+    routes
+}
+
+#[tokio::test]
+async fn the_spec_is_served_as_json() {
+    let app = spawn_app().await;
+
+    let response = reqwest::get(format!("{}/openapi.json", app.address))
+        .await
+        .unwrap();
+
+    assert_eq!(200, response.status().as_u16());
+    let spec: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(spec["openapi"], "3.1.0");
+    assert!(spec["paths"]["/api/v1/subscribers/{subscriber_id}"]["patch"].is_object());
+    assert!(spec["components"]["schemas"]["FormData"].is_object());
+}
+
+#[tokio::test]
+async fn the_docs_ui_points_at_the_spec() {
+    let app = spawn_app().await;
+
+    let response = reqwest::get(format!("{}/docs", app.address)).await.unwrap();
+
+    assert_eq!(200, response.status().as_u16());
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains(r#"data-url="/openapi.json""#));
+}
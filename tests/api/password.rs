@@ -0,0 +1,316 @@
+use crate::helpers::{spawn_app, spawn_app_with, TestApp, TestUser};
+use newsletter::domain::UserRole;
+use reqwest::Method;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+const NEW_PASSWORD: &str = "a brand new long password";
+
+fn request_as(app: &TestApp, token: &str, method: Method, path: &str) -> reqwest::RequestBuilder {
+    reqwest::Client::new()
+        .request(method, format!("{}{}", app.address, path))
+        .bearer_auth(token)
+}
+
+async fn put_password(
+    app: &TestApp,
+    token: &str,
+    current_password: &str,
+    new_password: &str,
+) -> reqwest::Response {
+    request_as(app, token, Method::PUT, "/admin/account/password")
+        .json(&serde_json::json!({
+            "current_password": current_password,
+            "new_password": new_password,
+        }))
+        .send()
+        .await
+        .unwrap()
+}
+
+async fn login_status(app: &TestApp, username: &str, password: &str) -> u16 {
+    app.post_login(&serde_json::json!({"username": username, "password": password}))
+        .await
+        .status()
+        .as_u16()
+}
+
+/// Stores a user with an email address, ready to reset the password.
+async fn user_with_email(app: &TestApp) -> TestUser {
+    let user = TestUser::generate(UserRole::Editor);
+    let user_id = user.store(&app.db_pool).await;
+    sqlx::query!(
+        "UPDATE users SET email = 'editor@example.com' WHERE user_id = $1",
+        user_id
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    user
+}
+
+/// Requests a reset for `user` and returns the link from the email.
+async fn request_reset_link(app: &TestApp, user: &TestUser) -> String {
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+    let sent_before = sent_emails(app).await;
+    let response = post_password_reset(app, &user.username).await;
+    assert_eq!(200, response.status().as_u16());
+    let email_request = wait_for_email(app, sent_before).await;
+    app.get_confirmation_link(&email_request)
+}
+
+async fn sent_emails(app: &TestApp) -> usize {
+    app.email_server.received_requests().await.unwrap().len()
+}
+
+/// Reset emails go out after the response, this waits for the next one.
+async fn wait_for_email(app: &TestApp, sent_before: usize) -> wiremock::Request {
+    for _ in 0..100 {
+        let mut requests = app.email_server.received_requests().await.unwrap();
+        if requests.len() > sent_before {
+            return requests.pop().unwrap();
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    panic!("No email was sent.");
+}
+
+async fn post_password_reset(app: &TestApp, username: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("{}/password_reset", app.address))
+        .json(&serde_json::json!({ "username": username }))
+        .send()
+        .await
+        .unwrap()
+}
+
+async fn post_new_password(link: &str, new_password: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(link)
+        .form(&[("new_password", new_password)])
+        .send()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn changing_the_password_needs_the_current_one() {
+    let app = spawn_app().await;
+    let user = TestUser::generate(UserRole::Viewer);
+    user.store(&app.db_pool).await;
+    let token = user.login(&app).await;
+
+    let response = put_password(&app, &token, "not the password", NEW_PASSWORD).await;
+
+    assert_eq!(400, response.status().as_u16());
+    assert_eq!(
+        200,
+        login_status(&app, &user.username, &user.password).await
+    );
+}
+
+#[tokio::test]
+async fn weak_new_passwords_are_rejected() {
+    let app = spawn_app().await;
+    let user = TestUser::generate(UserRole::Viewer);
+    user.store(&app.db_pool).await;
+    let token = user.login(&app).await;
+    let with_username = format!("my name is {}", user.username);
+    let test_cases = vec![
+        ("short", "a short password"),
+        (with_username.as_str(), "the username"),
+        (user.password.as_str(), "the current password"),
+    ];
+
+    for (new_password, description) in test_cases {
+        let response = put_password(&app, &token, &user.password, new_password).await;
+
+        assert_eq!(
+            400,
+            response.status().as_u16(),
+            "The API did not reject {} as the new password.",
+            description
+        );
+    }
+}
+
+#[tokio::test]
+async fn a_changed_password_ends_the_other_sessions() {
+    let app = spawn_app().await;
+    let user = TestUser::generate(UserRole::Viewer);
+    user.store(&app.db_pool).await;
+    let token = user.login(&app).await;
+    let other_token = user.login(&app).await;
+
+    let response = put_password(&app, &token, &user.password, NEW_PASSWORD).await;
+
+    assert_eq!(200, response.status().as_u16());
+    assert_eq!(
+        401,
+        login_status(&app, &user.username, &user.password).await
+    );
+    assert_eq!(200, login_status(&app, &user.username, NEW_PASSWORD).await);
+    let current = request_as(&app, &token, Method::GET, "/admin/lists")
+        .send()
+        .await
+        .unwrap();
+    let other = request_as(&app, &other_token, Method::GET, "/admin/lists")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(200, current.status().as_u16());
+    assert_eq!(401, other.status().as_u16());
+}
+
+#[tokio::test]
+async fn a_reset_link_sets_a_new_password_once() {
+    let app = spawn_app().await;
+    let user = user_with_email(&app).await;
+    let old_session = user.login(&app).await;
+    let link = request_reset_link(&app, &user).await;
+
+    let form = reqwest::get(&link).await.unwrap();
+    let first = post_new_password(&link, NEW_PASSWORD).await;
+    let second = post_new_password(&link, "yet another long password").await;
+
+    assert_eq!(200, form.status().as_u16());
+    assert!(form
+        .text()
+        .await
+        .unwrap()
+        .contains(r#"name="new_password""#));
+    assert_eq!(200, first.status().as_u16());
+    assert_eq!(404, second.status().as_u16());
+    assert_eq!(200, login_status(&app, &user.username, NEW_PASSWORD).await);
+    let old = request_as(&app, &old_session, Method::GET, "/admin/lists")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(401, old.status().as_u16());
+}
+
+#[tokio::test]
+async fn a_weak_password_keeps_the_reset_link_usable() {
+    let app = spawn_app().await;
+    let user = user_with_email(&app).await;
+    let link = request_reset_link(&app, &user).await;
+
+    let weak = post_new_password(&link, "short").await;
+    let strong = post_new_password(&link, NEW_PASSWORD).await;
+
+    assert_eq!(400, weak.status().as_u16());
+    assert!(weak.text().await.unwrap().contains("between 12 and 128"));
+    assert_eq!(200, strong.status().as_u16());
+}
+
+#[tokio::test]
+async fn using_one_reset_link_voids_the_others() {
+    let app = spawn_app().await;
+    let user = user_with_email(&app).await;
+    let first_link = request_reset_link(&app, &user).await;
+    let second_link = request_reset_link(&app, &user).await;
+
+    post_new_password(&second_link, NEW_PASSWORD)
+        .await
+        .error_for_status()
+        .unwrap();
+    let response = post_new_password(&first_link, "yet another long password").await;
+
+    assert_eq!(404, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn expired_reset_links_are_rejected() {
+    let app = spawn_app().await;
+    let user = user_with_email(&app).await;
+    let link = request_reset_link(&app, &user).await;
+    sqlx::query!("UPDATE password_reset_tokens SET expires_at = now() - interval '1 minute'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let form = reqwest::get(&link).await.unwrap();
+    let response = post_new_password(&link, NEW_PASSWORD).await;
+
+    assert_eq!(404, form.status().as_u16());
+    assert_eq!(404, response.status().as_u16());
+    assert_eq!(
+        200,
+        login_status(&app, &user.username, &user.password).await
+    );
+}
+
+#[tokio::test]
+async fn reset_requests_do_not_reveal_which_users_exist() {
+    let app = spawn_app().await;
+    let without_email = TestUser::generate(UserRole::Viewer);
+    without_email.store(&app.db_pool).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    let unknown = post_password_reset(&app, "nobody").await;
+    let no_email = post_password_reset(&app, &without_email.username).await;
+
+    assert_eq!(200, unknown.status().as_u16());
+    assert_eq!(200, no_email.status().as_u16());
+}
+
+#[tokio::test]
+async fn reset_requests_are_limited_per_username() {
+    let app = spawn_app_with(|c| {
+        c.admin.password_reset_rate_limiting.per_username_requests = 1;
+    })
+    .await;
+    let user = user_with_email(&app).await;
+    request_reset_link(&app, &user).await;
+
+    let response = post_password_reset(&app, &user.username).await;
+
+    assert_eq!(200, response.status().as_u16());
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    let tokens = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM password_reset_tokens"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(1, tokens.count);
+}
+
+#[tokio::test]
+async fn reset_requests_are_limited_per_address() {
+    let app = spawn_app_with(|c| {
+        c.admin.password_reset_rate_limiting.per_ip_requests = 1;
+    })
+    .await;
+    let user = user_with_email(&app).await;
+    post_password_reset(&app, "nobody").await;
+
+    let response = post_password_reset(&app, &user.username).await;
+
+    assert_eq!(200, response.status().as_u16());
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    assert_eq!(0, sent_emails(&app).await);
+}
+
+#[tokio::test]
+async fn reset_tokens_are_stored_hashed() {
+    let app = spawn_app().await;
+    let user = user_with_email(&app).await;
+    let link = request_reset_link(&app, &user).await;
+    let token = link.rsplit('/').next().unwrap();
+
+    let stored = sqlx::query!("SELECT token_hash FROM password_reset_tokens")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+
+    assert_ne!(token, stored.token_hash);
+}
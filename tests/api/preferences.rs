@@ -0,0 +1,360 @@
+use crate::helpers::{spawn_app, TestApp};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+/// Subscribes a reader, sends them an issue and returns the preference link
+/// it contained along with the issue id.
+async fn preferences_link(app: &TestApp) -> (String, String) {
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.post_lists(serde_json::json!({"slug": "rust-weekly", "name": "Rust Weekly"}))
+        .await;
+    app.post_confirmed_subscription("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    let response = app
+        .post_issues(serde_json::json!({
+            "title": "Hello",
+            "content": {"text": "Manage: {{ preferences_url }}", "html": "<p>Hi</p>"},
+            "tracking": false,
+        }))
+        .await;
+    let issue_id = response.json::<serde_json::Value>().await.unwrap()["issue_id"]
+        .as_str()
+        .unwrap()
+        .to_owned();
+    let request = app.email_server.received_requests().await.unwrap().pop().unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+    let link = body["Text"].as_str().unwrap().trim_start_matches("Manage: ").to_owned();
+    (link, issue_id)
+}
+
+fn without_query(link: &str) -> &str {
+    link.split('?').next().unwrap()
+}
+
+/// Opens the preference center at `link` like a browser, then posts `body`
+/// to `url` with the CSRF cookie and token the page handed out.
+async fn post_form(link: &str, url: &str, body: &str) -> reqwest::Response {
+    let page = reqwest::get(link).await.unwrap();
+    let cookie = page.headers()["Set-Cookie"]
+        .to_str()
+        .unwrap()
+        .split(';')
+        .next()
+        .unwrap()
+        .to_owned();
+    let (_, csrf_token) = cookie.split_once('=').unwrap();
+    reqwest::Client::new()
+        .post(url)
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .header("Cookie", &cookie)
+        .body(format!("{}&csrf_token={}", body, csrf_token))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+#[tokio::test]
+async fn the_preference_center_shows_the_current_choices() {
+    let app = spawn_app().await;
+    let (link, issue_id) = preferences_link(&app).await;
+
+    let response = reqwest::get(&link).await.unwrap();
+
+    assert_eq!(200, response.status().as_u16());
+    let html = response.text().await.unwrap();
+    assert!(html.contains(r#"value="le guin""#));
+    assert!(html.contains(r#"name="list.default" checked"#));
+    assert!(html.contains(r#"<input type="checkbox" name="list.rust-weekly" />"#));
+    assert!(html.contains(&format!(r#"name="issue" value="{}""#, issue_id)));
+}
+
+#[tokio::test]
+async fn unknown_preference_tokens_are_a_404() {
+    let app = spawn_app().await;
+
+    let response = reqwest::get(format!("{}/preferences/not-a-token", app.address))
+        .await
+        .unwrap();
+
+    assert_eq!(404, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn saving_preferences_updates_name_lists_and_frequency() {
+    let app = spawn_app().await;
+    let (link, _) = preferences_link(&app).await;
+
+    let response = post_form(
+        &link,
+        without_query(&link),
+        "name=Ursula%20K.%20Le%20Guin&frequency=weekly&list.rust-weekly=on",
+    )
+    .await;
+
+    assert_eq!(200, response.status().as_u16());
+    let saved = sqlx::query!("SELECT name, delivery_frequency FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!("Ursula K. Le Guin", saved.name);
+    assert_eq!("weekly", saved.delivery_frequency);
+    let memberships = sqlx::query!(
+        r#"
+        SELECT l.slug, m.status
+        FROM list_memberships m JOIN lists l ON l.list_id = m.list_id
+        ORDER BY l.slug
+        "#
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(("default", "unsubscribed"), (memberships[0].slug.as_str(), memberships[0].status.as_str()));
+    assert_eq!(("rust-weekly", "confirmed"), (memberships[1].slug.as_str(), memberships[1].status.as_str()));
+}
+
+#[tokio::test]
+async fn invalid_preferences_are_rejected() {
+    let app = spawn_app().await;
+    let (link, _) = preferences_link(&app).await;
+    let test_cases = vec![
+        ("name=&frequency=weekly", "an empty name"),
+        ("name=%3Cb%3E&frequency=weekly", "a name with forbidden characters"),
+        ("name=Ursula&frequency=hourly", "an unknown frequency"),
+        ("name=Ursula&frequency=weekly&list.nope=on", "an unknown list"),
+    ];
+
+    for (body, description) in test_cases {
+        let response = post_form(&link, without_query(&link), body).await;
+        assert_eq!(
+            400,
+            response.status().as_u16(),
+            "The API did not return a 400 when the payload had {}.",
+            description
+        );
+    }
+    let saved = sqlx::query!("SELECT name FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!("le guin", saved.name);
+}
+
+#[tokio::test]
+async fn unsubscribing_from_everything_is_counted_against_the_issue() {
+    let app = spawn_app().await;
+    let (link, issue_id) = preferences_link(&app).await;
+
+    let response = post_form(
+        &link,
+        &format!("{}/unsubscribe", without_query(&link)),
+        &format!("issue={}", issue_id),
+    )
+    .await;
+
+    assert_eq!(200, response.status().as_u16());
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!("unsubscribed", saved.status);
+    let stats: serde_json::Value = app.get_issue_stats(&issue_id, "json").await.json().await.unwrap();
+    assert_eq!(1, stats["unsubscribed"]);
+}
+
+#[tokio::test]
+async fn forms_without_the_csrf_token_are_rejected() {
+    let app = spawn_app().await;
+    let (link, _) = preferences_link(&app).await;
+    let page = reqwest::get(&link).await.unwrap();
+    let cookie = page.headers()["Set-Cookie"].to_str().unwrap().to_owned();
+    let cookie = cookie.split(';').next().unwrap();
+    let client = reqwest::Client::new();
+
+    for (path, body, cookie) in [
+        ("", "name=Mallory&frequency=weekly", cookie),
+        ("/unsubscribe", "csrf_token=guessed", cookie),
+        ("/email", "email=mallory%40example.com", ""),
+    ] {
+        let response = client
+            .post(format!("{}{}", without_query(&link), path))
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .header("Cookie", cookie)
+            .body(body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(403, response.status().as_u16(), "{} was accepted", path);
+    }
+    let saved = sqlx::query!("SELECT name, status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(
+        ("le guin", "confirmed"),
+        (saved.name.as_str(), saved.status.as_str())
+    );
+}
+
+/// Requests an email change from the preference center and returns the
+/// confirmation link sent to the new address.
+async fn request_email_change(app: &TestApp, link: &str, new_email: &str) -> String {
+    let response = post_form(
+        link,
+        &format!("{}/email", without_query(link)),
+        &format!("email={}", new_email.replace('@', "%40")),
+    )
+    .await;
+    assert_eq!(200, response.status().as_u16());
+    let request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+    assert_eq!(body["To"], new_email);
+    let text = body["Text"].as_str().unwrap();
+    let start = text.find("http://").unwrap();
+    text[start..].split_whitespace().next().unwrap().to_owned()
+}
+
+#[tokio::test]
+async fn the_email_stays_the_same_until_the_new_address_is_confirmed() {
+    let app = spawn_app().await;
+    let (link, _) = preferences_link(&app).await;
+
+    request_email_change(&app, &link, "ursula@example.com").await;
+
+    let saved = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.email, "ursula_le_guin@gmail.com");
+}
+
+#[tokio::test]
+async fn confirming_an_email_change_swaps_the_address_and_keeps_history() {
+    let app = spawn_app().await;
+    let (link, _) = preferences_link(&app).await;
+    let confirmation_link = request_email_change(&app, &link, "ursula@example.com").await;
+
+    let response = reqwest::get(&confirmation_link).await.unwrap();
+
+    assert_eq!(200, response.status().as_u16());
+    let saved = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.email, "ursula@example.com");
+    let history = sqlx::query!("SELECT previous_email, new_email FROM subscriber_email_history")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch email history.");
+    assert_eq!(history.previous_email, "ursula_le_guin@gmail.com");
+    assert_eq!(history.new_email, "ursula@example.com");
+
+    // Confirmation links are single use.
+    let response = reqwest::get(&confirmation_link).await.unwrap();
+    assert_eq!(404, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn expired_email_change_links_change_nothing() {
+    let app = spawn_app().await;
+    let (link, _) = preferences_link(&app).await;
+    let confirmation_link = request_email_change(&app, &link, "ursula@example.com").await;
+    sqlx::query!("UPDATE email_change_requests SET expires_at = now() - interval '1 minute'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let response = reqwest::get(&confirmation_link).await.unwrap();
+
+    assert_eq!(410, response.status().as_u16());
+    let saved = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.email, "ursula_le_guin@gmail.com");
+}
+
+#[tokio::test]
+async fn email_changes_to_risky_addresses_are_flagged() {
+    let app = spawn_app().await;
+    let (link, _) = preferences_link(&app).await;
+    let confirmation_link = request_email_change(&app, &link, "postmaster@example.com").await;
+
+    let response = reqwest::get(&confirmation_link).await.unwrap();
+
+    assert_eq!(200, response.status().as_u16());
+    let saved = sqlx::query!("SELECT email, flagged_as FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.email, "postmaster@example.com");
+    assert_eq!(saved.flagged_as.as_deref(), Some("role_account"));
+}
+
+#[tokio::test]
+async fn email_changes_to_an_address_already_subscribed_are_rejected() {
+    let app = spawn_app().await;
+    let (link, _) = preferences_link(&app).await;
+    app.post_subscriptions("name=other&email=taken%40example.com".into())
+        .await;
+
+    let response = post_form(
+        &link,
+        &format!("{}/email", without_query(&link)),
+        "email=taken%40example.com",
+    )
+    .await;
+
+    assert_eq!(409, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn email_changes_to_an_invalid_address_are_a_400() {
+    let app = spawn_app().await;
+    let (link, _) = preferences_link(&app).await;
+
+    let response = post_form(
+        &link,
+        &format!("{}/email", without_query(&link)),
+        "email=not-an-email",
+    )
+    .await;
+
+    assert_eq!(400, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn weekly_readers_get_at_most_one_issue_a_week() {
+    let app = spawn_app().await;
+    preferences_link(&app).await;
+    sqlx::query!("UPDATE subscriptions SET delivery_frequency = 'weekly'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    let sent_emails = || async { app.email_server.received_requests().await.unwrap().len() };
+    let issue = serde_json::json!({
+        "title": "Again",
+        "content": {"text": "Hi", "html": "<p>Hi</p>"},
+        "tracking": false,
+    });
+    let before = sent_emails().await;
+
+    app.post_issues(issue.clone()).await.error_for_status().unwrap();
+    assert_eq!(before, sent_emails().await);
+    sqlx::query!("UPDATE issue_deliveries SET sent_at = now() - interval '8 days'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    app.post_issues(issue).await.error_for_status().unwrap();
+
+    assert_eq!(before + 1, sent_emails().await);
+}
@@ -0,0 +1,221 @@
+use crate::helpers::{spawn_app, spawn_app_with, TestApp};
+use newsletter::configuration::{RateLimitStore, Settings};
+use newsletter::rate_limiting::{
+    purge_expired_rate_limits, PostgresRateLimiter, RateLimit, RateLimiter,
+};
+use std::time::Duration;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+async fn spawn_limited_app(configure: impl FnOnce(&mut Settings)) -> TestApp {
+    let app = spawn_app_with(configure).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app
+}
+
+async fn sign_up(app: &TestApp, email: &str) -> reqwest::Response {
+    app.post_subscriptions(format!(
+        "name=le%20guin&email={}",
+        email.replace('@', "%40")
+    ))
+    .await
+}
+
+/// Signs up through a proxy that says the request came from `client`.
+async fn sign_up_from(app: &TestApp, email: &str, client: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("{}/subscriptions", app.address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .header("X-Forwarded-For", client)
+        .body(format!(
+            "name=le%20guin&email={}",
+            email.replace('@', "%40")
+        ))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+fn retry_after(response: &reqwest::Response) -> u64 {
+    response.headers()["Retry-After"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn signups_over_the_per_ip_limit_get_a_429() {
+    let app = spawn_limited_app(|c| c.subscriptions.rate_limiting.per_ip_requests = 2).await;
+
+    sign_up(&app, "ursula@example.com")
+        .await
+        .error_for_status()
+        .unwrap();
+    sign_up(&app, "ursula@gmail.com")
+        .await
+        .error_for_status()
+        .unwrap();
+    let response = sign_up(&app, "ursula@outlook.com").await;
+
+    assert_eq!(429, response.status().as_u16());
+    assert_eq!(
+        "application/problem+json",
+        response.headers()["Content-Type"]
+    );
+    let wait = retry_after(&response);
+    assert!(
+        (3590..=3600).contains(&wait),
+        "Unexpected Retry-After {}",
+        wait
+    );
+    let saved = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(2, saved.len());
+}
+
+#[tokio::test]
+async fn signups_over_the_per_domain_limit_get_a_429() {
+    let app = spawn_limited_app(|c| c.subscriptions.rate_limiting.per_domain_requests = 2).await;
+
+    sign_up(&app, "ursula@example.com")
+        .await
+        .error_for_status()
+        .unwrap();
+    app.post_subscriptions_json(
+        serde_json::json!({"name": "le guin", "email": "le-guin@example.com"}),
+    )
+    .await
+    .error_for_status()
+    .unwrap();
+    let same_domain = sign_up(&app, "guin@EXAMPLE.com").await;
+    let other_domain = sign_up(&app, "ursula@gmail.com").await;
+
+    assert_eq!(429, same_domain.status().as_u16());
+    assert_eq!(200, other_domain.status().as_u16());
+}
+
+#[tokio::test]
+async fn subdomains_count_towards_their_registrable_domain() {
+    let app = spawn_limited_app(|c| c.subscriptions.rate_limiting.per_domain_requests = 1).await;
+
+    sign_up(&app, "ursula@example.com")
+        .await
+        .error_for_status()
+        .unwrap();
+    let response = sign_up(&app, "le-guin@x7f3.example.com").await;
+
+    assert_eq!(429, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn shared_webmail_domains_are_not_limited() {
+    let app = spawn_limited_app(|c| c.subscriptions.rate_limiting.per_domain_requests = 1).await;
+
+    for email in ["ursula@gmail.com", "le-guin@gmail.com", "guin@GMAIL.com"] {
+        sign_up(&app, email).await.error_for_status().unwrap();
+    }
+    sign_up(&app, "ursula@example.com")
+        .await
+        .error_for_status()
+        .unwrap();
+    let response = sign_up(&app, "le-guin@example.com").await;
+
+    assert_eq!(429, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn trusted_proxies_name_the_client() {
+    let app = spawn_limited_app(|c| {
+        c.application.trusted_proxies = vec!["127.0.0.1".parse().unwrap()];
+        c.subscriptions.rate_limiting.per_ip_requests = 1;
+    })
+    .await;
+
+    sign_up_from(&app, "ursula@example.com", "192.0.2.1")
+        .await
+        .error_for_status()
+        .unwrap();
+    sign_up_from(&app, "le-guin@example.com", "192.0.2.2")
+        .await
+        .error_for_status()
+        .unwrap();
+    let response = sign_up_from(&app, "guin@example.com", "192.0.2.1").await;
+
+    assert_eq!(429, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn forwarding_headers_of_other_peers_are_ignored() {
+    let app = spawn_limited_app(|c| c.subscriptions.rate_limiting.per_ip_requests = 1).await;
+
+    sign_up_from(&app, "ursula@example.com", "192.0.2.1")
+        .await
+        .error_for_status()
+        .unwrap();
+    let response = sign_up_from(&app, "le-guin@example.com", "192.0.2.2").await;
+
+    assert_eq!(429, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn limited_signups_send_no_email() {
+    let app = spawn_app_with(|c| c.subscriptions.rate_limiting.per_ip_requests = 1).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    sign_up(&app, "ursula@example.com").await;
+    sign_up(&app, "ursula@gmail.com").await;
+}
+
+#[tokio::test]
+async fn the_postgres_store_limits_signups() {
+    let app = spawn_limited_app(|c| {
+        c.subscriptions.rate_limiting.store = RateLimitStore::Postgres;
+        c.subscriptions.rate_limiting.per_ip_requests = 1;
+    })
+    .await;
+
+    sign_up(&app, "ursula@example.com")
+        .await
+        .error_for_status()
+        .unwrap();
+    let response = sign_up(&app, "ursula@gmail.com").await;
+
+    assert_eq!(429, response.status().as_u16());
+    let counter = sqlx::query!("SELECT hits FROM rate_limit_counters WHERE key LIKE 'signup:ip:%'")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(2, counter.hits);
+}
+
+#[tokio::test]
+async fn postgres_counts_are_shared_and_expire() {
+    let app = spawn_app().await;
+    let limit = RateLimit {
+        requests: 1,
+        window: Duration::from_secs(60),
+    };
+    let first = PostgresRateLimiter::new(app.db_pool.clone());
+    let second = PostgresRateLimiter::new(app.db_pool.clone());
+
+    assert_eq!(None, first.hit("key", limit).await.unwrap());
+    assert!(second.hit("key", limit).await.unwrap().is_some());
+    sqlx::query!("UPDATE rate_limit_counters SET expires_at = now() - interval '1 second'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(1, purge_expired_rate_limits(&app.db_pool).await.unwrap());
+    assert_eq!(None, second.hit("key", limit).await.unwrap());
+}
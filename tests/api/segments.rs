@@ -0,0 +1,112 @@
+use crate::helpers::{spawn_app, TestApp};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+async fn create_subscribers(app: &TestApp, emails: &[&str]) {
+    let _guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount_as_scoped(&app.email_server)
+        .await;
+    for email in emails {
+        app.post_confirmed_subscription(format!("name=reader&email={}", email.replace('@', "%40")))
+            .await
+            .error_for_status()
+            .unwrap();
+    }
+}
+
+#[tokio::test]
+async fn invalid_segment_filters_are_rejected() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_segments(serde_json::json!({"name": "broken", "filter": "tag:beta AND"}))
+        .await;
+
+    assert_eq!(400, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn saving_a_segment_reports_how_many_subscribers_match() {
+    let app = spawn_app().await;
+    create_subscribers(&app, &["a@example.com", "b@example.com"]).await;
+    app.post_subscriber_tags("a@example.com", &["beta"]).await;
+
+    let response = app
+        .post_segments(serde_json::json!({
+            "name": "beta testers",
+            "filter": "tag:beta AND subscribed_at >= 2020-01-01",
+        }))
+        .await;
+
+    assert_eq!(200, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(1, body["matching_subscribers"]);
+}
+
+#[tokio::test]
+async fn tagging_an_unknown_subscriber_is_a_404() {
+    let app = spawn_app().await;
+
+    let response = app
+        .admin_request(
+            reqwest::Method::POST,
+            &format!("/admin/subscribers/{}/tags", uuid::Uuid::new_v4()),
+        )
+        .json(&serde_json::json!({"tags": ["beta"]}))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(404, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn issues_targeting_a_segment_only_reach_matching_subscribers() {
+    let app = spawn_app().await;
+    create_subscribers(&app, &["a@example.com", "b@example.com", "c@example.com"]).await;
+    app.post_subscriber_tags("a@example.com", &["beta"]).await;
+    app.post_subscriber_tags("b@example.com", &["beta", "churned"]).await;
+    app.post_segments(serde_json::json!({
+        "name": "active beta",
+        "filter": "tag:beta AND NOT tag:churned",
+    }))
+    .await
+    .error_for_status()
+    .unwrap();
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_issues(serde_json::json!({
+            "title": "Beta news",
+            "content": {"text": "Hello", "html": "<p>Hello</p>"},
+            "segment": "active beta",
+        }))
+        .await;
+
+    assert_eq!(200, response.status().as_u16());
+    let request = app.email_server.received_requests().await.unwrap().pop().unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+    assert_eq!("a@example.com", body["To"]);
+}
+
+#[tokio::test]
+async fn issues_targeting_an_unknown_segment_are_rejected() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_issues(serde_json::json!({
+            "title": "Beta news",
+            "content": {"text": "Hello", "html": "<p>Hello</p>"},
+            "segment": "nobody",
+        }))
+        .await;
+
+    assert_eq!(400, response.status().as_u16());
+}
@@ -0,0 +1,250 @@
+use crate::helpers::{spawn_app, spawn_app_with, TestApp};
+use chrono::{Duration, Utc};
+use newsletter::signup_protection::{
+    proof_of_work_challenge, proof_of_work_valid, purge_used_form_tokens,
+};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+const SIGNUP: &str = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+async fn mock_email(app: &TestApp, expected: u64) {
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(expected)
+        .mount(&app.email_server)
+        .await;
+}
+
+async fn get_challenge(app: &TestApp) -> serde_json::Value {
+    reqwest::get(format!("{}/subscriptions/challenge", app.address))
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap()
+}
+
+/// A nonce solving the proof of work of `token` and `email`, and none for
+/// `other_email`.
+fn solve(token: &str, email: &str, other_email: &str, difficulty: u32) -> String {
+    (0u64..)
+        .map(|nonce| nonce.to_string())
+        .find(|nonce| {
+            proof_of_work_valid(&proof_of_work_challenge(token, email), nonce, difficulty)
+                && !proof_of_work_valid(
+                    &proof_of_work_challenge(token, other_email),
+                    nonce,
+                    difficulty,
+                )
+        })
+        .unwrap()
+}
+
+async fn error_codes(response: reqwest::Response) -> Vec<(String, String)> {
+    let body: serde_json::Value = response.json().await.unwrap();
+    body["errors"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| {
+            (
+                e["field"].as_str().unwrap().to_owned(),
+                e["code"].as_str().unwrap().to_owned(),
+            )
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn a_filled_honeypot_rejects_the_signup() {
+    let app = spawn_app().await;
+    mock_email(&app, 0).await;
+
+    let response = app
+        .post_subscriptions(format!("{}&website=http%3A%2F%2Fspam.example.com", SIGNUP))
+        .await;
+
+    assert_eq!(400, response.status().as_u16());
+    assert_eq!(
+        vec![("form".to_owned(), "automated".to_owned())],
+        error_codes(response).await
+    );
+    let saved = sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(saved.is_empty());
+}
+
+#[tokio::test]
+async fn an_empty_honeypot_is_not_a_custom_attribute() {
+    let app = spawn_app().await;
+    mock_email(&app, 1).await;
+
+    let response = app.post_subscriptions(format!("{}&website=", SIGNUP)).await;
+
+    assert_eq!(200, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn signups_need_a_form_token_old_enough() {
+    let app = spawn_app_with(|c| c.subscriptions.spam_protection.min_fill_seconds = 3).await;
+    mock_email(&app, 1).await;
+    let settings = newsletter::configuration::get_configuration()
+        .unwrap()
+        .subscriptions
+        .spam_protection;
+    let fresh = get_challenge(&app).await["form_token"]
+        .as_str()
+        .unwrap()
+        .to_owned();
+    let filled_in = settings.issue_form_token(Utc::now() - Duration::seconds(10));
+
+    let without_token = app.post_subscriptions(SIGNUP.into()).await;
+    let too_fast = app
+        .post_subscriptions(format!("{}&form_token={}", SIGNUP, fresh))
+        .await;
+    let forged = app
+        .post_subscriptions(format!("{}&form_token=1.00", SIGNUP))
+        .await;
+    let in_time = app
+        .post_subscriptions(format!("{}&form_token={}", SIGNUP, filled_in))
+        .await;
+
+    assert_eq!(
+        vec![("form_token".to_owned(), "missing".to_owned())],
+        error_codes(without_token).await
+    );
+    assert_eq!(
+        vec![("form_token".to_owned(), "too_fast".to_owned())],
+        error_codes(too_fast).await
+    );
+    assert_eq!(
+        vec![("form_token".to_owned(), "invalid".to_owned())],
+        error_codes(forged).await
+    );
+    assert_eq!(200, in_time.status().as_u16());
+}
+
+#[tokio::test]
+async fn the_proof_of_work_is_checked_against_the_form_token_and_email() {
+    let app =
+        spawn_app_with(|c| c.subscriptions.spam_protection.proof_of_work_difficulty = 8).await;
+    mock_email(&app, 1).await;
+    let challenge = get_challenge(&app).await;
+    assert_eq!(8, challenge["proof_of_work_difficulty"]);
+    let token = challenge["form_token"].as_str().unwrap();
+    let solution = solve(token, "ursula_le_guin@gmail.com", "le-guin@gmail.com", 8);
+    let wrong = (0u64..)
+        .map(|nonce| nonce.to_string())
+        .find(|nonce| {
+            !proof_of_work_valid(
+                &proof_of_work_challenge(token, "ursula_le_guin@gmail.com"),
+                nonce,
+                8,
+            )
+        })
+        .unwrap();
+
+    let unsolved = app
+        .post_subscriptions_json(serde_json::json!({
+            "name": "le guin",
+            "email": "ursula_le_guin@gmail.com",
+            "form_token": token,
+            "proof_of_work": wrong,
+        }))
+        .await;
+    let other_email = app
+        .post_subscriptions_json(serde_json::json!({
+            "name": "le guin",
+            "email": "le-guin@gmail.com",
+            "form_token": token,
+            "proof_of_work": solution,
+        }))
+        .await;
+    let solved = app
+        .post_subscriptions_json(serde_json::json!({
+            "name": "le guin",
+            "email": "ursula_le_guin@gmail.com",
+            "form_token": token,
+            "proof_of_work": solution,
+        }))
+        .await;
+
+    assert_eq!(
+        vec![("proof_of_work".to_owned(), "invalid".to_owned())],
+        error_codes(unsolved).await
+    );
+    assert_eq!(
+        vec![("proof_of_work".to_owned(), "invalid".to_owned())],
+        error_codes(other_email).await
+    );
+    assert_eq!(200, solved.status().as_u16());
+}
+
+#[tokio::test]
+async fn a_form_token_is_good_for_one_signup() {
+    let app = spawn_app_with(|c| {
+        c.subscriptions.spam_protection.min_fill_seconds = 3;
+        c.subscriptions.spam_protection.proof_of_work_difficulty = 8;
+    })
+    .await;
+    mock_email(&app, 1).await;
+    let settings = newsletter::configuration::get_configuration()
+        .unwrap()
+        .subscriptions
+        .spam_protection;
+    let token = settings.issue_form_token(Utc::now() - Duration::seconds(10));
+    let signup = serde_json::json!({
+        "name": "le guin",
+        "email": "ursula_le_guin@gmail.com",
+        "form_token": token,
+        "proof_of_work": solve(&token, "ursula_le_guin@gmail.com", "le-guin@gmail.com", 8),
+    });
+
+    let first = app.post_subscriptions_json(signup.clone()).await;
+    let replayed = app.post_subscriptions_json(signup).await;
+
+    assert_eq!(200, first.status().as_u16());
+    assert_eq!(
+        vec![("form_token".to_owned(), "used".to_owned())],
+        error_codes(replayed).await
+    );
+    sqlx::query!("UPDATE used_form_tokens SET expires_at = now() - interval '1 second'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(1, purge_used_form_tokens(&app.db_pool).await.unwrap());
+}
+
+#[tokio::test]
+async fn the_signup_form_carries_the_spam_defences() {
+    let app =
+        spawn_app_with(|c| c.subscriptions.spam_protection.proof_of_work_difficulty = 8).await;
+
+    let response = reqwest::get(format!("{}/subscriptions", app.address))
+        .await
+        .unwrap();
+
+    assert_eq!(200, response.status().as_u16());
+    let page = response.text().await.unwrap();
+    assert!(page.contains(r#"name="form_token""#));
+    assert!(page.contains(r#"name="website""#));
+    assert!(page.contains(r#"name="proof_of_work""#));
+    assert!(page.contains("crypto.subtle.digest"));
+}
+
+#[tokio::test]
+async fn the_challenge_has_no_token_when_nothing_checks_it() {
+    let app = spawn_app().await;
+
+    let challenge = get_challenge(&app).await;
+
+    assert!(challenge["form_token"].is_null());
+    assert_eq!("website", challenge["honeypot_field"]);
+    assert_eq!(0, challenge["proof_of_work_difficulty"]);
+}
@@ -0,0 +1,128 @@
+use crate::helpers::{spawn_app, TestApp};
+use newsletter::subscription_cleanup::{
+    cleanup_unconfirmed, refresh_canonical_emails, CleanupReport,
+};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+async fn subscribe(app: &TestApp, email: &str) {
+    app.post_subscriptions(format!(
+        "name=reader&email={}",
+        email.replace('@', "%40").replace('+', "%2B")
+    ))
+    .await
+    .error_for_status()
+    .unwrap();
+}
+
+/// Pretends the signup of `email` happened `days` ago.
+async fn backdate(app: &TestApp, email: &str, days: i32) {
+    sqlx::query!(
+        r#"
+        UPDATE subscriptions SET subscribed_at = now() - make_interval(days => $2)
+        WHERE email = $1
+        "#,
+        email,
+        days
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    sqlx::query!(
+        r#"
+        UPDATE subscription_tokens
+        SET created_at = now() - make_interval(days => $2),
+            expires_at = now() - make_interval(days => $2 - 3)
+        WHERE subscriber_id = (SELECT id FROM subscriptions WHERE email = $1)
+        "#,
+        email,
+        days
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn cleanup_purges_stale_unconfirmed_subscribers_only() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    subscribe(&app, "stale@example.com").await;
+    subscribe(&app, "recent@example.com").await;
+    app.post_confirmed_subscription("name=reader&email=confirmed%40example.com".into())
+        .await;
+    backdate(&app, "stale@example.com", 40).await;
+    backdate(&app, "recent@example.com", 10).await;
+    backdate(&app, "confirmed@example.com", 40).await;
+
+    let report = cleanup_unconfirmed(&app.db_pool, chrono::Duration::days(30))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        report,
+        CleanupReport {
+            expired_tokens: 2,
+            purged_subscribers: 1
+        }
+    );
+    let remaining: Vec<_> = sqlx::query!("SELECT email FROM subscriptions ORDER BY email")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|r| r.email)
+        .collect();
+    assert_eq!(remaining, vec!["confirmed@example.com", "recent@example.com"]);
+    let kept = sqlx::query!(
+        r#"
+        SELECT s.email FROM subscription_tokens t
+        JOIN subscriptions s ON s.id = t.subscriber_id
+        "#
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(1, kept.len());
+    assert_eq!("recent@example.com", kept[0].email);
+}
+
+async fn canonical_emails(app: &TestApp) -> Vec<String> {
+    sqlx::query!("SELECT canonical_email FROM subscriptions ORDER BY email")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|r| r.canonical_email)
+        .collect()
+}
+
+#[tokio::test]
+async fn canonical_emails_follow_the_alias_folding_setting() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    subscribe(&app, "octavia+news@example.com").await;
+    subscribe(&app, "ursula.le.guin+news@gmail.com").await;
+
+    let folded = refresh_canonical_emails(&app.db_pool, true).await.unwrap();
+
+    assert_eq!(1, folded);
+    assert_eq!(
+        vec!["octavia+news@example.com", "ursulaleguin@gmail.com"],
+        canonical_emails(&app).await
+    );
+    let unfolded = refresh_canonical_emails(&app.db_pool, false).await.unwrap();
+    assert_eq!(1, unfolded);
+    assert_eq!(
+        vec!["octavia+news@example.com", "ursula.le.guin+news@gmail.com"],
+        canonical_emails(&app).await
+    );
+}
@@ -1,4 +1,4 @@
-use crate::helpers::spawn_app;
+use crate::helpers::{spawn_app, spawn_app_with, TestApp};
 use wiremock::{Mock,ResponseTemplate};
 use wiremock::matchers::{method,path};
 
@@ -97,3 +97,582 @@ async fn subscribe_returns_400_when_data_missing(){
         );
     }
 }
+
+#[tokio::test]
+async fn subscribe_persists_the_new_subscriber_as_pending() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.status, "pending_confirmation");
+}
+
+#[tokio::test]
+async fn clicking_the_confirmation_link_confirms_the_subscriber() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+
+    let response = reqwest::get(app.get_confirmation_link(email_request))
+        .await
+        .unwrap();
+
+    assert_eq!(200, response.status().as_u16());
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.status, "confirmed");
+    let membership = sqlx::query!("SELECT status FROM list_memberships")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch membership.");
+    assert_eq!(membership.status, "confirmed");
+}
+
+#[tokio::test]
+async fn unknown_confirmation_tokens_are_rejected_with_a_401() {
+    let app = spawn_app().await;
+
+    let response = reqwest::get(format!(
+        "{}/subscriptions/confirm?subscription_token=not-a-token",
+        app.address
+    ))
+    .await
+    .unwrap();
+
+    assert_eq!(401, response.status().as_u16());
+    let html = response.text().await.unwrap();
+    assert!(html.contains(r#"href="/subscriptions""#));
+}
+
+#[tokio::test]
+async fn subscribing_twice_while_pending_resends_the_same_confirmation_link() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    app.post_subscriptions(body.into()).await;
+    let response = app.post_subscriptions(body.into()).await;
+
+    assert_eq!(200, response.status().as_u16());
+    let requests = app.email_server.received_requests().await.unwrap();
+    assert_eq!(
+        app.get_confirmation_link(&requests[0]),
+        app.get_confirmation_link(&requests[1])
+    );
+    let saved = sqlx::query!("SELECT COUNT(*) AS \"count!\" FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to count subscriptions.");
+    assert_eq!(saved.count, 1);
+}
+
+#[tokio::test]
+async fn subscribing_again_once_confirmed_sends_nothing() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    {
+        let _guard = Mock::given(path("/email"))
+            .and(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount_as_scoped(&app.email_server)
+            .await;
+        app.post_confirmed_subscription(body.into()).await;
+    }
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app.post_subscriptions(body.into()).await;
+
+    assert_eq!(200, response.status().as_u16());
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscriptions.");
+    assert_eq!(saved.len(), 1);
+    assert_eq!(saved[0].status, "confirmed");
+}
+
+#[tokio::test]
+async fn unsubscribed_readers_go_through_a_fresh_double_opt_in() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    app.post_confirmed_subscription(body.into()).await;
+    sqlx::query!("UPDATE subscriptions SET status = 'unsubscribed'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    sqlx::query!("UPDATE list_memberships SET status = 'unsubscribed'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let response = app.post_subscriptions(body.into()).await;
+
+    assert_eq!(200, response.status().as_u16());
+    let requests = app.email_server.received_requests().await.unwrap();
+    let old_link = app.get_confirmation_link(&requests[0]);
+    let new_link = app.get_confirmation_link(&requests[1]);
+    assert_ne!(old_link, new_link);
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.status, "pending_confirmation");
+
+    assert_eq!(401, reqwest::get(&old_link).await.unwrap().status().as_u16());
+    reqwest::get(&new_link).await.unwrap().error_for_status().unwrap();
+    let membership = sqlx::query!("SELECT status FROM list_memberships")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch membership.");
+    assert_eq!(membership.status, "confirmed");
+}
+
+async fn expire_subscription_tokens(app: &TestApp) {
+    sqlx::query!("UPDATE subscription_tokens SET expires_at = now() - interval '1 hour'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn expired_confirmation_links_offer_to_resend() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    let old_link = app.get_confirmation_link(&app.email_server.received_requests().await.unwrap()[0]);
+    expire_subscription_tokens(&app).await;
+
+    let response = reqwest::get(&old_link).await.unwrap();
+
+    assert_eq!(410, response.status().as_u16());
+    let html = response.text().await.unwrap();
+    assert!(html.contains(r#"action="/subscriptions/confirm/resend""#));
+    let token = old_link.split("subscription_token=").nth(1).unwrap();
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions/confirm/resend", app.address))
+        .form(&[("subscription_token", token)])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(200, response.status().as_u16());
+    let new_link = app.get_confirmation_link(&app.email_server.received_requests().await.unwrap()[1]);
+    assert_eq!(401, reqwest::get(&old_link).await.unwrap().status().as_u16());
+    reqwest::get(&new_link).await.unwrap().error_for_status().unwrap();
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.status, "confirmed");
+}
+
+#[tokio::test]
+async fn subscribing_again_after_the_link_expired_sends_a_new_one() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    app.post_subscriptions(body.into()).await;
+    expire_subscription_tokens(&app).await;
+
+    app.post_subscriptions(body.into()).await;
+
+    let requests = app.email_server.received_requests().await.unwrap();
+    let new_link = app.get_confirmation_link(&requests[1]);
+    assert_ne!(app.get_confirmation_link(&requests[0]), new_link);
+    reqwest::get(&new_link).await.unwrap().error_for_status().unwrap();
+}
+
+#[tokio::test]
+async fn emails_differing_only_by_case_are_the_same_subscriber() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions("name=le%20guin&email=Ursula_Le_Guin%40Gmail.com".into())
+        .await;
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+
+    assert_eq!(200, response.status().as_u16());
+    let saved = sqlx::query!("SELECT email, canonical_email FROM subscriptions")
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscriptions.");
+    assert_eq!(saved.len(), 1);
+    assert_eq!(saved[0].email, "Ursula_Le_Guin@Gmail.com");
+    assert_eq!(saved[0].canonical_email, "ursula_le_guin@gmail.com");
+    let requests = app.email_server.received_requests().await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&requests[0].body).unwrap();
+    assert_eq!(body["To"], "Ursula_Le_Guin@gmail.com");
+}
+
+#[tokio::test]
+async fn disposable_addresses_are_rejected() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula%40mailinator.com".into())
+        .await;
+
+    assert_eq!(400, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["errors"][0]["code"], "disposable_domain");
+    assert_eq!(
+        body["errors"][0]["message"],
+        "mailinator.com is a disposable email provider."
+    );
+}
+
+#[tokio::test]
+async fn role_addresses_are_accepted_but_flagged() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_subscriptions("name=le%20guin&email=postmaster%40example.com".into())
+        .await;
+
+    assert_eq!(200, response.status().as_u16());
+    let saved = sqlx::query!("SELECT flagged_as FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.flagged_as.as_deref(), Some("role_account"));
+}
+
+#[tokio::test]
+async fn risky_email_lists_are_read_from_the_configured_files() {
+    let disposable_domains = std::env::temp_dir().join(format!("{}.txt", uuid::Uuid::new_v4()));
+    std::fs::write(&disposable_domains, "# Our own list\nexample.com\n").unwrap();
+    let file = disposable_domains.to_str().unwrap().to_owned();
+    let app = spawn_app_with(|c| c.subscriptions.disposable_domains_file = file).await;
+
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula%40example.com".into())
+        .await;
+
+    assert_eq!(400, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["errors"][0]["code"], "disposable_domain");
+    std::fs::remove_file(disposable_domains).unwrap();
+}
+
+#[tokio::test]
+async fn domains_that_cannot_receive_email_are_rejected() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula%40nowhere.test".into())
+        .await;
+
+    assert_eq!(400, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["errors"][0]["code"], "no_mail_server");
+    assert_eq!(
+        body["errors"][0]["message"],
+        "nowhere.test does not accept email."
+    );
+}
+
+#[tokio::test]
+async fn misspelled_popular_domains_get_a_suggestion() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_subscriptions("name=le%20guin&email=Ursula%40hotmial.com".into())
+        .await;
+
+    assert_eq!(400, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["errors"][0]["code"], "typo");
+    assert_eq!(body["errors"][0]["did_you_mean"], "Ursula@hotmail.com");
+}
+
+#[tokio::test]
+async fn suspected_typos_can_be_kept() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    let suspected = app
+        .post_subscriptions("name=le%20guin&email=Ursula%40yahoo.co.kr".into())
+        .await;
+    let form = app
+        .post_subscriptions("name=le%20guin&email=Ursula%40yahoo.co.kr&keep_email=true".into())
+        .await;
+    let json = app
+        .post_subscriptions_json(serde_json::json!({
+            "name": "le guin",
+            "email": "octavia@yahoo.co.kr",
+            "keep_email": true,
+        }))
+        .await;
+
+    assert_eq!(400, suspected.status().as_u16());
+    assert_eq!(200, form.status().as_u16());
+    assert_eq!(200, json.status().as_u16());
+    let saved = sqlx::query!("SELECT email FROM subscriptions ORDER BY email")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!("Ursula@yahoo.co.kr", saved[0].email);
+    assert_eq!("octavia@yahoo.co.kr", saved[1].email);
+}
+
+#[tokio::test]
+async fn the_signup_form_offers_to_keep_a_suspected_typo() {
+    let app = spawn_app().await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions", app.address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .header("Accept", "text/html")
+        .body("name=le%20guin&email=Ursula%40hotmial.com")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(400, response.status().as_u16());
+    let page = response.text().await.unwrap();
+    assert!(page.contains("Did you mean Ursula@hotmail.com?"));
+    assert!(page.contains(r#"name="keep_email" value="true""#));
+}
+
+#[tokio::test]
+async fn validation_errors_are_reported_as_problem_json_for_every_field() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_subscriptions("name=%3Cscript%3E&email=definitely-not-an-email&list=nope".into())
+        .await;
+
+    assert_eq!(400, response.status().as_u16());
+    assert_eq!(
+        "application/problem+json",
+        response.headers()["Content-Type"].to_str().unwrap()
+    );
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["status"], 400);
+    let errors: Vec<_> = body["errors"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| (e["field"].as_str().unwrap(), e["code"].as_str().unwrap()))
+        .collect();
+    assert_eq!(
+        errors,
+        vec![
+            ("name", "forbidden_character"),
+            ("email", "invalid_syntax"),
+            ("list", "unknown"),
+        ]
+    );
+    assert!(!body.to_string().contains("definitely-not-an-email"));
+}
+
+#[tokio::test]
+async fn html_clients_get_the_form_back_with_inline_errors() {
+    let app = spawn_app().await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions", app.address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .header("Accept", "text/html,application/xhtml+xml")
+        .body("name=le%20guin&email=Ursula%40hotmial.com")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(400, response.status().as_u16());
+    let html = response.text().await.unwrap();
+    assert!(html.contains(r#"name="name" value="le guin""#));
+    assert!(html.contains(r#"name="email" value="Ursula@hotmial.com""#));
+    assert!(html.contains("Did you mean Ursula@hotmail.com?"));
+}
+
+#[tokio::test]
+async fn json_signups_of_new_and_confirmed_subscribers_get_the_same_answer() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+    let signup = serde_json::json!({
+        "name": "le guin",
+        "email": "ursula_le_guin@gmail.com",
+    });
+
+    let new = app.post_subscriptions_json(signup.clone()).await;
+    let confirmation_link =
+        app.get_confirmation_link(&app.email_server.received_requests().await.unwrap()[0]);
+    reqwest::get(confirmation_link)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    let confirmed = app.post_subscriptions_json(signup.clone()).await;
+    let other = app
+        .post_subscriptions_json(serde_json::json!({
+            "name": "butler",
+            "email": "octavia@example.com",
+        }))
+        .await;
+
+    assert_eq!(200, new.status().as_u16());
+    assert_eq!(200, confirmed.status().as_u16());
+    let new = new.text().await.unwrap();
+    assert!(!new.contains("subscriber_id"));
+    assert_eq!(new, confirmed.text().await.unwrap());
+    assert_eq!(new, other.text().await.unwrap());
+}
+
+#[tokio::test]
+async fn content_types_are_matched_case_insensitively() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    let json = reqwest::Client::new()
+        .post(format!("{}/subscriptions", app.address))
+        .header("Content-Type", "Application/JSON; charset=utf-8")
+        .body(r#"{"name": "le guin", "email": "ursula_le_guin@gmail.com"}"#)
+        .send()
+        .await
+        .unwrap();
+    let form = reqwest::Client::new()
+        .post(format!("{}/subscriptions", app.address))
+        .header("Content-Type", "Application/X-WWW-Form-URLEncoded")
+        .body("name=butler&email=octavia%40example.com")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(200, json.status().as_u16());
+    assert_eq!(200, form.status().as_u16());
+}
+
+#[tokio::test]
+async fn oversized_signups_are_rejected() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_subscriptions(format!(
+            "name=le%20guin&email=ursula_le_guin%40gmail.com&bio={}",
+            "a".repeat(20 * 1024)
+        ))
+        .await;
+
+    assert_eq!(413, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn json_signups_are_validated_like_forms() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_subscriptions_json(serde_json::json!({"name": "le guin"}))
+        .await;
+
+    assert_eq!(400, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["errors"][0]["field"], "email");
+    assert_eq!(body["errors"][0]["code"], "empty");
+}
+
+#[tokio::test]
+async fn malformed_json_is_rejected_with_a_problem() {
+    let app = spawn_app().await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions", app.address))
+        .header("Content-Type", "application/json")
+        .body(r#"{"name": "le guin","#)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(400, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["errors"][0]["field"], "body");
+    assert_eq!(body["errors"][0]["code"], "malformed");
+}
+
+#[tokio::test]
+async fn other_content_types_are_unsupported() {
+    let app = spawn_app().await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions", app.address))
+        .header("Content-Type", "text/plain")
+        .body("name=le%20guin&email=ursula_le_guin%40gmail.com")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(415, response.status().as_u16());
+}
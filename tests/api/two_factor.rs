@@ -0,0 +1,415 @@
+use crate::helpers::{spawn_app, TestApp, TestUser};
+use newsletter::domain::UserRole;
+use reqwest::Method;
+use std::time::{SystemTime, UNIX_EPOCH};
+use totp_rs::{Algorithm, Secret, TOTP};
+
+/// The code the authenticator app shows `steps_ahead` periods from now, the
+/// server accepts one period of drift.
+fn code_for(secret: &str, steps_ahead: u64) -> String {
+    let totp = TOTP::new_unchecked(
+        Algorithm::SHA1,
+        6,
+        1,
+        30,
+        Secret::Encoded(secret.to_owned()).to_bytes().unwrap(),
+        None,
+        String::new(),
+    );
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    totp.generate(now + steps_ahead * 30)
+}
+
+fn request_as(app: &TestApp, token: &str, method: Method, path: &str) -> reqwest::RequestBuilder {
+    reqwest::Client::new()
+        .request(method, format!("{}{}", app.address, path))
+        .bearer_auth(token)
+}
+
+struct EnrolledUser {
+    user: TestUser,
+    secret: String,
+    /// The code that confirmed the enrollment.
+    confirmed_with: String,
+    recovery_codes: Vec<String>,
+}
+
+/// Stores a user and turns two-factor authentication on, confirming with
+/// the code of the current period.
+async fn enrolled_user(app: &TestApp) -> EnrolledUser {
+    let user = TestUser::generate(UserRole::Editor);
+    user.store(&app.db_pool).await;
+    let token = user.login(app).await;
+    let enrollment: serde_json::Value =
+        request_as(app, &token, Method::POST, "/admin/account/two_factor")
+            .send()
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+    let secret = enrollment["secret"].as_str().unwrap().to_owned();
+    let confirmed_with = code_for(&secret, 0);
+    let confirmed: serde_json::Value = request_as(
+        app,
+        &token,
+        Method::POST,
+        "/admin/account/two_factor/confirm",
+    )
+    .json(&serde_json::json!({ "code": confirmed_with }))
+    .send()
+    .await
+    .unwrap()
+    .error_for_status()
+    .unwrap()
+    .json()
+    .await
+    .unwrap();
+    let recovery_codes = confirmed["recovery_codes"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|c| c.as_str().unwrap().to_owned())
+        .collect();
+    EnrolledUser {
+        user,
+        secret,
+        confirmed_with,
+        recovery_codes,
+    }
+}
+
+/// Logs in with the password and returns the pending session token.
+async fn start_login(app: &TestApp, user: &TestUser) -> String {
+    let body: serde_json::Value = app
+        .post_login(&serde_json::json!({"username": user.username, "password": user.password}))
+        .await
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(true, body["second_factor_required"]);
+    body["session_token"].as_str().unwrap().to_owned()
+}
+
+async fn post_second_factor(
+    app: &TestApp,
+    token: &str,
+    body: serde_json::Value,
+) -> reqwest::Response {
+    request_as(app, token, Method::POST, "/login/two_factor")
+        .json(&body)
+        .send()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn enrollment_hands_out_a_provisioning_uri_and_a_qr_code() {
+    let app = spawn_app().await;
+
+    let response = app
+        .admin_request(Method::POST, "/admin/account/two_factor")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(200, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    let secret = body["secret"].as_str().unwrap();
+    let uri = body["provisioning_uri"].as_str().unwrap();
+    assert!(uri.starts_with("otpauth://totp/newsletter:"));
+    assert!(uri.contains(&format!("secret={}", secret)));
+    assert!(body["qr_code_svg"].as_str().unwrap().contains("<svg"));
+}
+
+#[tokio::test]
+async fn secrets_are_stored_encrypted() {
+    let app = spawn_app().await;
+    let enrolled = enrolled_user(&app).await;
+
+    let stored = sqlx::query!(
+        r#"SELECT totp_secret AS "totp_secret!" FROM users WHERE username = $1"#,
+        enrolled.user.username
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap()
+    .totp_secret;
+
+    assert!(!stored.contains(&enrolled.secret));
+    let pending = start_login(&app, &enrolled.user).await;
+    let response = post_second_factor(
+        &app,
+        &pending,
+        serde_json::json!({ "code": code_for(&enrolled.secret, 1) }),
+    )
+    .await;
+    assert_eq!(200, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn confirming_enrollment_ends_the_other_sessions() {
+    let app = spawn_app().await;
+    let user = TestUser::generate(UserRole::Editor);
+    user.store(&app.db_pool).await;
+    let other = user.login(&app).await;
+    let current = user.login(&app).await;
+    let enrollment: serde_json::Value =
+        request_as(&app, &current, Method::POST, "/admin/account/two_factor")
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+
+    request_as(
+        &app,
+        &current,
+        Method::POST,
+        "/admin/account/two_factor/confirm",
+    )
+    .json(&serde_json::json!({ "code": code_for(enrollment["secret"].as_str().unwrap(), 0) }))
+    .send()
+    .await
+    .unwrap()
+    .error_for_status()
+    .unwrap();
+
+    for (token, expected) in [(&other, 401), (&current, 200)] {
+        let response = request_as(&app, token, Method::GET, "/admin/lists")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(expected, response.status().as_u16());
+    }
+}
+
+#[tokio::test]
+async fn enrollment_needs_a_valid_code_and_happens_once() {
+    let app = spawn_app().await;
+    let enrolled = enrolled_user(&app).await;
+    let token = app.login_as(UserRole::Viewer).await;
+    request_as(&app, &token, Method::POST, "/admin/account/two_factor")
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    let wrong_code = request_as(
+        &app,
+        &token,
+        Method::POST,
+        "/admin/account/two_factor/confirm",
+    )
+    .json(&serde_json::json!({"code": "000000"}))
+    .send()
+    .await
+    .unwrap();
+
+    assert_eq!(400, wrong_code.status().as_u16());
+    assert_eq!(10, enrolled.recovery_codes.len());
+    let pending = start_login(&app, &enrolled.user).await;
+    let body: serde_json::Value = post_second_factor(
+        &app,
+        &pending,
+        serde_json::json!({ "recovery_code": enrolled.recovery_codes[0] }),
+    )
+    .await
+    .json()
+    .await
+    .unwrap();
+    let again = request_as(
+        &app,
+        body["session_token"].as_str().unwrap(),
+        Method::POST,
+        "/admin/account/two_factor",
+    )
+    .send()
+    .await
+    .unwrap();
+    assert_eq!(409, again.status().as_u16());
+}
+
+#[tokio::test]
+async fn enrolled_users_need_a_code_after_the_password() {
+    let app = spawn_app().await;
+    let enrolled = enrolled_user(&app).await;
+    let pending = start_login(&app, &enrolled.user).await;
+
+    let before = request_as(&app, &pending, Method::GET, "/admin/lists")
+        .send()
+        .await
+        .unwrap();
+    let response = post_second_factor(
+        &app,
+        &pending,
+        serde_json::json!({ "code": code_for(&enrolled.secret, 1) }),
+    )
+    .await;
+
+    assert_eq!(401, before.status().as_u16());
+    assert_eq!(200, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(false, body["second_factor_required"]);
+    let token = body["session_token"].as_str().unwrap();
+    let after = request_as(&app, token, Method::GET, "/admin/lists")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(200, after.status().as_u16());
+    let reused = request_as(&app, &pending, Method::GET, "/admin/lists")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(401, reused.status().as_u16());
+}
+
+#[tokio::test]
+async fn a_wrong_code_ends_the_pending_login() {
+    let app = spawn_app().await;
+    let enrolled = enrolled_user(&app).await;
+    let pending = start_login(&app, &enrolled.user).await;
+
+    let wrong = post_second_factor(&app, &pending, serde_json::json!({"code": "not a code"})).await;
+    let right = post_second_factor(
+        &app,
+        &pending,
+        serde_json::json!({ "code": code_for(&enrolled.secret, 1) }),
+    )
+    .await;
+
+    assert_eq!(401, wrong.status().as_u16());
+    assert_eq!(401, right.status().as_u16());
+}
+
+#[tokio::test]
+async fn codes_cannot_be_replayed() {
+    let app = spawn_app().await;
+    let enrolled = enrolled_user(&app).await;
+    let pending = start_login(&app, &enrolled.user).await;
+
+    let response = post_second_factor(
+        &app,
+        &pending,
+        serde_json::json!({ "code": enrolled.confirmed_with }),
+    )
+    .await;
+
+    assert_eq!(401, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn recovery_codes_work_once() {
+    let app = spawn_app().await;
+    let enrolled = enrolled_user(&app).await;
+    let code = enrolled.recovery_codes[0].to_uppercase();
+
+    let pending = start_login(&app, &enrolled.user).await;
+    let first =
+        post_second_factor(&app, &pending, serde_json::json!({ "recovery_code": code })).await;
+    let pending = start_login(&app, &enrolled.user).await;
+    let second =
+        post_second_factor(&app, &pending, serde_json::json!({ "recovery_code": code })).await;
+
+    assert_eq!(200, first.status().as_u16());
+    assert_eq!(401, second.status().as_u16());
+    let stored = sqlx::query!("SELECT code_hash FROM recovery_codes")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(stored
+        .iter()
+        .all(|c| !enrolled.recovery_codes.contains(&c.code_hash)));
+}
+
+#[tokio::test]
+async fn the_second_step_needs_exactly_one_kind_of_code() {
+    let app = spawn_app().await;
+    let enrolled = enrolled_user(&app).await;
+    let pending = start_login(&app, &enrolled.user).await;
+
+    let response = post_second_factor(
+        &app,
+        &pending,
+        serde_json::json!({"code": "123456", "recovery_code": enrolled.recovery_codes[0]}),
+    )
+    .await;
+
+    assert_eq!(400, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn turning_two_factor_off_needs_a_code() {
+    let app = spawn_app().await;
+    let enrolled = enrolled_user(&app).await;
+    let pending = start_login(&app, &enrolled.user).await;
+    let body: serde_json::Value = post_second_factor(
+        &app,
+        &pending,
+        serde_json::json!({ "recovery_code": enrolled.recovery_codes[0] }),
+    )
+    .await
+    .json()
+    .await
+    .unwrap();
+    let token = body["session_token"].as_str().unwrap();
+
+    let without_code = request_as(&app, token, Method::DELETE, "/admin/account/two_factor")
+        .json(&serde_json::json!({"code": "000000"}))
+        .send()
+        .await
+        .unwrap();
+    let with_code = request_as(&app, token, Method::DELETE, "/admin/account/two_factor")
+        .json(&serde_json::json!({ "recovery_code": enrolled.recovery_codes[1] }))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(400, without_code.status().as_u16());
+    assert_eq!(200, with_code.status().as_u16());
+    let login: serde_json::Value = app
+        .post_login(&serde_json::json!({"username": enrolled.user.username, "password": enrolled.user.password}))
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(false, login["second_factor_required"]);
+}
+
+#[tokio::test]
+async fn only_a_valid_code_clears_failed_logins() {
+    let app = spawn_app().await;
+    let enrolled = enrolled_user(&app).await;
+    let count_failures = || async {
+        sqlx::query!(r#"SELECT count(*) AS "count!" FROM failed_logins"#)
+            .fetch_one(&app.db_pool)
+            .await
+            .unwrap()
+            .count
+    };
+
+    let pending = start_login(&app, &enrolled.user).await;
+    post_second_factor(&app, &pending, serde_json::json!({"code": "not a code"})).await;
+    start_login(&app, &enrolled.user).await;
+    assert_eq!(1, count_failures().await);
+    let pending = start_login(&app, &enrolled.user).await;
+    post_second_factor(
+        &app,
+        &pending,
+        serde_json::json!({ "recovery_code": enrolled.recovery_codes[0] }),
+    )
+    .await
+    .error_for_status()
+    .unwrap();
+
+    assert_eq!(0, count_failures().await);
+}